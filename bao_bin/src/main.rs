@@ -11,53 +11,100 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 // Note that docopt.rs currently has a bug related to commands wrapped over multiple lines, so
 // don't wrap them. https://github.com/docopt/docopt.rs/issues/244
 const USAGE: &str = "
-Usage: bao hash [<inputs>...]
-       bao encode <input> (<output> | --outboard=<file>)
-       bao decode <hash> [<input>] [<output>] [--outboard=<file>] [--start=<offset>] [--count=<count>]
+Usage: bao hash [<inputs>...] [--format=<format>]
+       bao encode <input> (<output> | --outboard=<file>) [--crc=<file>] [--trailer-crc] [--print-hash] [--verify] [--format=<format>]
+       bao decode <hash> [<input>] [<output>] [--outboard=<file>] [--start=<offset>] [--count=<count>] [--crc=<file>] [--content-len=<len>] [--atomic] [--format=<format>]
        bao slice <start> <count> [<input>] [<output>] [--outboard=<file>]
-       bao decode-slice <hash> <start> <count> [<input>] [<output>]
+       bao decode-slice <hash> <start> <count> [<input>] [<output>] [--format=<format>]
+       bao dedup <file1> <file2>
+       bao cat <hash> [<input>] [--outboard=<file>] [--format=<format>]
+       bao plan <hash> <content-len> [--have=<file>] [--format=<format>]
+       bao verify-shards <hash> <content-len> --dir=<dir> [--format=<format>]
+       bao test-vectors
        bao (--help | --version)
+
+Hashes are printed and parsed as hex by default. --format selects hex, base64, or base32 instead.
+A <hash> argument of @<path> reads the hash from a file instead, as 32 raw bytes or as hex.
+encode --verify decodes the output again after encoding, and fails if it doesn't reproduce the
+input. With --outboard, the input must be a real file.
+encode --trailer-crc appends a CRC32 of the whole encoding, and decode checks it first when the
+input file has one. It only catches accidental corruption, and decoding still verifies the hash.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
+    cmd_cat: bool,
     cmd_decode: bool,
     cmd_encode: bool,
     cmd_hash: bool,
+    cmd_plan: bool,
     cmd_slice: bool,
+    cmd_test_vectors: bool,
     cmd_decode_slice: bool,
+    cmd_dedup: bool,
+    cmd_verify_shards: bool,
     arg_input: Option<PathBuf>,
     arg_inputs: Vec<PathBuf>,
+    arg_file1: PathBuf,
+    arg_file2: PathBuf,
     arg_output: Option<PathBuf>,
+    arg_content_len: u64,
     arg_hash: String,
     arg_start: u64,
     arg_count: u64,
+    flag_atomic: bool,
+    flag_content_len: Option<u64>,
     flag_count: Option<u64>,
+    flag_crc: Option<PathBuf>,
+    flag_dir: PathBuf,
+    flag_format: Option<String>,
+    flag_have: Option<PathBuf>,
     flag_help: bool,
     flag_outboard: Option<PathBuf>,
+    flag_print_hash: bool,
     flag_start: Option<u64>,
+    flag_trailer_crc: bool,
+    flag_verify: bool,
     flag_version: bool,
 }
 
-fn main() -> Result<(), Error> {
+fn main() {
     let args: Args = docopt::Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
+    // Print errors with Display rather than Debug, so that a hash mismatch reports its content
+    // offset in words.
+    if let Err(e) = run(&args) {
+        eprintln!("bao: {}", e);
+        std::process::exit(1);
+    }
+}
 
+fn run(args: &Args) -> Result<(), Error> {
     if args.flag_help {
         print!("{}", USAGE);
     } else if args.flag_version {
         println!("{}", VERSION);
     } else if args.cmd_hash {
-        hash(&args)?;
+        hash(args)?;
     } else if args.cmd_encode {
-        encode(&args)?;
+        encode(args)?;
     } else if args.cmd_decode {
-        decode(&args)?;
+        decode(args)?;
     } else if args.cmd_slice {
-        slice(&args)?;
+        slice(args)?;
     } else if args.cmd_decode_slice {
-        decode_slice(&args)?;
+        decode_slice(args)?;
+    } else if args.cmd_dedup {
+        dedup(args)?;
+    } else if args.cmd_cat {
+        cat(args)?;
+    } else if args.cmd_plan {
+        plan(args)?;
+    } else if args.cmd_verify_shards {
+        verify_shards(args)?;
+    } else if args.cmd_test_vectors {
+        test_vectors()?;
     } else {
         unreachable!();
     }
@@ -109,6 +156,7 @@ fn hash_one(maybe_path: &Option<PathBuf>) -> Result<bao::Hash, Error> {
 }
 
 fn hash(args: &Args) -> Result<(), Error> {
+    let format = hash_format(args)?;
     if !args.arg_inputs.is_empty() {
         let mut did_error = false;
         for input in args.arg_inputs.iter() {
@@ -119,9 +167,9 @@ fn hash(args: &Args) -> Result<(), Error> {
             match hash_one(&Some(input.clone())) {
                 Ok(hash) => {
                     if args.arg_inputs.len() > 1 {
-                        println!("{}  {}", hash.to_hex(), input_str);
+                        println!("{}  {}", format_hash(&hash, format), input_str);
                     } else {
-                        println!("{}", hash.to_hex());
+                        println!("{}", format_hash(&hash, format));
                     }
                 }
                 Err(e) => {
@@ -135,33 +183,200 @@ fn hash(args: &Args) -> Result<(), Error> {
         }
     } else {
         let hash = hash_one(&None)?;
-        println!("{}", hash.to_hex());
+        println!("{}", format_hash(&hash, format));
     }
     Ok(())
 }
 
 fn encode(args: &Args) -> Result<(), Error> {
+    let format = hash_format(args)?;
     let mut input = open_input(&args.arg_input)?;
     let out_maybe_path = if args.flag_outboard.is_some() {
         &args.flag_outboard
     } else {
         &args.arg_output
     };
+    if args.flag_trailer_crc && args.flag_outboard.is_some() {
+        return Err(err_msg("--trailer-crc requires a combined encoding"));
+    }
+    if args.flag_verify
+        && args.flag_outboard.is_some()
+        && path_if_some_and_not_dash(&args.arg_input).is_none()
+    {
+        return Err(err_msg(
+            "--verify with --outboard requires a real input file",
+        ));
+    }
     let output = open_output(out_maybe_path)?;
     let mut encoder = if args.flag_outboard.is_some() {
         bao::encode::Encoder::new_outboard(output.require_file()?)
     } else {
         bao::encode::Encoder::new(output.require_file()?)
     };
-    copy_reader_to_writer(&mut input, &mut encoder)?;
-    encoder.finalize()?;
+    // With --verify, the input is also hashed separately from the encoder, so that a bug in the
+    // encoder's own tree can't vouch for itself.
+    let mut input_hasher = blake3::Hasher::new();
+    let mut sink = io::sink();
+    let side: &mut dyn Write = if args.flag_verify {
+        &mut input_hasher
+    } else {
+        &mut sink
+    };
+    let mut writer = TeeWriter(&mut encoder, side);
+    if let Some(crc_path) = &args.flag_crc {
+        let mut tee = TeeWriter(&mut writer, bao::encode::CrcSidecar::new());
+        copy_reader_to_writer(&mut input, &mut tee)?;
+        std::fs::write(crc_path, tee.1.finalize())?;
+    } else {
+        copy_reader_to_writer(&mut input, &mut writer)?;
+    }
+    let hash = encoder.finalize()?;
+    if args.flag_verify {
+        verify_encoded(args, &hash, &input_hasher.finalize())?;
+    }
+    if args.flag_trailer_crc {
+        let path = path_if_some_and_not_dash(out_maybe_path).expect("output is a real file");
+        let mut encoded = OpenOptions::new().read(true).write(true).open(path)?;
+        bao::encode::append_trailer_crc(&mut encoded)?;
+    }
+    if args.flag_print_hash {
+        println!("{}", format_hash(&hash, format));
+    }
     Ok(())
 }
 
+// Decode the output of `bao encode` again, from disk, and check that it reproduces the input. The
+// input hash was computed independently while encoding. Once the two hashes agree, a successful
+// decode means the encoding holds exactly the input.
+fn verify_encoded(args: &Args, hash: &bao::Hash, input_hash: &bao::Hash) -> Result<(), Error> {
+    let out_path = if args.flag_outboard.is_some() {
+        &args.flag_outboard
+    } else {
+        &args.arg_output
+    };
+    let out_path = path_if_some_and_not_dash(out_path).expect("output is a real file");
+    #[cfg(debug_assertions)]
+    {
+        // A fault injection hook for testing that verification catches a bad encoding.
+        if std::env::var_os("BAO_DEBUG_CORRUPT_ENCODED").is_some() {
+            let mut encoded = std::fs::read(out_path)?;
+            if let Some(last) = encoded.last_mut() {
+                *last ^= 1;
+            }
+            std::fs::write(out_path, encoded)?;
+        }
+    }
+    if hash != input_hash {
+        return Err(err_msg(
+            "verification failed: encoded hash doesn't match the input",
+        ));
+    }
+    let encoded = File::open(out_path)?;
+    let result = if args.flag_outboard.is_some() {
+        let input_path = path_if_some_and_not_dash(&args.arg_input).expect("checked in encode");
+        let mut decoder =
+            bao::decode::Decoder::new_outboard(File::open(input_path)?, encoded, hash);
+        io::copy(&mut decoder, &mut io::sink())
+    } else {
+        io::copy(
+            &mut bao::decode::Decoder::new(encoded, hash),
+            &mut io::sink(),
+        )
+    };
+    result.map_err(|e| err_msg(format!("verification failed: {}", e)))?;
+    Ok(())
+}
+
+// Passes every write through to both writers. The first writer decides how many bytes are
+// accepted, and the second gets exactly those bytes.
+struct TeeWriter<A: Write, B: Write>(A, B);
+
+impl<A: Write, B: Write> Write for TeeWriter<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.1.write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+// The CRC pass is only a fast way to fail early on accidental corruption. It's not verification,
+// and the regular decode that follows it is still authoritative.
+// Large files are mapped rather than read into memory, and small ones are read and then rewound.
+fn crc_check_input(input: Input, crc_path: &Path) -> Result<Input, Error> {
+    let sidecar = std::fs::read(crc_path)?;
+    let input = Input::File(input.require_file()?);
+    if let Some(map) = maybe_memmap_input(&input)? {
+        bao::decode::crc_check(&map, &sidecar).map_err(io::Error::from)?;
+        return Ok(input);
+    }
+    let mut file = input.require_file()?;
+    let mut encoded = Vec::new();
+    file.read_to_end(&mut encoded)?;
+    bao::decode::crc_check(&encoded, &sidecar).map_err(io::Error::from)?;
+    file.seek(io::SeekFrom::Start(0))?;
+    Ok(Input::File(file))
+}
+
 fn decode(args: &Args) -> Result<(), Error> {
-    let input = open_input(&args.arg_input)?;
-    let mut output = open_output(&args.arg_output)?;
+    let mut input = open_input(&args.arg_input)?;
+    if let Some(crc_path) = &args.flag_crc {
+        if args.flag_outboard.is_some() {
+            return Err(err_msg("--crc requires a combined encoding"));
+        }
+        if args.flag_content_len.is_some() {
+            return Err(err_msg("--crc requires a length header"));
+        }
+        input = crc_check_input(input, crc_path)?;
+    }
+    if args.flag_outboard.is_none() && args.flag_content_len.is_none() {
+        trailer_check_input(&mut input)?;
+    }
+    // With --atomic, decode into a temporary file, and rename it over the output only once
+    // everything has verified. If anything fails, the temporary file is deleted.
+    let atomic_output = if args.flag_atomic {
+        let path = path_if_some_and_not_dash(&args.arg_output)
+            .ok_or_else(|| err_msg("--atomic requires an output file"))?;
+        Some(AtomicOutput::create(path)?)
+    } else {
+        None
+    };
+    let mut output = match &atomic_output {
+        Some(atomic_output) => Output::File(atomic_output.file.try_clone()?),
+        None => open_output(&args.arg_output)?,
+    };
     let hash = parse_hash(args)?;
+    // A large combined encoding is decoded from a map of the input into a map of the output. If
+    // that can't finish for an I/O reason, for example because the disk fills up, the output is
+    // emptied and the streaming decoder below tries again. A bad encoding fails right away.
+    if args.flag_outboard.is_none()
+        && args.flag_start.is_none()
+        && args.flag_count.is_none()
+        && args.flag_content_len.is_none()
+    {
+        if let (Some(map), Output::File(file)) = (maybe_memmap_input(&input)?, &mut output) {
+            if file.metadata()?.is_file() {
+                if decode_mapped(&map, &hash, file)? {
+                    if let Some(atomic_output) = atomic_output {
+                        atomic_output.commit()?;
+                    }
+                    return Ok(());
+                }
+                // A hook for testing that the mapped path was taken, in debug builds.
+                if cfg!(debug_assertions)
+                    && std::env::var_os("BAO_DEBUG_NO_STREAMING_FALLBACK").is_some()
+                {
+                    return Err(err_msg("mapped decoding failed"));
+                }
+                file.set_len(0)?;
+                file.seek(io::SeekFrom::Start(0))?;
+            }
+        }
+    }
     let outboard;
     let mut generic_decoder;
     let mut file_decoder;
@@ -169,24 +384,25 @@ fn decode(args: &Args) -> Result<(), Error> {
     if args.flag_outboard.is_some() {
         outboard = open_input(&args.flag_outboard)?;
         if let Some(offset) = args.flag_start {
-            file_decoder = bao::decode::Decoder::new_outboard(
+            file_decoder = outboard_decoder(
                 input.require_file()?,
                 outboard.require_file()?,
                 &hash,
+                args.flag_content_len,
             );
             file_decoder.seek(io::SeekFrom::Start(offset))?;
             decoder = &mut file_decoder;
         } else {
-            generic_decoder = bao::decode::Decoder::new_outboard(input, outboard, &hash);
+            generic_decoder = outboard_decoder(input, outboard, &hash, args.flag_content_len);
             decoder = &mut generic_decoder;
         }
     } else {
         if let Some(offset) = args.flag_start {
-            file_decoder = bao::decode::Decoder::new(input.require_file()?, &hash);
+            file_decoder = combined_decoder(input.require_file()?, &hash, args.flag_content_len);
             file_decoder.seek(io::SeekFrom::Start(offset))?;
             decoder = &mut file_decoder;
         } else {
-            generic_decoder = bao::decode::Decoder::new(input, &hash);
+            generic_decoder = combined_decoder(input, &hash, args.flag_content_len);
             decoder = &mut generic_decoder;
         }
     }
@@ -196,9 +412,144 @@ fn decode(args: &Args) -> Result<(), Error> {
     } else {
         allow_broken_pipe(copy_reader_to_writer(&mut decoder, &mut output))?;
     }
+    if let Some(atomic_output) = atomic_output {
+        atomic_output.commit()?;
+    }
+    Ok(())
+}
+
+// Returns false if the output couldn't be mapped or written, and it's worth retrying by streaming.
+fn decode_mapped(encoded: &[u8], hash: &bao::Hash, output: &File) -> Result<bool, Error> {
+    // The input map is at least 16 KiB, so the header is there.
+    let content_len = bao::hash::decode_len(array_ref!(encoded, 0, 8));
+    if content_len == 0 || content_len > isize::MAX as u64 {
+        return Ok(false);
+    }
+    let mut map = match map_output(output, content_len) {
+        Ok(map) => map,
+        Err(_) => return Ok(false),
+    };
+    // A fault injection hook for testing the fallback, in debug builds. The map runs out of room
+    // halfway, as though the disk filled up.
+    let target_len =
+        if cfg!(debug_assertions) && std::env::var_os("BAO_DEBUG_SHORT_MMAP_OUTPUT").is_some() {
+            content_len as usize / 2
+        } else {
+            content_len as usize
+        };
+    match bao::decode::decode_to_writer(encoded, hash, &mut map[..target_len]) {
+        Ok(_) => {}
+        Err(bao::decode::DecodeToWriterError::Verify(e)) => return Err(io::Error::from(e).into()),
+        Err(bao::decode::DecodeToWriterError::Io(_)) => return Ok(false),
+    }
+    Ok(map.flush().is_ok())
+}
+
+fn map_output(output: &File, len: u64) -> io::Result<memmap::MmapMut> {
+    output.set_len(len)?;
+    // Explicitly set the length of the memory map, as in maybe_memmap_input.
+    unsafe { memmap::MmapOptions::new().len(len as usize).map_mut(output) }
+}
+
+// Like the CRC sidecar, the trailer CRC is only a fast way to fail early. Large files are mapped,
+// and small regular files are read and then rewound. Other input, like stdin or a pipe, can't be
+// read twice, so it's decoded without the check.
+fn trailer_check_input(input: &mut Input) -> Result<(), Error> {
+    let result = if let Some(map) = maybe_memmap_input(input)? {
+        bao::decode::check_trailer_crc(&map)
+    } else {
+        match input {
+            Input::File(file) if file.metadata()?.is_file() => {
+                let mut encoded = Vec::new();
+                file.read_to_end(&mut encoded)?;
+                file.seek(io::SeekFrom::Start(0))?;
+                bao::decode::check_trailer_crc(&encoded)
+            }
+            _ => return Ok(()),
+        }
+    };
+    // Anything but a mismatch, including a truncated header, is left to the decoder.
+    if result == Err(bao::decode::CrcError::Mismatch) {
+        return Err(err_msg("trailer CRC mismatch"));
+    }
     Ok(())
 }
 
+// A temporary file next to an output path, which replaces the output when it's committed, and
+// which is deleted if it's dropped without committing. Being in the same directory keeps the
+// rename on the same filesystem, where it's atomic.
+struct AtomicOutput {
+    file: File,
+    temp_path: PathBuf,
+    target_path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicOutput {
+    fn create(target_path: &Path) -> Result<Self, Error> {
+        let file_name = target_path
+            .file_name()
+            .ok_or_else(|| err_msg(format!("invalid output path: {:?}", target_path)))?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp_path = target_path.with_file_name(temp_name);
+        // Both reading and writing permissions are required for MmapMut, as in open_output.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        Ok(Self {
+            file,
+            temp_path,
+            target_path: target_path.to_owned(),
+            committed: false,
+        })
+    }
+
+    fn commit(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        std::fs::rename(&self.temp_path, &self.target_path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for AtomicOutput {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+// With --content-len, the encoding has no length header, and the caller supplies the length.
+fn combined_decoder<T: Read>(
+    input: T,
+    hash: &bao::Hash,
+    content_len: Option<u64>,
+) -> bao::decode::Decoder<T, T> {
+    if let Some(content_len) = content_len {
+        bao::decode::Decoder::new_with_content_len(input, hash, content_len)
+    } else {
+        bao::decode::Decoder::new(input, hash)
+    }
+}
+
+fn outboard_decoder<T: Read, O: Read>(
+    input: T,
+    outboard: O,
+    hash: &bao::Hash,
+    content_len: Option<u64>,
+) -> bao::decode::Decoder<T, O> {
+    if let Some(content_len) = content_len {
+        bao::decode::Decoder::new_outboard_with_content_len(input, outboard, hash, content_len)
+    } else {
+        bao::decode::Decoder::new_outboard(input, outboard, hash)
+    }
+}
+
 fn slice(args: &Args) -> Result<(), Error> {
     let input = open_input(&args.arg_input)?;
     let mut output = open_output(&args.arg_output)?;
@@ -230,6 +581,235 @@ fn decode_slice(args: &Args) -> Result<(), Error> {
     Ok(())
 }
 
+// Like decode to stdout, but with line buffering, so that piping into something like grep shows
+// each verified line as soon as it's decoded.
+fn cat(args: &Args) -> Result<(), Error> {
+    let input = open_input(&args.arg_input)?;
+    let hash = parse_hash(args)?;
+    let stdout = io::stdout();
+    let mut output = io::LineWriter::new(stdout.lock());
+    if args.flag_outboard.is_some() {
+        let outboard = open_input(&args.flag_outboard)?;
+        let mut decoder = bao::decode::Decoder::new_outboard(input, outboard, &hash);
+        allow_broken_pipe(copy_reader_to_writer(&mut decoder, &mut output))?;
+    } else {
+        let mut decoder = bao::decode::Decoder::new(input, &hash);
+        allow_broken_pipe(copy_reader_to_writer(&mut decoder, &mut output))?;
+    }
+    allow_broken_pipe(output.flush())?;
+    Ok(())
+}
+
+// Prints the byte ranges of a combined encoding that a partial download still needs, one
+// `start len` pair per line. The --have file lists the content ranges already downloaded and
+// verified, in the same format. The hash isn't used to compute the plan, but it's checked, so that
+// plans aren't mixed up between files.
+fn plan(args: &Args) -> Result<(), Error> {
+    parse_hash(args)?;
+    let mut have = Vec::new();
+    if let Some(have_path) = &args.flag_have {
+        let have_contents = std::fs::read_to_string(have_path)?;
+        for line in have_contents.lines() {
+            let mut words = line.split_whitespace();
+            let (start, len) = match (words.next(), words.next(), words.next()) {
+                (None, _, _) => continue,
+                (Some(start), Some(len), None) => (start.parse::<u64>()?, len.parse::<u64>()?),
+                _ => return Err(err_msg(format!("invalid range: {:?}", line))),
+            };
+            let end = start
+                .checked_add(len)
+                .ok_or_else(|| err_msg(format!("invalid range: {:?}", line)))?;
+            have.push((start, end));
+        }
+    }
+    let ranges = bao::encode::required_encoded_ranges(args.arg_content_len, &have)
+        .ok_or_else(|| err_msg("content length too large"))?;
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    for (start, end) in ranges {
+        writeln!(output, "{} {}", start, end - start)?;
+    }
+    Ok(())
+}
+
+// Checks a directory of shards, each of them a slice from `bao slice`, named `<start>-<len>` with
+// an optional extension. Sorted by start, the shards should cover the whole content, and each one
+// should decode against the hash. Every problem is printed with the shard's index in that order.
+// A gap between shards counts as one missing shard, even if several are gone.
+fn verify_shards(args: &Args) -> Result<(), Error> {
+    let hash = parse_hash(args)?;
+    let content_len = args.arg_content_len;
+    let mut shards = Vec::new();
+    for entry in std::fs::read_dir(&args.flag_dir)? {
+        let path = entry?.path();
+        let range = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| {
+                let mut words = stem.split('-');
+                match (words.next(), words.next(), words.next()) {
+                    (Some(start), Some(len), None) => {
+                        Some((start.parse().ok()?, len.parse().ok()?))
+                    }
+                    _ => None,
+                }
+            });
+        let (start, len): (u64, u64) =
+            range.ok_or_else(|| err_msg(format!("invalid shard name: {:?}", path)))?;
+        shards.push((start, len, path));
+    }
+    shards.sort();
+
+    let mut problems = 0;
+    let mut index = 0;
+    let mut covered = 0;
+    for (start, len, path) in &shards {
+        let end = std::cmp::max(
+            *start,
+            std::cmp::min(start.saturating_add(*len), content_len),
+        );
+        if *start > covered {
+            eprintln!("shard {} missing: bytes {}..{}", index, covered, start);
+            problems += 1;
+            index += 1;
+        } else if *start < covered {
+            eprintln!("shard {} overlaps the previous shard: {:?}", index, path);
+            problems += 1;
+            index += 1;
+            covered = std::cmp::max(covered, end);
+            continue;
+        }
+        // The last shard can be named with a length that runs past the end.
+        if let Err(e) = verify_shard(&hash, content_len, *start, end - start, path) {
+            eprintln!("shard {} corrupt: {:?}: {}", index, path, e);
+            problems += 1;
+        }
+        index += 1;
+        covered = end;
+    }
+    // Even empty content needs one shard, to check the hash.
+    if covered < content_len || shards.is_empty() {
+        eprintln!(
+            "shard {} missing: bytes {}..{}",
+            index, covered, content_len
+        );
+        problems += 1;
+    }
+    if problems > 0 {
+        return Err(err_msg(format!("{} bad shards", problems)));
+    }
+    Ok(())
+}
+
+fn verify_shard(
+    hash: &bao::Hash,
+    content_len: u64,
+    start: u64,
+    len: u64,
+    path: &Path,
+) -> Result<(), Error> {
+    let slice = std::fs::read(path)?;
+    // The slice decoder verifies the header it finds, so this makes sure it's the expected one.
+    if slice.len() < 8 || bao::hash::decode_len(array_ref!(slice, 0, 8)) != content_len {
+        return Err(err_msg("wrong content length"));
+    }
+    bao::decode::decode_slice(&*slice, hash, start, len)?;
+    Ok(())
+}
+
+// The input lengths that `bao test-vectors` covers. These are the interesting lengths around chunk
+// and subtree boundaries that the library tests use, plus a few larger trees.
+const TEST_VECTOR_LENGTHS: &[u64] = &[
+    0,
+    1,
+    10,
+    1023,
+    1024,
+    1025,
+    2047,
+    2048,
+    2049,
+    3071,
+    3072,
+    3073,
+    4095,
+    4096,
+    4097,
+    8191,
+    8192,
+    8193,
+    16383,
+    16384,
+    16385,
+    1 << 20,
+    (1 << 20) + 1,
+    (1 << 24) + 1025,
+];
+
+// Every input byte in the test vectors is the same, so other implementations can reconstruct the
+// input from its length alone.
+const TEST_VECTOR_FILL: u8 = 0x42;
+
+// Prints a JSON array of test vectors for checking other implementations. The format is simple
+// enough to write by hand, and the output is the same on every run.
+fn test_vectors() -> Result<(), Error> {
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    writeln!(output, "[")?;
+    for (i, &input_len) in TEST_VECTOR_LENGTHS.iter().enumerate() {
+        let input = vec![TEST_VECTOR_FILL; input_len as usize];
+        let separator = if i + 1 < TEST_VECTOR_LENGTHS.len() {
+            ","
+        } else {
+            ""
+        };
+        writeln!(
+            output,
+            "  {{\"input_len\": {}, \"input_fill\": {}, \"hash\": \"{}\", \"encoded_len\": {}, \"outboard_len\": {}}}{}",
+            input_len,
+            TEST_VECTOR_FILL,
+            blake3::hash(&input).to_hex(),
+            bao::encode::encoded_size(input_len),
+            bao::encode::outboard_size(input_len),
+            separator,
+        )?;
+    }
+    writeln!(output, "]")?;
+    Ok(())
+}
+
+// Reports how much of file2 is covered by subtrees it has in common with file1, as a rough
+// estimate of how much a delta transfer from file1 to file2 could save.
+fn dedup(args: &Args) -> Result<(), Error> {
+    let contents1 = map_or_read(&args.arg_file1)?;
+    let contents2 = map_or_read(&args.arg_file2)?;
+    let input1: &[u8] = (*contents1).as_ref();
+    let input2: &[u8] = (*contents2).as_ref();
+    let shared = bao::hash::shared_subtree_len(input1, input2);
+    let percent = if input2.is_empty() {
+        0.0
+    } else {
+        100.0 * shared as f64 / input2.len() as f64
+    };
+    println!(
+        "{} of {} bytes shared ({:.2}%)",
+        shared,
+        input2.len(),
+        percent
+    );
+    Ok(())
+}
+
+fn map_or_read(path: &Path) -> Result<Box<dyn AsRef<[u8]>>, Error> {
+    let mut input = open_input(&Some(path.to_owned()))?;
+    if let Some(map) = maybe_memmap_input(&input)? {
+        return Ok(Box::new(map));
+    }
+    let mut contents = Vec::new();
+    input.read_to_end(&mut contents)?;
+    Ok(Box::new(contents))
+}
+
 fn open_input(maybe_path: &Option<PathBuf>) -> Result<Input, Error> {
     Ok(
         if let Some(ref path) = path_if_some_and_not_dash(maybe_path) {
@@ -350,8 +930,97 @@ fn maybe_memmap_input(input: &Input) -> Result<Option<memmap::Mmap>, Error> {
     })
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HashFormat {
+    Hex,
+    Base64,
+    Base32,
+}
+
+fn hash_format(args: &Args) -> Result<HashFormat, Error> {
+    match args.flag_format.as_deref() {
+        None | Some("hex") => Ok(HashFormat::Hex),
+        Some("base64") => Ok(HashFormat::Base64),
+        Some("base32") => Ok(HashFormat::Base32),
+        Some(other) => Err(err_msg(format!(
+            "invalid format: {:?} (expected hex, base64, or base32)",
+            other
+        ))),
+    }
+}
+
+// The standard alphabets from RFC 4648, with padding.
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// Encode with an RFC 4648 alphabet of 2^bits characters, padding the output with '=' to a
+// multiple of `group` characters.
+fn radix_encode(bytes: &[u8], alphabet: &[u8], bits: u32, group: usize) -> String {
+    let mask = (1 << bits) - 1;
+    let mut output = String::new();
+    let mut acc: u32 = 0;
+    let mut acc_bits = 0;
+    for &byte in bytes {
+        acc = (acc << 8 | byte as u32) & 0xffff;
+        acc_bits += 8;
+        while acc_bits >= bits {
+            acc_bits -= bits;
+            output.push(alphabet[(acc >> acc_bits & mask) as usize] as char);
+        }
+    }
+    if acc_bits > 0 {
+        output.push(alphabet[(acc << (bits - acc_bits) & mask) as usize] as char);
+    }
+    while !output.len().is_multiple_of(group) {
+        output.push('=');
+    }
+    output
+}
+
+// The inverse of radix_encode. Only the canonical encoding is accepted, so that every hash has
+// exactly one spelling in each format.
+fn radix_decode(encoded: &str, alphabet: &[u8], bits: u32, group: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut acc: u32 = 0;
+    let mut acc_bits = 0;
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = alphabet.iter().position(|&a| a == c)? as u32;
+        acc = (acc << bits | value) & 0xffff;
+        acc_bits += bits;
+        if acc_bits >= 8 {
+            acc_bits -= 8;
+            output.push((acc >> acc_bits) as u8);
+        }
+    }
+    if radix_encode(&output, alphabet, bits, group) != encoded {
+        return None;
+    }
+    Some(output)
+}
+
+fn format_hash(hash: &bao::Hash, format: HashFormat) -> String {
+    match format {
+        HashFormat::Hex => hash.to_hex().to_string(),
+        HashFormat::Base64 => radix_encode(hash.as_bytes(), BASE64_ALPHABET, 6, 4),
+        HashFormat::Base32 => radix_encode(hash.as_bytes(), BASE32_ALPHABET, 5, 8),
+    }
+}
+
 fn parse_hash(args: &Args) -> Result<bao::Hash, Error> {
-    let hash_vec = hex::decode(&args.arg_hash).map_err(|_| err_msg("invalid hex"))?;
+    // @<path> reads the hash from a file, as raw bytes or hex, regardless of --format.
+    if let Some(path) = args.arg_hash.strip_prefix('@') {
+        return Ok(bao::decode::read_hash_file(path)?);
+    }
+    let hash_vec = match hash_format(args)? {
+        HashFormat::Hex => hex::decode(&args.arg_hash).map_err(|_| err_msg("invalid hex"))?,
+        HashFormat::Base64 => radix_decode(&args.arg_hash, BASE64_ALPHABET, 6, 4)
+            .ok_or_else(|| err_msg("invalid base64"))?,
+        // Base32 is case-insensitive.
+        HashFormat::Base32 => {
+            radix_decode(&args.arg_hash.to_ascii_uppercase(), BASE32_ALPHABET, 5, 8)
+                .ok_or_else(|| err_msg("invalid base32"))?
+        }
+    };
     if hash_vec.len() != bao::HASH_SIZE {
         return Err(err_msg("wrong length hash"));
     };
@@ -2,6 +2,8 @@ use duct::cmd;
 use rand::prelude::*;
 use std::env::consts::EXE_EXTENSION;
 use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::sync::Once;
 use tempfile::tempdir;
@@ -124,6 +126,33 @@ fn test_encode_decode_combined() {
     assert_eq!(input_bytes[1..2], *partial_output);
 }
 
+#[test]
+fn test_encode_print_hash() {
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("input");
+    let input_bytes = vec![0xab; 10_000];
+    fs::write(&input_path, &input_bytes).unwrap();
+    let encoded_path = dir.path().join("encoded");
+    let output = cmd!(
+        bao_exe(),
+        "encode",
+        &input_path,
+        &encoded_path,
+        "--print-hash"
+    )
+    .read()
+    .unwrap();
+    assert_eq!(&*blake3::hash(&input_bytes).to_hex(), &*output);
+    let (expected_encoded, _) = bao::encode::encode(&input_bytes);
+    assert_eq!(expected_encoded, fs::read(&encoded_path).unwrap());
+
+    // Without the flag, nothing is printed.
+    let output = cmd!(bao_exe(), "encode", &input_path, &encoded_path)
+        .read()
+        .unwrap();
+    assert_eq!("", output);
+}
+
 #[test]
 fn test_encode_decode_outboard() {
     let dir = tempdir().unwrap();
@@ -277,3 +306,1016 @@ fn test_slice() {
     .unwrap();
     assert_hash_mismatch(&output);
 }
+
+#[test]
+fn test_encode_decode_crc() {
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("input");
+    let mut input_bytes = vec![0; 10_000];
+    rand::thread_rng().fill_bytes(&mut input_bytes);
+    fs::write(&input_path, &input_bytes).unwrap();
+    let input_hash = cmd!(bao_exe(), "hash", &input_path).read().unwrap();
+    let encoded_path = dir.path().join("encoded");
+    let crc_path = dir.path().join("crc");
+    cmd!(
+        bao_exe(),
+        "encode",
+        &input_path,
+        &encoded_path,
+        "--crc",
+        &crc_path
+    )
+    .run()
+    .unwrap();
+    assert_eq!(
+        bao::encode::crc_sidecar(&input_bytes),
+        fs::read(&crc_path).unwrap()
+    );
+
+    // Decoding with the CRC pass works as usual.
+    let decoded_bytes = cmd!(
+        bao_exe(),
+        "decode",
+        &input_hash,
+        &encoded_path,
+        "--crc",
+        &crc_path
+    )
+    .stdout_capture()
+    .run()
+    .unwrap()
+    .stdout;
+    assert_eq!(input_bytes, decoded_bytes);
+
+    // A flipped content byte fails the CRC pass before decoding starts, so no output is produced.
+    let mut bad_encoded = fs::read(&encoded_path).unwrap();
+    *bad_encoded.last_mut().unwrap() ^= 1;
+    fs::write(&encoded_path, &bad_encoded).unwrap();
+    let output = cmd!(
+        bao_exe(),
+        "decode",
+        &input_hash,
+        &encoded_path,
+        "--crc",
+        &crc_path
+    )
+    .stdout_capture()
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(bao::decode::CrcError::Mismatch.to_string().as_str()));
+
+    // A large encoding is mapped for the CRC pass rather than read into memory, with the same
+    // results.
+    let mut input_bytes = vec![0; 100_000];
+    rand::thread_rng().fill_bytes(&mut input_bytes);
+    let (mut encoded, hash) = bao::encode::encode(&input_bytes);
+    fs::write(&encoded_path, &encoded).unwrap();
+    fs::write(&crc_path, bao::encode::crc_sidecar(&input_bytes)).unwrap();
+    let decoded_bytes = cmd!(
+        bao_exe(),
+        "decode",
+        &*hash.to_hex(),
+        &encoded_path,
+        "--crc",
+        &crc_path
+    )
+    .stdout_capture()
+    .run()
+    .unwrap()
+    .stdout;
+    assert_eq!(input_bytes, decoded_bytes);
+    *encoded.last_mut().unwrap() ^= 1;
+    fs::write(&encoded_path, &encoded).unwrap();
+    let output = cmd!(
+        bao_exe(),
+        "decode",
+        &*hash.to_hex(),
+        &encoded_path,
+        "--crc",
+        &crc_path
+    )
+    .stdout_capture()
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(bao::decode::CrcError::Mismatch.to_string().as_str()));
+}
+
+#[test]
+fn test_dedup() {
+    let dir = tempdir().unwrap();
+    let mut prefix = vec![0; 100_000];
+    rand::thread_rng().fill_bytes(&mut prefix);
+    let mut contents1 = prefix.clone();
+    contents1.extend_from_slice(b"the end of file 1");
+    let mut contents2 = prefix.clone();
+    contents2.extend_from_slice(b"and file 2 is different");
+    let file1 = dir.path().join("file1");
+    fs::write(&file1, &contents1).unwrap();
+    let file2 = dir.path().join("file2");
+    fs::write(&file2, &contents2).unwrap();
+
+    let output = cmd!(bao_exe(), "dedup", &file1, &file2).read().unwrap();
+    let mut words = output.split_whitespace();
+    let shared: u64 = words.next().unwrap().parse().unwrap();
+    assert_eq!(Some("of"), words.next());
+    let total: u64 = words.next().unwrap().parse().unwrap();
+    assert_eq!(contents2.len() as u64, total);
+    // At least every whole chunk of the common prefix is shared.
+    let aligned_prefix = (prefix.len() / blake3::CHUNK_LEN * blake3::CHUNK_LEN) as u64;
+    assert!(shared >= aligned_prefix, "{} < {}", shared, aligned_prefix);
+    assert!(shared < total);
+
+    // A file shares everything with itself.
+    let output = cmd!(bao_exe(), "dedup", &file1, &file1).read().unwrap();
+    let expected = format!("{0} of {0} bytes shared (100.00%)", contents1.len());
+    assert_eq!(expected, output);
+}
+
+#[test]
+fn test_decode_content_len() {
+    let dir = tempdir().unwrap();
+    let mut input_bytes = vec![0; 10_000];
+    rand::thread_rng().fill_bytes(&mut input_bytes);
+    let input_path = dir.path().join("input");
+    fs::write(&input_path, &input_bytes).unwrap();
+    let input_hash = cmd!(bao_exe(), "hash", &input_path).read().unwrap();
+    let encoded_path = dir.path().join("encoded");
+    cmd!(bao_exe(), "encode", &input_path, &encoded_path)
+        .run()
+        .unwrap();
+    let outboard_path = dir.path().join("outboard");
+    cmd!(
+        bao_exe(),
+        "encode",
+        &input_path,
+        "--outboard",
+        &outboard_path
+    )
+    .run()
+    .unwrap();
+
+    // Strip the 8-byte length headers.
+    let headerless_path = dir.path().join("headerless");
+    fs::write(&headerless_path, &fs::read(&encoded_path).unwrap()[8..]).unwrap();
+    let headerless_outboard_path = dir.path().join("headerless_outboard");
+    fs::write(
+        &headerless_outboard_path,
+        &fs::read(&outboard_path).unwrap()[8..],
+    )
+    .unwrap();
+    let content_len_arg = format!("--content-len={}", input_bytes.len());
+
+    let decoded = cmd!(
+        bao_exe(),
+        "decode",
+        &input_hash,
+        &headerless_path,
+        &content_len_arg
+    )
+    .stdout_capture()
+    .run()
+    .unwrap()
+    .stdout;
+    assert_eq!(input_bytes, decoded);
+
+    let decoded = cmd!(
+        bao_exe(),
+        "decode",
+        &input_hash,
+        &input_path,
+        "--outboard",
+        &headerless_outboard_path,
+        &content_len_arg,
+        "--start=5000",
+        "--count=100"
+    )
+    .stdout_capture()
+    .run()
+    .unwrap()
+    .stdout;
+    assert_eq!(&input_bytes[5000..5100], &*decoded);
+
+    // The wrong length fails verification.
+    let output = cmd!(
+        bao_exe(),
+        "decode",
+        &input_hash,
+        &headerless_path,
+        "--content-len=9999"
+    )
+    .stdout_capture()
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert_hash_mismatch(&output);
+}
+
+#[test]
+fn test_cat() {
+    let dir = tempdir().unwrap();
+    let content: String = (0..10_000).map(|i| format!("line {}\n", i)).collect();
+    let (encoded, hash) = bao::encode::encode(content.as_bytes());
+    let (outboard, _) = bao::encode::outboard(content.as_bytes());
+    let content_path = dir.path().join("content");
+    fs::write(&content_path, &content).unwrap();
+    let encoded_path = dir.path().join("encoded");
+    fs::write(&encoded_path, &encoded).unwrap();
+    let outboard_path = dir.path().join("outboard");
+    fs::write(&outboard_path, &outboard).unwrap();
+    let hash_hex = hash.to_hex();
+    let expected: Vec<&str> = content.lines().filter(|l| l.contains("99")).collect();
+
+    let combined_output = cmd!(bao_exe(), "cat", &*hash_hex, &encoded_path)
+        .read()
+        .unwrap();
+    let outboard_output = cmd!(
+        bao_exe(),
+        "cat",
+        &*hash_hex,
+        &content_path,
+        "--outboard",
+        &outboard_path
+    )
+    .read()
+    .unwrap();
+    for output in &[combined_output, outboard_output] {
+        let filtered: Vec<&str> = output.lines().filter(|l| l.contains("99")).collect();
+        assert_eq!(expected, filtered);
+    }
+
+    // Feed the encoding in two halves, and make sure the first line comes out before the second
+    // half goes in.
+    let mut child = std::process::Command::new(bao_exe())
+        .arg("cat")
+        .arg(&*hash_hex)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let half = encoded.len() / 2;
+    stdin.write_all(&encoded[..half]).unwrap();
+    let mut first_line = String::new();
+    stdout.read_line(&mut first_line).unwrap();
+    assert_eq!("line 0\n", first_line);
+    stdin.write_all(&encoded[half..]).unwrap();
+    drop(stdin);
+    let mut rest = String::new();
+    stdout.read_to_string(&mut rest).unwrap();
+    assert!(child.wait().unwrap().success());
+    assert_eq!(content, first_line + &rest);
+
+    // Closing the pipe early, like `head` does, isn't an error.
+    let mut child = std::process::Command::new(bao_exe())
+        .arg("cat")
+        .arg(&*hash_hex)
+        .arg(&encoded_path)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut first_line = String::new();
+    stdout.read_line(&mut first_line).unwrap();
+    assert_eq!("line 0\n", first_line);
+    drop(stdout);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn test_plan() {
+    let dir = tempdir().unwrap();
+    let content_len = 16 * blake3::CHUNK_LEN;
+    let input = vec![0xab; content_len];
+    let (encoded, hash) = bao::encode::encode(&input);
+    let hash_hex = hash.to_hex();
+    let have_path = dir.path().join("have");
+    fs::write(&have_path, format!("0 {}\n", content_len / 2)).unwrap();
+
+    let output = cmd!(
+        bao_exe(),
+        "plan",
+        &*hash_hex,
+        content_len.to_string(),
+        "--have",
+        &have_path
+    )
+    .read()
+    .unwrap();
+    let ranges: Vec<(usize, usize)> = output
+        .lines()
+        .map(|line| {
+            let mut words = line.split_whitespace().map(|w| w.parse().unwrap());
+            (words.next().unwrap(), words.next().unwrap())
+        })
+        .collect();
+
+    // The plan is the header and root node, and then the whole right half of the tree, which
+    // holds the second half's chunks and their parent nodes.
+    let header_and_root = 8 + 64;
+    // A subtree's encoding is the same as a whole encoding of its content, minus the header.
+    let right_half_len = bao::encode::encoded_size(content_len as u64 / 2) as usize - 8;
+    let right_half_start = encoded.len() - right_half_len;
+    assert_eq!(
+        vec![(0, header_and_root), (right_half_start, right_half_len)],
+        ranges
+    );
+
+    // Those ranges, together with the content we already have, are enough to decode.
+    let mut partial = vec![0; encoded.len()];
+    for &(start, len) in &ranges {
+        partial[start..][..len].copy_from_slice(&encoded[start..][..len]);
+    }
+    let mut decoder = bao::decode::Decoder::new(std::io::Cursor::new(&partial), &hash);
+    decoder
+        .seek(std::io::SeekFrom::Start(content_len as u64 / 2))
+        .unwrap();
+    let mut second_half = Vec::new();
+    decoder.read_to_end(&mut second_half).unwrap();
+    assert_eq!(&input[content_len / 2..], &*second_half);
+
+    // Without a have file, the plan is the whole encoding.
+    let output = cmd!(bao_exe(), "plan", &*hash_hex, content_len.to_string())
+        .read()
+        .unwrap();
+    assert_eq!(format!("0 {}", encoded.len()), output);
+}
+
+#[derive(serde::Deserialize)]
+struct TestVector {
+    input_len: u64,
+    input_fill: u8,
+    hash: String,
+    encoded_len: u64,
+    outboard_len: u64,
+}
+
+#[test]
+fn test_test_vectors() {
+    let output = cmd!(bao_exe(), "test-vectors").read().unwrap();
+    let output_again = cmd!(bao_exe(), "test-vectors").read().unwrap();
+    assert_eq!(output, output_again);
+
+    let vectors: Vec<TestVector> = serde_json::from_str(&output).unwrap();
+    assert!(!vectors.is_empty());
+    for vector in &vectors {
+        let input = vec![vector.input_fill; vector.input_len as usize];
+        assert_eq!(vector.hash, blake3::hash(&input).to_hex().to_string());
+        assert_eq!(
+            vector.encoded_len as u128,
+            bao::encode::encoded_size(vector.input_len)
+        );
+        assert_eq!(
+            vector.outboard_len as u128,
+            bao::encode::outboard_size(vector.input_len)
+        );
+        if vector.input_len <= 1 << 20 {
+            let (encoded, hash) = bao::encode::encode(&input);
+            assert_eq!(vector.hash, hash.to_hex().to_string());
+            assert_eq!(vector.encoded_len, encoded.len() as u64);
+        }
+    }
+}
+
+#[test]
+fn test_verify_shards() {
+    let dir = tempdir().unwrap();
+    let content_len = 10 * blake3::CHUNK_LEN + 1;
+    let input = vec![0xab; content_len];
+    let (encoded, hash) = bao::encode::encode(&input);
+    let hash_hex = hash.to_hex();
+    let shard_len = 3 * blake3::CHUNK_LEN;
+    let mut shard_paths = Vec::new();
+    for start in (0..content_len).step_by(shard_len) {
+        let mut extractor = bao::encode::SliceExtractor::new(
+            std::io::Cursor::new(&encoded),
+            start as u64,
+            shard_len as u64,
+        );
+        let mut slice = Vec::new();
+        extractor.read_to_end(&mut slice).unwrap();
+        let path = dir.path().join(format!("{}-{}.slice", start, shard_len));
+        fs::write(&path, &slice).unwrap();
+        shard_paths.push(path);
+    }
+    assert_eq!(4, shard_paths.len());
+    let verify = || {
+        cmd!(
+            bao_exe(),
+            "verify-shards",
+            &*hash_hex,
+            content_len.to_string(),
+            "--dir",
+            dir.path()
+        )
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap()
+    };
+
+    // A good set passes.
+    let output = verify();
+    assert!(output.status.success());
+
+    // A corrupt shard is reported by its index.
+    let mut slice = fs::read(&shard_paths[2]).unwrap();
+    *slice.last_mut().unwrap() ^= 1;
+    fs::write(&shard_paths[2], &slice).unwrap();
+    let output = verify();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("shard 2 corrupt"), "{}", stderr);
+    assert!(!stderr.contains("shard 1 "), "{}", stderr);
+
+    // So is a missing one, including the last.
+    fs::remove_file(&shard_paths[2]).unwrap();
+    let output = verify();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("shard 2 missing"), "{}", stderr);
+    assert!(!stderr.contains("corrupt"), "{}", stderr);
+    fs::remove_file(&shard_paths[3]).unwrap();
+    let output = verify();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("shard 2 missing"), "{}", stderr);
+    assert!(!stderr.contains("shard 3"), "{}", stderr);
+}
+
+#[test]
+fn test_hash_thread_counts() {
+    // A file large enough to be memory mapped, which `bao hash` splits across rayon threads. Only
+    // hashing is multi-threaded; `bao encode` always runs on one thread.
+    let input_len = (1 << 20) + 1025;
+    let input = vec![0xab; input_len];
+    let expected_hash = blake3::hash(&input);
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("input");
+    fs::write(&input_path, &input).unwrap();
+    for &threads in &[1, 2, 4, 8] {
+        let hash_hex = cmd!(bao_exe(), "hash", &input_path)
+            .env("RAYON_NUM_THREADS", threads.to_string())
+            .read()
+            .unwrap();
+        assert_eq!(&*expected_hash.to_hex(), &*hash_hex, "threads {}", threads);
+    }
+}
+
+#[test]
+fn test_decode_atomic() {
+    let dir = tempdir().unwrap();
+    let input = vec![0xab; 10_000];
+    let (encoded, hash) = bao::encode::encode(&input);
+    let hash_hex = hash.to_hex();
+    let encoded_path = dir.path().join("encoded");
+    fs::write(&encoded_path, &encoded).unwrap();
+    let mut bad_encoded = encoded.clone();
+    *bad_encoded.last_mut().unwrap() ^= 1;
+    let bad_encoded_path = dir.path().join("bad_encoded");
+    fs::write(&bad_encoded_path, &bad_encoded).unwrap();
+    let output_path = dir.path().join("output");
+    let list_dir = || {
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+
+    // A failed decode leaves no output behind.
+    let output = cmd!(
+        bao_exe(),
+        "decode",
+        &*hash_hex,
+        &bad_encoded_path,
+        &output_path,
+        "--atomic"
+    )
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(vec!["bad_encoded", "encoded"], list_dir());
+
+    // Nor does it touch an existing output.
+    fs::write(&output_path, b"original").unwrap();
+    let output = cmd!(
+        bao_exe(),
+        "decode",
+        &*hash_hex,
+        &bad_encoded_path,
+        &output_path,
+        "--atomic"
+    )
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(b"original", &*fs::read(&output_path).unwrap());
+    assert_eq!(vec!["bad_encoded", "encoded", "output"], list_dir());
+
+    // A successful decode replaces it.
+    cmd!(
+        bao_exe(),
+        "decode",
+        &*hash_hex,
+        &encoded_path,
+        &output_path,
+        "--atomic"
+    )
+    .run()
+    .unwrap();
+    assert_eq!(input, fs::read(&output_path).unwrap());
+    assert_eq!(vec!["bad_encoded", "encoded", "output"], list_dir());
+
+    // There's no file to rename over when the output is stdout.
+    let output = cmd!(bao_exe(), "decode", &*hash_hex, &encoded_path, "--atomic")
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_hash_format() {
+    // Reference values from RFC 4648 implementations, for blake3("foo").
+    let hash = blake3::hash(b"foo");
+    let formats = [
+        ("hex", hash.to_hex().to_string()),
+        (
+            "base64",
+            "BOC7OfMLGj/rifU2yTvhUFVILfdIZ0sA0m5adXd3Auk=".to_string(),
+        ),
+        (
+            "base32",
+            "ATQLWOPTBMND724J6U3MSO7BKBKUQLPXJBTUWAGSNZNHK53XALUQ====".to_string(),
+        ),
+    ];
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("input");
+    fs::write(&input_path, b"foo").unwrap();
+    let encoded_path = dir.path().join("encoded");
+    cmd!(bao_exe(), "encode", &input_path, &encoded_path)
+        .run()
+        .unwrap();
+    for (format, expected) in &formats {
+        let format_arg = format!("--format={}", format);
+        let output = cmd!(bao_exe(), "hash", &format_arg)
+            .stdin_bytes("foo")
+            .read()
+            .unwrap();
+        assert_eq!(expected, &output);
+        let output = cmd!(
+            bao_exe(),
+            "encode",
+            &input_path,
+            &encoded_path,
+            "--print-hash",
+            &format_arg
+        )
+        .read()
+        .unwrap();
+        assert_eq!(expected, &output);
+
+        // The printed hash reads back with the same format.
+        let output = cmd!(bao_exe(), "decode", &output, &encoded_path, &format_arg)
+            .read()
+            .unwrap();
+        assert_eq!("foo", output);
+        let output = cmd!(bao_exe(), "cat", expected, &encoded_path, &format_arg)
+            .read()
+            .unwrap();
+        assert_eq!("foo", output);
+
+        // But not with the default.
+        if *format != "hex" {
+            let output = cmd!(bao_exe(), "decode", expected, &encoded_path)
+                .stderr_capture()
+                .unchecked()
+                .run()
+                .unwrap();
+            assert!(!output.status.success());
+        }
+    }
+
+    // Base32 is case-insensitive, but non-canonical spellings are rejected.
+    let lowercase = formats[2].1.to_lowercase();
+    let output = cmd!(
+        bao_exe(),
+        "decode",
+        &lowercase,
+        &encoded_path,
+        "--format=base32"
+    )
+    .read()
+    .unwrap();
+    assert_eq!("foo", output);
+    let unpadded = formats[1].1.trim_end_matches('=');
+    let output = cmd!(
+        bao_exe(),
+        "decode",
+        unpadded,
+        &encoded_path,
+        "--format=base64"
+    )
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid base64"));
+
+    // And an unknown format is an error.
+    let output = cmd!(bao_exe(), "hash", "--format=base58")
+        .stdin_bytes("foo")
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid format"));
+}
+
+#[test]
+fn test_encode_verify() {
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("input");
+    let input_bytes = vec![0xab; 10_000];
+    fs::write(&input_path, &input_bytes).unwrap();
+    let encoded_path = dir.path().join("encoded");
+    let outboard_path = dir.path().join("outboard");
+    let outboard_flag = format!("--outboard={}", outboard_path.to_str().unwrap());
+
+    // Verification passes on a normal encode, combined and outboard.
+    let output = cmd!(
+        bao_exe(),
+        "encode",
+        &input_path,
+        &encoded_path,
+        "--verify",
+        "--print-hash"
+    )
+    .read()
+    .unwrap();
+    assert_eq!(&*blake3::hash(&input_bytes).to_hex(), &*output);
+    let (expected_encoded, _) = bao::encode::encode(&input_bytes);
+    assert_eq!(expected_encoded, fs::read(&encoded_path).unwrap());
+    cmd!(bao_exe(), "encode", &input_path, &outboard_flag, "--verify")
+        .run()
+        .unwrap();
+
+    // With a fault injected into the output after encoding, verification catches it.
+    for args in &[
+        vec![encoded_path.to_str().unwrap()],
+        vec![outboard_flag.as_str()],
+    ] {
+        let output = cmd(
+            bao_exe(),
+            ["encode", input_path.to_str().unwrap(), "--verify"]
+                .iter()
+                .chain(args),
+        )
+        .env("BAO_DEBUG_CORRUPT_ENCODED", "1")
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("verification failed"), "{}", stderr);
+    }
+
+    // Without --verify the hook isn't reached, and the encode succeeds.
+    cmd!(bao_exe(), "encode", &input_path, &encoded_path)
+        .env("BAO_DEBUG_CORRUPT_ENCODED", "1")
+        .run()
+        .unwrap();
+
+    // An outboard encode can only be verified against a real input file.
+    let output = cmd!(bao_exe(), "encode", "-", &outboard_flag, "--verify")
+        .stdin_bytes(&*input_bytes)
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_trailer_crc() {
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("input");
+    let input_bytes = vec![0xab; 10_000];
+    fs::write(&input_path, &input_bytes).unwrap();
+    let encoded_path = dir.path().join("encoded");
+    let hash = cmd!(
+        bao_exe(),
+        "encode",
+        &input_path,
+        &encoded_path,
+        "--trailer-crc",
+        "--print-hash"
+    )
+    .read()
+    .unwrap();
+    let encoded = fs::read(&encoded_path).unwrap();
+    let (expected_encoded, _) = bao::encode::encode(&input_bytes);
+    assert_eq!(&expected_encoded[..], &encoded[..expected_encoded.len()]);
+    assert_eq!(Ok(true), bao::decode::check_trailer_crc(&encoded));
+
+    // The trailer is checked and then ignored, and the content decodes as usual.
+    let output = cmd!(bao_exe(), "decode", &hash, &encoded_path)
+        .stdout_capture()
+        .run()
+        .unwrap();
+    assert_eq!(input_bytes, output.stdout);
+
+    // A flipped byte in the header, a parent node, or a chunk is caught by the trailer CRC,
+    // before the decoder gets to it.
+    let bad_path = dir.path().join("bad");
+    for &i in &[0, 8, 100, 5000, expected_encoded.len() - 1] {
+        let mut bad = encoded.clone();
+        bad[i] ^= 1;
+        fs::write(&bad_path, &bad).unwrap();
+        let output = cmd!(bao_exe(), "decode", &hash, &bad_path)
+            .stdout_capture()
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("trailer CRC mismatch"), "{}", stderr);
+    }
+
+    // Large files are memory-mapped for the check, and that catches it too.
+    let big_input = vec![0xab; 20_000];
+    fs::write(&input_path, &big_input).unwrap();
+    let hash = cmd!(
+        bao_exe(),
+        "encode",
+        &input_path,
+        &encoded_path,
+        "--trailer-crc",
+        "--print-hash"
+    )
+    .read()
+    .unwrap();
+    let mut bad = fs::read(&encoded_path).unwrap();
+    bad[15_000] ^= 1;
+    fs::write(&bad_path, &bad).unwrap();
+    let output = cmd!(bao_exe(), "decode", &hash, &bad_path)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("trailer CRC mismatch"));
+
+    // A trailer only goes on a combined encoding.
+    let outboard_flag = format!(
+        "--outboard={}",
+        dir.path().join("outboard").to_str().unwrap()
+    );
+    let output = cmd!(
+        bao_exe(),
+        "encode",
+        &input_path,
+        &outboard_flag,
+        "--trailer-crc"
+    )
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_empty_input() {
+    let dir = tempdir().unwrap();
+    let empty_path = dir.path().join("empty");
+    fs::write(&empty_path, b"").unwrap();
+    let expected_hash = blake3::hash(b"").to_hex();
+
+    // Hash from stdin and from an empty file, which can't be memory mapped.
+    let hash = cmd!(bao_exe(), "hash")
+        .stdin_bytes(&b""[..])
+        .read()
+        .unwrap();
+    assert_eq!(&*expected_hash, &*hash);
+    let output = cmd!(bao_exe(), "hash", &empty_path).read().unwrap();
+    assert_eq!(&*expected_hash, &*output);
+
+    // The combined encoding is just the header.
+    let encoded_path = dir.path().join("encoded");
+    cmd!(bao_exe(), "encode", &empty_path, &encoded_path)
+        .run()
+        .unwrap();
+    let encoded_bytes = fs::read(&encoded_path).unwrap();
+    assert_eq!(&[0; 8][..], &*encoded_bytes);
+    let decoded_path = dir.path().join("decoded");
+    cmd!(bao_exe(), "decode", &hash, &encoded_path, &decoded_path)
+        .run()
+        .unwrap();
+    assert_eq!(0, fs::read(&decoded_path).unwrap().len());
+    let output = cmd!(bao_exe(), "decode", &hash)
+        .stdin_bytes(&*encoded_bytes)
+        .stdout_capture()
+        .run()
+        .unwrap();
+    assert!(output.stdout.is_empty());
+
+    // The outboard encoding is the same header.
+    let outboard_path = dir.path().join("outboard");
+    cmd!(
+        bao_exe(),
+        "encode",
+        &empty_path,
+        "--outboard",
+        &outboard_path
+    )
+    .run()
+    .unwrap();
+    assert_eq!(encoded_bytes, fs::read(&outboard_path).unwrap());
+    let output = cmd!(
+        bao_exe(),
+        "decode",
+        &hash,
+        &empty_path,
+        "--outboard",
+        &outboard_path
+    )
+    .stdout_capture()
+    .run()
+    .unwrap();
+    assert!(output.stdout.is_empty());
+
+    // An empty slice of empty input is just the header too, and it decodes.
+    let slice_bytes = cmd!(bao_exe(), "slice", "0", "0", &encoded_path)
+        .stdout_capture()
+        .run()
+        .unwrap()
+        .stdout;
+    assert_eq!(encoded_bytes, slice_bytes);
+    let outboard_slice_bytes = cmd!(
+        bao_exe(),
+        "slice",
+        "0",
+        "0",
+        &empty_path,
+        "--outboard",
+        &outboard_path
+    )
+    .stdout_capture()
+    .run()
+    .unwrap()
+    .stdout;
+    assert_eq!(slice_bytes, outboard_slice_bytes);
+    let output = cmd!(bao_exe(), "decode-slice", &hash, "0", "0")
+        .stdin_bytes(&*slice_bytes)
+        .stdout_capture()
+        .run()
+        .unwrap();
+    assert!(output.stdout.is_empty());
+
+    // The empty hash still has to match.
+    let zero_hash = "0".repeat(hash.len());
+    let output = cmd!(bao_exe(), "decode", &zero_hash, &encoded_path)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert_hash_mismatch(&output);
+
+    let output = cmd!(bao_exe(), "cat", &hash, &encoded_path)
+        .stdout_capture()
+        .run()
+        .unwrap();
+    assert!(output.stdout.is_empty());
+
+    let output = cmd!(bao_exe(), "dedup", &empty_path, &empty_path)
+        .read()
+        .unwrap();
+    assert_eq!("0 of 0 bytes shared (0.00%)", output);
+}
+
+#[test]
+fn test_decode_mmap_fallback() {
+    // Large enough that the input gets mapped, and the output too.
+    let mut input = vec![0; 100_000];
+    rand::thread_rng().fill_bytes(&mut input);
+    let dir = tempdir().unwrap();
+    let encoded_path = dir.path().join("encoded");
+    let hash = cmd!(bao_exe(), "encode", "-", &encoded_path, "--print-hash")
+        .stdin_bytes(&*input)
+        .read()
+        .unwrap();
+    let output_path = dir.path().join("output");
+    cmd!(bao_exe(), "decode", &hash, &encoded_path, &output_path)
+        .run()
+        .unwrap();
+    assert_eq!(input, fs::read(&output_path).unwrap());
+
+    // When writing the mapped output fails partway, decoding falls back to streaming.
+    fs::remove_file(&output_path).unwrap();
+    cmd!(bao_exe(), "decode", &hash, &encoded_path, &output_path)
+        .env("BAO_DEBUG_SHORT_MMAP_OUTPUT", "1")
+        .run()
+        .unwrap();
+    assert_eq!(input, fs::read(&output_path).unwrap());
+
+    // With the fallback disabled, the same failure is an error, so decoding that succeeds that way
+    // took the mapped path. That includes decoding into the temporary file of --atomic.
+    fs::remove_file(&output_path).unwrap();
+    let output = cmd!(bao_exe(), "decode", &hash, &encoded_path, &output_path)
+        .env("BAO_DEBUG_SHORT_MMAP_OUTPUT", "1")
+        .env("BAO_DEBUG_NO_STREAMING_FALLBACK", "1")
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+    for extra_args in &[vec![], vec!["--atomic"]] {
+        let _ = fs::remove_file(&output_path);
+        let args = [
+            "decode",
+            &*hash,
+            encoded_path.to_str().unwrap(),
+            output_path.to_str().unwrap(),
+        ];
+        cmd(bao_exe(), args.iter().chain(extra_args))
+            .env("BAO_DEBUG_NO_STREAMING_FALLBACK", "1")
+            .run()
+            .unwrap();
+        assert_eq!(input, fs::read(&output_path).unwrap());
+    }
+
+    // But a bad encoding fails without retrying.
+    let mut encoded = fs::read(&encoded_path).unwrap();
+    *encoded.last_mut().unwrap() ^= 1;
+    fs::write(&encoded_path, &encoded).unwrap();
+    let output = cmd!(bao_exe(), "decode", &hash, &encoded_path, &output_path)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert_hash_mismatch(&output);
+}
+
+#[test]
+fn test_hash_file_argument() {
+    let dir = tempdir().unwrap();
+    let input = b"some input";
+    let (encoded, hash) = bao::encode::encode(input);
+    let encoded_path = dir.path().join("encoded");
+    fs::write(&encoded_path, &encoded).unwrap();
+
+    // Hex with a trailing newline, and raw bytes, both work.
+    let hash_path = dir.path().join("hash");
+    for contents in &[
+        format!("{}\n", hash.to_hex()).into_bytes(),
+        hash.as_bytes().to_vec(),
+    ] {
+        fs::write(&hash_path, contents).unwrap();
+        let hash_arg = format!("@{}", hash_path.to_string_lossy());
+        let output = cmd!(bao_exe(), "decode", &hash_arg, &encoded_path)
+            .stdout_capture()
+            .run()
+            .unwrap();
+        assert_eq!(input, &*output.stdout);
+    }
+
+    // A malformed hash file is an error.
+    fs::write(&hash_path, b"not a hash").unwrap();
+    let hash_arg = format!("@{}", hash_path.to_string_lossy());
+    let output = cmd!(bao_exe(), "decode", &hash_arg, &encoded_path)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("wrong length hash"));
+}
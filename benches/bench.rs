@@ -2,7 +2,6 @@
 
 extern crate test;
 
-#[cfg(feature = "std")]
 use bao::{decode, encode};
 use rand::prelude::*;
 use std::io::prelude::*;
@@ -17,6 +16,12 @@ const MEDIUM: usize = bao::benchmarks::CHUNK_SIZE * blake2s_simd::many::MAX_DEGR
 
 const LONG: usize = 1 << 24; // about 17 MB
 
+// A realistic file size for the all-at-once and slice benchmarks, which also use LONG.
+const LONG_1M: usize = 1 << 20;
+
+// The slice benchmarks extract this much from the middle of the input.
+const SLICE_LEN: u64 = 1 << 16;
+
 // This struct randomizes two things:
 // 1. The actual bytes of input.
 // 2. The page offset the input starts at.
@@ -102,6 +107,41 @@ fn bench_bao_hasher_long(b: &mut Bencher) {
     });
 }
 
+#[bench]
+fn bench_bao_state_merge_long(b: &mut Bencher) {
+    // Only the tree bookkeeping and parent hashing, with no chunk hashing.
+    let num_chunks = (LONG / bao::benchmarks::CHUNK_SIZE) as u64;
+    b.bytes += LONG as u64;
+    b.iter(|| bao::benchmarks::state_merge(num_chunks));
+}
+
+// Many small inputs, like a directory of small files, hashed one after another and with hash_many.
+const MANY_COUNT: usize = 1000;
+
+fn many_inputs(b: &mut Bencher) -> Vec<Vec<u8>> {
+    let mut input = RandomInput::new(b, MEDIUM);
+    b.bytes *= MANY_COUNT as u64;
+    (0..MANY_COUNT).map(|_| input.get().to_vec()).collect()
+}
+
+#[bench]
+fn bench_bao_hash_each_many(b: &mut Bencher) {
+    let inputs = many_inputs(b);
+    b.iter(|| {
+        inputs
+            .iter()
+            .map(|input| blake3::hash(input))
+            .collect::<Vec<_>>()
+    });
+}
+
+#[bench]
+fn bench_bao_hash_many(b: &mut Bencher) {
+    let inputs = many_inputs(b);
+    let slices: Vec<&[u8]> = inputs.iter().map(|input| &input[..]).collect();
+    b.iter(|| bao::hash::hash_many(&slices));
+}
+
 #[cfg(feature = "std")]
 #[bench]
 fn bench_bao_encoder_combined_short(b: &mut Bencher) {
@@ -285,3 +325,124 @@ fn bench_bao_seek_file(b: &mut Bencher) {
         decoder.seek(Start(seek_offset)).expect("seek error");
     });
 }
+
+#[bench]
+fn bench_bao_encode_all_short(b: &mut Bencher) {
+    let mut input = RandomInput::new(b, SHORT);
+    b.iter(|| encode::encode(input.get()));
+}
+
+#[bench]
+fn bench_bao_encode_all_1m(b: &mut Bencher) {
+    let mut input = RandomInput::new(b, LONG_1M);
+    b.iter(|| encode::encode(input.get()));
+}
+
+#[bench]
+fn bench_bao_encode_all_16m(b: &mut Bencher) {
+    let mut input = RandomInput::new(b, LONG);
+    b.iter(|| encode::encode(input.get()));
+}
+
+#[bench]
+fn bench_bao_decode_all_short(b: &mut Bencher) {
+    let input = RandomInput::new(b, SHORT).get().to_vec();
+    let (encoded, hash) = encode::encode(&input);
+    b.iter(|| decode::decode(&encoded, &hash).unwrap());
+}
+
+#[bench]
+fn bench_bao_is_valid_short(b: &mut Bencher) {
+    let input = RandomInput::new(b, SHORT).get().to_vec();
+    let (encoded, hash) = encode::encode(&input);
+    b.iter(|| assert!(decode::is_valid(&encoded, &hash)));
+}
+
+#[bench]
+fn bench_bao_is_valid_one_chunk(b: &mut Bencher) {
+    let input = RandomInput::new(b, bao::benchmarks::CHUNK_SIZE)
+        .get()
+        .to_vec();
+    let (encoded, hash) = encode::encode(&input);
+    b.iter(|| assert!(decode::is_valid(&encoded, &hash)));
+}
+
+#[bench]
+fn bench_bao_is_valid_medium(b: &mut Bencher) {
+    let input = RandomInput::new(b, MEDIUM).get().to_vec();
+    let (encoded, hash) = encode::encode(&input);
+    b.iter(|| assert!(decode::is_valid(&encoded, &hash)));
+}
+
+#[bench]
+fn bench_bao_decode_all_1m(b: &mut Bencher) {
+    let input = RandomInput::new(b, LONG_1M).get().to_vec();
+    let (encoded, hash) = encode::encode(&input);
+    b.iter(|| decode::decode(&encoded, &hash).unwrap());
+}
+
+#[bench]
+fn bench_bao_decode_all_16m(b: &mut Bencher) {
+    let input = RandomInput::new(b, LONG).get().to_vec();
+    let (encoded, hash) = encode::encode(&input);
+    b.iter(|| decode::decode(&encoded, &hash).unwrap());
+}
+
+// The same as bench_bao_decode_all_1m, without any hashing.
+#[cfg(feature = "unverified")]
+#[bench]
+fn bench_bao_decode_unverified_1m(b: &mut Bencher) {
+    let input = RandomInput::new(b, LONG_1M).get().to_vec();
+    let (encoded, _) = encode::encode(&input);
+    b.iter(|| decode::decode_unverified(&encoded, LONG_1M as u64).unwrap());
+}
+
+#[bench]
+fn bench_bao_decoder_streaming_1m(b: &mut Bencher) {
+    let input = RandomInput::new(b, LONG_1M).get().to_vec();
+    let (encoded, hash) = encode::encode(&input);
+    b.iter(|| {
+        let mut decoder = decode::Decoder::new(Cursor::new(&encoded), &hash);
+        std::io::copy(&mut decoder, &mut std::io::sink()).unwrap()
+    });
+}
+
+#[bench]
+fn bench_bao_decoder_streaming_16m(b: &mut Bencher) {
+    let input = RandomInput::new(b, LONG).get().to_vec();
+    let (encoded, hash) = encode::encode(&input);
+    b.iter(|| {
+        let mut decoder = decode::Decoder::new(Cursor::new(&encoded), &hash);
+        std::io::copy(&mut decoder, &mut std::io::sink()).unwrap()
+    });
+}
+
+// Extract a slice from the middle of the encoding and decode it. Only the slice counts towards
+// the throughput.
+fn bench_slice_round_trip(b: &mut Bencher, len: usize) {
+    let input = RandomInput::new(b, len).get().to_vec();
+    b.bytes = SLICE_LEN;
+    let (encoded, hash) = encode::encode(&input);
+    let slice_start = len as u64 / 2;
+    let mut slice = Vec::new();
+    let mut output = Vec::new();
+    b.iter(|| {
+        slice.clear();
+        let mut extractor =
+            encode::SliceExtractor::new(Cursor::new(&encoded), slice_start, SLICE_LEN);
+        extractor.read_to_end(&mut slice).unwrap();
+        output.clear();
+        let mut decoder = decode::SliceDecoder::new(&*slice, &hash, slice_start, SLICE_LEN);
+        decoder.read_to_end(&mut output).unwrap();
+    });
+}
+
+#[bench]
+fn bench_bao_slice_round_trip_1m(b: &mut Bencher) {
+    bench_slice_round_trip(b, LONG_1M);
+}
+
+#[bench]
+fn bench_bao_slice_round_trip_16m(b: &mut Bencher) {
+    bench_slice_round_trip(b, LONG);
+}
@@ -0,0 +1,270 @@
+//! Content-defined segmentation on top of the Bao tree.
+//!
+//! With fixed-size chunks, inserting a byte shifts every chunk boundary after it, so nothing past
+//! the insertion deduplicates against the old version. This module splits the input into segments
+//! at boundaries picked by a rolling gear hash of the content itself, so boundaries move along with
+//! an insertion, and only the segments around an edit change.
+//!
+//! The BLAKE3 tree itself can't have variable-size chunks without giving up the property that the
+//! Bao root hash is the BLAKE3 hash, so segmentation is a layer above it. Each segment is an
+//! ordinary combined encoding with its own root hash. The encoding starts with a manifest listing
+//! every segment's length and hash, and the root hash of the whole thing is a hash of the
+//! manifest, in its own BLAKE3 key derivation context. Note that this root hash is *not* the
+//! BLAKE3 hash of the content.
+//!
+//! The layout is:
+//!
+//! - the number of segments, 8 bytes little-endian
+//! - for each segment, its content length, 8 bytes little-endian, and its 32-byte root hash
+//! - for each segment, its combined encoding
+//!
+//! This requires the `cdc` Cargo feature.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let input = vec![0xab; 100_000];
+//! let (encoded, hash) = bao::cdc::encode(&input);
+//! assert_eq!(input, bao::cdc::decode(&encoded, &hash)?);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::decode::Error;
+use crate::encode;
+use crate::{Hash, HASH_SIZE, HEADER_SIZE};
+use arrayref::array_ref;
+use std::io;
+
+/// Segments are never shorter than this, except for the last one.
+pub const MIN_SEGMENT_SIZE: usize = 2048;
+/// The average segment size that boundaries are tuned for. This must be a power of two.
+pub const AVG_SEGMENT_SIZE: usize = 8192;
+/// Segments are never longer than this. If no boundary turns up, the segment is cut here.
+pub const MAX_SEGMENT_SIZE: usize = 65536;
+
+const MANIFEST_ENTRY_SIZE: usize = HEADER_SIZE + HASH_SIZE;
+const MANIFEST_CONTEXT: &str = "bao 2020-05-01 content-defined segment manifest";
+
+// The gear table maps each byte to a pseudorandom 64-bit value. It's generated with SplitMix64
+// from a zero seed, so it's fixed, and every implementation has to use the same one.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+// Return the length of the segment at the front of `input`. A boundary falls after any byte where
+// the top bits of the rolling hash are all zero, which happens once every AVG_SEGMENT_SIZE bytes
+// on average. The hash shifts left once per byte, so only the last 64 bytes affect it.
+fn next_segment_len(input: &[u8]) -> usize {
+    if input.len() <= MIN_SEGMENT_SIZE {
+        return input.len();
+    }
+    let shift = 64 - AVG_SEGMENT_SIZE.trailing_zeros();
+    let end = std::cmp::min(input.len(), MAX_SEGMENT_SIZE);
+    let mut hash: u64 = 0;
+    for (i, &byte) in input.iter().enumerate().take(end).skip(MIN_SEGMENT_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if hash >> shift == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Split the input into content-defined segments, and return the `(start, len)` content range of
+/// each one. The empty input has no segments.
+pub fn segments(input: &[u8]) -> Vec<(u64, u64)> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < input.len() {
+        let len = next_segment_len(&input[start..]);
+        ranges.push((start as u64, len as u64));
+        start += len;
+    }
+    ranges
+}
+
+/// Compute the root hash of each segment of the input. Segments with the same content have the
+/// same hash, wherever they are, so comparing these sets between two inputs shows what they share.
+pub fn segment_hashes(input: &[u8]) -> Vec<Hash> {
+    segments(input)
+        .into_iter()
+        .map(|(start, len)| blake3::hash(&input[start as usize..][..len as usize]))
+        .collect()
+}
+
+fn manifest_hash(manifest: &[u8]) -> Hash {
+    let mut hasher = blake3::Hasher::new_derive_key(MANIFEST_CONTEXT);
+    hasher.update(manifest);
+    hasher.finalize()
+}
+
+/// Encode an entire slice with content-defined segments, and return the encoding and its root
+/// hash.
+pub fn encode(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
+    let bytes = input.as_ref();
+    let ranges = segments(bytes);
+    let mut manifest = Vec::with_capacity(HEADER_SIZE + ranges.len() * MANIFEST_ENTRY_SIZE);
+    manifest.extend_from_slice(&crate::hash::encode_len(ranges.len() as u64));
+    let mut body = Vec::new();
+    for &(start, len) in &ranges {
+        let (segment_encoded, segment_hash) =
+            encode::encode(&bytes[start as usize..][..len as usize]);
+        manifest.extend_from_slice(&crate::hash::encode_len(len));
+        manifest.extend_from_slice(segment_hash.as_bytes());
+        body.extend_from_slice(&segment_encoded);
+    }
+    let hash = manifest_hash(&manifest);
+    manifest.extend_from_slice(&body);
+    (manifest, hash)
+}
+
+/// Decode an entire encoding produced by `encode`. The manifest is verified against `hash` before
+/// any segment is decoded, and each segment is verified against its hash in the manifest.
+pub fn decode(encoded: &[u8], hash: &Hash) -> io::Result<Vec<u8>> {
+    if encoded.len() < HEADER_SIZE {
+        return Err(Error::Truncated.into());
+    }
+    let count = crate::hash::decode_len(array_ref!(encoded, 0, HEADER_SIZE));
+    // Don't trust the count enough to allocate for it before checking it against the input.
+    let manifest_len = (count as u128) * MANIFEST_ENTRY_SIZE as u128 + HEADER_SIZE as u128;
+    if (encoded.len() as u128) < manifest_len {
+        return Err(Error::Truncated.into());
+    }
+    let (manifest, mut body) = encoded.split_at(manifest_len as usize);
+    // Hash implements constant time equality.
+    if &manifest_hash(manifest) != hash {
+        return Err(Error::HashMismatch.into());
+    }
+    let mut output = Vec::new();
+    for entry in manifest[HEADER_SIZE..].chunks_exact(MANIFEST_ENTRY_SIZE) {
+        let len = crate::hash::decode_len(array_ref!(entry, 0, HEADER_SIZE));
+        let segment_hash: Hash = (*array_ref!(entry, HEADER_SIZE, HASH_SIZE)).into();
+        let segment_encoded_len = encode::encoded_size(len);
+        if (body.len() as u128) < segment_encoded_len {
+            return Err(Error::Truncated.into());
+        }
+        let (segment_encoded, rest) = body.split_at(segment_encoded_len as usize);
+        output.extend_from_slice(&crate::decode::decode(segment_encoded, &segment_hash)?);
+        body = rest;
+    }
+    if !body.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "trailing bytes after encoding",
+        ));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use crate::CHUNK_SIZE;
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+    use std::collections::HashSet;
+
+    fn random_input(len: usize, seed: u64) -> Vec<u8> {
+        let mut input = vec![0; len];
+        ChaChaRng::seed_from_u64(seed).fill_bytes(&mut input);
+        input
+    }
+
+    #[test]
+    fn test_segments() {
+        for &len in &[0, 1, MIN_SEGMENT_SIZE, MIN_SEGMENT_SIZE + 1, 1_000_000] {
+            let input = random_input(len, 0);
+            let ranges = segments(&input);
+            let mut expected_start = 0;
+            for (i, &(start, seg_len)) in ranges.iter().enumerate() {
+                assert_eq!(expected_start, start);
+                assert!(seg_len as usize <= MAX_SEGMENT_SIZE);
+                if i + 1 < ranges.len() {
+                    assert!(seg_len as usize > MIN_SEGMENT_SIZE);
+                }
+                expected_start += seg_len;
+            }
+            assert_eq!(len as u64, expected_start);
+        }
+        // Input with no boundaries gets cut at the max size.
+        let zeros = vec![0; 3 * MAX_SEGMENT_SIZE];
+        let sizes: Vec<u64> = segments(&zeros).iter().map(|&(_, len)| len).collect();
+        assert_eq!(vec![MAX_SEGMENT_SIZE as u64; 3], sizes);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut cases: Vec<usize> = crate::test::TEST_CASES.to_vec();
+        cases.extend_from_slice(&[MIN_SEGMENT_SIZE + 1, MAX_SEGMENT_SIZE + 1, 300_000]);
+        for case in cases {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode(&input);
+            assert_eq!(input, decode(&encoded, &hash).unwrap());
+
+            // Corrupting the manifest, or the last byte of the last segment, is caught.
+            let mut bad_encoded = encoded.clone();
+            bad_encoded[0] ^= 1;
+            assert!(decode(&bad_encoded, &hash).is_err());
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                *bad_encoded.last_mut().unwrap() ^= 1;
+                let err = decode(&bad_encoded, &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+                let err = decode(&encoded[..encoded.len() - 1], &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_insertion_changes_few_segments() {
+        let old = random_input(500_000, 1);
+        let mut new = old.clone();
+        let inserted = random_input(10, 2);
+        new.splice(200_000..200_000, inserted.iter().cloned());
+
+        // With content-defined segments, only the segments around the insertion are new.
+        let old_hashes: HashSet<Hash> = segment_hashes(&old).into_iter().collect();
+        let new_ranges = segments(&new);
+        let new_hashes = segment_hashes(&new);
+        let cdc_changed: usize = new_ranges
+            .iter()
+            .zip(&new_hashes)
+            .filter(|(_, hash)| !old_hashes.contains(hash))
+            .map(|(&(_, len), _)| len as usize)
+            .sum();
+
+        // With fixed chunks, every chunk from the insertion onward changes.
+        let fixed_changed: usize = new
+            .chunks(CHUNK_SIZE)
+            .enumerate()
+            .filter(|&(i, chunk)| old.chunks(CHUNK_SIZE).nth(i) != Some(chunk))
+            .map(|(_, chunk)| chunk.len())
+            .sum();
+
+        println!(
+            "cdc changed {} bytes, fixed changed {}",
+            cdc_changed, fixed_changed
+        );
+        assert!(cdc_changed <= 2 * MAX_SEGMENT_SIZE);
+        assert!(fixed_changed >= new.len() - 200_000 - CHUNK_SIZE);
+        assert!(cdc_changed * 4 < fixed_changed);
+    }
+}
@@ -37,28 +37,31 @@
 
 use crate::encode;
 use crate::encode::NextRead;
-use crate::{Finalization, Hash, CHUNK_SIZE, HEADER_SIZE, MAX_DEPTH, PARENT_SIZE};
+use crate::{
+    Finalization, Hash, ParentNode, CHUNK_SIZE, CRC_SIZE, HASH_SIZE, HEADER_SIZE, MAX_DEPTH,
+    PARENT_SIZE, TRAILER_MAGIC, TRAILER_SIZE, VERSION_MAGIC, VERSION_PREFIX_SIZE,
+};
 use arrayref::array_ref;
 use arrayvec::ArrayVec;
+use std::borrow::Cow;
 use std::cmp;
+use std::collections::HashMap;
 use std::error;
 use std::fmt;
+#[cfg(any(unix, windows))]
+use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 /// Decode an entire slice in the default combined mode into a bytes vector.
 /// This is a convenience wrapper around `Decoder`.
 pub fn decode(encoded: impl AsRef<[u8]>, hash: &Hash) -> io::Result<Vec<u8>> {
     let bytes = encoded.as_ref();
-    if bytes.len() < HEADER_SIZE {
-        return Err(Error::Truncated.into());
-    }
-    let content_len = crate::decode_len(array_ref!(bytes, 0, HEADER_SIZE));
     // Sanity check the length before making a potentially large allocation.
-    if (bytes.len() as u128) < encode::encoded_size(content_len) {
-        return Err(Error::Truncated.into());
-    }
+    let content_len = checked_content_len(bytes)?;
     // There's no way to avoid zeroing this vector without unsafe code, because
     // Decoder::initializer is the default (safe) zeroing implementation anyway.
     let mut vec = vec![0; content_len as usize];
@@ -74,266 +77,611 @@ pub fn decode(encoded: impl AsRef<[u8]>, hash: &Hash) -> io::Result<Vec<u8>> {
     Ok(vec)
 }
 
-// This incremental verifier layers on top of encode::ParseState, and supports
-// both the Decoder and the SliceDecoder.
-#[derive(Clone)]
-struct VerifyState {
-    stack: ArrayVec<[Hash; MAX_DEPTH]>,
-    parser: encode::ParseState,
-    root_hash: Hash,
+/// Like `decode`, but reject any bytes after the end of the encoding. `decode` and `Decoder` stop
+/// reading once the content is verified, so they're lenient about trailing bytes. That includes
+/// a header claiming zero length followed by garbage, which they decode as empty content. Here
+/// that's an `InvalidData` error, the same as for a hash mismatch, and an encoding shorter than
+/// its header says is still an `UnexpectedEof` error.
+///
+/// # Example
+///
+/// ```
+/// let (mut encoded, hash) = bao::encode::encode(b"");
+/// encoded.extend_from_slice(b"garbage");
+/// assert!(bao::decode::decode(&encoded, &hash).unwrap().is_empty());
+/// let err = bao::decode::decode_strict(&encoded, &hash).unwrap_err();
+/// assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+/// ```
+pub fn decode_strict(encoded: impl AsRef<[u8]>, hash: &Hash) -> io::Result<Vec<u8>> {
+    let bytes = encoded.as_ref();
+    if bytes.len() < HEADER_SIZE {
+        return Err(Error::Truncated.into());
+    }
+    parse_header(array_ref!(bytes, 0, HEADER_SIZE), Some(bytes.len() as u64))?;
+    decode(bytes, hash)
 }
 
-impl VerifyState {
-    fn new(hash: &Hash) -> Self {
-        let mut stack = ArrayVec::new();
-        stack.push(*hash);
-        Self {
-            stack,
-            parser: encode::ParseState::new(),
-            root_hash: *hash,
-        }
+/// Decode an entire slice produced by `encode::encode_padded`, returning the content without its
+/// padding. The padding after the final chunk isn't verified. Beyond that, this is the same as
+/// `decode`.
+pub fn decode_padded(encoded: impl AsRef<[u8]>, hash: &Hash) -> io::Result<Vec<u8>> {
+    let bytes = encoded.as_ref();
+    let content_len = checked_content_len(bytes)?;
+    let unpadded_len = encode::encoded_size(content_len);
+    let padded_len = unpadded_len + encode::final_chunk_padding(content_len) as u128;
+    if (bytes.len() as u128) < padded_len {
+        return Err(Error::Truncated.into());
     }
+    decode(&bytes[..unpadded_len as usize], hash)
+}
 
-    fn content_position(&self) -> u64 {
-        self.parser.content_position()
+/// Like `decode`, but when the content is a single chunk, verify it in place and borrow it from
+/// `encoded` rather than copying it. Larger content has parent nodes mixed in with it, so it has to
+/// be copied out, and that case returns an owned vector.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::borrow::Cow;
+///
+/// let (encoded, hash) = bao::encode::encode(b"some input");
+/// let content = bao::decode::decode_cow(&encoded, &hash)?;
+/// assert!(matches!(content, Cow::Borrowed(_)));
+/// assert_eq!(b"some input", &*content);
+/// # Ok(())
+/// # }
+/// ```
+pub fn decode_cow<'a>(encoded: &'a [u8], hash: &Hash) -> Result<Cow<'a, [u8]>, Error> {
+    let content_len = checked_content_len(encoded)?;
+    if content_len > CHUNK_SIZE as u64 {
+        // The length was checked above, so decoding can only fail with a bad hash.
+        return decode(encoded, hash)
+            .map(Cow::Owned)
+            .map_err(|_| Error::HashMismatch);
     }
-
-    fn read_next(&self) -> NextRead {
-        self.parser.read_next()
+    let chunk = &encoded[HEADER_SIZE..][..content_len as usize];
+    let chunk_hash = crate::hash::hash_subtree_inner(chunk, 0, Finalization::Root);
+    // Hash implements constant time equality.
+    if &chunk_hash != hash {
+        return Err(Error::HashMismatch);
     }
+    Ok(Cow::Borrowed(chunk))
+}
 
-    fn seek_next(&self, seek_to: u64) -> encode::SeekBookkeeping {
-        self.parser.seek_next(seek_to)
+/// **Extract the content of a combined encoding WITHOUT VERIFYING IT.** This walks the tree
+/// geometry and copies out the chunks, skipping every hash check, so it's faster than `decode`.
+/// It's only for encodings you produced yourself and kept in trusted memory, like re-reading an
+/// encoding a moment after writing it. **Never use this on untrusted input.** Nothing here checks
+/// that the content matches any hash, and for invalid input the output is unspecified garbage.
+///
+/// The length header has to match `content_len`, or this returns `HashMismatch`, and an encoding
+/// shorter than its header says returns `Truncated`. Those checks are only about geometry.
+///
+/// This requires the `unverified` Cargo feature, so that it can't be used by accident.
+///
+/// # Example
+///
+/// ```
+/// let (encoded, _) = bao::encode::encode(b"some input");
+/// let content = bao::decode::decode_unverified(&encoded, 10).unwrap();
+/// assert_eq!(b"some input", &*content);
+/// ```
+#[cfg(feature = "unverified")]
+pub fn decode_unverified(encoded: &[u8], content_len: u64) -> Result<Vec<u8>, Error> {
+    if checked_content_len(encoded)? != content_len {
+        return Err(Error::HashMismatch);
     }
+    let mut content = Vec::with_capacity(content_len as usize);
+    encode::append_subtree_content(&encoded[HEADER_SIZE..], content_len, &mut content);
+    Ok(content)
+}
 
-    fn seek_bookkeeping_done(&mut self, bookkeeping: encode::SeekBookkeeping) -> encode::NextRead {
-        // Leftward seeks require resetting the stack to the beginning.
-        if bookkeeping.reset_to_root() {
-            self.stack.clear();
-            self.stack.push(self.root_hash);
-        }
-        // Rightward seeks require popping subtrees off the stack.
-        debug_assert!(self.stack.len() >= bookkeeping.stack_depth());
-        while self.stack.len() > bookkeeping.stack_depth() {
-            self.stack.pop();
-        }
-        self.parser.seek_bookkeeping_done(bookkeeping)
-    }
+/// Check whether `encoded` is a valid combined encoding of content with root hash `hash`, without
+/// allocating or reporting what went wrong. This returns `true` exactly when `decode` would
+/// succeed, including ignoring any trailing bytes after the encoding. It's meant for hot paths
+/// that check many small encodings, and content of a single chunk or less costs just one chunk
+/// hash and a comparison.
+///
+/// # Example
+///
+/// ```
+/// let (mut encoded, hash) = bao::encode::encode(b"some input");
+/// assert!(bao::decode::is_valid(&encoded, &hash));
+/// let last_index = encoded.len() - 1;
+/// encoded[last_index] ^= 1;
+/// assert!(!bao::decode::is_valid(&encoded, &hash));
+/// ```
+pub fn is_valid(encoded: &[u8], hash: &Hash) -> bool {
+    verify_combined(encoded, hash).is_ok()
+}
 
-    fn len_next(&self) -> encode::LenNext {
-        self.parser.len_next()
+/// Verify an entire combined encoding against `hash`, after first checking that its length header
+/// is `expected_len`. The root hash commits to the exact length, so an encoding with the wrong
+/// header can never verify, and this rejects it with `HashMismatch` before hashing anything. That
+/// lets a caller that stores `(hash, content_len)` pairs turn away a declared length that's
+/// already wrong, without paying for a full verification. A header that's cut short is
+/// `Truncated`. Trailing bytes after the encoding are ignored, like `decode` ignores them.
+///
+/// # Example
+///
+/// ```
+/// let (encoded, hash) = bao::encode::encode(b"some input");
+/// assert!(bao::decode::verify_with_expected_len(&encoded, &hash, 10).is_ok());
+/// assert_eq!(
+///     Err(bao::decode::Error::HashMismatch),
+///     bao::decode::verify_with_expected_len(&encoded, &hash, 11),
+/// );
+/// ```
+pub fn verify_with_expected_len(
+    encoded: &[u8],
+    hash: &Hash,
+    expected_len: u64,
+) -> Result<(), Error> {
+    if encoded.len() < HEADER_SIZE {
+        return Err(Error::Truncated);
+    }
+    if crate::hash::decode_len(array_ref!(encoded, 0, HEADER_SIZE)) != expected_len {
+        return Err(Error::HashMismatch);
     }
+    verify_combined(encoded, hash)
+}
 
-    fn feed_header(&mut self, header: &[u8; HEADER_SIZE]) {
-        self.parser.feed_header(header);
+// Verify an entire combined encoding in place, without allocating. This accepts exactly what
+// `decode` accepts.
+fn verify_combined(encoded: &[u8], hash: &Hash) -> Result<(), Error> {
+    // The length check makes all the slicing below in bounds.
+    let content_len = checked_content_len(encoded)?;
+    if content_len <= CHUNK_SIZE as u64 {
+        let chunk = &encoded[HEADER_SIZE..][..content_len as usize];
+        // Hash implements constant time equality.
+        if &crate::hash::hash_subtree_inner(chunk, 0, Finalization::Root) != hash {
+            return Err(Error::HashMismatch);
+        }
+        return Ok(());
     }
 
-    fn feed_parent(&mut self, parent: &crate::ParentNode) -> Result<(), Error> {
-        let finalization = self.parser.finalization();
-        let expected_hash: &Hash = self.stack.last().expect("unexpectedly empty stack");
-        let left_child: Hash = (*array_ref!(parent, 0, 32)).into();
-        let right_child: Hash = (*array_ref!(parent, 32, 32)).into();
+    fn recurse(
+        encoded: &mut &[u8],
+        content_len: u64,
+        chunk_index: u64,
+        hash: &Hash,
+        finalization: Finalization,
+    ) -> Result<(), Error> {
+        if content_len <= CHUNK_SIZE as u64 {
+            let (chunk, rest) = encoded.split_at(content_len as usize);
+            *encoded = rest;
+            let chunk_hash = blake3::guts::ChunkState::new(chunk_index)
+                .update(chunk)
+                .finalize(finalization.is_root());
+            if &chunk_hash != hash {
+                return Err(Error::HashMismatch);
+            }
+            return Ok(());
+        }
+        let left_child: Hash = (*array_ref!(encoded, 0, HASH_SIZE)).into();
+        let right_child: Hash = (*array_ref!(encoded, HASH_SIZE, HASH_SIZE)).into();
         let computed_hash: Hash =
             blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
-        // Hash implements constant time equality.
-        if expected_hash != &computed_hash {
+        if hash != &computed_hash {
             return Err(Error::HashMismatch);
         }
-        self.stack.pop();
-        self.stack.push(right_child.into());
-        self.stack.push(left_child.into());
-        self.parser.advance_parent();
-        Ok(())
+        *encoded = &encoded[PARENT_SIZE..];
+        let left_len = encode::left_subtree_len(content_len);
+        let left_chunks = left_len / CHUNK_SIZE as u64;
+        recurse(
+            encoded,
+            left_len,
+            chunk_index,
+            &left_child,
+            Finalization::NotRoot,
+        )?;
+        recurse(
+            encoded,
+            content_len - left_len,
+            chunk_index + left_chunks,
+            &right_child,
+            Finalization::NotRoot,
+        )
     }
+    let mut tree = &encoded[HEADER_SIZE..];
+    recurse(&mut tree, content_len, 0, hash, Finalization::Root)
+}
 
-    fn feed_chunk(&mut self, chunk_hash: &Hash) -> Result<(), Error> {
-        let expected_hash = self.stack.last().expect("unexpectedly empty stack");
-        // Hash implements constant time equality.
-        if chunk_hash != expected_hash {
-            return Err(Error::HashMismatch);
+/// Decode an entire slice in the default combined mode into a writer, for example a memory-mapped
+/// output file, and return the number of content bytes written. Only verified bytes are written,
+/// but if decoding fails partway through, the output will already contain the content before the
+/// failure.
+///
+/// Verification failures are returned as `DecodeToWriterError::Verify`, and errors from `output`
+/// as `DecodeToWriterError::Io`, whatever their `ErrorKind`. That lets a caller decide whether a
+/// failure is worth retrying, for example with a different output, or whether the encoding is bad.
+///
+/// # Example
+///
+/// ```
+/// use bao::decode::DecodeToWriterError;
+///
+/// let (encoded, hash) = bao::encode::encode(vec![0; 10_000]);
+/// // A slice that's too short fails like a full disk.
+/// let mut output = [0; 5000];
+/// match bao::decode::decode_to_writer(&encoded, &hash, &mut output[..]) {
+///     Err(DecodeToWriterError::Io(e)) => assert_eq!(std::io::ErrorKind::WriteZero, e.kind()),
+///     other => panic!("unexpected result {:?}", other),
+/// }
+/// ```
+pub fn decode_to_writer<W: Write>(
+    encoded: impl AsRef<[u8]>,
+    hash: &Hash,
+    output: W,
+) -> Result<u64, DecodeToWriterError> {
+    let bytes = encoded.as_ref();
+    checked_content_len(bytes)?;
+    copy_decoded(Decoder::new(bytes, hash), output)
+}
+
+// The decoder must read from memory, so that every error it returns is a verification failure.
+fn copy_decoded<T: Read, O: Read, W: Write>(
+    mut decoder: Decoder<T, O>,
+    mut output: W,
+) -> Result<u64, DecodeToWriterError> {
+    let mut buf = [0; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        let n = decoder.read(&mut buf).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                Error::Truncated
+            } else {
+                Error::HashMismatch
+            }
+        })?;
+        if n == 0 {
+            break;
         }
-        self.stack.pop();
-        self.parser.advance_chunk();
-        Ok(())
+        output
+            .write_all(&buf[..n])
+            .map_err(DecodeToWriterError::Io)?;
+        total += n as u64;
     }
+    output.flush().map_err(DecodeToWriterError::Io)?;
+    Ok(total)
 }
 
-// It's important to manually implement Debug for VerifyState, because it holds hashes that
-// might be secret, and it would be bad to leak them to some debug log somewhere.
-impl fmt::Debug for VerifyState {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "VerifyState {{ stack_size: {}, parser: {:?} }}",
-            self.stack.len(), // *Only* the stack size, not the hashes themselves.
-            self.parser,      // The parser state only reveals the content length.
-        )
+/// Like `decode_to_writer`, but also compute a weak rolling checksum of the content as it's
+/// verified, for rsync-style protocols that look for matching blocks in another version of a file.
+/// For every offset where a whole `window` of content starts, `emit` gets the offset and the
+/// Adler-32 checksum of the `window` bytes there, in order. Content shorter than `window` emits
+/// nothing. The checksums only cover verified bytes, so if decoding fails partway through, the
+/// ones already emitted are still good. A `window` of zero is an `InvalidInput` error.
+///
+/// **Adler-32 isn't cryptographic.** It's only for finding candidate matches, which need to be
+/// confirmed some other way, for example by comparing subtree hashes.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (encoded, hash) = bao::encode::encode(b"Wikipedia");
+/// let mut checksums = Vec::new();
+/// let mut output = Vec::new();
+/// bao::decode::decode_with_rolling(&encoded, &hash, 9, &mut output, |offset, checksum| {
+///     checksums.push((offset, checksum));
+/// })?;
+/// assert_eq!(b"Wikipedia", &*output);
+/// assert_eq!(vec![(0, 0x11e60398)], checksums);
+/// # Ok(())
+/// # }
+/// ```
+pub fn decode_with_rolling<W: Write>(
+    encoded: impl AsRef<[u8]>,
+    hash: &Hash,
+    window: usize,
+    mut output: W,
+    mut emit: impl FnMut(u64, u32),
+) -> io::Result<u64> {
+    const MOD_ADLER: u64 = 65521;
+    if window == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "window must be nonzero",
+        ));
+    }
+    let bytes = encoded.as_ref();
+    let content_len = checked_content_len(bytes)?;
+    let mut decoder = Decoder::new(bytes, hash);
+    // The last `window` bytes, as a ring buffer. Once it's full, each new byte replaces the
+    // oldest one, and the checksum rolls forward by one offset. If the window is longer than the
+    // content, the ring never fills, and only the first `content_len` slots get used. The header
+    // hasn't been verified yet, but checked_content_len means the encoding is at least that long.
+    let mut ring = vec![0; cmp::min(window as u64, content_len) as usize];
+    let (mut a, mut b) = (1u64, 0u64);
+    let mut buf = [0; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        let n = decoder.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        output.write_all(&buf[..n])?;
+        for &byte in &buf[..n] {
+            let slot = (total % window as u64) as usize;
+            if total < window as u64 {
+                a = (a + byte as u64) % MOD_ADLER;
+                b = (b + a) % MOD_ADLER;
+            } else {
+                // Drop the oldest byte and add the new one. Every term of b loses one copy of
+                // the old byte, and the new a adds the new byte once.
+                let old = ring[slot] as u64;
+                a = (a + MOD_ADLER - old + byte as u64) % MOD_ADLER;
+                let dropped = (window as u64 % MOD_ADLER) * old % MOD_ADLER;
+                b = (b + 2 * MOD_ADLER - dropped - 1 + a) % MOD_ADLER;
+            }
+            ring[slot] = byte;
+            total += 1;
+            if total >= window as u64 {
+                emit(total - window as u64, ((b << 16) | a) as u32);
+            }
+        }
     }
+    output.flush()?;
+    Ok(total)
 }
 
-/// Errors that can happen during decoding.
+/// Like `decode_to_writer`, but the combined encoding comes as a list of buffers, for example
+/// network frames, rather than one contiguous slice. The encoding is the concatenation of the
+/// frames, and frame boundaries can fall anywhere, including in the middle of a header, a parent
+/// node, or a chunk. The frames are never concatenated. A node or chunk that straddles a boundary
+/// is assembled in the decoder's usual fixed-size buffers.
 ///
-/// Two errors are possible when decoding, apart from the usual IO issues: the content bytes might
-/// not have the right hash, or the encoding might not be as long as it's supposed to be. In
-/// `std::io::Read` interfaces where we have to return `std::io::Error`, these variants are
-/// converted to `ErrorKind::InvalidData` and `ErrorKind::UnexpectedEof` respectively.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum Error {
-    HashMismatch,
-    Truncated,
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 10_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let frames: Vec<&[u8]> = encoded.chunks(1500).collect();
+/// let mut output = Vec::new();
+/// bao::decode::decode_frames(&frames, &hash, &mut output)?;
+/// assert_eq!(input, output);
+/// # Ok(())
+/// # }
+/// ```
+pub fn decode_frames<W: Write>(frames: &[&[u8]], hash: &Hash, output: W) -> io::Result<u64> {
+    // Reads the frames in order, as though they were one slice.
+    struct FramesReader<'a> {
+        frames: &'a [&'a [u8]],
+        // The position in the first frame.
+        position: usize,
+    }
+
+    impl Read for FramesReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // Skip finished and empty frames, so that only the real end returns zero.
+            while let Some(frame) = self.frames.first() {
+                if self.position < frame.len() {
+                    break;
+                }
+                self.frames = &self.frames[1..];
+                self.position = 0;
+            }
+            let frame = match self.frames.first() {
+                Some(frame) => &frame[self.position..],
+                None => return Ok(0),
+            };
+            let n = cmp::min(buf.len(), frame.len());
+            buf[..n].copy_from_slice(&frame[..n]);
+            self.position += n;
+            Ok(n)
+        }
+    }
+
+    let reader = FramesReader {
+        frames,
+        position: 0,
+    };
+    Ok(copy_decoded(Decoder::new(reader, hash), output)?)
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Error::HashMismatch => write!(f, "hash mismatch"),
-            Error::Truncated => write!(f, "truncated encoding"),
+/// Like `decode_to_writer`, but accept any of several root hashes, and return the index of the one
+/// that matched. The root hash is computed from the root node up front and compared against every
+/// candidate, so the content is only decoded once. If the same hash appears more than once, the
+/// first index is returned. If none of them match, nothing is written, and this returns
+/// `Error::HashMismatch` converted to `ErrorKind::InvalidData`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (encoded, hash) = bao::encode::encode(b"some input");
+/// let old_hash = blake3::hash(b"some other input");
+/// let mut output = Vec::new();
+/// let index = bao::decode::decode_any(&encoded, &[old_hash, hash], &mut output)?;
+/// assert_eq!(1, index);
+/// assert_eq!(b"some input", &*output);
+/// # Ok(())
+/// # }
+/// ```
+pub fn decode_any<W: Write>(
+    encoded: impl AsRef<[u8]>,
+    hashes: &[Hash],
+    output: W,
+) -> io::Result<usize> {
+    let bytes = encoded.as_ref();
+    checked_content_len(bytes)?;
+    let (root_hash, _) = hash_from_encoded(bytes)?;
+    let mut matched = None;
+    for (i, hash) in hashes.iter().enumerate() {
+        // Hash implements constant time equality.
+        if (hash == &root_hash) & matched.is_none() {
+            matched = Some(i);
         }
     }
+    let index = matched.ok_or(Error::HashMismatch)?;
+    decode_to_writer(bytes, &hashes[index], output)?;
+    Ok(index)
 }
 
-impl error::Error for Error {}
+/// Decode an entire slice in the default combined mode, given a root hash from `hash::Params`,
+/// like one that commits to associated data. The standard root hash is computed from the root
+/// node of the encoding and checked against `hash` with `params` before anything else is read.
+/// Then all of the content is verified against that standard root hash, exactly like `decode`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut params = bao::hash::Params::new();
+/// params.associated_data(b"protocol v1");
+/// let (encoded, _) = bao::encode::encode(b"some input");
+/// let hash = params.hash(b"some input");
+/// let content = bao::decode::decode_with_params(&encoded, &hash, &params)?;
+/// assert_eq!(b"some input", &*content);
+/// # Ok(())
+/// # }
+/// ```
+pub fn decode_with_params(
+    encoded: impl AsRef<[u8]>,
+    hash: &Hash,
+    params: &crate::hash::Params,
+) -> io::Result<Vec<u8>> {
+    let bytes = encoded.as_ref();
+    let content_len = checked_content_len(bytes)?;
+    let node_len = if content_len <= CHUNK_SIZE as u64 {
+        content_len as usize
+    } else {
+        PARENT_SIZE
+    };
+    let root_node = &bytes[HEADER_SIZE..][..node_len];
+    // The length check above makes the node the right size for this content length.
+    let root_hash = crate::hash::root_finalize(root_node, content_len)
+        .expect("root node should be the right size");
+    // Hash implements constant time equality.
+    if &params.bind_root(&root_hash, content_len) != hash {
+        return Err(Error::HashMismatch.into());
+    }
+    decode(bytes, &root_hash)
+}
 
-impl From<Error> for io::Error {
-    fn from(e: Error) -> io::Error {
-        match e {
-            Error::HashMismatch => io::Error::new(io::ErrorKind::InvalidData, "hash mismatch"),
-            Error::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, "truncated encoding"),
+/// Copy a combined encoding from an async reader to an async writer, verifying it along the way,
+/// and return the number of content bytes written. Like `decode_to_writer`, only verified bytes
+/// are written, and errors from `dst` are returned as-is.
+///
+/// This respects the backpressure of a slow `dst`. It reads and verifies one chunk at a time, and
+/// it doesn't read anything more from `src` until `dst` has accepted all of the previous chunk, so
+/// at most one chunk of verified content is ever buffered.
+///
+/// This requires the `tokio` Cargo feature.
+#[cfg(feature = "tokio")]
+pub async fn async_copy_verified<R, W>(mut src: R, hash: &Hash, mut dst: W) -> io::Result<u64>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut state = VerifyState::new(hash);
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        match state.read_next() {
+            NextRead::Done => break,
+            NextRead::Header => {
+                let mut header = [0; HEADER_SIZE];
+                src.read_exact(&mut header).await?;
+                state.feed_header(&header);
+            }
+            NextRead::Parent => {
+                let mut parent = [0; PARENT_SIZE];
+                src.read_exact(&mut parent).await?;
+                state.feed_parent(&parent)?;
+            }
+            NextRead::Chunk {
+                size,
+                finalization,
+                skip,
+                index,
+            } => {
+                // We never seek, so we never skip.
+                debug_assert_eq!(0, skip);
+                let chunk = &mut chunk[..size];
+                src.read_exact(chunk).await?;
+                let chunk_hash = blake3::guts::ChunkState::new(index)
+                    .update(chunk)
+                    .finalize(finalization.is_root());
+                state.feed_chunk(&chunk_hash)?;
+                dst.write_all(chunk).await?;
+                total += size as u64;
+            }
         }
     }
+    dst.flush().await?;
+    Ok(total)
 }
 
-// Shared between Decoder and SliceDecoder.
-#[derive(Clone)]
-struct DecoderShared<T: Read, O: Read> {
-    input: T,
-    outboard: Option<O>,
+/// Verify content and its outboard tree arriving on two separate async streams, like two
+/// connections to a peer, and return the content one verified chunk at a time. The header and
+/// parent nodes come from `outboard`, and the chunks come from `content`. A chunk can't be
+/// verified until the parent nodes above it have arrived, so each read waits on whichever stream
+/// the next piece comes from. Nothing is read ahead, and only one chunk is buffered.
+///
+/// This requires the `tokio` Cargo feature.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 10_000];
+/// let (outboard, hash) = bao::encode::outboard(&input);
+/// let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+/// let mut output = Vec::new();
+/// let verifier = bao::decode::AsyncOutboardVerifier::new(&input[..], &outboard[..], &hash);
+/// runtime.block_on(verifier.copy_to(&mut output))?;
+/// assert_eq!(input, output);
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "tokio")]
+pub struct AsyncOutboardVerifier<C, O> {
+    content: C,
+    outboard: O,
     state: VerifyState,
-    buf: [u8; CHUNK_SIZE],
-    buf_start: usize,
-    buf_end: usize,
+    chunk: [u8; CHUNK_SIZE],
 }
 
-impl<T: Read, O: Read> DecoderShared<T, O> {
-    fn new(input: T, outboard: Option<O>, hash: &Hash) -> Self {
+#[cfg(feature = "tokio")]
+impl<C, O> AsyncOutboardVerifier<C, O>
+where
+    C: tokio::io::AsyncRead + Unpin,
+    O: tokio::io::AsyncRead + Unpin,
+{
+    pub fn new(content: C, outboard: O, hash: &Hash) -> Self {
         Self {
-            input,
+            content,
             outboard,
             state: VerifyState::new(hash),
-            buf: [0; CHUNK_SIZE],
-            buf_start: 0,
-            buf_end: 0,
+            chunk: [0; CHUNK_SIZE],
         }
     }
 
-    fn adjusted_content_position(&self) -> u64 {
-        // If the current buffer_len is non-empty, then it contains the bytes
-        // immediately prior to the next read.
-        self.state.content_position() - self.buf_len() as u64
-    }
+    /// Read and verify the next chunk, and return its content. This returns `None` once all of
+    /// the content has been returned and the length has been verified. The empty chunk of empty
+    /// content is verified but never returned. Verification failures are converted from `Error`
+    /// as usual, to `ErrorKind::InvalidData` or `ErrorKind::UnexpectedEof`.
+    pub async fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        use tokio::io::AsyncReadExt;
 
-    fn buf_len(&self) -> usize {
-        self.buf_end - self.buf_start
-    }
-
-    fn clear_buf(&mut self) {
-        self.buf_start = 0;
-        self.buf_end = 0;
-    }
-
-    // These bytes are always verified before going in the buffer.
-    fn take_buffered_bytes(&mut self, output: &mut [u8]) -> usize {
-        let take = cmp::min(self.buf_len(), output.len());
-        output[..take].copy_from_slice(&self.buf[self.buf_start..self.buf_start + take]);
-        self.buf_start += take;
-        take
-    }
-
-    fn get_and_feed_header(&mut self) -> io::Result<()> {
-        debug_assert_eq!(0, self.buf_len());
-        let mut header = [0; HEADER_SIZE];
-        if let Some(outboard) = &mut self.outboard {
-            outboard.read_exact(&mut header)?;
-        } else {
-            self.input.read_exact(&mut header)?;
-        }
-        self.state.feed_header(&header);
-        Ok(())
-    }
-
-    fn get_parent(&mut self) -> io::Result<crate::ParentNode> {
-        debug_assert_eq!(0, self.buf_len());
-        let mut parent = [0; PARENT_SIZE];
-        if let Some(outboard) = &mut self.outboard {
-            outboard.read_exact(&mut parent)?;
-        } else {
-            self.input.read_exact(&mut parent)?;
-        }
-        Ok(parent)
-    }
-
-    fn get_and_feed_parent(&mut self) -> io::Result<()> {
-        let parent = self.get_parent()?;
-        self.state.feed_parent(&parent)?;
-        Ok(())
-    }
-
-    fn buffer_verified_chunk(
-        &mut self,
-        size: usize,
-        finalization: Finalization,
-        skip: usize,
-        index: u64,
-        parents_to_read: usize,
-    ) -> io::Result<()> {
-        debug_assert_eq!(0, self.buf_len());
-        self.buf_start = 0;
-        self.buf_end = 0;
-        for _ in 0..parents_to_read {
-            // Making a separate read call for each parent isn't ideal, but
-            // this is the slow path anyway. The fast path's read ahead
-            // approach optimizes parent reads better.
-            self.get_and_feed_parent()?;
-        }
-        let buf_slice = &mut self.buf[..size];
-        self.input.read_exact(buf_slice)?;
-        let hash = blake3::guts::ChunkState::new(index)
-            .update(buf_slice)
-            .finalize(finalization.is_root());
-        self.state.feed_chunk(&hash)?;
-        self.buf_start = skip;
-        self.buf_end = size;
-        Ok(())
-    }
-
-    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
-        // Explicitly short-circuit zero-length reads. We're within our rights
-        // to buffer an internal chunk in this case, or to make progress if
-        // there's an empty chunk, but this matches the current behavior of
-        // SliceExtractor for zero-length slices. This might change in the
-        // future.
-        if output.is_empty() {
-            return Ok(0);
-        }
-
-        // If there are bytes in the internal buffer, just return those.
-        if self.buf_len() > 0 {
-            return Ok(self.take_buffered_bytes(output));
-        }
-
-        // Otherwise try to verify a new chunk.
         loop {
             match self.state.read_next() {
-                NextRead::Done => {
-                    // This is EOF. We know the internal buffer is empty,
-                    // because we checked it before this loop.
-                    return Ok(0);
-                }
+                NextRead::Done => return Ok(None),
                 NextRead::Header => {
-                    self.get_and_feed_header()?;
+                    let mut header = [0; HEADER_SIZE];
+                    self.outboard.read_exact(&mut header).await?;
+                    self.state.feed_header(&header);
                 }
                 NextRead::Parent => {
-                    self.get_and_feed_parent()?;
+                    let mut parent = [0; PARENT_SIZE];
+                    self.outboard.read_exact(&mut parent).await?;
+                    self.state.feed_parent(&parent)?;
                 }
                 NextRead::Chunk {
                     size,
@@ -341,404 +689,5256 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
                     skip,
                     index,
                 } => {
-                    debug_assert_eq!(self.buf_len(), 0);
-
-                    // If we can, read the chunk directly into the `output`
-                    // buffer, to avoid extra copies. If there's a verification
-                    // error, the caller won't read the invalid bytes, because
-                    // we won't return a length.
-                    let (read_buf, direct_output) = if output.len() >= size && skip == 0 {
-                        (&mut output[..size], true)
-                    } else {
-                        (&mut self.buf[..size], false)
-                    };
-
-                    // Read the unverified chunk.
-                    self.input.read_exact(read_buf)?;
-
-                    // Hash it and push its hash into the VerifyState. This
-                    // returns an error if the hash is bad. Otherwise, the
-                    // chunk is verifiied.
+                    // We never seek, so we never skip.
+                    debug_assert_eq!(0, skip);
+                    let chunk = &mut self.chunk[..size];
+                    self.content.read_exact(chunk).await?;
                     let chunk_hash = blake3::guts::ChunkState::new(index)
-                        .update(read_buf)
+                        .update(chunk)
                         .finalize(finalization.is_root());
                     self.state.feed_chunk(&chunk_hash)?;
-
-                    // If the output buffer was large enough for direct output,
-                    // we're done. Otherwise, we need to update the internal
-                    // buffer state and return some bytes.
-                    if direct_output {
-                        return Ok(size);
-                    } else {
-                        self.buf_start = skip;
-                        self.buf_end = size;
-                        return Ok(self.take_buffered_bytes(output));
+                    if size > 0 {
+                        return Ok(Some(&self.chunk[..size]));
                     }
                 }
             }
         }
     }
 
-    // Returns Ok(true) to indicate the seek is finished. Note that both the
-    // Decoder and the SliceDecoder will use this method (which doesn't depend on
-    // io::Seek), but only the Decoder will call handle_seek_bookkeeping first.
-    // This may read a chunk, but it never leaves output bytes in the buffer,
-    // because the only time seeking reads a chunk it also skips the entire
-    // thing.
-    fn handle_seek_read(&mut self, next: NextRead) -> io::Result<bool> {
-        debug_assert_eq!(0, self.buf_len());
-        match next {
-            NextRead::Header => self.get_and_feed_header()?,
-            NextRead::Parent => self.get_and_feed_parent()?,
-            NextRead::Chunk {
-                size,
-                finalization,
-                skip,
-                index,
-            } => {
-                self.buffer_verified_chunk(
-                    size,
-                    finalization,
-                    skip,
-                    index,
-                    0, /* parents_to_read */
-                )?;
-                debug_assert_eq!(0, self.buf_len());
-            }
-            NextRead::Done => return Ok(true), // The seek is done.
-        }
-        Ok(false)
-    }
-}
+    /// Write all the verified content to `dst`, and return the number of content bytes written.
+    /// Like `async_copy_verified`, errors from `dst` are returned as-is.
+    pub async fn copy_to<W: tokio::io::AsyncWrite + Unpin>(
+        mut self,
+        mut dst: W,
+    ) -> io::Result<u64> {
+        use tokio::io::AsyncWriteExt;
 
-impl<T: Read + Seek, O: Read + Seek> DecoderShared<T, O> {
-    // The Decoder will call this as part of seeking, but note that the
-    // SliceDecoder won't, because all the seek bookkeeping has already been
-    // taken care of during slice extraction.
-    fn handle_seek_bookkeeping(
-        &mut self,
-        bookkeeping: encode::SeekBookkeeping,
-    ) -> io::Result<NextRead> {
-        // The VerifyState handles all the subtree stack management. We just
-        // need to handle the underlying seek. This is done differently
-        // depending on whether the encoding is combined or outboard.
-        if let Some(outboard) = &mut self.outboard {
-            if let Some((content_pos, outboard_pos)) = bookkeeping.underlying_seek_outboard() {
-                // As with Decoder in the outboard case, the outboard extractor has to seek both of
-                // its inner readers. The content position of the state goes into the content
-                // reader, and the rest of the reported seek offset goes into the outboard reader.
-                self.input.seek(SeekFrom::Start(content_pos))?;
-                outboard.seek(SeekFrom::Start(outboard_pos))?;
-            }
-        } else {
-            if let Some(encoding_position) = bookkeeping.underlying_seek() {
-                let position_u64: u64 = encode::cast_offset(encoding_position)?;
-                self.input.seek(SeekFrom::Start(position_u64))?;
-            }
+        let mut total = 0;
+        while let Some(chunk) = self.next_chunk().await? {
+            dst.write_all(chunk).await?;
+            total += chunk.len() as u64;
         }
-        let next = self.state.seek_bookkeeping_done(bookkeeping);
-        Ok(next)
+        dst.flush().await?;
+        Ok(total)
     }
 }
 
-impl<T: Read, O: Read> fmt::Debug for DecoderShared<T, O> {
+#[cfg(feature = "tokio")]
+impl<C, O> fmt::Debug for AsyncOutboardVerifier<C, O> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "DecoderShared {{ is_outboard: {}, state: {:?}, buf_start: {}, buf_end: {} }}",
-            self.outboard.is_some(),
-            self.state,
-            self.buf_start,
-            self.buf_end,
-        )
+        // Avoid printing the content or the hash.
+        write!(f, "AsyncOutboardVerifier {{ state: {:?} }}", self.state)
     }
 }
 
-/// An incremental decoder, which reads and verifies the output of
-/// [`Encoder`](../encode/struct.Encoder.html).
-///
-/// `Decoder` supports both the combined and outboard encoding format,
-/// depending on which constructor you use.
+/// Decode an entire slice in the default combined mode into a file, verifying and writing
+/// different subtrees in parallel. Each verified chunk is written to its content offset with a
+/// positioned write, so the threads don't share a file cursor. The file is resized to the content
+/// length first. If decoding fails, the file might contain some verified content, with zeros
+/// in place of anything that wasn't written.
 ///
-/// `Decoder` supports
-/// [`std::io::Seek`](https://doc.rust-lang.org/std/io/trait.Seek.html) if the
-/// underlying reader does, but it's also compatible with non-seekable readers.
+/// This requires the `rayon` Cargo feature, and it's only available on Unix and Windows.
 ///
 /// # Example
 ///
 /// ```
 /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-/// use std::io::prelude::*;
-///
-/// // Create both combined and outboard encodings.
-/// let input = b"some input";
-/// let (encoded, hash) = bao::encode::encode(input);
-/// let (outboard, _) = bao::encode::outboard(input);
-///
-/// // Decode the combined mode.
-/// let mut combined_output = Vec::new();
-/// let mut decoder = bao::decode::Decoder::new(&*encoded, &hash);
-/// decoder.read_to_end(&mut combined_output)?;
-///
-/// // Decode the outboard mode.
-/// let mut outboard_output = Vec::new();
-/// let mut decoder = bao::decode::Decoder::new_outboard(&input[..], &*outboard, &hash);
-/// decoder.read_to_end(&mut outboard_output)?;
-///
-/// assert_eq!(input, &*combined_output);
-/// assert_eq!(input, &*outboard_output);
+/// let input = vec![0xab; 1_000_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let output = tempfile::tempfile()?;
+/// bao::decode::decode_parallel(&encoded, &hash, &output)?;
+/// assert_eq!(input.len() as u64, output.metadata()?.len());
 /// # Ok(())
 /// # }
 /// ```
-#[derive(Clone, Debug)]
-pub struct Decoder<T: Read, O: Read> {
-    shared: DecoderShared<T, O>,
+#[cfg(all(feature = "rayon", any(unix, windows)))]
+pub fn decode_parallel(encoded: &[u8], hash: &Hash, output: &File) -> io::Result<()> {
+    let content_len = checked_content_len(encoded)?;
+    output.set_len(content_len)?;
+    decode_parallel_recurse(
+        &encoded[HEADER_SIZE..],
+        0,
+        content_len,
+        hash,
+        Finalization::Root,
+        output,
+    )
 }
 
-impl<T: Read> Decoder<T, T> {
-    pub fn new(inner: T, hash: &Hash) -> Self {
-        Self {
-            shared: DecoderShared::new(inner, None, hash),
+// Verify each parent node before descending into its children, so that a chunk is only written
+// once every node above it has been verified.
+#[cfg(all(feature = "rayon", any(unix, windows)))]
+fn decode_parallel_recurse(
+    subtree: &[u8],
+    start: u64,
+    len: u64,
+    expected_hash: &Hash,
+    finalization: Finalization,
+    output: &File,
+) -> io::Result<()> {
+    if len <= CHUNK_SIZE as u64 {
+        let chunk = &subtree[..len as usize];
+        let chunk_hash = blake3::guts::ChunkState::new(start / CHUNK_SIZE as u64)
+            .update(chunk)
+            .finalize(finalization.is_root());
+        // Hash implements constant time equality.
+        if &chunk_hash != expected_hash {
+            return Err(Error::HashMismatch.into());
         }
+        return write_all_at(output, chunk, start);
     }
+    let left_child: Hash = (*array_ref!(subtree, 0, HASH_SIZE)).into();
+    let right_child: Hash = (*array_ref!(subtree, HASH_SIZE, HASH_SIZE)).into();
+    let computed_hash = blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
+    if &computed_hash != expected_hash {
+        return Err(Error::HashMismatch.into());
+    }
+    let left_len = encode::left_subtree_len(len);
+    let left_size = encode::encoded_subtree_size(left_len) as usize;
+    let (left_subtree, right_subtree) = subtree[PARENT_SIZE..].split_at(left_size);
+    let (left_result, right_result) = rayon::join(
+        || {
+            decode_parallel_recurse(
+                left_subtree,
+                start,
+                left_len,
+                &left_child,
+                Finalization::NotRoot,
+                output,
+            )
+        },
+        || {
+            decode_parallel_recurse(
+                right_subtree,
+                start + left_len,
+                len - left_len,
+                &right_child,
+                Finalization::NotRoot,
+                output,
+            )
+        },
+    );
+    left_result.and(right_result)
 }
 
-impl<T: Read, O: Read> Decoder<T, O> {
-    pub fn new_outboard(inner: T, outboard: O, hash: &Hash) -> Self {
-        Self {
-            shared: DecoderShared::new(inner, Some(outboard), hash),
+/// Decode an entire slice in the default combined mode into a file, leaving a hole wherever a
+/// chunk is all zeros. Each verified chunk is written to its content offset with a positioned
+/// write, except that all-zero chunks aren't written at all. The file is truncated and then
+/// resized to the content length first, so that the parts that aren't written read back as zeros,
+/// and on filesystems that support it, they don't take up any space. That keeps the output as
+/// sparse as a sparse original. If decoding fails, the file might contain some verified content,
+/// with zeros in place of anything that wasn't written.
+///
+/// This is only available on Unix and Windows.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut input = vec![0; 1_000_000];
+/// input[500_000] = 1;
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let output = tempfile::tempfile()?;
+/// bao::decode::decode_sparse(&encoded, &hash, &output)?;
+/// assert_eq!(input.len() as u64, output.metadata()?.len());
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(any(unix, windows))]
+pub fn decode_sparse(encoded: &[u8], hash: &Hash, output: &File) -> io::Result<()> {
+    let content_len = checked_content_len(encoded)?;
+    output.set_len(0)?;
+    output.set_len(content_len)?;
+    // A read of CHUNK_SIZE at a chunk boundary always gets exactly one whole verified chunk.
+    let mut decoder = Decoder::new(encoded, hash);
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let n = decoder.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        if chunk[..n].iter().any(|&b| b != 0) {
+            write_all_at(output, &chunk[..n], offset)?;
         }
+        offset += n as u64;
     }
+    Ok(())
 }
 
-impl<T: Read, O: Read> Read for Decoder<T, O> {
-    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
-        self.shared.read(output)
-    }
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
 }
 
-impl<T: Read + Seek, O: Read + Seek> Seek for Decoder<T, O> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        // Clear the internal buffer when seeking. The buffered bytes won't be
-        // valid reads at the new offset.
-        self.shared.clear_buf();
-
-        // Get the absolute seek offset. If the caller passed in
-        // SeekFrom::Start, that's what we've got. If not, we need to compute
-        // it.
-        let seek_to = match pos {
-            SeekFrom::Start(offset) => offset,
-            SeekFrom::End(offset) => {
-                // To seek from the end we have to get the length, and that may
-                // require as a seek loop of its own to verify the length.
-                let content_len = loop {
-                    match self.shared.state.len_next() {
-                        encode::LenNext::Seek(bookkeeping) => {
-                            let next_read = self.shared.handle_seek_bookkeeping(bookkeeping)?;
-                            let done = self.shared.handle_seek_read(next_read)?;
-                            debug_assert!(!done);
-                        }
-                        encode::LenNext::Len(len) => break len,
-                    }
-                };
-                add_offset(content_len, offset)?
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
             }
-            SeekFrom::Current(offset) => {
-                add_offset(self.shared.adjusted_content_position(), offset)?
-            }
-        };
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
 
-        // Now with the absolute seek offset, we perform the real (possibly
-        // second) seek loop.
-        loop {
-            let bookkeeping = self.shared.state.seek_next(seek_to);
-            let next_read = self.shared.handle_seek_bookkeeping(bookkeeping)?;
-            let done = self.shared.handle_seek_read(next_read)?;
-            if done {
-                return Ok(seek_to);
+/// Verify a combined encoding and write just its content to `output`. This is the same as
+/// copying from a `Decoder`, and like a `Decoder` it writes each chunk as soon as it's verified,
+/// so on error `output` might have received some valid content. Along with `extract_outboard`,
+/// this splits a combined encoding into the raw content and its outboard tree.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = b"some input";
+/// let (encoded, hash) = bao::encode::encode(input);
+/// let mut content = Vec::new();
+/// bao::decode::extract_content(&*encoded, &hash, &mut content)?;
+/// assert_eq!(input, &*content);
+/// # Ok(())
+/// # }
+/// ```
+pub fn extract_content(combined: impl Read, hash: &Hash, mut output: impl Write) -> io::Result<()> {
+    io::copy(&mut Decoder::new(combined, hash), &mut output)?;
+    Ok(())
+}
+
+/// Decode a combined encoding as an iterator of owned chunks, each one verified before it's
+/// yielded. Every chunk is `CHUNK_SIZE` bytes except possibly the last, and empty content yields
+/// nothing. This is a wrapper around `Decoder`, so the encoding can be a stream, and only one
+/// chunk is in memory at a time. After an error, the iterator is finished.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 3000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let chunks = bao::decode::into_chunks(&*encoded, &hash).collect::<Result<Vec<_>, _>>()?;
+/// assert_eq!(vec![1024, 1024, 952], chunks.iter().map(Vec::len).collect::<Vec<_>>());
+/// assert_eq!(input, chunks.concat());
+/// # Ok(())
+/// # }
+/// ```
+pub fn into_chunks<T: Read>(encoded: T, hash: &Hash) -> IntoChunks<T> {
+    IntoChunks {
+        decoder: Decoder::new(encoded, hash),
+        done: false,
+    }
+}
+
+/// Verify a combined encoding in one forward pass, and split it into the raw content and its
+/// outboard tree. The header and parent nodes go to `outboard`, and the chunks go to `content`.
+/// Unlike `extract_outboard`, this never seeks, so `combined` can be a pipe, and nothing is
+/// buffered beyond the current chunk. Each parent node and chunk is written as soon as it's
+/// verified. The header is written first, but like any header, it's only verified at the end.
+/// Returns the number of content bytes written.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 10_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let mut content = Vec::new();
+/// let mut outboard = Vec::new();
+/// bao::decode::demux(&*encoded, &hash, &mut content, &mut outboard)?;
+/// assert_eq!(input, content);
+/// assert_eq!(bao::encode::outboard(&input).0, outboard);
+/// # Ok(())
+/// # }
+/// ```
+pub fn demux(
+    mut combined: impl Read,
+    hash: &Hash,
+    mut content: impl Write,
+    mut outboard: impl Write,
+) -> io::Result<u64> {
+    let mut state = VerifyState::new(hash);
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        match state.read_next() {
+            NextRead::Done => break,
+            NextRead::Header => {
+                let mut header = [0; HEADER_SIZE];
+                combined.read_exact(&mut header)?;
+                state.feed_header(&header);
+                outboard.write_all(&header)?;
+            }
+            NextRead::Parent => {
+                let mut parent = [0; PARENT_SIZE];
+                combined.read_exact(&mut parent)?;
+                state.feed_parent(&parent)?;
+                outboard.write_all(&parent)?;
+            }
+            NextRead::Chunk {
+                size,
+                finalization,
+                skip,
+                index,
+            } => {
+                // We never seek, so we never skip.
+                debug_assert_eq!(0, skip);
+                let chunk = &mut chunk[..size];
+                combined.read_exact(chunk)?;
+                let chunk_hash = blake3::guts::ChunkState::new(index)
+                    .update(chunk)
+                    .finalize(finalization.is_root());
+                state.feed_chunk(&chunk_hash)?;
+                content.write_all(chunk)?;
+                total += size as u64;
             }
         }
     }
+    content.flush()?;
+    outboard.flush()?;
+    Ok(total)
 }
 
-fn add_offset(position: u64, offset: i64) -> io::Result<u64> {
-    let sum = position as i128 + offset as i128;
-    if sum < 0 {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "seek before beginning",
-        ))
-    } else if sum > u64::max_value() as i128 {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "seek target overflowed u64",
-        ))
-    } else {
-        Ok(sum as u64)
+/// Copy the header and parent nodes of a combined encoding to `output`, seeking past the chunks.
+/// The result is the outboard encoding of the same content, and along with `extract_content`, this
+/// splits a combined encoding into the raw content and its outboard tree.
+///
+/// This doesn't verify anything, since it never reads the content. Decoding the pieces with
+/// `Decoder::new_outboard` does that.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 10_000];
+/// let (encoded, _) = bao::encode::encode(&input);
+/// let mut outboard = Vec::new();
+/// bao::decode::extract_outboard(std::io::Cursor::new(&encoded), &mut outboard)?;
+/// assert_eq!(bao::encode::outboard(&input).0, outboard);
+/// # Ok(())
+/// # }
+/// ```
+pub fn extract_outboard(mut combined: impl Read + Seek, mut output: impl Write) -> io::Result<()> {
+    let mut parser = encode::ParseState::new();
+    loop {
+        match parser.read_next() {
+            NextRead::Header => {
+                let mut header = [0; HEADER_SIZE];
+                combined.read_exact(&mut header)?;
+                output.write_all(&header)?;
+                parser.feed_header(&header);
+            }
+            NextRead::Parent => {
+                let mut parent = [0; PARENT_SIZE];
+                combined.read_exact(&mut parent)?;
+                output.write_all(&parent)?;
+                parser.advance_parent();
+            }
+            NextRead::Chunk { size, .. } => {
+                combined.seek(SeekFrom::Current(size as i64))?;
+                parser.advance_chunk();
+            }
+            NextRead::Done => return Ok(()),
+        }
     }
 }
 
-/// An incremental slice decoder. This reads and verifies the output of the
-/// [`SliceExtractor`](../encode/struct.SliceExtractor.html).
+/// Estimate the peak memory needed to decode content of the given length into memory, as with
+/// `decode`. That's the output itself plus the stack of subtree hashes waiting to be verified,
+/// which holds at most one hash per level of the tree, plus the root. It doesn't count the
+/// fixed-size state of the decoder. The streaming `Decoder` only needs the stack, so when this
+/// is too large, use that instead.
+pub fn peak_memory(content_len: u64) -> u64 {
+    let num_chunks = encode::count_chunks(content_len);
+    // The number of levels of parent nodes above the chunks.
+    let depth = 64 - (num_chunks - 1).leading_zeros() as u64;
+    content_len.saturating_add((depth + 1) * HASH_SIZE as u64)
+}
+
+/// Read the header and the root node of a combined encoding, and return the root hash that they
+/// imply, along with the content length from the header.
 ///
-/// Note that there is no such thing as an "outboard slice". All slices include
-/// the content bytes and tree nodes intermixed, as in the combined encoding
-/// mode.
+/// **This doesn't verify anything.** It only reads the first node of the tree, and any encoding
+/// will produce *some* hash. What it's good for is a cheap consistency check on a stored `(hash,
+/// length)` pair: if the returned hash and length both match, then a full decode against that hash
+/// will verify the header's length along with the content. If either one doesn't match, the pair
+/// doesn't describe this encoding, and there's no need to download the rest of it.
 ///
 /// # Example
 ///
 /// ```
 /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-/// use std::io::prelude::*;
+/// let input = b"some input";
+/// let (encoded, hash) = bao::encode::encode(input);
+/// let (found_hash, found_len) = bao::decode::hash_from_encoded(&*encoded)?;
+/// assert_eq!(hash, found_hash);
+/// assert_eq!(input.len() as u64, found_len);
+/// # Ok(())
+/// # }
+/// ```
+pub fn hash_from_encoded<T: Read>(encoded: T) -> io::Result<(Hash, u64)> {
+    hash_from_encoded_inner::<T, T>(encoded, None)
+}
+
+/// Like `hash_from_encoded`, but for an outboard encoding and its content. The content is only
+/// read when it's a single chunk, since otherwise the root node is in the outboard tree.
+pub fn hash_from_outboard<T: Read, O: Read>(content: T, outboard: O) -> io::Result<(Hash, u64)> {
+    hash_from_encoded_inner(content, Some(outboard))
+}
+
+/// The layout of an encoding passed to `same_content`. An outboard encoding also needs its content,
+/// because when the content is a single chunk, the root hash comes from hashing that chunk.
+#[derive(Clone, Copy, Debug)]
+pub enum EncodingKind<'a> {
+    Combined,
+    Outboard(&'a [u8]),
+}
+
+/// Check whether two encodings, each either combined or outboard, claim the same content. This
+/// compares the root hashes and content lengths from `hash_from_encoded` or `hash_from_outboard`,
+/// so it only reads the first node of each tree, and it never decodes the rest.
 ///
-/// // Start by encoding some input.
-/// let input = vec![0; 1_000_000];
-/// let (encoded, hash) = bao::encode::encode(&input);
+/// **Like those functions, this doesn't verify anything.** A `true` result means that both
+/// encodings will decode to the same content, or that at least one of them will fail to decode.
+/// Returns `Error::Truncated` if either encoding is too short to contain its first node.
 ///
-/// // Slice the encoding. These parameters are multiples of the chunk size, which avoids
-/// // unnecessary overhead.
-/// let slice_start = 65536;
-/// let slice_len = 8192;
-/// let encoded_cursor = std::io::Cursor::new(&encoded);
-/// let mut extractor = bao::encode::SliceExtractor::new(encoded_cursor, slice_start, slice_len);
-/// let mut slice = Vec::new();
-/// extractor.read_to_end(&mut slice)?;
+/// # Example
 ///
-/// // Decode the slice. The result should be the same as the part of the input that the slice
-/// // represents. Note that we're using the same hash that encoding produced, which is
-/// // independent of the slice parameters. That's the whole point; if we just wanted to re-encode
-/// // a portion of the input and wind up with a different hash, we wouldn't need slicing.
-/// let mut decoded = Vec::new();
-/// let mut decoder = bao::decode::SliceDecoder::new(&*slice, &hash, slice_start, slice_len);
-/// decoder.read_to_end(&mut decoded)?;
-/// assert_eq!(&input[slice_start as usize..][..slice_len as usize], &*decoded);
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use bao::decode::EncodingKind;
 ///
-/// // Like regular decoding, slice decoding will fail if the hash doesn't match.
-/// let mut bad_slice = slice.clone();
-/// let last_index = bad_slice.len() - 1;
-/// bad_slice[last_index] ^= 1;
-/// let mut decoder = bao::decode::SliceDecoder::new(&*bad_slice, &hash, slice_start, slice_len);
-/// let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
-/// assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+/// let input = vec![0; 5000];
+/// let (encoded, _) = bao::encode::encode(&input);
+/// let (outboard, _) = bao::encode::outboard(&input);
+/// assert!(bao::decode::same_content(
+///     &encoded,
+///     EncodingKind::Combined,
+///     &outboard,
+///     EncodingKind::Outboard(&input),
+/// )?);
 /// # Ok(())
 /// # }
 /// ```
-pub struct SliceDecoder<T: Read> {
-    shared: DecoderShared<T, T>,
-    slice_start: u64,
-    slice_remaining: u64,
-    // If the caller requested no bytes, the extractor is still required to
-    // include a chunk. We're not required to verify it, but we want to
-    // aggressively check for extractor bugs.
-    need_fake_read: bool,
+pub fn same_content(
+    enc_a: &[u8],
+    kind_a: EncodingKind,
+    enc_b: &[u8],
+    kind_b: EncodingKind,
+) -> Result<bool, Error> {
+    fn root(encoded: &[u8], kind: EncodingKind) -> Result<(Hash, u64), Error> {
+        let result = match kind {
+            EncodingKind::Combined => hash_from_encoded(encoded),
+            EncodingKind::Outboard(content) => hash_from_outboard(content, encoded),
+        };
+        // Reading from slices can only fail at EOF.
+        result.map_err(|_| Error::Truncated)
+    }
+    let (hash_a, len_a) = root(enc_a, kind_a)?;
+    let (hash_b, len_b) = root(enc_b, kind_b)?;
+    // Hash implements constant time equality.
+    let same_hash = hash_a == hash_b;
+    Ok(same_hash & (len_a == len_b))
 }
 
-impl<T: Read> SliceDecoder<T> {
-    pub fn new(inner: T, hash: &Hash, slice_start: u64, slice_len: u64) -> Self {
-        Self {
-            shared: DecoderShared::new(inner, None, hash),
-            slice_start,
-            slice_remaining: slice_len,
-            need_fake_read: slice_len == 0,
-        }
+fn hash_from_encoded_inner<T: Read, O: Read>(
+    mut input: T,
+    mut outboard: Option<O>,
+) -> io::Result<(Hash, u64)> {
+    let tree: &mut dyn Read = match &mut outboard {
+        Some(outboard) => outboard,
+        None => &mut input,
+    };
+    let mut header = [0; HEADER_SIZE];
+    tree.read_exact(&mut header)?;
+    let content_len = crate::hash::decode_len(&header);
+    let hash = if content_len > CHUNK_SIZE as u64 {
+        let mut parent = [0; PARENT_SIZE];
+        tree.read_exact(&mut parent)?;
+        let left_child: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+        let right_child: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+        blake3::guts::parent_cv(&left_child, &right_child, true)
+    } else {
+        let mut chunk = [0; CHUNK_SIZE];
+        input.read_exact(&mut chunk[..content_len as usize])?;
+        blake3::guts::ChunkState::new(0)
+            .update(&chunk[..content_len as usize])
+            .finalize(true)
+    };
+    Ok((hash, content_len))
+}
+
+// Parse the length header of an all-at-once combined encoding, and make sure the encoding is long
+// enough to match it. Note that the length isn't validated until the final chunk is verified.
+fn checked_content_len(bytes: &[u8]) -> Result<u64, Error> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(Error::Truncated);
+    }
+    let content_len = crate::hash::decode_len(array_ref!(bytes, 0, HEADER_SIZE));
+    if (bytes.len() as u128) < encode::encoded_size(content_len) {
+        return Err(Error::Truncated);
     }
+    Ok(content_len)
 }
 
-impl<T: Read> Read for SliceDecoder<T> {
-    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
-        // If we haven't done the initial seek yet, do the full seek loop
-        // first. Note that this will never leave any buffered output. The only
-        // scenario where handle_seek_read reads a chunk is if it needs to
-        // validate the final chunk, and then it skips the whole thing.
-        if self.shared.state.content_position() < self.slice_start {
-            loop {
-                let bookkeeping = self.shared.state.seek_next(self.slice_start);
-                // Note here, we skip to seek_bookkeeping_done without
-                // calling handle_seek_bookkeeping. That is, we never
-                // perform any underlying seeks. The slice extractor
-                // already took care of lining everything up for us.
-                let next = self.shared.state.seek_bookkeeping_done(bookkeeping);
-                let done = self.shared.handle_seek_read(next)?;
-                if done {
-                    break;
-                }
-            }
-            debug_assert_eq!(0, self.shared.buf_len());
+/// Check the internal consistency of an outboard tree without its content, and return the root
+/// hash it implies. Every parent node whose child is also a parent node has to agree with that
+/// child's hash, and the header has to match `content_len`. Comparing the results for two
+/// outboard files is a cheap way to tell whether they describe the same tree.
+///
+/// **The chunk hashes at the bottom of the tree aren't verified**, since that takes the content.
+/// A consistent outboard can still fail to decode against its content, and this isn't a
+/// substitute for decoding. Trailing bytes after the tree are ignored, like the decoder ignores
+/// them.
+///
+/// For `content_len` of a single chunk or less, the outboard is just the header, and the root
+/// hash is the hash of the content itself. The root can't be derived without the content, so once
+/// the header checks out, this returns `Ok(None)`. `Truncated` always means the outboard is
+/// shorter than `content_len` requires.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 10_000];
+/// let (outboard, hash) = bao::encode::outboard(&input);
+/// assert_eq!(Some(hash), bao::decode::outboard_root(&outboard, 10_000)?);
+///
+/// // A single chunk has no tree to check.
+/// let (outboard, _) = bao::encode::outboard(b"foo");
+/// assert_eq!(None, bao::decode::outboard_root(&outboard, 3)?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn outboard_root(outboard: &[u8], content_len: u64) -> Result<Option<Hash>, Error> {
+    // Check that this parent node hashes to `expected`, and then do the same for its children,
+    // returning the offset of the next parent node.
+    fn check_subtree(
+        outboard: &[u8],
+        offset: usize,
+        len: u64,
+        expected: &Hash,
+        finalization: Finalization,
+    ) -> Result<usize, Error> {
+        let parent = array_ref!(outboard, offset, PARENT_SIZE);
+        let left: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+        let right: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+        // Hash implements constant time equality.
+        if &blake3::guts::parent_cv(&left, &right, finalization.is_root()) != expected {
+            return Err(Error::HashMismatch);
         }
-
-        // We either just finished the seek (if any), or already did it during
-        // a previous call. Continue the read. Cap the output buffer to be at
-        // most the slice bytes remaining.
-        if self.need_fake_read {
-            // Read one byte and throw it away, just to verify a chunk.
-            self.shared.read(&mut [0])?;
-            self.need_fake_read = false;
-            Ok(0)
-        } else {
-            let cap = cmp::min(self.slice_remaining, output.len() as u64) as usize;
-            let capped_output = &mut output[..cap];
-            let n = self.shared.read(capped_output)?;
-            self.slice_remaining -= n as u64;
-            Ok(n)
+        let mut offset = offset + PARENT_SIZE;
+        let left_len = encode::left_subtree_len(len);
+        if left_len > CHUNK_SIZE as u64 {
+            offset = check_subtree(outboard, offset, left_len, &left, Finalization::NotRoot)?;
         }
+        if len - left_len > CHUNK_SIZE as u64 {
+            let right_len = len - left_len;
+            offset = check_subtree(outboard, offset, right_len, &right, Finalization::NotRoot)?;
+        }
+        Ok(offset)
+    }
+
+    if (outboard.len() as u128) < encode::outboard_size(content_len) {
+        return Err(Error::Truncated);
+    }
+    if crate::hash::decode_len(array_ref!(outboard, 0, HEADER_SIZE)) != content_len {
+        return Err(Error::HashMismatch);
     }
+    if content_len <= CHUNK_SIZE as u64 {
+        return Ok(None);
+    }
+    let root_node = &outboard[HEADER_SIZE..][..PARENT_SIZE];
+    let root = crate::hash::root_finalize(root_node, content_len).expect("a parent node");
+    check_subtree(
+        outboard,
+        HEADER_SIZE,
+        content_len,
+        &root,
+        Finalization::Root,
+    )?;
+    Ok(Some(root))
 }
 
-#[cfg(test)]
-pub(crate) fn make_test_input(len: usize) -> Vec<u8> {
-    // Fill the input with incrementing bytes, so that reads from different sections are very
-    // unlikely to accidentally match.
-    let mut ret = Vec::new();
-    let mut counter = 0u64;
-    while ret.len() < len {
-        if counter < u8::max_value() as u64 {
-            ret.push(counter as u8);
-        } else if counter < u16::max_value() as u64 {
-            ret.extend_from_slice(&(counter as u16).to_be_bytes());
-        } else if counter < u32::max_value() as u64 {
-            ret.extend_from_slice(&(counter as u32).to_be_bytes());
-        } else {
-            ret.extend_from_slice(&(counter as u64).to_be_bytes());
+/// Parse a length header, rejecting lengths that no real encoding could have before anything
+/// trusts them to size an allocation or drive a traversal. A length above
+/// [`MAX_CONTENT_LEN`](../constant.MAX_CONTENT_LEN.html) fails with `HashMismatch`, because its
+/// encoding couldn't be addressed with `u64` offsets.
+///
+/// If the total length of the combined encoding is known, pass it as `encoded_total_len`, and the
+/// header has to agree with it exactly. An encoding shorter than the header implies fails with
+/// `Truncated`, and a longer one fails with `HashMismatch`. Like `is_valid_encoded_len`, this is
+/// only a structural check. The length still isn't verified until the final chunk is.
+///
+/// # Example
+///
+/// ```
+/// let (encoded, _) = bao::encode::encode(b"foo");
+/// let header = *arrayref::array_ref!(encoded, 0, 8);
+/// assert_eq!(Ok(3), bao::decode::parse_header(&header, Some(encoded.len() as u64)));
+/// assert_eq!(Err(bao::decode::Error::Truncated), bao::decode::parse_header(&header, Some(10)));
+/// ```
+pub fn parse_header(
+    bytes: &[u8; HEADER_SIZE],
+    encoded_total_len: Option<u64>,
+) -> Result<u64, Error> {
+    let content_len = crate::hash::decode_len(bytes);
+    if content_len > crate::MAX_CONTENT_LEN {
+        return Err(Error::HashMismatch);
+    }
+    if let Some(encoded_total_len) = encoded_total_len {
+        let expected = encode::encoded_size(content_len);
+        if (encoded_total_len as u128) < expected {
+            return Err(Error::Truncated);
+        }
+        if encoded_total_len as u128 > expected {
+            return Err(Error::HashMismatch);
         }
-        counter += 1;
     }
-    ret.truncate(len);
-    ret
+    Ok(content_len)
 }
 
-#[cfg(test)]
-mod test {
-    use rand::prelude::*;
-    use rand_chacha::ChaChaRng;
-    use std::io;
-    use std::io::prelude::*;
-    use std::io::Cursor;
-
-    use super::*;
-    use crate::encode;
+/// Check whether `encoded_len` is the size of a combined encoding for some content length, and
+/// return that content length if so. Encoded sizes are strictly increasing in the content length,
+/// so there's at most one answer. This is a cheap structural check for truncated or padded files,
+/// and it doesn't verify anything about the bytes themselves, not even the header.
+///
+/// # Example
+///
+/// ```
+/// let encoded_len = bao::encode::encoded_size(4096) as u64;
+/// assert_eq!(Some(4096), bao::decode::is_valid_encoded_len(encoded_len));
+/// // A fifth chunk would need another parent node.
+/// assert_eq!(None, bao::decode::is_valid_encoded_len(encoded_len + 1));
+/// ```
+pub fn is_valid_encoded_len(encoded_len: u64) -> Option<u64> {
+    // Every chunk after the first adds one parent node, so each full chunk of content costs
+    // CHUNK_SIZE + PARENT_SIZE bytes of encoding. Adding one PARENT_SIZE up front makes the first
+    // chunk look like all the others, and then the number of chunks is a division.
+    let padded_len =
+        (encoded_len as u128 + PARENT_SIZE as u128).checked_sub(HEADER_SIZE as u128)?;
+    let chunk_cost = (CHUNK_SIZE + PARENT_SIZE) as u128;
+    let num_chunks = cmp::max(1, padded_len.div_ceil(chunk_cost));
+    let content_len = padded_len.checked_sub(num_chunks * PARENT_SIZE as u128)?;
+    if content_len > u64::MAX as u128
+        || encode::encoded_size(content_len as u64) != encoded_len as u128
+    {
+        return None;
+    }
+    Some(content_len as u64)
+}
 
-    #[test]
-    fn test_decode() {
-        for &case in crate::test::TEST_CASES {
+/// Extract the smallest slice of a combined encoding that proves a single content byte. This is
+/// the chunk containing `offset`, along with the header and the parent nodes on the path from the
+/// root down to that chunk, so its size grows with the depth of the tree rather than with the
+/// length of the content. It's equivalent to a `SliceExtractor` with a `slice_len` of 1, and it can
+/// be decoded with `SliceDecoder::new(proof, hash, offset, 1)`.
+///
+/// As with any slice, an `offset` at or past the end of the content produces a proof of the final
+/// chunk, which verifies the content length.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = vec![0xab; 1_000_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let proof = bao::decode::single_byte_proof(&encoded, 500_000)?;
+/// assert!(proof.len() < 2048);
+///
+/// let mut byte = Vec::new();
+/// let mut decoder = bao::decode::SliceDecoder::new(&*proof, &hash, 500_000, 1);
+/// decoder.read_to_end(&mut byte)?;
+/// assert_eq!(&[0xab], &*byte);
+/// # Ok(())
+/// # }
+/// ```
+pub fn single_byte_proof(encoded: &[u8], offset: u64) -> Result<Vec<u8>, Error> {
+    checked_content_len(encoded)?;
+    let mut extractor = encode::SliceExtractor::new(io::Cursor::new(encoded), offset, 1);
+    let mut proof = Vec::new();
+    // Extraction doesn't check any hashes, and we've already checked the length of the encoding,
+    // so reading from memory can't fail.
+    extractor
+        .read_to_end(&mut proof)
+        .expect("extracting from a full-length encoding");
+    Ok(proof)
+}
+
+/// Verify a proof from [`encode::eof_proof`](../encode/fn.eof_proof.html) that the content with
+/// root hash `hash` is exactly `claimed_len` bytes long, with no more chunks after that. A proof
+/// whose header claims some other length, or that doesn't verify, returns `HashMismatch`, and a
+/// proof that's cut short returns `Truncated`. Bytes after the end of the proof are ignored.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (encoded, hash) = bao::encode::encode(b"some input");
+/// let proof = bao::encode::eof_proof(&encoded, 10)?;
+/// bao::decode::verify_eof_proof(&hash, &proof, 10)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn verify_eof_proof(hash: &Hash, proof: &[u8], claimed_len: u64) -> Result<(), Error> {
+    let header = proof.get(..HEADER_SIZE).ok_or(Error::Truncated)?;
+    if crate::hash::decode_len(array_ref!(header, 0, HEADER_SIZE)) != claimed_len {
+        return Err(Error::HashMismatch);
+    }
+    // A slice starting at the end of the content returns no bytes, but it still has to verify
+    // the final chunk, and that checks the length against the hash.
+    let mut decoder = SliceDecoder::new(proof, hash, claimed_len, 1);
+    match decoder.read(&mut [0]) {
+        Ok(0) => Ok(()),
+        Ok(_) => unreachable!("no content past the end"),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(Error::Truncated),
+        Err(_) => Err(Error::HashMismatch),
+    }
+}
+
+/// Check the chunks of a combined encoding against a CRC sidecar from
+/// [`encode::crc_sidecar`](../encode/fn.crc_sidecar.html), without hashing anything. This is what
+/// `bao decode --crc` does before decoding.
+///
+/// **This is not verification.** A CRC only catches accidental corruption, and it says nothing
+/// about the parent nodes in the encoding. An `Ok` result here must always be followed by a real
+/// decode against the root hash, which remains authoritative. The point of this function is to
+/// fail fast, for example to retry a download before spending time on a full decode.
+///
+/// A mismatched CRC, or a sidecar of the wrong length, returns `CrcError::Mismatch`. An encoding
+/// that's too short for its length header returns `CrcError::Truncated`.
+///
+/// # Example
+///
+/// ```
+/// let input = b"some input";
+/// let (encoded, hash) = bao::encode::encode(input);
+/// let sidecar = bao::encode::crc_sidecar(input);
+///
+/// // The fast pass comes first, but it doesn't replace decoding.
+/// bao::decode::crc_check(&encoded, &sidecar).unwrap();
+/// bao::decode::decode(&encoded, &hash).unwrap();
+///
+/// let mut bad_encoded = encoded.clone();
+/// let last_index = bad_encoded.len() - 1;
+/// bad_encoded[last_index] ^= 1;
+/// let err = bao::decode::crc_check(&bad_encoded, &sidecar).unwrap_err();
+/// assert_eq!(bao::decode::CrcError::Mismatch, err);
+/// ```
+pub fn crc_check(encoded: impl AsRef<[u8]>, sidecar: impl AsRef<[u8]>) -> Result<(), CrcError> {
+    let bytes = encoded.as_ref();
+    let sidecar = sidecar.as_ref();
+    let content_len = checked_content_len(bytes).map_err(|_| CrcError::Truncated)?;
+    if sidecar.len() as u128 != encode::crc_sidecar_size(content_len) {
+        return Err(CrcError::Mismatch);
+    }
+    // Walk the chunks in pre-order, skipping over the parent nodes in front of each one.
+    let mut position = HEADER_SIZE;
+    for (chunk_index, expected) in sidecar.chunks(CRC_SIZE).enumerate() {
+        let chunk_index = chunk_index as u64;
+        position += encode::pre_order_parent_nodes(chunk_index, content_len) as usize * PARENT_SIZE;
+        let size = encode::chunk_size(chunk_index, content_len);
+        let crc = crc32fast::hash(&bytes[position..][..size]);
+        if crc.to_le_bytes() != expected {
+            return Err(CrcError::Mismatch);
+        }
+        position += size;
+    }
+    Ok(())
+}
+
+/// Check the CRC trailer from [`encode::append_trailer_crc`](../encode/fn.append_trailer_crc.html),
+/// if `encoded` has one, without hashing anything. Returns `Ok(true)` if the trailer is present and
+/// matches, and `Ok(false)` if there's no trailer. A trailer that doesn't match returns
+/// `CrcError::Mismatch`. Input shorter than a length header returns `CrcError::Truncated`.
+///
+/// A trailer is present if `encoded` ends with the trailer magic, and either its length doesn't
+/// match what the length header says a bare encoding should be, or its CRC matches. That way a
+/// corrupted header, which changes the expected length, still gets its CRC checked, and an
+/// encoding whose content happens to end with the magic isn't mistaken for one with a trailer.
+///
+/// **This is not verification**, the same as `crc_check`. An `Ok` result must always be followed
+/// by a real decode against the root hash, and that's all there is to do for `Ok(false)`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (encoded, _) = bao::encode::encode(b"some input");
+/// assert!(!bao::decode::check_trailer_crc(&encoded)?);
+///
+/// let mut cursor = std::io::Cursor::new(encoded);
+/// bao::encode::append_trailer_crc(&mut cursor)?;
+/// let mut with_trailer = cursor.into_inner();
+/// with_trailer[9] ^= 1;
+/// assert_eq!(
+///     Err(bao::decode::CrcError::Mismatch),
+///     bao::decode::check_trailer_crc(&with_trailer),
+/// );
+/// # Ok(())
+/// # }
+/// ```
+pub fn check_trailer_crc(encoded: &[u8]) -> Result<bool, CrcError> {
+    if encoded.len() < HEADER_SIZE {
+        return Err(CrcError::Truncated);
+    }
+    let content_len = crate::hash::decode_len(array_ref!(encoded, 0, HEADER_SIZE));
+    if !encoded.ends_with(&TRAILER_MAGIC) || encoded.len() < HEADER_SIZE + TRAILER_SIZE {
+        return Ok(false);
+    }
+    let (body, trailer) = encoded.split_at(encoded.len() - TRAILER_SIZE);
+    if crc32fast::hash(body).to_le_bytes() == trailer[..CRC_SIZE] {
+        Ok(true)
+    } else if encoded.len() as u128 == encode::encoded_size(content_len) {
+        // A bare encoding that happens to end with the magic.
+        Ok(false)
+    } else {
+        Err(CrcError::Mismatch)
+    }
+}
+
+/// Errors from `crc_check` and `check_trailer_crc`. A CRC isn't verification, so these are kept
+/// apart from `Error`. `Mismatch` converts to `ErrorKind::InvalidData`, and `Truncated` to
+/// `ErrorKind::UnexpectedEof`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrcError {
+    /// A CRC doesn't match, or the sidecar is the wrong length for the encoding.
+    Mismatch,
+    /// The encoding is too short for its length header.
+    Truncated,
+}
+
+impl fmt::Display for CrcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CrcError::Mismatch => write!(f, "crc mismatch"),
+            CrcError::Truncated => write!(f, "truncated encoding"),
+        }
+    }
+}
+
+impl error::Error for CrcError {}
+
+impl From<CrcError> for io::Error {
+    fn from(e: CrcError) -> io::Error {
+        match e {
+            CrcError::Mismatch => io::Error::new(io::ErrorKind::InvalidData, e),
+            CrcError::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+        }
+    }
+}
+
+/// Return the format version of an encoding from `encode::encode_versioned`, or `None` if the
+/// encoding doesn't start with the version prefix. Unprefixed encodings are the default format.
+/// The magic can't be told apart from a header by itself, so an unprefixed encoding whose content
+/// length starts with the same four bytes (little-endian) is detected as versioned. Decoding it as
+/// versioned then fails verification rather than succeeding.
+///
+/// # Example
+///
+/// ```
+/// use bao::decode::Version;
+///
+/// let (versioned, _) = bao::encode::encode_versioned(b"foo");
+/// assert_eq!(Some(Version::CURRENT), bao::decode::detect_version(&versioned));
+/// let (plain, _) = bao::encode::encode(b"foo");
+/// assert_eq!(None, bao::decode::detect_version(&plain));
+/// ```
+pub fn detect_version(encoded: &[u8]) -> Option<Version> {
+    if encoded.len() < VERSION_PREFIX_SIZE || !encoded.starts_with(&VERSION_MAGIC) {
+        return None;
+    }
+    Some(Version(encoded[VERSION_MAGIC.len()]))
+}
+
+/// Decode an encoding from `encode::encode_versioned`. The version prefix is checked before
+/// anything else, and a missing prefix or a version this crate can't decode is an `InvalidData`
+/// error carrying a `VersionError`. After the prefix, this is the same as `decode`.
+///
+/// # Example
+///
+/// ```
+/// use bao::decode::VersionError;
+///
+/// let (mut encoded, hash) = bao::encode::encode_versioned(b"foo");
+/// encoded[4] = 99;
+/// let err = bao::decode::decode_versioned(&encoded, &hash).unwrap_err();
+/// assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+/// let version_err = err.get_ref().unwrap().downcast_ref::<VersionError>().unwrap();
+/// assert_eq!(&VersionError::Unsupported(bao::decode::Version(99)), version_err);
+/// ```
+pub fn decode_versioned(encoded: impl AsRef<[u8]>, hash: &Hash) -> io::Result<Vec<u8>> {
+    decode_versioned_inner(encoded.as_ref(), hash, &[Version::CURRENT])
+}
+
+// Only decoding of the current version exists so far. The supported set is a parameter so that
+// the tests can stand in for a decoder from a later version.
+fn decode_versioned_inner(
+    encoded: &[u8],
+    hash: &Hash,
+    supported: &[Version],
+) -> io::Result<Vec<u8>> {
+    let version = detect_version(encoded).ok_or(VersionError::Unversioned)?;
+    if !supported.contains(&version) {
+        return Err(VersionError::Unsupported(version).into());
+    }
+    decode(&encoded[VERSION_PREFIX_SIZE..], hash)
+}
+
+/// The format version in the prefix of an encoding from `encode::encode_versioned`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version(pub u8);
+
+impl Version {
+    /// The version of the format this crate encodes: 1024-byte chunks and BLAKE3 finalization.
+    pub const CURRENT: Version = Version(1);
+
+    /// The version byte as it appears in the prefix.
+    pub fn number(self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// Errors from `decode_versioned` about the version prefix. They're about the framing rather than
+/// the encoding inside it, so they're kept apart from `Error`. They convert to
+/// `ErrorKind::InvalidData`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VersionError {
+    /// The encoding doesn't start with the version prefix.
+    Unversioned,
+    /// The prefix holds a version this crate can't decode.
+    Unsupported(Version),
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VersionError::Unversioned => write!(f, "missing format version prefix"),
+            VersionError::Unsupported(v) => write!(f, "unsupported format version {}", v),
+        }
+    }
+}
+
+impl error::Error for VersionError {}
+
+impl From<VersionError> for io::Error {
+    fn from(e: VersionError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+// This incremental verifier layers on top of encode::ParseState, and supports
+// both the Decoder and the SliceDecoder.
+#[derive(Clone)]
+struct VerifyState {
+    // The deepest tree, for a content length of u64::MAX, has MAX_DEPTH parent nodes above its
+    // first chunk. Descending to that chunk leaves a right child on the stack for each of them,
+    // plus the chunk's own hash, so the stack needs one more slot than the depth.
+    stack: ArrayVec<[Hash; MAX_DEPTH + 1]>,
+    parser: encode::ParseState,
+    root_hash: Hash,
+}
+
+impl VerifyState {
+    fn new(hash: &Hash) -> Self {
+        let mut stack = ArrayVec::new();
+        stack.push(*hash);
+        Self {
+            stack,
+            parser: encode::ParseState::new(),
+            root_hash: *hash,
+        }
+    }
+
+    fn new_subtree(hash: &Hash) -> Self {
+        Self {
+            parser: encode::ParseState::new_subtree(),
+            ..Self::new(hash)
+        }
+    }
+
+    fn content_position(&self) -> u64 {
+        self.parser.content_position()
+    }
+
+    fn read_next(&self) -> NextRead {
+        self.parser.read_next()
+    }
+
+    fn seek_next(&self, seek_to: u64) -> encode::SeekBookkeeping {
+        self.parser.seek_next(seek_to)
+    }
+
+    fn seek_bookkeeping_done(&mut self, bookkeeping: encode::SeekBookkeeping) -> encode::NextRead {
+        // Leftward seeks require resetting the stack to the beginning.
+        if bookkeeping.reset_to_root() {
+            self.stack.clear();
+            self.stack.push(self.root_hash);
+        }
+        // Rightward seeks require popping subtrees off the stack.
+        debug_assert!(self.stack.len() >= bookkeeping.stack_depth());
+        while self.stack.len() > bookkeeping.stack_depth() {
+            self.stack.pop();
+        }
+        self.parser.seek_bookkeeping_done(bookkeeping)
+    }
+
+    fn len_next(&self) -> encode::LenNext {
+        self.parser.len_next()
+    }
+
+    fn feed_header(&mut self, header: &[u8; HEADER_SIZE]) {
+        self.parser.feed_header(header);
+    }
+
+    fn feed_parent(&mut self, parent: &crate::ParentNode) -> Result<(), Error> {
+        let finalization = self.parser.finalization();
+        let expected_hash: &Hash = self.stack.last().expect("unexpectedly empty stack");
+        let left_child: Hash = (*array_ref!(parent, 0, 32)).into();
+        let right_child: Hash = (*array_ref!(parent, 32, 32)).into();
+        let computed_hash: Hash =
+            blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
+        // Hash implements constant time equality.
+        if expected_hash != &computed_hash {
+            return Err(Error::HashMismatch);
+        }
+        self.stack.pop();
+        self.stack.push(right_child.into());
+        self.stack.push(left_child.into());
+        self.parser.advance_parent();
+        Ok(())
+    }
+
+    fn feed_chunk(&mut self, chunk_hash: &Hash) -> Result<(), Error> {
+        let expected_hash = self.stack.last().expect("unexpectedly empty stack");
+        // Hash implements constant time equality.
+        if chunk_hash != expected_hash {
+            return Err(Error::HashMismatch);
+        }
+        self.stack.pop();
+        self.parser.advance_chunk();
+        Ok(())
+    }
+}
+
+// It's important to manually implement Debug for VerifyState, because it holds hashes that
+// might be secret, and it would be bad to leak them to some debug log somewhere.
+impl fmt::Debug for VerifyState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "VerifyState {{ stack_size: {}, parser: {:?} }}",
+            self.stack.len(), // *Only* the stack size, not the hashes themselves.
+            self.parser,      // The parser state only reveals the content length.
+        )
+    }
+}
+
+/// Errors that can happen during decoding.
+///
+/// Two errors are possible when decoding, apart from the usual IO issues: the content bytes might
+/// not have the right hash, or the encoding might not be as long as it's supposed to be. In
+/// `std::io::Read` interfaces where we have to return `std::io::Error`, these variants are
+/// converted to `ErrorKind::InvalidData` and `ErrorKind::UnexpectedEof` respectively.
+///
+/// When `Decoder` or `SliceDecoder` hits a hash mismatch, the `InvalidData` error carries a
+/// `HashMismatchAt` with the content offset of the node that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    HashMismatch,
+    Truncated,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::HashMismatch => write!(f, "hash mismatch"),
+            Error::Truncated => write!(f, "truncated encoding"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::HashMismatch => io::Error::new(io::ErrorKind::InvalidData, "hash mismatch"),
+            Error::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, "truncated encoding"),
+        }
+    }
+}
+
+/// The payload of the `ErrorKind::InvalidData` errors that `Decoder` and `SliceDecoder` return
+/// when a parent node or a chunk fails to verify. `content_offset` is where the content covered by
+/// that node starts, so for a chunk it's the chunk's first byte, and for a parent node it's the
+/// first byte of its subtree. Streaming callers can get it from the `std::io::Error` with
+/// `get_ref` and `downcast_ref`. It displays as a hash mismatch, and it converts to
+/// `Error::HashMismatch`.
+///
+/// # Example
+///
+/// ```
+/// use std::io::prelude::*;
+///
+/// let input = vec![0; 10_000];
+/// let (mut encoded, hash) = bao::encode::encode(&input);
+/// let last_index = encoded.len() - 1;
+/// encoded[last_index] ^= 1;
+/// let err = bao::decode::Decoder::new(&*encoded, &hash)
+///     .read_to_end(&mut Vec::new())
+///     .unwrap_err();
+/// let mismatch = err
+///     .get_ref()
+///     .and_then(|e| e.downcast_ref::<bao::decode::HashMismatchAt>())
+///     .unwrap();
+/// assert_eq!(9 * 1024, mismatch.content_offset);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashMismatchAt {
+    pub content_offset: u64,
+}
+
+impl fmt::Display for HashMismatchAt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hash mismatch at content offset {}", self.content_offset)
+    }
+}
+
+impl error::Error for HashMismatchAt {}
+
+impl From<HashMismatchAt> for Error {
+    fn from(_: HashMismatchAt) -> Error {
+        Error::HashMismatch
+    }
+}
+
+impl From<HashMismatchAt> for io::Error {
+    fn from(e: HashMismatchAt) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// The error returned by `decode_to_writer`. Verification failures and errors from the output are
+/// kept apart, so that a writer that fails with `ErrorKind::InvalidData`, say, can't be mistaken
+/// for a corrupt encoding. It converts to `io::Error`, with `Verify` converted from `Error` as
+/// usual and `Io` passed through as-is.
+#[derive(Debug)]
+pub enum DecodeToWriterError {
+    /// The encoding failed to verify. Only content before the failure was written.
+    Verify(Error),
+    /// Writing to or flushing the output failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for DecodeToWriterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeToWriterError::Verify(e) => write!(f, "{}", e),
+            DecodeToWriterError::Io(e) => write!(f, "output error: {}", e),
+        }
+    }
+}
+
+impl error::Error for DecodeToWriterError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DecodeToWriterError::Verify(e) => Some(e),
+            DecodeToWriterError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<Error> for DecodeToWriterError {
+    fn from(e: Error) -> DecodeToWriterError {
+        DecodeToWriterError::Verify(e)
+    }
+}
+
+impl From<DecodeToWriterError> for io::Error {
+    fn from(e: DecodeToWriterError) -> io::Error {
+        match e {
+            DecodeToWriterError::Verify(e) => e.into(),
+            DecodeToWriterError::Io(e) => e,
+        }
+    }
+}
+
+/// The trusted metadata needed to decode an encoding: its root hash and its content length. This
+/// is for formats that keep the metadata in a separate sidecar record, rather than alongside the
+/// hash in application code. `to_tlv` and `from_tlv` convert it to and from a compact, versioned
+/// type-length-value encoding, and `Decoder::from_metadata` decodes with it.
+///
+/// Each TLV record is a one-byte type, a one-byte length, and then that many value bytes. The
+/// first record must be the version (type 0, value 1). The record types are:
+///
+/// - `0x00`: the version, 1 byte, currently 1.
+/// - `0x01`: the root hash, 32 bytes. Required.
+/// - `0x02`: the content length, 8 bytes little-endian. Required.
+/// - `0x03`: the chunk size, 8 bytes little-endian. Optional, but if present it must be 1024.
+///
+/// Types `0x80` and above are optional extensions, and `from_tlv` skips any it doesn't know.
+/// Unknown types below `0x80` are required fields from some later version, and they're rejected.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = b"some input";
+/// let (encoded, hash) = bao::encode::encode(input);
+/// let metadata = bao::decode::Metadata {
+///     hash,
+///     content_len: input.len() as u64,
+/// };
+/// let tlv = metadata.to_tlv();
+///
+/// let parsed = bao::decode::Metadata::from_tlv(&tlv)?;
+/// let mut output = Vec::new();
+/// bao::decode::Decoder::from_metadata(&*encoded, &parsed).read_to_end(&mut output)?;
+/// assert_eq!(input, &*output);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub hash: Hash,
+    pub content_len: u64,
+}
+
+const METADATA_VERSION: u8 = 1;
+const TLV_VERSION: u8 = 0x00;
+const TLV_HASH: u8 = 0x01;
+const TLV_CONTENT_LEN: u8 = 0x02;
+const TLV_CHUNK_SIZE: u8 = 0x03;
+const TLV_OPTIONAL: u8 = 0x80;
+
+impl Metadata {
+    /// Encode the metadata as TLV records, starting with the version.
+    pub fn to_tlv(&self) -> Vec<u8> {
+        let mut tlv = Vec::with_capacity(3 + 2 + HASH_SIZE + 2 * (2 + 8));
+        tlv.extend_from_slice(&[TLV_VERSION, 1, METADATA_VERSION]);
+        tlv.extend_from_slice(&[TLV_HASH, HASH_SIZE as u8]);
+        tlv.extend_from_slice(self.hash.as_bytes());
+        tlv.extend_from_slice(&[TLV_CONTENT_LEN, 8]);
+        tlv.extend_from_slice(&self.content_len.to_le_bytes());
+        tlv.extend_from_slice(&[TLV_CHUNK_SIZE, 8]);
+        tlv.extend_from_slice(&(CHUNK_SIZE as u64).to_le_bytes());
+        tlv
+    }
+
+    /// Parse metadata from TLV records. The version must come first, the hash and the content
+    /// length must each appear exactly once, and no record may run past the end of `tlv`.
+    pub fn from_tlv(tlv: &[u8]) -> Result<Metadata, MetadataError> {
+        let mut records = Vec::new();
+        let mut rest = tlv;
+        while !rest.is_empty() {
+            if rest.len() < 2 || rest.len() - 2 < rest[1] as usize {
+                return Err(MetadataError::Malformed);
+            }
+            let (record_type, len) = (rest[0], rest[1] as usize);
+            records.push((record_type, &rest[2..2 + len]));
+            rest = &rest[2 + len..];
+        }
+        match records.first() {
+            Some(&(TLV_VERSION, &[METADATA_VERSION])) => {}
+            Some(&(TLV_VERSION, &[version])) => {
+                return Err(MetadataError::UnsupportedVersion(version))
+            }
+            _ => return Err(MetadataError::Malformed),
+        }
+        let mut hash = None;
+        let mut content_len = None;
+        let mut chunk_size = None;
+        for &(record_type, value) in &records[1..] {
+            let (field, width) = match record_type {
+                TLV_HASH => (&mut hash, HASH_SIZE),
+                TLV_CONTENT_LEN => (&mut content_len, 8),
+                TLV_CHUNK_SIZE => (&mut chunk_size, 8),
+                t if t >= TLV_OPTIONAL => continue,
+                t => return Err(MetadataError::UnknownRequiredField(t)),
+            };
+            if field.is_some() || value.len() != width {
+                return Err(MetadataError::Malformed);
+            }
+            *field = Some(value);
+        }
+        if let Some(chunk_size) = chunk_size {
+            if u64::from_le_bytes(*array_ref!(chunk_size, 0, 8)) != CHUNK_SIZE as u64 {
+                return Err(MetadataError::Malformed);
+            }
+        }
+        let hash = hash.ok_or(MetadataError::MissingField(TLV_HASH))?;
+        let content_len = content_len.ok_or(MetadataError::MissingField(TLV_CONTENT_LEN))?;
+        Ok(Metadata {
+            hash: (*array_ref!(hash, 0, HASH_SIZE)).into(),
+            content_len: u64::from_le_bytes(*array_ref!(content_len, 0, 8)),
+        })
+    }
+}
+
+/// Errors from `Metadata::from_tlv`. These are about the sidecar record rather than the encoding,
+/// so they're kept apart from `Error`. They convert to `ErrorKind::InvalidData`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataError {
+    /// A record runs past the end of the input, a field has the wrong length or appears twice,
+    /// the version record isn't first, or the chunk size isn't 1024.
+    Malformed,
+    /// The version record holds a version this crate doesn't know.
+    UnsupportedVersion(u8),
+    /// A record type below `0x80` that this crate doesn't know.
+    UnknownRequiredField(u8),
+    /// The record type of a required field that's missing.
+    MissingField(u8),
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MetadataError::Malformed => write!(f, "malformed metadata"),
+            MetadataError::UnsupportedVersion(v) => {
+                write!(f, "unsupported metadata version {}", v)
+            }
+            MetadataError::UnknownRequiredField(t) => {
+                write!(f, "unknown required metadata field {:#04x}", t)
+            }
+            MetadataError::MissingField(t) => write!(f, "missing metadata field {:#04x}", t),
+        }
+    }
+}
+
+impl error::Error for MetadataError {}
+
+impl From<MetadataError> for io::Error {
+    fn from(e: MetadataError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// A reader wrapper for sources that sometimes return `Ok(0)` before more data is available, like
+/// some wrappers around non-blocking sockets. The `Read` contract says that `Ok(0)` for a nonempty
+/// buffer means EOF, and `Decoder` and `SliceDecoder` follow it: an EOF before the end of the
+/// encoding is an `ErrorKind::UnexpectedEof` error. Wrapping a quirky source in `RetryZeroReads`
+/// retries up to `max_retries` zero-length reads in a row before passing one through as EOF.
+///
+/// Don't wrap sources that follow the contract, like files, pipes, and terminals. Every real EOF
+/// gets read again `max_retries` more times, and on a terminal that means waiting for the user to
+/// end the input again.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use bao::decode::{Decoder, RetryZeroReads};
+/// use std::io::prelude::*;
+///
+/// let (encoded, hash) = bao::encode::encode(b"some input");
+/// // Some source that might return Ok(0) once in a while.
+/// let source = std::io::Cursor::new(encoded);
+/// let mut decoder = Decoder::new(RetryZeroReads::new(source, 1), &hash);
+/// let mut output = Vec::new();
+/// decoder.read_to_end(&mut output)?;
+/// assert_eq!(b"some input", &*output);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RetryZeroReads<R> {
+    inner: R,
+    max_retries: usize,
+}
+
+impl<R: Read> RetryZeroReads<R> {
+    pub fn new(inner: R, max_retries: usize) -> Self {
+        Self { inner, max_retries }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for RetryZeroReads<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Ok(0) for an empty buffer doesn't mean anything.
+        if buf.is_empty() {
+            return self.inner.read(buf);
+        }
+        let mut retries = 0;
+        loop {
+            let n = self.inner.read(buf)?;
+            if n > 0 || retries == self.max_retries {
+                return Ok(n);
+            }
+            retries += 1;
+        }
+    }
+}
+
+// Shared between Decoder and SliceDecoder.
+#[derive(Clone)]
+struct DecoderShared<T: Read, O: Read> {
+    input: T,
+    outboard: Option<O>,
+    state: VerifyState,
+    buf: [u8; CHUNK_SIZE],
+    buf_start: usize,
+    buf_end: usize,
+    // If the caller supplied the content length, the encoding has no header, and underlying seek
+    // offsets need to be shifted back by HEADER_SIZE.
+    headerless: bool,
+    // Sorted, disjoint, half-open content ranges of every chunk verified so far.
+    verified: Vec<(u64, u64)>,
+    parent_cache: Option<Arc<ParentCache>>,
+    stats: DecoderStats,
+    // Set when the caller knows the content length in advance but the encoding still has a
+    // header, as with subtree slices. A header that disagrees is rejected.
+    expected_len: Option<u64>,
+}
+
+impl<T: Read, O: Read> DecoderShared<T, O> {
+    fn new(input: T, outboard: Option<O>, hash: &Hash) -> Self {
+        Self {
+            input,
+            outboard,
+            state: VerifyState::new(hash),
+            buf: [0; CHUNK_SIZE],
+            buf_start: 0,
+            buf_end: 0,
+            headerless: false,
+            verified: Vec::new(),
+            parent_cache: None,
+            stats: DecoderStats::default(),
+            expected_len: None,
+        }
+    }
+
+    fn new_headerless(input: T, outboard: Option<O>, hash: &Hash, content_len: u64) -> Self {
+        let mut shared = Self::new(input, outboard, hash);
+        // This length is no more trusted than one read from a header would be. It still has to
+        // pass the final chunk requirement before it's exposed to the caller.
+        shared
+            .state
+            .feed_header(&crate::hash::encode_len(content_len));
+        shared.headerless = true;
+        shared
+    }
+
+    fn adjusted_content_position(&self) -> u64 {
+        // If the current buffer_len is non-empty, then it contains the bytes
+        // immediately prior to the next read.
+        self.state.content_position() - self.buf_len() as u64
+    }
+
+    fn buf_len(&self) -> usize {
+        self.buf_end - self.buf_start
+    }
+
+    // Where the inner readers are in the combined layout, not counting a header that a headerless
+    // encoding doesn't have.
+    fn encoded_position(&self) -> u64 {
+        let position = self.state.parser.encoding_position();
+        let skipped_header = if self.headerless { HEADER_SIZE } else { 0 };
+        position.saturating_sub(skipped_header as u128) as u64
+    }
+
+    fn clear_buf(&mut self) {
+        self.buf_start = 0;
+        self.buf_end = 0;
+    }
+
+    fn record_verified_chunk(&mut self, index: u64, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let start = index * CHUNK_SIZE as u64;
+        merge_range(&mut self.verified, start, start + size as u64);
+    }
+
+    // These bytes are always verified before going in the buffer.
+    fn take_buffered_bytes(&mut self, output: &mut [u8]) -> usize {
+        let take = cmp::min(self.buf_len(), output.len());
+        output[..take].copy_from_slice(&self.buf[self.buf_start..self.buf_start + take]);
+        self.buf_start += take;
+        take
+    }
+
+    fn get_and_feed_header(&mut self) -> io::Result<()> {
+        debug_assert_eq!(0, self.buf_len());
+        let mut header = [0; HEADER_SIZE];
+        if let Some(outboard) = &mut self.outboard {
+            outboard.read_exact(&mut header)?;
+        } else {
+            self.input.read_exact(&mut header)?;
+        }
+        self.stats.encoded_bytes_read += HEADER_SIZE as u64;
+        if let Some(expected_len) = self.expected_len {
+            if crate::hash::decode_len(&header) != expected_len {
+                return Err(Error::HashMismatch.into());
+            }
+        }
+        self.state.feed_header(&header);
+        Ok(())
+    }
+
+    fn get_parent(&mut self) -> io::Result<crate::ParentNode> {
+        debug_assert_eq!(0, self.buf_len());
+        let mut parent = [0; PARENT_SIZE];
+        if let Some(outboard) = &mut self.outboard {
+            outboard.read_exact(&mut parent)?;
+        } else {
+            self.input.read_exact(&mut parent)?;
+        }
+        self.stats.encoded_bytes_read += PARENT_SIZE as u64;
+        Ok(parent)
+    }
+
+    // Verification failures from the VerifyState are reported with the content offset where the
+    // failing node starts. The parser hasn't advanced past it yet.
+    fn feed_parent(&mut self, parent: &crate::ParentNode) -> io::Result<()> {
+        let content_offset = self.state.content_position();
+        self.state
+            .feed_parent(parent)
+            .map_err(|_| HashMismatchAt { content_offset }.into())
+    }
+
+    fn feed_chunk(&mut self, chunk_hash: &Hash) -> io::Result<()> {
+        let content_offset = self.state.content_position();
+        self.state
+            .feed_chunk(chunk_hash)
+            .map_err(|_| HashMismatchAt { content_offset }.into())
+    }
+
+    fn get_and_feed_parent(&mut self) -> io::Result<()> {
+        let parent = self.get_parent()?;
+        self.feed_parent(&parent)?;
+        self.stats.parents_verified += 1;
+        Ok(())
+    }
+
+    fn buffer_verified_chunk(
+        &mut self,
+        size: usize,
+        finalization: Finalization,
+        skip: usize,
+        index: u64,
+        parents_to_read: usize,
+    ) -> io::Result<()> {
+        debug_assert_eq!(0, self.buf_len());
+        self.buf_start = 0;
+        self.buf_end = 0;
+        for _ in 0..parents_to_read {
+            // Making a separate read call for each parent isn't ideal, but
+            // this is the slow path anyway. The fast path's read ahead
+            // approach optimizes parent reads better.
+            self.get_and_feed_parent()?;
+        }
+        let buf_slice = &mut self.buf[..size];
+        self.input.read_exact(buf_slice)?;
+        self.stats.encoded_bytes_read += size as u64;
+        let hash = blake3::guts::ChunkState::new(index)
+            .update(buf_slice)
+            .finalize(finalization.is_root());
+        self.feed_chunk(&hash)?;
+        self.stats.chunks_verified += 1;
+        self.record_verified_chunk(index, size);
+        self.buf_start = skip;
+        self.buf_end = size;
+        Ok(())
+    }
+
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        // Explicitly short-circuit zero-length reads. We're within our rights
+        // to buffer an internal chunk in this case, or to make progress if
+        // there's an empty chunk, but this matches the current behavior of
+        // SliceExtractor for zero-length slices. This might change in the
+        // future.
+        if output.is_empty() {
+            return Ok(0);
+        }
+
+        // If there are bytes in the internal buffer, just return those.
+        if self.buf_len() > 0 {
+            return Ok(self.take_buffered_bytes(output));
+        }
+
+        // Otherwise try to verify a new chunk.
+        loop {
+            match self.state.read_next() {
+                NextRead::Done => {
+                    // This is EOF. We know the internal buffer is empty,
+                    // because we checked it before this loop.
+                    return Ok(0);
+                }
+                NextRead::Header => {
+                    self.get_and_feed_header()?;
+                }
+                NextRead::Parent => {
+                    self.get_and_feed_parent()?;
+                }
+                NextRead::Chunk {
+                    size,
+                    finalization,
+                    skip,
+                    index,
+                } => {
+                    debug_assert_eq!(self.buf_len(), 0);
+
+                    // If we can, read the chunk directly into the `output`
+                    // buffer, to avoid extra copies. If there's a verification
+                    // error, the caller won't read the invalid bytes, because
+                    // we won't return a length.
+                    let (read_buf, direct_output) = if output.len() >= size && skip == 0 {
+                        (&mut output[..size], true)
+                    } else {
+                        (&mut self.buf[..size], false)
+                    };
+
+                    // Read the unverified chunk.
+                    self.input.read_exact(read_buf)?;
+                    self.stats.encoded_bytes_read += size as u64;
+
+                    // Hash it and push its hash into the VerifyState. This
+                    // returns an error if the hash is bad. Otherwise, the
+                    // chunk is verifiied.
+                    let chunk_hash = blake3::guts::ChunkState::new(index)
+                        .update(read_buf)
+                        .finalize(finalization.is_root());
+                    self.feed_chunk(&chunk_hash)?;
+                    self.stats.chunks_verified += 1;
+                    self.record_verified_chunk(index, size);
+
+                    // If the output buffer was large enough for direct output,
+                    // we're done. Otherwise, we need to update the internal
+                    // buffer state and return some bytes.
+                    if direct_output {
+                        return Ok(size);
+                    } else {
+                        self.buf_start = skip;
+                        self.buf_end = size;
+                        return Ok(self.take_buffered_bytes(output));
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns Ok(true) to indicate the seek is finished. Note that both the
+    // Decoder and the SliceDecoder will use this method (which doesn't depend on
+    // io::Seek), but only the Decoder will call handle_seek_bookkeeping first.
+    // This may read a chunk, but it never leaves output bytes in the buffer,
+    // because the only time seeking reads a chunk it also skips the entire
+    // thing.
+    fn handle_seek_read(&mut self, next: NextRead) -> io::Result<bool> {
+        debug_assert_eq!(0, self.buf_len());
+        match next {
+            NextRead::Header => self.get_and_feed_header()?,
+            NextRead::Parent => self.get_and_feed_parent()?,
+            NextRead::Chunk {
+                size,
+                finalization,
+                skip,
+                index,
+            } => {
+                self.buffer_verified_chunk(
+                    size,
+                    finalization,
+                    skip,
+                    index,
+                    0, /* parents_to_read */
+                )?;
+                debug_assert_eq!(0, self.buf_len());
+            }
+            NextRead::Done => return Ok(true), // The seek is done.
+        }
+        Ok(false)
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek> DecoderShared<T, O> {
+    // The Decoder will call this as part of seeking, but note that the
+    // SliceDecoder won't, because all the seek bookkeeping has already been
+    // taken care of during slice extraction.
+    fn handle_seek_bookkeeping(
+        &mut self,
+        bookkeeping: encode::SeekBookkeeping,
+    ) -> io::Result<NextRead> {
+        // The VerifyState handles all the subtree stack management. We just
+        // need to handle the underlying seek. This is done differently
+        // depending on whether the encoding is combined or outboard.
+        let skipped_header = if self.headerless {
+            HEADER_SIZE as u64
+        } else {
+            0
+        };
+        if let Some(outboard) = &mut self.outboard {
+            if let Some((content_pos, outboard_pos)) = bookkeeping.underlying_seek_outboard() {
+                // As with Decoder in the outboard case, the outboard extractor has to seek both of
+                // its inner readers. The content position of the state goes into the content
+                // reader, and the rest of the reported seek offset goes into the outboard reader.
+                self.input.seek(SeekFrom::Start(content_pos))?;
+                outboard.seek(SeekFrom::Start(outboard_pos - skipped_header))?;
+            }
+        } else {
+            if let Some(encoding_position) = bookkeeping.underlying_seek() {
+                let position_u64: u64 = encode::cast_offset(encoding_position)? - skipped_header;
+                self.input.seek(SeekFrom::Start(position_u64))?;
+            }
+        }
+        let next = self.state.seek_bookkeeping_done(bookkeeping);
+        Ok(next)
+    }
+
+    // Like handle_seek_read, but with a parent cache, parent nodes come from the cache when they
+    // can. A cached parent is still verified like any other, and the underlying reader seeks past
+    // it instead of reading it. Parents that do get read are cached once they're verified.
+    fn handle_seek_read_cached(&mut self, next: NextRead) -> io::Result<bool> {
+        let cache = match (&self.parent_cache, &next) {
+            (Some(cache), NextRead::Parent) => Arc::clone(cache),
+            _ => return self.handle_seek_read(next),
+        };
+        let key = match encode::cast_offset(self.state.parser.encoding_position()) {
+            Ok(offset) => (self.state.root_hash, offset),
+            Err(_) => return self.handle_seek_read(next),
+        };
+        if let Some(parent) = cache.get(&key) {
+            self.feed_parent(&parent)?;
+            self.stats.parents_verified += 1;
+            let tree: &mut dyn Seek = match &mut self.outboard {
+                Some(outboard) => outboard,
+                None => &mut self.input,
+            };
+            tree.seek(SeekFrom::Current(PARENT_SIZE as i64))?;
+        } else {
+            let parent = self.get_parent()?;
+            self.feed_parent(&parent)?;
+            self.stats.parents_verified += 1;
+            cache.insert(key, parent);
+        }
+        Ok(false)
+    }
+}
+
+impl<T: Read, O: Read> fmt::Debug for DecoderShared<T, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DecoderShared {{ is_outboard: {}, state: {:?}, buf_start: {}, buf_end: {} }}",
+            self.outboard.is_some(),
+            self.state,
+            self.buf_start,
+            self.buf_end,
+        )
+    }
+}
+
+/// An incremental decoder, which reads and verifies the output of
+/// [`Encoder`](../encode/struct.Encoder.html).
+///
+/// `Decoder` supports both the combined and outboard encoding format,
+/// depending on which constructor you use.
+///
+/// `Decoder` supports
+/// [`std::io::Seek`](https://doc.rust-lang.org/std/io/trait.Seek.html) if the
+/// underlying reader does, but it's also compatible with non-seekable readers.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// // Create both combined and outboard encodings.
+/// let input = b"some input";
+/// let (encoded, hash) = bao::encode::encode(input);
+/// let (outboard, _) = bao::encode::outboard(input);
+///
+/// // Decode the combined mode.
+/// let mut combined_output = Vec::new();
+/// let mut decoder = bao::decode::Decoder::new(&*encoded, &hash);
+/// decoder.read_to_end(&mut combined_output)?;
+///
+/// // Decode the outboard mode.
+/// let mut outboard_output = Vec::new();
+/// let mut decoder = bao::decode::Decoder::new_outboard(&input[..], &*outboard, &hash);
+/// decoder.read_to_end(&mut outboard_output)?;
+///
+/// assert_eq!(input, &*combined_output);
+/// assert_eq!(input, &*outboard_output);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Because the decoder only ever returns verified bytes, it can be wrapped in
+/// another reader, like a decompressor, without that reader ever seeing
+/// unverified input:
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let mut compressor = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+/// compressor.write_all(b"some input")?;
+/// let (encoded, hash) = bao::encode::encode(compressor.finish()?);
+///
+/// let decoder = bao::decode::Decoder::new(&*encoded, &hash);
+/// let mut output = Vec::new();
+/// flate2::read::GzDecoder::new(decoder).read_to_end(&mut output)?;
+/// assert_eq!(b"some input", &*output);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Decoder<T: Read, O: Read> {
+    shared: DecoderShared<T, O>,
+}
+
+impl<T: Read, O: Read> fmt::Debug for Decoder<T, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing buffered content or hashes. DecoderShared only shows the shape of the
+        // traversal.
+        write!(
+            f,
+            "Decoder {{ content_position: {}, shared: {:?} }}",
+            self.shared.adjusted_content_position(),
+            self.shared,
+        )
+    }
+}
+
+impl<T: Read> Decoder<T, T> {
+    pub fn new(inner: T, hash: &Hash) -> Self {
+        Self {
+            shared: DecoderShared::new(inner, None, hash),
+        }
+    }
+
+    /// Create a new `Decoder` for a combined encoding with its 8-byte length header stripped off,
+    /// taking the content length from the caller instead. This is for storage systems that keep
+    /// the length somewhere else. The length gets verified like a header would be, so a wrong
+    /// length leads to a decoding error, not to wrong output. This corresponds to `bao decode
+    /// --content-len`.
+    pub fn new_with_content_len(inner: T, hash: &Hash, content_len: u64) -> Self {
+        Self {
+            shared: DecoderShared::new_headerless(inner, None, hash, content_len),
+        }
+    }
+
+    /// Create a new `Decoder`, reading the expected hash from the file at `hash_path` with
+    /// `read_hash_file`. This is for formats that keep the root hash in trusted metadata, separate
+    /// from the encoding.
+    pub fn from_hash_file(inner: T, hash_path: impl AsRef<Path>) -> io::Result<Self> {
+        let hash = read_hash_file(hash_path)?;
+        Ok(Self::new(inner, &hash))
+    }
+
+    /// Create a new `Decoder` from parsed `Metadata`. On top of checking the hash, this requires
+    /// the length header of the encoding to match `metadata.content_len`, and anything else is a
+    /// hash mismatch.
+    pub fn from_metadata(inner: T, metadata: &Metadata) -> Self {
+        let mut decoder = Self::new(inner, &metadata.hash);
+        decoder.shared.expected_len = Some(metadata.content_len);
+        decoder
+    }
+}
+
+/// Read a root hash from the file at `path`, for formats that keep it in trusted metadata,
+/// separate from the encoding. The file must hold either the 32 raw bytes of the hash, or its 64
+/// hex digits, optionally surrounded by whitespace like a trailing newline. Anything else is an
+/// `InvalidData` error. `Decoder::from_hash_file` uses this.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let dir = tempfile::tempdir()?;
+/// let path = dir.path().join("hash");
+/// let hash = blake3::hash(b"some input");
+/// std::fs::write(&path, format!("{}\n", hash.to_hex()))?;
+/// assert_eq!(hash, bao::decode::read_hash_file(&path)?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn read_hash_file(path: impl AsRef<Path>) -> io::Result<Hash> {
+    let contents = std::fs::read(path)?;
+    if contents.len() == HASH_SIZE {
+        return Ok((*array_ref!(contents, 0, HASH_SIZE)).into());
+    }
+    let hex = String::from_utf8_lossy(&contents);
+    let hex = hex.trim();
+    if hex.len() != 2 * HASH_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "wrong length hash",
+        ));
+    }
+    let mut bytes = [0; HASH_SIZE];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = hex
+            .get(2 * i..2 * i + 2)
+            .filter(|digits| digits.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid hex"))?;
+    }
+    Ok(bytes.into())
+}
+
+impl<T: Read + Seek> Decoder<T, T> {
+    /// Verify the entire encoding before returning a `Decoder` for it. A regular `Decoder` returns
+    /// each chunk as soon as it's verified, so a caller can read some valid content before it hits
+    /// an error further along. This makes an extra pass over `inner` to rule that out: if any part
+    /// of the encoding is invalid, it returns the error up front, and no content is emitted at
+    /// all. The returned `Decoder` starts from the current position of `inner`, just like `new`.
+    ///
+    /// Note that this only holds if the encoding doesn't change between the two passes. The
+    /// second pass still verifies everything it reads.
+    pub fn verify_all_first(mut inner: T, hash: &Hash) -> io::Result<Self> {
+        let start = inner.stream_position()?;
+        io::copy(&mut Decoder::new(&mut inner, hash), &mut io::sink())?;
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(Self::new(inner, hash))
+    }
+}
+
+impl<T: Read, O: Read> Decoder<T, O> {
+    pub fn new_outboard(inner: T, outboard: O, hash: &Hash) -> Self {
+        Self {
+            shared: DecoderShared::new(inner, Some(outboard), hash),
+        }
+    }
+
+    /// Like `new_with_content_len`, but for an outboard encoding with its header stripped off.
+    pub fn new_outboard_with_content_len(
+        inner: T,
+        outboard: O,
+        hash: &Hash,
+        content_len: u64,
+    ) -> Self {
+        Self {
+            shared: DecoderShared::new_headerless(inner, Some(outboard), hash, content_len),
+        }
+    }
+}
+
+impl<T: Read, O: Read> Decoder<T, O> {
+    /// The content ranges that this decoder has verified so far, as sorted, non-overlapping
+    /// `(start, end)` pairs, with `end` exclusive. Adjacent ranges are merged.
+    ///
+    /// Verification happens a whole chunk at a time, so these ranges can include bytes that were
+    /// skipped by a seek within a chunk, or that are still buffered. Regions that a seek jumped
+    /// over entirely are never verified, and they don't appear here.
+    pub fn verified_ranges(&self) -> &[(u64, u64)] {
+        &self.shared.verified
+    }
+
+    /// Save this decoder's progress, so that a later decoder, maybe in another process, can pick
+    /// up where it left off with `restore`. See `Checkpoint`.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint::new(
+            self.shared.adjusted_content_position(),
+            &self.shared.state.root_hash,
+        )
+    }
+
+    /// Counters of the work this decoder has done so far. See `DecoderStats`.
+    pub fn stats(&self) -> DecoderStats {
+        self.shared.stats
+    }
+
+    /// How far the decoder has gotten through the encoding, in encoded bytes. Compared to the
+    /// size of the whole encoding, this gives an accurate percentage for a progress bar, even
+    /// though the encoding is larger than the content and its parent nodes aren't spread evenly.
+    ///
+    /// This is the position of the next read from the inner reader. The decoder reads a whole
+    /// chunk at a time, so it can run ahead of the content returned so far by up to a chunk. In
+    /// outboard mode, it counts both content and outboard bytes, and the total to compare it to is
+    /// the content length plus the outboard size. After a seek, it's the position the seek moved
+    /// to, not a count of everything read, which is `DecoderStats::encoded_bytes_read`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::prelude::*;
+    ///
+    /// let input = vec![0; 1_000_000];
+    /// let (encoded, hash) = bao::encode::encode(&input);
+    /// let mut decoder = bao::decode::Decoder::new(&*encoded, &hash);
+    /// assert_eq!(0, decoder.encoded_position());
+    /// decoder.read_to_end(&mut Vec::new())?;
+    /// assert_eq!(encoded.len() as u64, decoder.encoded_position());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn encoded_position(&self) -> u64 {
+        self.shared.encoded_position()
+    }
+}
+
+/// Counters of the work a `Decoder` has done, from `Decoder::stats`. These are for diagnosing slow
+/// decodes, for example a random access pattern that seeks more than it reads, and for feeding
+/// metrics.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = vec![0xab; 10_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let mut decoder = bao::decode::Decoder::new(&*encoded, &hash);
+/// decoder.read_to_end(&mut Vec::new())?;
+/// let stats = decoder.stats();
+/// assert_eq!(encoded.len() as u64, stats.encoded_bytes_read);
+/// assert_eq!(10, stats.chunks_verified);
+/// assert_eq!(9, stats.parents_verified);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecoderStats {
+    /// Bytes read from the underlying readers, including the header and parent nodes. Parent nodes
+    /// taken from a `ParentCache` aren't read, so they don't count here.
+    pub encoded_bytes_read: u64,
+    /// Verified content bytes returned to the caller.
+    pub content_bytes_returned: u64,
+    /// Parent nodes verified, including ones taken from a `ParentCache`.
+    pub parents_verified: u64,
+    /// Chunks verified, including ones that a seek read through without returning them.
+    pub chunks_verified: u64,
+    /// Calls to `seek`, including the ones made by `content_len` and `restore`.
+    pub seeks: u64,
+}
+
+impl<T: Read + Seek, O: Read + Seek> Decoder<T, O> {
+    /// Share a `ParentCache` with other decoders. From then on, seeking takes the parent nodes on
+    /// the path from the root from the cache when it can, and adds the ones it has to read.
+    pub fn set_parent_cache(&mut self, cache: Arc<ParentCache>) {
+        self.shared.parent_cache = Some(cache);
+    }
+
+    /// The verified content length. A length read from the header isn't trusted until the final
+    /// chunk has been verified, so unless the decoder has already read that far, this seeks to
+    /// the end to verify it, and then seeks back. The length is kept after that, so later calls
+    /// are free.
+    pub fn content_len(&mut self) -> io::Result<u64> {
+        if let encode::LenNext::Len(len) = self.shared.state.len_next() {
+            return Ok(len);
+        }
+        let position = self.shared.adjusted_content_position();
+        let len = self.verify_content_len()?;
+        self.seek(SeekFrom::Start(position))?;
+        Ok(len)
+    }
+
+    /// The size of the combined encoding, from `encode::encoded_size` of the verified content
+    /// length. This is useful for things like progress bars. Note that for an outboard decoder
+    /// this is still the combined size, and `encode::outboard_size` gives the outboard size. See
+    /// `content_len` for what this costs.
+    pub fn encoded_len(&mut self) -> io::Result<u64> {
+        let content_len = self.content_len()?;
+        encode::cast_offset(encode::encoded_size(content_len))
+    }
+
+    // This may require a seek loop of its own, and it leaves the decoder at the end of the
+    // content.
+    fn verify_content_len(&mut self) -> io::Result<u64> {
+        self.shared.clear_buf();
+        loop {
+            match self.shared.state.len_next() {
+                encode::LenNext::Seek(bookkeeping) => {
+                    let next_read = self.shared.handle_seek_bookkeeping(bookkeeping)?;
+                    let done = self.shared.handle_seek_read_cached(next_read)?;
+                    debug_assert!(!done);
+                }
+                encode::LenNext::Len(len) => return Ok(len),
+            }
+        }
+    }
+
+    /// Continue decoding from a `Checkpoint`. This returns an `ErrorKind::InvalidData` error if the
+    /// checkpoint was taken with a different hash. Otherwise it seeks to the checkpoint's content
+    /// position, just like any other seek. The parent nodes from the root down to that position
+    /// are verified before any content is returned.
+    pub fn restore(&mut self, checkpoint: &Checkpoint) -> io::Result<()> {
+        if checkpoint != &Checkpoint::new(checkpoint.content_position, &self.shared.state.root_hash)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checkpoint doesn't match hash",
+            ));
+        }
+        self.seek(SeekFrom::Start(checkpoint.content_position))?;
+        Ok(())
+    }
+}
+
+/// A cache of verified parent nodes, shared between seekable `Decoder`s with
+/// `Decoder::set_parent_cache`. A server answering many requests for different parts of the same
+/// large encoding reads the same parent nodes near the root every time it seeks. With a shared
+/// cache, each of those is read from the underlying reader once.
+///
+/// Entries are keyed by the root hash and the node's offset in the combined encoding, so
+/// decoders for different encodings can share a cache without colliding, and combined and
+/// outboard decoders for the same content share entries. Only nodes that verified go in the
+/// cache, and nodes that come out of it get verified again anyway, which is cheap next to a read.
+///
+/// The cache only holds nodes read while seeking, which are most of the ones on the paths to the
+/// seek targets. It has no size limit. Use `clear` to empty it.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+/// use std::io::{Cursor, SeekFrom};
+/// use std::sync::Arc;
+///
+/// let input = vec![0xab; 1_000_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let cache = Arc::new(bao::decode::ParentCache::new());
+/// for &offset in &[100_000, 200_000] {
+///     let mut decoder = bao::decode::Decoder::new(Cursor::new(&encoded), &hash);
+///     decoder.set_parent_cache(Arc::clone(&cache));
+///     decoder.seek(SeekFrom::Start(offset))?;
+///     let mut buf = [0; 1000];
+///     decoder.read_exact(&mut buf)?;
+/// }
+/// assert!(!cache.is_empty());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ParentCache {
+    nodes: Mutex<HashMap<(Hash, u64), ParentNode>>,
+}
+
+impl ParentCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of cached parent nodes.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove every cached node.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn get(&self, key: &(Hash, u64)) -> Option<ParentNode> {
+        self.lock().get(key).copied()
+    }
+
+    fn insert(&self, key: (Hash, u64), parent: ParentNode) {
+        self.lock().insert(key, parent);
+    }
+
+    // A panic while holding the lock can't leave the map half-updated, so poisoning is ignored.
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(Hash, u64), ParentNode>> {
+        self.nodes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for ParentCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes, they might be secret.
+        write!(f, "ParentCache {{ len: {} }}", self.len())
+    }
+}
+
+/// The saved progress of a `Decoder`, from `Decoder::checkpoint`. A download manager can persist
+/// this with `to_bytes`, and after a restart, reopen the encoding, create a new `Decoder` with the
+/// same hash, and call `Decoder::restore` to continue from the same content position.
+///
+/// A checkpoint doesn't carry any of the decoder's verified hashes. Anyone who knows the root hash
+/// could forge those, and a forged subtree hash would let bad content through. Instead, `restore`
+/// verifies its way back down from the root, which only costs reading the parent nodes along one
+/// path. The checkpoint does carry a keyed hash of its position under the root hash, so that a
+/// checkpoint from some other encoding is rejected rather than quietly used.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+/// use std::io::Cursor;
+///
+/// let input = vec![0xab; 10_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let mut decoder = bao::decode::Decoder::new(Cursor::new(&encoded), &hash);
+/// let mut first_part = [0; 4000];
+/// decoder.read_exact(&mut first_part)?;
+/// let saved = decoder.checkpoint().to_bytes();
+///
+/// // Later...
+/// let checkpoint = bao::decode::Checkpoint::from_bytes(&saved)?;
+/// let mut decoder = bao::decode::Decoder::new(Cursor::new(&encoded), &hash);
+/// decoder.restore(&checkpoint)?;
+/// let mut rest = Vec::new();
+/// decoder.read_to_end(&mut rest)?;
+/// assert_eq!(&input[4000..], &*rest);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    content_position: u64,
+    tag: [u8; HASH_SIZE],
+}
+
+/// The size of a serialized `Checkpoint`: the 8-byte little-endian content position, followed by
+/// the 32-byte tag.
+pub const CHECKPOINT_SIZE: usize = HEADER_SIZE + HASH_SIZE;
+
+impl Checkpoint {
+    fn new(content_position: u64, hash: &Hash) -> Self {
+        let position_bytes = crate::hash::encode_len(content_position);
+        Self {
+            content_position,
+            tag: *blake3::keyed_hash(hash.as_bytes(), &position_bytes).as_bytes(),
+        }
+    }
+
+    /// The content position that decoding will continue from.
+    pub fn content_position(&self) -> u64 {
+        self.content_position
+    }
+
+    pub fn to_bytes(&self) -> [u8; CHECKPOINT_SIZE] {
+        let mut bytes = [0; CHECKPOINT_SIZE];
+        bytes[..HEADER_SIZE].copy_from_slice(&crate::hash::encode_len(self.content_position));
+        bytes[HEADER_SIZE..].copy_from_slice(&self.tag);
+        bytes
+    }
+
+    /// Parse a serialized checkpoint. Any `CHECKPOINT_SIZE` bytes parse, and anything else is an
+    /// `ErrorKind::InvalidData` error. Whether the checkpoint is any good is up to `restore`.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() != CHECKPOINT_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "wrong length checkpoint",
+            ));
+        }
+        Ok(Self {
+            content_position: crate::hash::decode_len(array_ref!(bytes, 0, HEADER_SIZE)),
+            tag: *array_ref!(bytes, HEADER_SIZE, HASH_SIZE),
+        })
+    }
+}
+
+impl<T: Read, O: Read> Read for Decoder<T, O> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        let n = self.shared.read(output)?;
+        self.shared.stats.content_bytes_returned += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek> Seek for Decoder<T, O> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.shared.stats.seeks += 1;
+
+        // Clear the internal buffer when seeking. The buffered bytes won't be
+        // valid reads at the new offset.
+        self.shared.clear_buf();
+
+        // Get the absolute seek offset. If the caller passed in
+        // SeekFrom::Start, that's what we've got. If not, we need to compute
+        // it.
+        let seek_to = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => {
+                // To seek from the end we have to get the length.
+                let content_len = self.verify_content_len()?;
+                add_offset(content_len, offset)?
+            }
+            SeekFrom::Current(offset) => {
+                add_offset(self.shared.adjusted_content_position(), offset)?
+            }
+        };
+
+        // Now with the absolute seek offset, we perform the real (possibly
+        // second) seek loop.
+        loop {
+            let bookkeeping = self.shared.state.seek_next(seek_to);
+            let next_read = self.shared.handle_seek_bookkeeping(bookkeeping)?;
+            let done = self.shared.handle_seek_read_cached(next_read)?;
+            if done {
+                return Ok(seek_to);
+            }
+        }
+    }
+}
+
+fn add_offset(position: u64, offset: i64) -> io::Result<u64> {
+    let sum = position as i128 + offset as i128;
+    if sum < 0 {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "seek before beginning",
+        ))
+    } else if sum > u64::max_value() as i128 {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "seek target overflowed u64",
+        ))
+    } else {
+        Ok(sum as u64)
+    }
+}
+
+/// An incremental slice decoder. This reads and verifies the output of the
+/// [`SliceExtractor`](../encode/struct.SliceExtractor.html).
+///
+/// Note that there is no such thing as an "outboard slice". All slices include
+/// the content bytes and tree nodes intermixed, as in the combined encoding
+/// mode.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// // Start by encoding some input.
+/// let input = vec![0; 1_000_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+///
+/// // Slice the encoding. These parameters are multiples of the chunk size, which avoids
+/// // unnecessary overhead.
+/// let slice_start = 65536;
+/// let slice_len = 8192;
+/// let encoded_cursor = std::io::Cursor::new(&encoded);
+/// let mut extractor = bao::encode::SliceExtractor::new(encoded_cursor, slice_start, slice_len);
+/// let mut slice = Vec::new();
+/// extractor.read_to_end(&mut slice)?;
+///
+/// // Decode the slice. The result should be the same as the part of the input that the slice
+/// // represents. Note that we're using the same hash that encoding produced, which is
+/// // independent of the slice parameters. That's the whole point; if we just wanted to re-encode
+/// // a portion of the input and wind up with a different hash, we wouldn't need slicing.
+/// let mut decoded = Vec::new();
+/// let mut decoder = bao::decode::SliceDecoder::new(&*slice, &hash, slice_start, slice_len);
+/// decoder.read_to_end(&mut decoded)?;
+/// assert_eq!(&input[slice_start as usize..][..slice_len as usize], &*decoded);
+///
+/// // Like regular decoding, slice decoding will fail if the hash doesn't match.
+/// let mut bad_slice = slice.clone();
+/// let last_index = bad_slice.len() - 1;
+/// bad_slice[last_index] ^= 1;
+/// let mut decoder = bao::decode::SliceDecoder::new(&*bad_slice, &hash, slice_start, slice_len);
+/// let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+/// assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+/// # Ok(())
+/// # }
+/// ```
+pub struct SliceDecoder<T: Read> {
+    shared: DecoderShared<T, T>,
+    slice_start: u64,
+    slice_remaining: u64,
+    // If the caller requested no bytes, the extractor is still required to
+    // include a chunk. We're not required to verify it, but we want to
+    // aggressively check for extractor bugs.
+    need_fake_read: bool,
+}
+
+impl<T: Read> SliceDecoder<T> {
+    pub fn new(inner: T, hash: &Hash, slice_start: u64, slice_len: u64) -> Self {
+        Self {
+            shared: DecoderShared::new(inner, None, hash),
+            slice_start,
+            slice_remaining: slice_len,
+            need_fake_read: slice_len == 0,
+        }
+    }
+
+    /// Like `new`, but the range is `chunk_count` chunks starting at chunk index `first_chunk`,
+    /// matching `SliceExtractor::by_chunks`.
+    pub fn by_chunks(inner: T, hash: &Hash, first_chunk: u64, chunk_count: u64) -> Self {
+        let (slice_start, slice_len) = encode::chunks_to_bytes(first_chunk, chunk_count);
+        Self::new(inner, hash, slice_start, slice_len)
+    }
+
+    /// Verify a slice against the hash of a subtree, rather than against the root hash of the
+    /// whole input.
+    ///
+    /// The slice is extracted from an encoding of the subtree's content, as usual. The difference
+    /// is that the top node of that tree is an interior node of some larger tree, so it's
+    /// finalized as `NotRoot`, and its hash is the one that
+    /// [`hash::hash_subtree`](../hash/fn.hash_subtree.html) returns. That hash doesn't commit to
+    /// the length the way a root hash does, so the caller supplies `subtree_content_len`, and a
+    /// slice whose header disagrees with it fails with `InvalidData`. Chunks are counted from
+    /// zero, so this covers subtrees that begin at the start of the larger input, like the left
+    /// children along its left edge.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::prelude::*;
+    ///
+    /// // The first 8 chunks of a 10-chunk input form the left subtree of its root.
+    /// let input = vec![0xab; 10 * 1024];
+    /// let subtree = &input[..8 * 1024];
+    /// let subtree_hash = bao::hash::hash_subtree(subtree);
+    ///
+    /// let (encoded, _) = bao::encode::encode(subtree);
+    /// let mut extractor = bao::encode::SliceExtractor::new(std::io::Cursor::new(&encoded), 2048, 1024);
+    /// let mut slice = Vec::new();
+    /// extractor.read_to_end(&mut slice)?;
+    ///
+    /// let mut decoder = bao::decode::SliceDecoder::new_for_subtree(
+    ///     &*slice,
+    ///     &subtree_hash,
+    ///     subtree.len() as u64,
+    ///     2048,
+    ///     1024,
+    /// );
+    /// let mut decoded = Vec::new();
+    /// decoder.read_to_end(&mut decoded)?;
+    /// assert_eq!(&subtree[2048..3072], &*decoded);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new_for_subtree(
+        inner: T,
+        subtree_hash: &Hash,
+        subtree_content_len: u64,
+        slice_start: u64,
+        slice_len: u64,
+    ) -> Self {
+        let mut decoder = Self::new(inner, subtree_hash, slice_start, slice_len);
+        decoder.shared.state = VerifyState::new_subtree(subtree_hash);
+        decoder.shared.expected_len = Some(subtree_content_len);
+        decoder
+    }
+}
+
+impl<T: Read> Read for SliceDecoder<T> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        // If we haven't done the initial seek yet, do the full seek loop
+        // first. Note that this will never leave any buffered output. The only
+        // scenario where handle_seek_read reads a chunk is if it needs to
+        // validate the final chunk, and then it skips the whole thing.
+        if self.shared.state.content_position() < self.slice_start {
+            loop {
+                let bookkeeping = self.shared.state.seek_next(self.slice_start);
+                // Note here, we skip to seek_bookkeeping_done without
+                // calling handle_seek_bookkeeping. That is, we never
+                // perform any underlying seeks. The slice extractor
+                // already took care of lining everything up for us.
+                let next = self.shared.state.seek_bookkeeping_done(bookkeeping);
+                let done = self.shared.handle_seek_read(next)?;
+                if done {
+                    break;
+                }
+            }
+            debug_assert_eq!(0, self.shared.buf_len());
+        }
+
+        // We either just finished the seek (if any), or already did it during
+        // a previous call. Continue the read. Cap the output buffer to be at
+        // most the slice bytes remaining.
+        if self.need_fake_read {
+            // Read one byte and throw it away, just to verify a chunk.
+            self.shared.read(&mut [0])?;
+            self.need_fake_read = false;
+            Ok(0)
+        } else {
+            let cap = cmp::min(self.slice_remaining, output.len() as u64) as usize;
+            let capped_output = &mut output[..cap];
+            let n = self.shared.read(capped_output)?;
+            self.slice_remaining -= n as u64;
+            Ok(n)
+        }
+    }
+}
+
+impl<T: Read> fmt::Debug for SliceDecoder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing buffered content or hashes, like Decoder.
+        write!(
+            f,
+            "SliceDecoder {{ slice_start: {}, slice_remaining: {}, content_position: {}, shared: {:?} }}",
+            self.slice_start,
+            self.slice_remaining,
+            self.shared.adjusted_content_position(),
+            self.shared,
+        )
+    }
+}
+
+/// Errors that can happen in `decode_slice`. Unlike the `std::io::Error` that `SliceDecoder`
+/// returns, these tell apart a slice that doesn't verify, a slice that doesn't have the content
+/// it was supposed to, and a reader that failed.
+#[derive(Debug)]
+pub enum SliceError {
+    /// The slice doesn't match the hash. `offset` is the content offset of the chunk or subtree
+    /// that failed to verify.
+    Corrupt { offset: u64 },
+    /// The requested range goes past the end of the content. The slice itself verified, so
+    /// `content_len` is trustworthy.
+    OutOfRange {
+        start: u64,
+        len: u64,
+        content_len: u64,
+    },
+    /// The underlying reader failed.
+    Io(io::Error),
+    /// The slice is truncated, or it has extra bytes after the end.
+    MalformedFrame,
+}
+
+impl fmt::Display for SliceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SliceError::Corrupt { offset } => write!(f, "hash mismatch at offset {}", offset),
+            SliceError::OutOfRange {
+                start,
+                len,
+                content_len,
+            } => write!(
+                f,
+                "slice start {} len {} is out of range for content length {}",
+                start, len, content_len
+            ),
+            SliceError::Io(e) => write!(f, "{}", e),
+            SliceError::MalformedFrame => write!(f, "malformed slice"),
+        }
+    }
+}
+
+impl error::Error for SliceError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SliceError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SliceError> for io::Error {
+    fn from(e: SliceError) -> io::Error {
+        match e {
+            SliceError::Corrupt { .. } => io::Error::new(io::ErrorKind::InvalidData, e),
+            SliceError::OutOfRange { .. } => io::Error::new(io::ErrorKind::InvalidInput, e),
+            SliceError::Io(e) => e,
+            SliceError::MalformedFrame => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// Decode an entire slice, the output of `SliceExtractor`, and return the content it covers. This
+/// is like reading a `SliceDecoder` to the end, but it's stricter, and its errors say what went
+/// wrong. A `SliceDecoder` returns a short read for a range that goes past the end of the
+/// content, but this returns `SliceError::OutOfRange`. It also checks that nothing follows the
+/// slice in `slice`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = vec![0; 10_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let mut extractor = bao::encode::SliceExtractor::new(std::io::Cursor::new(&encoded), 9000, 2000);
+/// let mut slice = Vec::new();
+/// extractor.read_to_end(&mut slice)?;
+///
+/// match bao::decode::decode_slice(&*slice, &hash, 9000, 2000) {
+///     Err(bao::decode::SliceError::OutOfRange { content_len, .. }) => {
+///         assert_eq!(10_000, content_len);
+///     }
+///     _ => panic!("expected an out of range error"),
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn decode_slice(
+    slice: impl Read,
+    hash: &Hash,
+    slice_start: u64,
+    slice_len: u64,
+) -> Result<Vec<u8>, SliceError> {
+    // The decoder passes reader errors through unchanged, so the only way to tell them apart from
+    // its own errors is to watch the reader.
+    struct WatchedReader<R> {
+        inner: R,
+        failed: bool,
+    }
+
+    impl<R: Read> Read for WatchedReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let result = self.inner.read(buf);
+            self.failed |= result.is_err();
+            result
+        }
+    }
+
+    let mut reader = WatchedReader {
+        inner: slice,
+        failed: false,
+    };
+    let mut decoder = SliceDecoder::new(&mut reader, hash, slice_start, slice_len);
+    let mut output = Vec::new();
+    if let Err(e) = decoder.read_to_end(&mut output) {
+        let offset = decoder.shared.state.content_position();
+        return Err(if reader.failed {
+            SliceError::Io(e)
+        } else if e.kind() == io::ErrorKind::InvalidData {
+            SliceError::Corrupt { offset }
+        } else if e.kind() == io::ErrorKind::UnexpectedEof {
+            SliceError::MalformedFrame
+        } else {
+            SliceError::Io(e)
+        });
+    }
+    // Reading all the way through the slice always verifies the final chunk if the range reaches
+    // it, so a short read means the content length is verified.
+    if (output.len() as u64) < slice_len {
+        let content_len = match decoder.shared.state.len_next() {
+            encode::LenNext::Len(len) => len,
+            encode::LenNext::Seek(_) => unreachable!("short read without the final chunk"),
+        };
+        return Err(SliceError::OutOfRange {
+            start: slice_start,
+            len: slice_len,
+            content_len,
+        });
+    }
+    drop(decoder);
+    match reader.inner.read(&mut [0]) {
+        Ok(0) => Ok(output),
+        Ok(_) => Err(SliceError::MalformedFrame),
+        Err(e) => Err(SliceError::Io(e)),
+    }
+}
+
+// Add the half-open range [start, end) to a sorted list of disjoint ranges, merging it with any
+// existing ranges that overlap or touch it.
+fn merge_range(ranges: &mut Vec<(u64, u64)>, mut start: u64, mut end: u64) {
+    let first = ranges.partition_point(|&(_, e)| e < start);
+    let last = ranges.partition_point(|&(s, _)| s <= end);
+    if first < last {
+        start = cmp::min(start, ranges[first].0);
+        end = cmp::max(end, ranges[last - 1].1);
+    }
+    ranges.splice(first..last, Some((start, end)));
+}
+
+/// Assemble content from slices that arrive in any order, as from several peers at once. Each
+/// slice is verified against the root hash on its own, exactly like `SliceDecoder` does, and then
+/// its content is copied into place. The assembler keeps track of which content ranges are filled,
+/// so the caller can read any range as soon as it's complete, without waiting for the rest.
+///
+/// Slices can overlap. Bytes that are already filled are compared against the new slice rather
+/// than copied again, and a mismatch is rejected, though it would take a hash collision for two
+/// verified slices to disagree.
+///
+/// The content is held in memory, so this needs `content_len` bytes of buffer space.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = vec![0xab; 5000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let mut assembler = bao::decode::SliceAssembler::new(&hash, input.len() as u64);
+/// for &(start, len) in &[(2048, 5000), (0, 2048)] {
+///     let mut slice = Vec::new();
+///     let mut extractor =
+///         bao::encode::SliceExtractor::new(std::io::Cursor::new(&encoded), start, len);
+///     extractor.read_to_end(&mut slice)?;
+///     assembler.add_slice(start, len, &slice)?;
+/// }
+/// assert!(assembler.is_complete());
+/// assert_eq!(&input[..], assembler.read_range(0, 5000).unwrap());
+/// # Ok(())
+/// # }
+/// ```
+pub struct SliceAssembler {
+    hash: Hash,
+    content: Vec<u8>,
+    // Sorted, disjoint, half-open content ranges filled so far.
+    filled: Vec<(u64, u64)>,
+}
+
+impl SliceAssembler {
+    pub fn new(hash: &Hash, content_len: u64) -> Self {
+        Self {
+            hash: *hash,
+            content: vec![0; content_len as usize],
+            filled: Vec::new(),
+        }
+    }
+
+    /// Verify a slice, extracted with `slice_start` and `slice_len` like `SliceDecoder` expects,
+    /// and fill in its content. If the slice is invalid, or if its verified length doesn't match
+    /// the `content_len` this assembler was created with, this returns an error and nothing is
+    /// filled in. Errors are `ErrorKind::InvalidData` or `ErrorKind::UnexpectedEof`, as usual.
+    pub fn add_slice(&mut self, slice_start: u64, slice_len: u64, slice: &[u8]) -> io::Result<()> {
+        let mut decoder = SliceDecoder::new(slice, &self.hash, slice_start, slice_len);
+        let mut content = Vec::new();
+        decoder.read_to_end(&mut content)?;
+        // The header has been verified as part of the slice.
+        if crate::hash::decode_len(array_ref!(slice, 0, HEADER_SIZE)) != self.content.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "wrong content length",
+            ));
+        }
+        if content.is_empty() {
+            return Ok(());
+        }
+        let start = slice_start;
+        let end = start + content.len() as u64;
+
+        // Check that any overlap with what's already filled matches, before changing anything.
+        let first = self.filled.partition_point(|&(_, e)| e <= start);
+        for &(filled_start, filled_end) in &self.filled[first..] {
+            if filled_start >= end {
+                break;
+            }
+            let overlap_start = cmp::max(start, filled_start);
+            let overlap_end = cmp::min(end, filled_end);
+            let old = &self.content[overlap_start as usize..overlap_end as usize];
+            let new = &content[(overlap_start - start) as usize..(overlap_end - start) as usize];
+            if old != new {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "conflicting slices",
+                ));
+            }
+        }
+        self.content[start as usize..end as usize].copy_from_slice(&content);
+        merge_range(&mut self.filled, start, end);
+        Ok(())
+    }
+
+    /// The content ranges filled so far, as sorted, non-overlapping `(start, end)` pairs, with
+    /// `end` exclusive. Adjacent ranges are merged.
+    pub fn filled_ranges(&self) -> &[(u64, u64)] {
+        &self.filled
+    }
+
+    /// Whether all of the content is filled.
+    pub fn is_complete(&self) -> bool {
+        let content_len = self.content.len() as u64;
+        content_len == 0 || self.filled == [(0, content_len)]
+    }
+
+    /// Return the content in the range `[start, start + len)`, if all of it is filled. Otherwise,
+    /// or if the range goes past the end of the content, return `None`.
+    pub fn read_range(&self, start: u64, len: u64) -> Option<&[u8]> {
+        let end = start.checked_add(len)?;
+        if end > self.content.len() as u64 {
+            return None;
+        }
+        if len > 0 {
+            let i = self.filled.partition_point(|&(_, e)| e <= start);
+            let &(filled_start, filled_end) = self.filled.get(i)?;
+            if filled_start > start || filled_end < end {
+                return None;
+            }
+        }
+        Some(&self.content[start as usize..end as usize])
+    }
+
+    /// Consume the assembler and return the content, once it's complete. If it isn't, return the
+    /// assembler back.
+    pub fn into_content(self) -> Result<Vec<u8>, Self> {
+        if self.is_complete() {
+            Ok(self.content)
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Debug for SliceAssembler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing the hash or the content, they might be secret.
+        write!(
+            f,
+            "SliceAssembler {{ content_len: {}, filled: {:?} }}",
+            self.content.len(),
+            self.filled,
+        )
+    }
+}
+
+/// A decoder for the parents-first layout produced by
+/// [`encode_parents_first`](../encode/fn.encode_parents_first.html), where the header and all the
+/// parent nodes come before any of the content.
+///
+/// The first read consumes the header and the entire tree, and verifies every parent node against
+/// the root hash before reading a single content byte. If the tree is bad, that read returns an
+/// error, and the content is never touched. After that, content chunks are verified as they're
+/// read, just like `Decoder`. Note that the tree is held in memory, so this needs about 1/16th of
+/// the content length in buffer space.
+///
+/// If reading or verifying the tree fails, the inner reader is dropped, since its position is
+/// somewhere in the middle of the tree. Every later read returns the same error.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = b"some input";
+/// let (encoded, hash) = bao::encode::encode_parents_first(input);
+/// let mut output = Vec::new();
+/// let mut decoder = bao::decode::ParentsFirstReader::new(&*encoded, &hash);
+/// decoder.read_to_end(&mut output)?;
+/// assert_eq!(input, &*output);
+/// # Ok(())
+/// # }
+/// ```
+pub struct ParentsFirstReader<T: Read> {
+    // Before the tree is verified, we hold the inner reader here. After that, it moves into the
+    // outboard decoder as the content reader.
+    inner: Option<T>,
+    hash: Hash,
+    decoder: Option<Decoder<T, io::Cursor<Vec<u8>>>>,
+    // If reading the tree failed, the error that later reads return.
+    failed: Option<io::Error>,
+}
+
+impl<T: Read> ParentsFirstReader<T> {
+    pub fn new(inner: T, hash: &Hash) -> Self {
+        Self {
+            inner: Some(inner),
+            hash: *hash,
+            decoder: None,
+            failed: None,
+        }
+    }
+
+    fn read_and_verify_tree(&mut self) -> io::Result<()> {
+        let inner = self.inner.as_mut().expect("tree already read");
+        let mut header = [0; HEADER_SIZE];
+        inner.read_exact(&mut header)?;
+        let content_len = crate::hash::decode_len(&header);
+        // Don't trust the header enough to preallocate, it hasn't been verified yet. If the tree
+        // is short, we'll find out when we run out of bytes.
+        let tree_len = encode::outboard_size(content_len) - HEADER_SIZE as u128;
+        let mut tree = header.to_vec();
+        let tree_len = cmp::min(tree_len, u64::MAX as u128) as u64;
+        inner.take(tree_len).read_to_end(&mut tree)?;
+        if ((tree.len() - HEADER_SIZE) as u64) < tree_len {
+            return Err(Error::Truncated.into());
+        }
+        verify_parents(&tree[HEADER_SIZE..], content_len, &self.hash)?;
+        let inner = self.inner.take().unwrap();
+        self.decoder = Some(Decoder::new_outboard(
+            inner,
+            io::Cursor::new(tree),
+            &self.hash,
+        ));
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for ParentsFirstReader<T> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if let Some(err) = &self.failed {
+            return Err(io::Error::new(err.kind(), err.to_string()));
+        }
+        if self.decoder.is_none() {
+            if let Err(err) = self.read_and_verify_tree() {
+                self.inner = None;
+                self.failed = Some(io::Error::new(err.kind(), err.to_string()));
+                return Err(err);
+            }
+        }
+        self.decoder.as_mut().unwrap().read(output)
+    }
+}
+
+impl<T: Read> fmt::Debug for ParentsFirstReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing the hash, it might be secret.
+        let shared = self.decoder.as_ref().map(|decoder| &decoder.shared);
+        write!(f, "ParentsFirstReader {{ shared: {:?} }}", shared)
+    }
+}
+
+/// A decoder for the post-order layout produced by
+/// [`PostorderEncoder`](../encode/struct.PostorderEncoder.html), where each parent node follows its
+/// subtree and the length comes at the end. It only reads forward, so `inner` can be a pipe.
+///
+/// Nothing in a post-order encoding can be verified before the root node, which is the last
+/// parent node. So the first read consumes the entire encoding and verifies all of it, and only
+/// then returns content. If anything is wrong, no content is returned at all. Note that this needs
+/// buffer space for the whole encoding, unlike `Decoder`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = b"some input";
+/// let (encoded, hash) = bao::encode::encode_postorder(input);
+/// let mut output = Vec::new();
+/// let mut decoder = bao::decode::PostorderReader::new(&*encoded, &hash);
+/// decoder.read_to_end(&mut output)?;
+/// assert_eq!(input, &*output);
+/// # Ok(())
+/// # }
+/// ```
+pub struct PostorderReader<T: Read> {
+    // Before the encoding is verified, we hold the inner reader here. After that, the verified
+    // content is in the cursor.
+    inner: Option<T>,
+    hash: Hash,
+    content: Option<io::Cursor<Vec<u8>>>,
+}
+
+impl<T: Read> PostorderReader<T> {
+    pub fn new(inner: T, hash: &Hash) -> Self {
+        Self {
+            inner: Some(inner),
+            hash: *hash,
+            content: None,
+        }
+    }
+
+    fn read_and_verify(&mut self) -> io::Result<()> {
+        let inner = self.inner.as_mut().expect("encoding already read");
+        let mut encoded = Vec::new();
+        inner.read_to_end(&mut encoded)?;
+        verify_postorder(&mut encoded, &self.hash)?;
+        self.inner = None;
+        self.content = Some(io::Cursor::new(encoded));
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for PostorderReader<T> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if self.content.is_none() {
+            self.read_and_verify()?;
+        }
+        self.content.as_mut().unwrap().read(output)
+    }
+}
+
+impl<T: Read> fmt::Debug for PostorderReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing the hash or the content, they might be secret.
+        let position = self.content.as_ref().map(|content| content.position());
+        write!(f, "PostorderReader {{ position: {:?} }}", position)
+    }
+}
+
+// Verify an entire post-order encoding against the root hash, and then shift the content bytes
+// down over the parent nodes in place, so that `encoded` holds just the content. A chunk is only
+// moved after it's been read, and the content never gets ahead of the encoding, so nothing is
+// overwritten before it's read.
+fn verify_postorder(encoded: &mut Vec<u8>, hash: &Hash) -> Result<(), Error> {
+    if encoded.len() < HEADER_SIZE {
+        return Err(Error::Truncated);
+    }
+    let trailer_start = encoded.len() - HEADER_SIZE;
+    let content_len = crate::hash::decode_len(array_ref!(encoded, trailer_start, HEADER_SIZE));
+    let expected_len = encode::encoded_size(content_len);
+    if (encoded.len() as u128) < expected_len {
+        return Err(Error::Truncated);
+    } else if encoded.len() as u128 > expected_len {
+        return Err(Error::HashMismatch);
+    }
+    let total_chunks = encode::count_chunks(content_len);
+    let mut subtrees: ArrayVec<[Hash; MAX_DEPTH]> = ArrayVec::new();
+    let mut read_position = 0;
+    let mut write_position = 0;
+    for index in 0..total_chunks {
+        let size = encode::chunk_size(index, content_len);
+        let is_last = index == total_chunks - 1;
+        let chunk = &encoded[read_position..][..size];
+        let chunk_hash = blake3::guts::ChunkState::new(index)
+            .update(chunk)
+            .finalize(total_chunks == 1);
+        subtrees.push(chunk_hash);
+        encoded.copy_within(read_position..read_position + size, write_position);
+        read_position += size;
+        write_position += size;
+        let parents = if is_last {
+            encode::post_order_parent_nodes_final(index)
+        } else {
+            encode::post_order_parent_nodes_nonfinal(index)
+        };
+        for _ in 0..parents {
+            let right_child = subtrees.pop().unwrap();
+            let left_child = subtrees.pop().unwrap();
+            let parent = &encoded[read_position..][..PARENT_SIZE];
+            let parent_left: Hash = (*array_ref!(parent, 0, HASH_SIZE)).into();
+            let parent_right: Hash = (*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into();
+            // Hash implements constant time equality.
+            if parent_left != left_child || parent_right != right_child {
+                return Err(Error::HashMismatch);
+            }
+            read_position += PARENT_SIZE;
+            let is_root = is_last && subtrees.is_empty();
+            subtrees.push(blake3::guts::parent_cv(&left_child, &right_child, is_root));
+        }
+    }
+    debug_assert_eq!(trailer_start, read_position);
+    debug_assert_eq!(1, subtrees.len());
+    if &subtrees[0] != hash {
+        return Err(Error::HashMismatch);
+    }
+    encoded.truncate(write_position);
+    Ok(())
+}
+
+/// A wrapper around `Decoder` that returns verified content in blocks of a fixed size, for
+/// consumers like block devices that write in units other than the chunk size. Each read returns
+/// the rest of the current block, so a caller whose buffer is at least `block_size` bytes gets
+/// exactly one whole block per read. Only the last block can be short.
+///
+/// A block is buffered until it's complete, so this needs `block_size` bytes of buffer space.
+///
+/// # Panics
+///
+/// `new` panics if `block_size` is zero.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let input = vec![0; 5000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let mut reader = bao::decode::BlockReader::new(&*encoded, &hash, 4096);
+/// let mut buf = [0; 8192];
+/// assert_eq!(4096, reader.read(&mut buf)?);
+/// assert_eq!(904, reader.read(&mut buf)?);
+/// assert_eq!(0, reader.read(&mut buf)?);
+/// # Ok(())
+/// # }
+/// ```
+pub struct BlockReader<T: Read> {
+    decoder: Decoder<T, T>,
+    block: Vec<u8>,
+    block_start: usize,
+    block_end: usize,
+}
+
+impl<T: Read> BlockReader<T> {
+    pub fn new(inner: T, hash: &Hash, block_size: usize) -> Self {
+        assert!(block_size > 0, "block size must be nonzero");
+        Self {
+            decoder: Decoder::new(inner, hash),
+            block: vec![0; block_size],
+            block_start: 0,
+            block_end: 0,
+        }
+    }
+
+    // Decode until the block is full or the content is done. If this fails partway, the verified
+    // bytes stay in the block, and the next call picks up where this one left off.
+    fn fill_block(&mut self) -> io::Result<()> {
+        while self.block_end < self.block.len() {
+            match self.decoder.read(&mut self.block[self.block_end..]) {
+                Ok(0) => break,
+                Ok(n) => self.block_end += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for BlockReader<T> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if output.is_empty() {
+            return Ok(0);
+        }
+        if self.block_start == self.block_end {
+            self.block_start = 0;
+            self.block_end = 0;
+        }
+        // A block that hasn't been returned yet might be incomplete, if the last fill failed or
+        // it's the last block. Filling it again either finishes it or hits EOF right away.
+        if self.block_start == 0 {
+            self.fill_block()?;
+        }
+        let take = cmp::min(output.len(), self.block_end - self.block_start);
+        output[..take].copy_from_slice(&self.block[self.block_start..][..take]);
+        self.block_start += take;
+        Ok(take)
+    }
+}
+
+impl<T: Read> fmt::Debug for BlockReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing the content or the hash.
+        write!(
+            f,
+            "BlockReader {{ shared: {:?}, block_size: {}, block_start: {}, block_end: {} }}",
+            self.decoder.shared,
+            self.block.len(),
+            self.block_start,
+            self.block_end,
+        )
+    }
+}
+
+/// The iterator returned by `into_chunks`.
+#[derive(Debug)]
+pub struct IntoChunks<T: Read> {
+    decoder: Decoder<T, T>,
+    done: bool,
+}
+
+impl<T: Read> Iterator for IntoChunks<T> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.done {
+            return None;
+        }
+        // Chunk reads are aligned, so the decoder verifies exactly one chunk to fill each of
+        // these, and nothing from the next chunk is returned before it's verified.
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut filled = 0;
+        while filled < CHUNK_SIZE {
+            match self.decoder.read(&mut chunk[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        if filled < CHUNK_SIZE {
+            self.done = true;
+        }
+        if filled == 0 {
+            return None;
+        }
+        chunk.truncate(filled);
+        Some(Ok(chunk))
+    }
+}
+
+/// Positioned reads from a seekable encoding, for random access patterns where juggling `seek`
+/// and `read` on a `Decoder` gets awkward. Each call to `read_at` verifies the chunks covering the
+/// requested range, along with the parent nodes on the path from the root down to them. Verified
+/// parent nodes go in a `ParentCache`, so repeated reads don't go back to the underlying reader
+/// for the nodes near the root.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::Cursor;
+///
+/// let input = vec![0xab; 10_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let mut random_access = bao::decode::RandomAccess::new(Cursor::new(&encoded), &hash);
+/// let mut buf = [0; 100];
+/// assert_eq!(100, random_access.read_at(5000, &mut buf)?);
+/// assert_eq!(&input[5000..5100], &buf[..]);
+/// assert_eq!(0, random_access.read_at(10_000, &mut buf)?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct RandomAccess<T: Read + Seek, O: Read + Seek> {
+    decoder: Decoder<T, O>,
+    parent_cache: Arc<ParentCache>,
+}
+
+impl<T: Read + Seek> RandomAccess<T, T> {
+    pub fn new(inner: T, hash: &Hash) -> Self {
+        Self::from_decoder(Decoder::new(inner, hash))
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek> RandomAccess<T, O> {
+    pub fn new_outboard(inner: T, outboard: O, hash: &Hash) -> Self {
+        Self::from_decoder(Decoder::new_outboard(inner, outboard, hash))
+    }
+
+    fn from_decoder(mut decoder: Decoder<T, O>) -> Self {
+        let parent_cache = Arc::new(ParentCache::new());
+        decoder.set_parent_cache(Arc::clone(&parent_cache));
+        Self {
+            decoder,
+            parent_cache,
+        }
+    }
+
+    /// The cache of verified parent nodes. It can be cleared to bound memory use, or shared with
+    /// other decoders for the same encoding with `Decoder::set_parent_cache`.
+    pub fn parent_cache(&self) -> &Arc<ParentCache> {
+        &self.parent_cache
+    }
+
+    /// Read verified content starting at `offset`, filling as much of `buf` as the content allows.
+    /// This only returns fewer bytes than `buf.len()` at the end of the content, and it returns 0
+    /// for an offset at or past the end. Verification failures are `ErrorKind::InvalidData` or
+    /// `ErrorKind::UnexpectedEof`, like for `Decoder`.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.seek(SeekFrom::Start(offset))?;
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.decoder.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+
+    /// The verified content length. See `Decoder::content_len`.
+    pub fn content_len(&mut self) -> io::Result<u64> {
+        self.decoder.content_len()
+    }
+}
+
+/// Verify individual chunks of content against an outboard tree, for random access to large
+/// files. Each call to `verify_chunk` checks the parent nodes on the path from the root down to
+/// one chunk, then reads that chunk from the content and checks it too. Nothing else in the
+/// content is read, and no state is kept between calls, so chunks can be verified in any order.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 10_000];
+/// let (outboard, hash) = bao::encode::outboard(&input);
+/// let content = std::io::Cursor::new(&input);
+/// let mut verifier = bao::decode::OutboardChunkVerifier::new(content, &outboard, &hash);
+/// let chunk = verifier.verify_chunk(3)?;
+/// assert_eq!(&input[3072..4096], &*chunk);
+/// # Ok(())
+/// # }
+/// ```
+pub struct OutboardChunkVerifier<'a, C: Read + Seek> {
+    content: C,
+    outboard: &'a [u8],
+    hash: Hash,
+}
+
+impl<'a, C: Read + Seek> OutboardChunkVerifier<'a, C> {
+    pub fn new(content: C, outboard: &'a [u8], hash: &Hash) -> Self {
+        Self {
+            content,
+            outboard,
+            hash: *hash,
+        }
+    }
+
+    /// Verify the chunk at `chunk_index` and return its content. Verification failures are
+    /// converted from `Error` as usual, to `ErrorKind::InvalidData` or `ErrorKind::UnexpectedEof`.
+    /// An index past the last chunk is an `ErrorKind::InvalidInput` error. The outboard header is
+    /// trusted only as far as the path to the chunk verifies, just like in `Decoder`.
+    pub fn verify_chunk(&mut self, chunk_index: u64) -> io::Result<Vec<u8>> {
+        if self.outboard.len() < HEADER_SIZE {
+            return Err(Error::Truncated.into());
+        }
+        let content_len = crate::hash::decode_len(array_ref!(self.outboard, 0, HEADER_SIZE));
+        if chunk_index >= encode::count_chunks(content_len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "chunk index out of range",
+            ));
+        }
+        let chunk_start = chunk_index * CHUNK_SIZE as u64;
+
+        // Walk down from the root, verifying each parent node on the way.
+        let mut tree = &self.outboard[HEADER_SIZE..];
+        let mut expected_hash = self.hash;
+        let mut finalization = Finalization::Root;
+        let mut node_start = 0;
+        let mut node_len = content_len;
+        while node_len > CHUNK_SIZE as u64 {
+            if tree.len() < PARENT_SIZE {
+                return Err(Error::Truncated.into());
+            }
+            let left_child: Hash = (*array_ref!(tree, 0, HASH_SIZE)).into();
+            let right_child: Hash = (*array_ref!(tree, HASH_SIZE, HASH_SIZE)).into();
+            let computed_hash =
+                blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
+            // Hash implements constant time equality.
+            if expected_hash != computed_hash {
+                return Err(Error::HashMismatch.into());
+            }
+            tree = &tree[PARENT_SIZE..];
+            finalization = Finalization::NotRoot;
+            let left_len = encode::left_subtree_len(node_len);
+            if chunk_start < node_start + left_len {
+                expected_hash = left_child;
+                node_len = left_len;
+            } else {
+                let left_tree_len = encode::outboard_subtree_size(left_len);
+                if (tree.len() as u128) < left_tree_len {
+                    return Err(Error::Truncated.into());
+                }
+                tree = &tree[left_tree_len as usize..];
+                expected_hash = right_child;
+                node_start += left_len;
+                node_len -= left_len;
+            }
+        }
+
+        // Then read and verify the chunk itself.
+        let mut chunk = vec![0; encode::chunk_size(chunk_index, content_len)];
+        self.content.seek(SeekFrom::Start(chunk_start))?;
+        self.content.read_exact(&mut chunk)?;
+        let chunk_hash = blake3::guts::ChunkState::new(chunk_index)
+            .update(&chunk)
+            .finalize(finalization.is_root());
+        if expected_hash != chunk_hash {
+            return Err(Error::HashMismatch.into());
+        }
+        Ok(chunk)
+    }
+}
+
+impl<C: Read + Seek> fmt::Debug for OutboardChunkVerifier<'_, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing the hash, it might be secret.
+        write!(
+            f,
+            "OutboardChunkVerifier {{ outboard_len: {} }}",
+            self.outboard.len()
+        )
+    }
+}
+
+// Verify all the parent nodes of a pre-order outboard tree (without its header) against the root
+// hash, without looking at any chunks. Chunks are verified later, as content is read.
+fn verify_parents(tree: &[u8], content_len: u64, hash: &Hash) -> Result<(), Error> {
+    fn recurse(
+        tree: &mut &[u8],
+        content_len: u64,
+        hash: &Hash,
+        finalization: Finalization,
+    ) -> Result<(), Error> {
+        if content_len <= CHUNK_SIZE as u64 {
+            return Ok(());
+        }
+        if tree.len() < PARENT_SIZE {
+            return Err(Error::Truncated);
+        }
+        let left_child: Hash = (*array_ref!(tree, 0, HASH_SIZE)).into();
+        let right_child: Hash = (*array_ref!(tree, HASH_SIZE, HASH_SIZE)).into();
+        let computed_hash: Hash =
+            blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
+        // Hash implements constant time equality.
+        if hash != &computed_hash {
+            return Err(Error::HashMismatch);
+        }
+        *tree = &tree[PARENT_SIZE..];
+        let left_len = encode::left_subtree_len(content_len);
+        recurse(tree, left_len, &left_child, Finalization::NotRoot)?;
+        recurse(
+            tree,
+            content_len - left_len,
+            &right_child,
+            Finalization::NotRoot,
+        )
+    }
+    let mut remaining = tree;
+    recurse(&mut remaining, content_len, hash, Finalization::Root)
+}
+
+#[cfg(test)]
+pub(crate) fn make_test_input(len: usize) -> Vec<u8> {
+    // Fill the input with incrementing bytes, so that reads from different sections are very
+    // unlikely to accidentally match.
+    let mut ret = Vec::new();
+    let mut counter = 0u64;
+    while ret.len() < len {
+        if counter < u8::max_value() as u64 {
+            ret.push(counter as u8);
+        } else if counter < u16::max_value() as u64 {
+            ret.extend_from_slice(&(counter as u16).to_be_bytes());
+        } else if counter < u32::max_value() as u64 {
+            ret.extend_from_slice(&(counter as u32).to_be_bytes());
+        } else {
+            ret.extend_from_slice(&(counter as u64).to_be_bytes());
+        }
+        counter += 1;
+    }
+    ret.truncate(len);
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+    use std::io;
+    use std::io::prelude::*;
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::encode;
+
+    #[test]
+    fn test_decode() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = { encode::encode(&input) };
+            let output = decode(&encoded, &hash).unwrap();
+            assert_eq!(input, output);
+            assert_eq!(output.len(), output.capacity());
+        }
+    }
+
+    #[test]
+    fn test_decode_outboard() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (outboard, hash) = { encode::outboard(&input) };
+            let mut output = Vec::new();
+            let mut reader = Decoder::new_outboard(&input[..], &outboard[..], &hash);
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "rayon", any(unix, windows)))]
+    fn test_decode_parallel() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            // Start with a longer file, to make sure it gets truncated.
+            let mut file = tempfile::tempfile().unwrap();
+            file.write_all(&vec![0xff; case + 100]).unwrap();
+            decode_parallel(&encoded, &hash, &file).unwrap();
+            let mut output = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut output).unwrap();
+            assert_eq!(decode(&encoded, &hash).unwrap(), output);
+
+            let mut bad_encoded = encoded.clone();
+            let last = bad_encoded.len() - 1;
+            bad_encoded[last] ^= 1;
+            let err = decode_parallel(&bad_encoded, &hash, &file).unwrap_err();
+            if case == 0 {
+                assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+            } else {
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_verified_ranges() {
+        let input = make_test_input(20 * CHUNK_SIZE + 100);
+        let (encoded, hash) = encode::encode(&input);
+        let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+        assert!(decoder.verified_ranges().is_empty());
+        let mut buf = [0; 10];
+
+        // Two separate regions.
+        decoder
+            .seek(SeekFrom::Start(3 * CHUNK_SIZE as u64 + 5))
+            .unwrap();
+        decoder.read_exact(&mut buf).unwrap();
+        decoder
+            .seek(SeekFrom::Start(10 * CHUNK_SIZE as u64))
+            .unwrap();
+        decoder.read_exact(&mut buf).unwrap();
+        let chunk = |i: u64| (i * CHUNK_SIZE as u64, (i + 1) * CHUNK_SIZE as u64);
+        assert_eq!(&[chunk(3), chunk(10)], decoder.verified_ranges());
+
+        // Filling in the gap merges the ranges.
+        decoder
+            .seek(SeekFrom::Start(4 * CHUNK_SIZE as u64))
+            .unwrap();
+        let mut gap = vec![0; 6 * CHUNK_SIZE];
+        decoder.read_exact(&mut gap).unwrap();
+        assert_eq!(&[(chunk(3).0, chunk(10).1)], decoder.verified_ranges());
+
+        // Reading to the end adds the partial final chunk.
+        decoder
+            .seek(SeekFrom::Start(20 * CHUNK_SIZE as u64))
+            .unwrap();
+        decoder.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(
+            &[(chunk(3).0, chunk(10).1), (chunk(20).0, input.len() as u64)],
+            decoder.verified_ranges()
+        );
+
+        // An empty input has no content to verify.
+        let (encoded, hash) = encode::encode(b"");
+        let mut decoder = Decoder::new(&*encoded, &hash);
+        decoder.read_to_end(&mut Vec::new()).unwrap();
+        assert!(decoder.verified_ranges().is_empty());
+    }
+
+    #[test]
+    fn test_peak_memory() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let mut decoder = Decoder::new(&*encoded, &hash);
+            let mut output = Vec::new();
+            let mut max_stack = decoder.shared.state.stack.len();
+            let mut buf = [0; CHUNK_SIZE];
+            loop {
+                let n = decoder.read(&mut buf).unwrap();
+                max_stack = cmp::max(max_stack, decoder.shared.state.stack.len());
+                if n == 0 {
+                    break;
+                }
+                output.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(input, output);
+            let estimate = peak_memory(case as u64);
+            assert!(estimate >= (output.len() + max_stack * HASH_SIZE) as u64);
+            // The stack part stays small.
+            assert!(estimate - (case as u64) <= (MAX_DEPTH * HASH_SIZE) as u64);
+        }
+        assert_eq!(HASH_SIZE as u64, peak_memory(0));
+        assert_eq!(
+            CHUNK_SIZE as u64 + HASH_SIZE as u64,
+            peak_memory(CHUNK_SIZE as u64)
+        );
+        assert_eq!(
+            2 * CHUNK_SIZE as u64 + 2 * HASH_SIZE as u64,
+            peak_memory(2 * CHUNK_SIZE as u64)
+        );
+        assert_eq!(u64::MAX, peak_memory(u64::MAX));
+    }
+
+    #[test]
+    fn test_decode_to_writer_errors() {
+        struct FailingWriter {
+            capacity: usize,
+            kind: io::ErrorKind,
+            written: Vec<u8>,
+        }
+
+        impl Write for FailingWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.written.len() == self.capacity {
+                    return Err(io::Error::new(self.kind, "no space left"));
+                }
+                let n = cmp::min(buf.len(), self.capacity - self.written.len());
+                self.written.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let input = make_test_input(10_000);
+        let (encoded, hash) = encode::encode(&input);
+
+        let mut output = Vec::new();
+        let n = decode_to_writer(&encoded, &hash, &mut output).unwrap();
+        assert_eq!(input.len() as u64, n);
+        assert_eq!(input, output);
+
+        // A failing writer is reported as an I/O error, not as corruption, even when its error
+        // kind looks like one.
+        for &kind in &[io::ErrorKind::WriteZero, io::ErrorKind::InvalidData] {
+            let mut writer = FailingWriter {
+                capacity: 5000,
+                kind,
+                written: Vec::new(),
+            };
+            match decode_to_writer(&encoded, &hash, &mut writer) {
+                Err(DecodeToWriterError::Io(e)) => {
+                    assert_eq!(kind, e.kind());
+                    assert_eq!("no space left", e.to_string());
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+            assert_eq!(&input[..5000], &*writer.written);
+        }
+
+        // Corruption is reported as a verification error.
+        let mut bad_encoded = encoded.clone();
+        let last = bad_encoded.len() - 1;
+        bad_encoded[last] ^= 1;
+        match decode_to_writer(&bad_encoded, &hash, &mut Vec::new()) {
+            Err(DecodeToWriterError::Verify(Error::HashMismatch)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match decode_to_writer(&encoded[..100], &hash, &mut Vec::new()) {
+            Err(DecodeToWriterError::Verify(Error::Truncated)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let err: io::Error = decode_to_writer(&encoded[..100], &hash, &mut Vec::new())
+            .unwrap_err()
+            .into();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    // A sink that accepts at most 100 bytes per write, and that's only ready for every other
+    // write. Each time it accepts bytes, it records how many verified content bytes were buffered:
+    // the content bytes read from the source so far, minus the bytes it's already accepted.
+    #[cfg(feature = "tokio")]
+    struct SlowSink {
+        content_len: u64,
+        source_position: std::rc::Rc<std::cell::Cell<u64>>,
+        output: Vec<u8>,
+        max_buffered: u64,
+        ready: bool,
+    }
+
+    #[cfg(feature = "tokio")]
+    impl tokio::io::AsyncWrite for SlowSink {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context,
+            buf: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
+            }
+            let content_read =
+                content_in_encoded_prefix(self.content_len, self.source_position.get());
+            let buffered = content_read - self.output.len() as u64;
+            self.max_buffered = cmp::max(self.max_buffered, buffered);
+            let n = cmp::min(100, buf.len());
+            self.output.extend_from_slice(&buf[..n]);
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    // A source that reports how far it's been read.
+    #[cfg(feature = "tokio")]
+    struct TrackedSource<'a> {
+        encoded: &'a [u8],
+        position: std::rc::Rc<std::cell::Cell<u64>>,
+    }
+
+    #[cfg(feature = "tokio")]
+    impl tokio::io::AsyncRead for TrackedSource<'_> {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context,
+            buf: &mut tokio::io::ReadBuf,
+        ) -> std::task::Poll<io::Result<()>> {
+            let position = self.position.get() as usize;
+            let n = cmp::min(buf.remaining(), self.encoded.len() - position);
+            buf.put_slice(&self.encoded[position..][..n]);
+            self.position.set((position + n) as u64);
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    // A source that returns at most max_read bytes per read, and that's only ready for every other
+    // read.
+    #[cfg(feature = "tokio")]
+    struct SlowSource<'a> {
+        bytes: &'a [u8],
+        max_read: usize,
+        ready: bool,
+    }
+
+    #[cfg(feature = "tokio")]
+    impl tokio::io::AsyncRead for SlowSource<'_> {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context,
+            buf: &mut tokio::io::ReadBuf,
+        ) -> std::task::Poll<io::Result<()>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
+            }
+            let n = cmp::min(cmp::min(buf.remaining(), self.max_read), self.bytes.len());
+            buf.put_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    // Count the content bytes in the first `prefix_len` bytes of a combined encoding.
+    #[cfg(feature = "tokio")]
+    fn content_in_encoded_prefix(content_len: u64, prefix_len: u64) -> u64 {
+        let mut offset = HEADER_SIZE as u64;
+        let mut content = 0;
+        for index in 0..encode::count_chunks(content_len) {
+            offset +=
+                PARENT_SIZE as u64 * encode::pre_order_parent_nodes(index, content_len) as u64;
+            let size = encode::chunk_size(index, content_len) as u64;
+            content += cmp::min(size, prefix_len.saturating_sub(offset));
+            offset += size;
+        }
+        content
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_async_copy_verified() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let position = std::rc::Rc::new(std::cell::Cell::new(0));
+            let source = TrackedSource {
+                encoded: &encoded,
+                position: position.clone(),
+            };
+            let mut sink = SlowSink {
+                content_len: case as u64,
+                source_position: position.clone(),
+                output: Vec::new(),
+                max_buffered: 0,
+                ready: false,
+            };
+            let n = runtime
+                .block_on(async_copy_verified(source, &hash, &mut sink))
+                .unwrap();
+            assert_eq!(case as u64, n);
+            assert_eq!(input, sink.output);
+            assert!(sink.max_buffered <= CHUNK_SIZE as u64);
+            assert_eq!(encoded.len() as u64, position.get());
+
+            // A corrupt final chunk is never written.
+            if case == 0 {
+                continue;
+            }
+            let mut bad_encoded = encoded.clone();
+            let last = bad_encoded.len() - 1;
+            bad_encoded[last] ^= 1;
+            let mut output = Vec::new();
+            let err = runtime
+                .block_on(async_copy_verified(&*bad_encoded, &hash, &mut output))
+                .unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            let last_chunk_start = (encode::count_chunks(case as u64) - 1) as usize * CHUNK_SIZE;
+            assert_eq!(&input[..last_chunk_start], &*output);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_async_outboard_verifier() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (outboard, hash) = encode::outboard(&input);
+            // Feed the two streams at different rates, both ways around.
+            for &(content_rate, outboard_rate) in &[(7, 100), (1000, 3)] {
+                let content = SlowSource {
+                    bytes: &input,
+                    max_read: content_rate,
+                    ready: false,
+                };
+                let outboard = SlowSource {
+                    bytes: &outboard,
+                    max_read: outboard_rate,
+                    ready: false,
+                };
+                let verifier = AsyncOutboardVerifier::new(content, outboard, &hash);
+                let mut output = Vec::new();
+                let n = runtime.block_on(verifier.copy_to(&mut output)).unwrap();
+                assert_eq!(case as u64, n);
+                assert_eq!(input, output);
+            }
+
+            // Chunks come back one at a time, and a bad chunk is never returned.
+            let mut bad_input = input.clone();
+            if case > 0 {
+                *bad_input.last_mut().unwrap() ^= 1;
+            }
+            let mut verifier = AsyncOutboardVerifier::new(&bad_input[..], &outboard[..], &hash);
+            let mut output = Vec::new();
+            let err = runtime.block_on(async {
+                loop {
+                    match verifier.next_chunk().await {
+                        Ok(Some(chunk)) => output.extend_from_slice(chunk),
+                        Ok(None) => return None,
+                        Err(e) => return Some(e),
+                    }
+                }
+            });
+            if case == 0 {
+                assert!(err.is_none());
+                continue;
+            }
+            assert_eq!(io::ErrorKind::InvalidData, err.unwrap().kind());
+            let last_chunk_start = (encode::count_chunks(case as u64) - 1) as usize * CHUNK_SIZE;
+            assert_eq!(&input[..last_chunk_start], &*output);
+
+            // So is a chunk under a bad parent node.
+            let mut bad_outboard = outboard.clone();
+            *bad_outboard.last_mut().unwrap() ^= 1;
+            let verifier = AsyncOutboardVerifier::new(&input[..], &bad_outboard[..], &hash);
+            let err = runtime
+                .block_on(verifier.copy_to(&mut Vec::new()))
+                .unwrap_err();
+            let kind = err.kind();
+            assert!(
+                kind == io::ErrorKind::InvalidData || kind == io::ErrorKind::UnexpectedEof,
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_hash_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash_path = dir.path().join("hash");
+        let input = make_test_input(3 * CHUNK_SIZE + 1);
+        let (encoded, hash) = encode::encode(&input);
+
+        // Both the raw bytes and the hex of the hash work, with or without a trailing newline.
+        let valid_contents = [
+            hash.as_bytes().to_vec(),
+            hash.to_hex().as_bytes().to_vec(),
+            format!("{}\n", hash.to_hex()).into_bytes(),
+        ];
+        for contents in &valid_contents {
+            std::fs::write(&hash_path, contents).unwrap();
+            let mut output = Vec::new();
+            Decoder::from_hash_file(&*encoded, &hash_path)
+                .unwrap()
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(input, output);
+        }
+
+        // A short hash file.
+        std::fs::write(&hash_path, &hash.to_hex()[..60]).unwrap();
+        let err = Decoder::from_hash_file(&*encoded, &hash_path).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!("wrong length hash", err.to_string());
+
+        // A non-hex hash file of the right length. Note that a sign character is also rejected.
+        for bad_char in &["g", "+"] {
+            let bad_hex = format!("{}{}", bad_char, &hash.to_hex()[1..]);
+            std::fs::write(&hash_path, bad_hex).unwrap();
+            let err = Decoder::from_hash_file(&*encoded, &hash_path).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            assert_eq!("invalid hex", err.to_string());
+        }
+
+        // A missing hash file.
+        let err = Decoder::from_hash_file(&*encoded, dir.path().join("missing")).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    fn test_verify_all_first() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let mut output = Vec::new();
+            Decoder::verify_all_first(Cursor::new(&encoded), &hash)
+                .unwrap()
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(input, output);
+            if case == 0 {
+                continue;
+            }
+
+            // Corrupt the final chunk. The regular decoder emits everything before it, but
+            // verify_all_first fails before emitting anything.
+            let mut bad_encoded = encoded.clone();
+            let last = bad_encoded.len() - 1;
+            bad_encoded[last] ^= 1;
+            let err = Decoder::verify_all_first(Cursor::new(&bad_encoded), &hash).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            let mut decoder = Decoder::new(&*bad_encoded, &hash);
+            let mut emitted = Vec::new();
+            let mut buf = [0; CHUNK_SIZE];
+            while let Ok(n) = decoder.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                emitted.extend_from_slice(&buf[..n]);
+            }
+            let last_chunk_start = (encode::count_chunks(case as u64) - 1) as usize * CHUNK_SIZE;
+            assert_eq!(&input[..last_chunk_start], &*emitted);
+        }
+    }
+
+    #[test]
+    fn test_is_valid_encoded_len() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let encoded_len = encode::encoded_size(case as u64) as u64;
+            assert_eq!(Some(case as u64), is_valid_encoded_len(encoded_len));
+            // One byte less is always either a shorter content length or a missing parent node,
+            // and one byte more is always either a longer content length or a missing parent.
+            let shorter = is_valid_encoded_len(encoded_len - 1);
+            assert!(shorter.is_none() || shorter == Some(case as u64 - 1));
+            let longer = is_valid_encoded_len(encoded_len + 1);
+            assert!(longer.is_none() || longer == Some(case as u64 + 1));
+        }
+        // Lengths that fall inside the gap where a new chunk brings its parent node along.
+        let one_chunk = encode::encoded_size(CHUNK_SIZE as u64) as u64;
+        for extra in 1..=PARENT_SIZE as u64 {
+            assert_eq!(None, is_valid_encoded_len(one_chunk + extra));
+        }
+        assert_eq!(
+            Some(CHUNK_SIZE as u64 + 1),
+            is_valid_encoded_len(one_chunk + PARENT_SIZE as u64 + 1)
+        );
+        for short in 0..HEADER_SIZE as u64 {
+            assert_eq!(None, is_valid_encoded_len(short));
+        }
+        assert_eq!(Some(0), is_valid_encoded_len(HEADER_SIZE as u64));
+        // The largest encoded lengths shouldn't overflow.
+        for encoded_len in u64::MAX - 2 * (CHUNK_SIZE + PARENT_SIZE) as u64..=u64::MAX {
+            if let Some(content_len) = is_valid_encoded_len(encoded_len) {
+                assert_eq!(encoded_len as u128, encode::encoded_size(content_len));
+            }
+        }
+    }
+
+    #[test]
+    fn test_spurious_zero_reads() {
+        // Returns Ok(0) once before every real read.
+        struct QuirkyReader<R> {
+            inner: R,
+            zero_next: bool,
+        }
+
+        impl<R: Read> Read for QuirkyReader<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.zero_next = !self.zero_next;
+                if self.zero_next {
+                    Ok(0)
+                } else {
+                    self.inner.read(buf)
+                }
+            }
+        }
+
+        // Follows the Read contract, and counts the reads that hit EOF.
+        struct CountingReader<'a> {
+            inner: &'a [u8],
+            eof_reads: usize,
+        }
+
+        impl Read for CountingReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.inner.read(buf)?;
+                if n == 0 && !buf.is_empty() {
+                    self.eof_reads += 1;
+                }
+                Ok(n)
+            }
+        }
+
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+
+            // By default, Ok(0) is EOF, as the Read contract says.
+            let quirky = QuirkyReader {
+                inner: &*encoded,
+                zero_next: false,
+            };
+            let err = Decoder::new(quirky, &hash)
+                .read_to_end(&mut Vec::new())
+                .unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+
+            // Opting into retries makes the quirky reader work.
+            let quirky = QuirkyReader {
+                inner: &*encoded,
+                zero_next: false,
+            };
+            let mut output = Vec::new();
+            Decoder::new(RetryZeroReads::new(quirky, 1), &hash)
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(input, output);
+
+            // A real EOF is read once by default, and once more per retry, and it's still an
+            // error either way.
+            for &retries in &[0, 2] {
+                let mut counting = CountingReader {
+                    inner: &encoded[..encoded.len() - 1],
+                    eof_reads: 0,
+                };
+                let err = Decoder::new(RetryZeroReads::new(&mut counting, retries), &hash)
+                    .read_to_end(&mut Vec::new())
+                    .unwrap_err();
+                assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+                assert_eq!(1 + retries, counting.eof_reads);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_then_decompress() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let mut compressor =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            compressor.write_all(&input).unwrap();
+            let compressed = compressor.finish().unwrap();
+            let (encoded, hash) = encode::encode(&compressed);
+
+            // Read in small pieces, so that the decompressor sees short reads
+            // from the decoder.
+            let decoder = Decoder::new(&*encoded, &hash);
+            let mut decompressor = flate2::read::GzDecoder::new(decoder);
+            let mut output = Vec::new();
+            let mut buf = [0; 100];
+            loop {
+                let n = decompressor.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                output.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(input, output);
+
+            // Corrupting the encoding must fail before the decompressor sees
+            // any bad bytes.
+            let mut bad_encoded = encoded.clone();
+            let last = bad_encoded.len() - 1;
+            bad_encoded[last] ^= 1;
+            let decoder = Decoder::new(&*bad_encoded, &hash);
+            let err = flate2::read::GzDecoder::new(decoder)
+                .read_to_end(&mut Vec::new())
+                .unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_outboard_chunk_verifier() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (outboard, hash) = encode::outboard(&input);
+            let mut verifier = OutboardChunkVerifier::new(Cursor::new(&input), &outboard, &hash);
+            // Verify the chunks out of order, last to first.
+            let chunks = encode::count_chunks(case as u64);
+            for index in (0..chunks).rev() {
+                let start = index as usize * CHUNK_SIZE;
+                let expected = &input[start..][..encode::chunk_size(index, case as u64)];
+                assert_eq!(expected, &*verifier.verify_chunk(index).unwrap());
+            }
+            let err = verifier.verify_chunk(chunks).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+            // A corrupt parent node fails every chunk below it.
+            if case > CHUNK_SIZE {
+                let mut bad_outboard = outboard.clone();
+                bad_outboard[HEADER_SIZE] ^= 1;
+                let mut verifier =
+                    OutboardChunkVerifier::new(Cursor::new(&input), &bad_outboard, &hash);
+                let err = verifier.verify_chunk(0).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_outboard_chunk_verifier_corrupt_chunk() {
+        let case = 8 * CHUNK_SIZE + 1;
+        let input = make_test_input(case);
+        let (outboard, hash) = encode::outboard(&input);
+        let mut bad_input = input.clone();
+        bad_input[5 * CHUNK_SIZE + 10] ^= 1;
+        let mut verifier = OutboardChunkVerifier::new(Cursor::new(&bad_input), &outboard, &hash);
+        // Only the corrupted chunk fails. Its neighbors are fine.
+        for index in 0..encode::count_chunks(case as u64) {
+            let result = verifier.verify_chunk(index);
+            if index == 5 {
+                assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+            } else {
+                assert!(result.is_ok());
+            }
+        }
+        // Truncated content is an EOF error.
+        let mut verifier =
+            OutboardChunkVerifier::new(Cursor::new(&input[..case - 1]), &outboard, &hash);
+        let err = verifier.verify_chunk(8).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn test_decode_sparse() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let mut output = tempfile::tempfile().unwrap();
+            // Leftover content gets cleared.
+            output.write_all(&vec![0xff; case + 1]).unwrap();
+            decode_sparse(&encoded, &hash, &output).unwrap();
+            let mut decoded = Vec::new();
+            output.seek(SeekFrom::Start(0)).unwrap();
+            output.read_to_end(&mut decoded).unwrap();
+            assert_eq!(input, decoded);
+
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                *bad_encoded.last_mut().unwrap() ^= 1;
+                let err = decode_sparse(&bad_encoded, &hash, &output).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+
+        // Mostly zeros, with some content at either end and in the middle.
+        let len = 16 << 20;
+        let mut input = vec![0; len];
+        input[..10_000].copy_from_slice(&make_test_input(10_000));
+        input[len / 2] = 1;
+        input[len - 1] = 1;
+        let (encoded, hash) = encode::encode(&input);
+        let mut output = tempfile::tempfile().unwrap();
+        decode_sparse(&encoded, &hash, &output).unwrap();
+        let mut decoded = Vec::new();
+        output.read_to_end(&mut decoded).unwrap();
+        assert_eq!(input, decoded);
+        // The zero regions are holes. Every common Unix filesystem supports them.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let allocated = output.metadata().unwrap().blocks() * 512;
+            println!("allocated {} of {}", allocated, len);
+            assert!(allocated < len as u64 / 2);
+        }
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+            assert_eq!(encoded.len() as u64, decoder.encoded_len().unwrap());
+            assert_eq!(case as u64, decoder.content_len().unwrap());
+
+            // Getting the length partway through doesn't disturb the read position.
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+            let mut first_part = vec![0; case / 2];
+            decoder.read_exact(&mut first_part).unwrap();
+            assert_eq!(encoded.len() as u64, decoder.encoded_len().unwrap());
+            let mut rest = Vec::new();
+            decoder.read_to_end(&mut rest).unwrap();
+            assert_eq!(&input[case / 2..], &*rest);
+
+            // Outboard decoders report the combined size too.
+            let (outboard, _) = encode::outboard(&input);
+            let mut decoder =
+                Decoder::new_outboard(Cursor::new(&input), Cursor::new(&outboard), &hash);
+            assert_eq!(encoded.len() as u64, decoder.encoded_len().unwrap());
+
+            // A bad final chunk means the length can't be verified.
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                *bad_encoded.last_mut().unwrap() ^= 1;
+                let mut decoder = Decoder::new(Cursor::new(&bad_encoded), &hash);
+                let err = decoder.encoded_len().unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_parent_cache() {
+        // Counts the bytes read through it.
+        struct CountingReader<T> {
+            inner: T,
+            bytes_read: u64,
+        }
+
+        impl<T: Read> Read for CountingReader<T> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.inner.read(buf)?;
+                self.bytes_read += n as u64;
+                Ok(n)
+            }
+        }
+
+        impl<T: Seek> Seek for CountingReader<T> {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+                self.inner.seek(pos)
+            }
+        }
+
+        let case = 100 * CHUNK_SIZE + 1;
+        let input = make_test_input(case);
+        let (encoded, hash) = encode::encode(&input);
+        let (outboard, _) = encode::outboard(&input);
+        let offset = 90 * CHUNK_SIZE as u64 + 5;
+
+        // Seek with a fresh combined decoder, read a bit, and return how many bytes it read.
+        let seek_and_read = |cache: Option<&Arc<ParentCache>>| {
+            let reader = CountingReader {
+                inner: Cursor::new(&encoded),
+                bytes_read: 0,
+            };
+            let mut decoder = Decoder::new(reader, &hash);
+            if let Some(cache) = cache {
+                decoder.set_parent_cache(Arc::clone(cache));
+            }
+            decoder.seek(SeekFrom::Start(offset)).unwrap();
+            let mut buf = [0; 100];
+            decoder.read_exact(&mut buf).unwrap();
+            assert_eq!(&input[offset as usize..][..100], &buf[..]);
+            decoder.shared.input.bytes_read
+        };
+
+        let uncached = seek_and_read(None);
+        assert_eq!(uncached, seek_and_read(None));
+        let cache = Arc::new(ParentCache::new());
+        assert_eq!(uncached, seek_and_read(Some(&cache)));
+        assert!(!cache.is_empty());
+        let cached = seek_and_read(Some(&cache));
+        assert_eq!(uncached - cached, cache.len() as u64 * PARENT_SIZE as u64);
+
+        // An outboard decoder for the same content shares the entries. It reads the same tree
+        // bytes as the cached combined decoder, everything but the chunk.
+        let mut decoder = Decoder::new_outboard(
+            Cursor::new(&input),
+            CountingReader {
+                inner: Cursor::new(&outboard),
+                bytes_read: 0,
+            },
+            &hash,
+        );
+        decoder.set_parent_cache(Arc::clone(&cache));
+        decoder.seek(SeekFrom::Start(offset)).unwrap();
+        let mut buf = [0; 100];
+        decoder.read_exact(&mut buf).unwrap();
+        assert_eq!(&input[offset as usize..][..100], &buf[..]);
+        assert_eq!(
+            cached - CHUNK_SIZE as u64,
+            decoder.shared.outboard.as_ref().unwrap().bytes_read
+        );
+
+        // A different encoding doesn't get anything from the cache.
+        let other_input = make_test_input(case + 1);
+        let (other_encoded, other_hash) = encode::encode(&other_input);
+        let mut decoder = Decoder::new(Cursor::new(&other_encoded), &other_hash);
+        decoder.set_parent_cache(Arc::clone(&cache));
+        decoder.seek(SeekFrom::Start(offset)).unwrap();
+        let mut buf = [0; 100];
+        decoder.read_exact(&mut buf).unwrap();
+        assert_eq!(&other_input[offset as usize..][..100], &buf[..]);
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            for &stop in &[0, case / 3, case / 2, case] {
+                // Read part of the input, and save a checkpoint to bytes.
+                let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+                let mut first_part = vec![0; stop];
+                decoder.read_exact(&mut first_part).unwrap();
+                let saved = decoder.checkpoint().to_bytes();
+                drop(decoder);
+
+                // Start over with nothing but the saved bytes, the encoding, and the hash.
+                let checkpoint = Checkpoint::from_bytes(&saved).unwrap();
+                assert_eq!(stop as u64, checkpoint.content_position());
+                let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+                decoder.restore(&checkpoint).unwrap();
+                let mut rest = Vec::new();
+                decoder.read_to_end(&mut rest).unwrap();
+                first_part.extend_from_slice(&rest);
+                assert_eq!(input, first_part);
+
+                // The same checkpoint works with an outboard decoder.
+                let mut decoder =
+                    Decoder::new_outboard(Cursor::new(&input), Cursor::new(&outboard), &hash);
+                decoder.restore(&checkpoint).unwrap();
+                let mut rest = Vec::new();
+                decoder.read_to_end(&mut rest).unwrap();
+                assert_eq!(&input[stop..], &*rest);
+
+                // A checkpoint for a different hash, or with a tampered position, is rejected.
+                let other_hash = blake3::hash(b"other");
+                let mut decoder = Decoder::new(Cursor::new(&encoded), &other_hash);
+                let err = decoder.restore(&checkpoint).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+                let mut tampered = saved;
+                tampered[0] ^= 1;
+                let tampered = Checkpoint::from_bytes(&tampered).unwrap();
+                let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+                let err = decoder.restore(&tampered).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+
+            // Restoring still verifies the path from the root, so a corrupt encoding is caught.
+            if case > CHUNK_SIZE {
+                let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+                decoder.read_exact(&mut vec![0; case - 1]).unwrap();
+                let checkpoint = decoder.checkpoint();
+                let mut bad_encoded = encoded.clone();
+                bad_encoded[HEADER_SIZE] ^= 1;
+                let mut decoder = Decoder::new(Cursor::new(&bad_encoded), &hash);
+                let err = decoder.restore(&checkpoint).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+        let err = Checkpoint::from_bytes(&[0; CHECKPOINT_SIZE - 1]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    fn extract_slice(encoded: &[u8], start: u64, len: u64) -> Vec<u8> {
+        let mut slice = Vec::new();
+        encode::SliceExtractor::new(Cursor::new(encoded), start, len)
+            .read_to_end(&mut slice)
+            .unwrap();
+        slice
+    }
+
+    // Build the leftmost path of a tree for the given content length, with arbitrary right
+    // children, and return the combined encoding prefix up to the end of the first chunk, along
+    // with the root hash that makes it verify.
+    fn crafted_leftmost_path(content_len: u64) -> (Vec<u8>, Hash) {
+        let mut lens = vec![content_len];
+        while *lens.last().unwrap() > CHUNK_SIZE as u64 {
+            let len = *lens.last().unwrap();
+            lens.push(encode::left_subtree_len(len));
+        }
+        let chunk = vec![0xab; *lens.last().unwrap() as usize];
+        let mut hash = crate::hash::hash_subtree_inner(&chunk, 0, Finalization::NotRoot);
+        let mut parents = Vec::new();
+        for i in (0..lens.len() - 1).rev() {
+            let mut parent = [0; PARENT_SIZE];
+            parent[..HASH_SIZE].copy_from_slice(hash.as_bytes());
+            parent[HASH_SIZE..].copy_from_slice(&[i as u8; HASH_SIZE]);
+            hash = blake3::guts::parent_cv(
+                &hash,
+                &(*array_ref!(parent, HASH_SIZE, HASH_SIZE)).into(),
+                i == 0,
+            );
+            parents.push(parent);
+        }
+        let mut encoded = crate::hash::encode_len(content_len).to_vec();
+        for parent in parents.iter().rev() {
+            encoded.extend_from_slice(parent);
+        }
+        encoded.extend_from_slice(&chunk);
+        (encoded, hash)
+    }
+
+    #[test]
+    fn test_deepest_tree() {
+        // A header claiming u64::MAX bytes, with parent nodes crafted to verify all the way down
+        // the leftmost path, drives the verifier to its maximum depth. Nobody can produce the rest
+        // of the encoding, but the first chunk should verify without running out of stack.
+        let (encoded, hash) = crafted_leftmost_path(u64::MAX);
+        assert_eq!(
+            HEADER_SIZE + MAX_DEPTH * PARENT_SIZE + CHUNK_SIZE,
+            encoded.len()
+        );
+        let mut decoder = Decoder::new(&*encoded, &hash);
+        let mut buf = [0; CHUNK_SIZE];
+        decoder.read_exact(&mut buf).unwrap();
+        assert_eq!([0xab; CHUNK_SIZE][..], buf[..]);
+        let err = decoder.read(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+
+        // The same through a slice, and with a bad chunk at the bottom.
+        let mut decoder = SliceDecoder::new(&*encoded, &hash, 0, CHUNK_SIZE as u64);
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(vec![0xab; CHUNK_SIZE], output);
+        let mut bad_encoded = encoded.clone();
+        *bad_encoded.last_mut().unwrap() ^= 1;
+        let err = Decoder::new(&*bad_encoded, &hash)
+            .read(&mut buf)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_padded() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (padded, padded_hash) = encode::encode_padded(&input);
+            assert_eq!(hash, padded_hash);
+            let padded_len = encode::count_chunks(case as u64) * CHUNK_SIZE as u64;
+            assert_eq!(encode::encoded_size(padded_len), padded.len() as u128);
+            assert_eq!(&encoded[..], &padded[..encoded.len()]);
+            assert!(padded[encoded.len()..].iter().all(|&b| b == 0));
+            assert_eq!(input, decode_padded(&padded, &hash).unwrap());
+
+            // Missing padding is an error, and bad content still fails to verify.
+            let err = decode_padded(&padded[..padded.len() - 1], &hash).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+            if case > 0 {
+                let mut bad_padded = padded.clone();
+                bad_padded[encoded.len() - 1] ^= 1;
+                let err = decode_padded(&bad_padded, &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_cow() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let content = decode_cow(&encoded, &hash).unwrap();
+            assert_eq!(input, &*content);
+            match content {
+                Cow::Borrowed(_) => assert!(case <= CHUNK_SIZE),
+                Cow::Owned(_) => assert!(case > CHUNK_SIZE),
+            }
+
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                *bad_encoded.last_mut().unwrap() ^= 1;
+                assert_eq!(
+                    Error::HashMismatch,
+                    decode_cow(&bad_encoded, &hash).unwrap_err()
+                );
+            }
+            assert_eq!(
+                Error::Truncated,
+                decode_cow(&encoded[..encoded.len() - 1], &hash).unwrap_err()
+            );
+        }
+    }
+
+    #[cfg(feature = "unverified")]
+    #[test]
+    fn test_decode_unverified() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let output = decode_unverified(&encoded, case as u64).unwrap();
+            assert_eq!(decode(&encoded, &hash).unwrap(), output);
+            assert_eq!(
+                Err(Error::HashMismatch),
+                decode_unverified(&encoded, case as u64 + 1)
+            );
+            assert_eq!(
+                Err(Error::Truncated),
+                decode_unverified(&encoded[..encoded.len() - 1], case as u64)
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_valid() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            assert!(is_valid(&encoded, &hash));
+            assert!(!is_valid(&encoded, &blake3::hash(b"wrong")));
+            let mut extended = encoded.clone();
+            extended.push(0);
+            assert!(is_valid(&extended, &hash));
+
+            // Flip bits and truncate all over the header, the parents and the chunks. The stride
+            // doesn't divide the node sizes, so it hits every part of the tree.
+            let offsets = (0..HEADER_SIZE).chain((HEADER_SIZE..encoded.len()).step_by(31));
+            for i in offsets.chain(Some(encoded.len() - 1)) {
+                let mut bad = encoded.clone();
+                bad[i] ^= 1;
+                assert_eq!(decode(&bad, &hash).is_ok(), is_valid(&bad, &hash));
+                let truncated = &encoded[..i];
+                assert_eq!(decode(truncated, &hash).is_ok(), is_valid(truncated, &hash));
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_with_expected_len() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let len = case as u64;
+
+            // When the length agrees, the full verification runs.
+            assert_eq!(Ok(()), verify_with_expected_len(&encoded, &hash, len));
+            let mut bad = encoded.clone();
+            *bad.last_mut().unwrap() ^= 1;
+            if case > 0 {
+                assert_eq!(
+                    Err(Error::HashMismatch),
+                    verify_with_expected_len(&bad, &hash, len)
+                );
+            }
+            assert_eq!(
+                Err(Error::Truncated),
+                verify_with_expected_len(&encoded[..encoded.len() - 1], &hash, len)
+            );
+
+            // When it disagrees, the header alone is rejected, and nothing after it is read. A
+            // bare header would be truncated otherwise.
+            for &wrong in &[len + 1, len.wrapping_sub(1), u64::MAX] {
+                assert_eq!(
+                    Err(Error::HashMismatch),
+                    verify_with_expected_len(&encoded, &hash, wrong)
+                );
+                assert_eq!(
+                    Err(Error::HashMismatch),
+                    verify_with_expected_len(&encoded[..HEADER_SIZE], &hash, wrong)
+                );
+            }
+            assert_eq!(
+                Err(Error::Truncated),
+                verify_with_expected_len(&encoded[..HEADER_SIZE - 1], &hash, len)
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_with_rolling() {
+        fn adler32(bytes: &[u8]) -> u32 {
+            let (mut a, mut b) = (1u32, 0u32);
+            for &byte in bytes {
+                a = (a + byte as u32) % 65521;
+                b = (b + a) % 65521;
+            }
+            (b << 16) | a
+        }
+
+        for &case in crate::test::TEST_CASES {
             println!("case {}", case);
             let input = make_test_input(case);
-            let (encoded, hash) = { encode::encode(&input) };
-            let output = decode(&encoded, &hash).unwrap();
+            let (encoded, hash) = encode::encode(&input);
+            for &window in &[1, 7, CHUNK_SIZE + 1] {
+                let mut checksums = Vec::new();
+                let mut output = Vec::new();
+                let n = decode_with_rolling(&encoded, &hash, window, &mut output, |i, c| {
+                    checksums.push((i, c))
+                })
+                .unwrap();
+                assert_eq!(case as u64, n);
+                assert_eq!(input, output);
+                let expected: Vec<(u64, u32)> = output
+                    .windows(window)
+                    .enumerate()
+                    .map(|(i, w)| (i as u64, adler32(w)))
+                    .collect();
+                assert_eq!(expected, checksums);
+            }
+        }
+
+        // High bytes make the sums wrap around the modulus quickly.
+        let input = vec![0xff; 20_000];
+        let (encoded, hash) = encode::encode(&input);
+        let mut checksums = Vec::new();
+        decode_with_rolling(&encoded, &hash, 6000, io::sink(), |_, c| checksums.push(c)).unwrap();
+        assert_eq!(20_000 - 6000 + 1, checksums.len());
+        let expected = adler32(&input[..6000]);
+        assert!(checksums.iter().all(|&c| c == expected));
+
+        // A window longer than the content emits nothing, and doesn't allocate the whole window.
+        let mut output = Vec::new();
+        let n = decode_with_rolling(&encoded, &hash, usize::MAX, &mut output, |_, _| {
+            panic!("no whole window")
+        })
+        .unwrap();
+        assert_eq!(20_000, n);
+        assert_eq!(input, output);
+
+        // Only verified bytes are checksummed.
+        let input = make_test_input(4 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        let mut count = 0;
+        let err = decode_with_rolling(&encoded, &hash, 100, io::sink(), |_, _| count += 1);
+        assert_eq!(io::ErrorKind::InvalidData, err.unwrap_err().kind());
+        assert_eq!(3 * CHUNK_SIZE - 100 + 1, count);
+
+        let err = decode_with_rolling(&encoded, &hash, 0, io::sink(), |_, _| {}).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_decode_frames() {
+        let mut prng = ChaChaRng::from_seed([0; 32]);
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+
+            // Split the encoding at random places, including some empty frames, and some splits
+            // inside the header, parent nodes, and chunks.
+            let mut frames: Vec<&[u8]> = Vec::new();
+            let mut rest = &encoded[..];
+            while !rest.is_empty() {
+                let len = cmp::min(rest.len(), prng.gen_range(0, 2 * CHUNK_SIZE));
+                let (frame, remaining) = rest.split_at(len);
+                frames.push(frame);
+                rest = remaining;
+            }
+            let mut output = Vec::new();
+            let n = decode_frames(&frames, &hash, &mut output).unwrap();
+            assert_eq!(case as u64, n);
+            assert_eq!(decode(&encoded, &hash).unwrap(), output);
+
+            // One byte per frame.
+            let frames: Vec<&[u8]> = encoded.chunks(1).collect();
+            let mut output = Vec::new();
+            decode_frames(&frames, &hash, &mut output).unwrap();
             assert_eq!(input, output);
-            assert_eq!(output.len(), output.capacity());
+
+            // Dropping the last frame is a truncated encoding.
+            let err = decode_frames(&frames[..frames.len() - 1], &hash, io::sink()).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_decode_any() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let other_hash = blake3::hash(b"other");
+            let mut output = Vec::new();
+            assert_eq!(
+                1,
+                decode_any(&encoded, &[other_hash, hash, hash], &mut output).unwrap()
+            );
+            assert_eq!(input, output);
+
+            let mut output = Vec::new();
+            let err = decode_any(&encoded, &[other_hash], &mut output).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            assert!(output.is_empty());
+            let err = decode_any(&encoded, &[], io::sink()).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+            // A matching root node doesn't help if the content below it is bad.
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                *bad_encoded.last_mut().unwrap() ^= 1;
+                let err = decode_any(&bad_encoded, &[other_hash, hash], io::sink()).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_extract_content_and_outboard() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let mut content = Vec::new();
+            extract_content(&*encoded, &hash, &mut content).unwrap();
+            assert_eq!(input, content);
+            let mut outboard = Vec::new();
+            extract_outboard(Cursor::new(&encoded), &mut outboard).unwrap();
+            assert_eq!(encode::outboard(&input).0, outboard);
+
+            // The pieces verify as an outboard encoding.
+            let mut decoder = Decoder::new_outboard(&*content, &*outboard, &hash);
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                *bad_encoded.last_mut().unwrap() ^= 1;
+                let err = extract_content(&*bad_encoded, &hash, io::sink()).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+                // The last byte is content, so the tree splits out the same.
+                let mut bad_outboard = Vec::new();
+                extract_outboard(Cursor::new(&bad_encoded), &mut bad_outboard).unwrap();
+                assert_eq!(outboard, bad_outboard);
+            }
+        }
+    }
+
+    #[test]
+    fn test_demux() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let mut content = Vec::new();
+            let mut outboard = Vec::new();
+            let n = demux(&*encoded, &hash, &mut content, &mut outboard).unwrap();
+            assert_eq!(case as u64, n);
+            assert_eq!(input, content);
+            assert_eq!(encode::outboard(&input).0, outboard);
+
+            // The pieces verify as an outboard encoding, and they recombine into the same
+            // combined encoding.
+            let mut decoder = Decoder::new_outboard(&*content, &*outboard, &hash);
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+            assert_eq!(encoded, encode::encode(&content).0);
+            let combined_len = outboard.len() + content.len();
+            assert_eq!(encode::encoded_size(case as u64), combined_len as u128);
+
+            // Corrupting the last byte, which is content, fails verification after all the
+            // parents have been written.
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                *bad_encoded.last_mut().unwrap() ^= 1;
+                let mut bad_outboard = Vec::new();
+                let err = demux(&*bad_encoded, &hash, io::sink(), &mut bad_outboard).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+                assert_eq!(outboard, bad_outboard);
+            }
+
+            // A truncated encoding is an UnexpectedEof error.
+            let err =
+                demux(&encoded[..encoded.len() - 1], &hash, io::sink(), io::sink()).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_decode_slice_errors() {
+        struct FailingReader;
+
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "oops"))
+            }
+        }
+
+        let case = 20 * CHUNK_SIZE + 7;
+        let input = make_test_input(case);
+        let (encoded, hash) = encode::encode(&input);
+        let (start, len) = (3 * CHUNK_SIZE as u64, 4 * CHUNK_SIZE as u64);
+        let slice = extract_slice(&encoded, start, len);
+        let decoded = decode_slice(&*slice, &hash, start, len).unwrap();
+        assert_eq!(&input[start as usize..][..len as usize], &*decoded);
+
+        // Corrupting the last chunk of the slice reports that chunk's offset.
+        let mut bad_slice = slice.clone();
+        *bad_slice.last_mut().unwrap() ^= 1;
+        match decode_slice(&*bad_slice, &hash, start, len) {
+            Err(SliceError::Corrupt { offset }) => {
+                assert_eq!(start + len - CHUNK_SIZE as u64, offset)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // A range past the end of the content verifies, but it's out of range.
+        let past_end = extract_slice(&encoded, case as u64 - 10, 100);
+        match decode_slice(&*past_end, &hash, case as u64 - 10, 100) {
+            Err(SliceError::OutOfRange {
+                start,
+                len,
+                content_len,
+            }) => assert_eq!(
+                (case as u64 - 10, 100, case as u64),
+                (start, len, content_len)
+            ),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // Missing or extra bytes are a malformed frame.
+        match decode_slice(&slice[..slice.len() - 1], &hash, start, len) {
+            Err(SliceError::MalformedFrame) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let mut long_slice = slice.clone();
+        long_slice.push(0);
+        match decode_slice(&*long_slice, &hash, start, len) {
+            Err(SliceError::MalformedFrame) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // Reader failures come through unchanged, and converting back to io::Error keeps kinds.
+        match decode_slice(FailingReader, &hash, start, len) {
+            Err(SliceError::Io(e)) => assert_eq!(io::ErrorKind::ConnectionReset, e.kind()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let err: io::Error = decode_slice(&*bad_slice, &hash, start, len)
+            .unwrap_err()
+            .into();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("hash mismatch"));
+    }
+
+    #[test]
+    fn test_slice_assembler() {
+        let case = 20 * CHUNK_SIZE + 7;
+        let input = make_test_input(case);
+        let (encoded, hash) = encode::encode(&input);
+        // Three overlapping slices that cover everything, added out of order.
+        let ranges = [(7000, 10000), (0, 8000), (13000, case as u64 - 13000)];
+        let mut assembler = SliceAssembler::new(&hash, case as u64);
+        assert!(!assembler.is_complete());
+        assert_eq!(None, assembler.read_range(0, 1));
+        for (i, &(start, len)) in ranges.iter().enumerate() {
+            let slice = extract_slice(&encoded, start, len);
+            assembler.add_slice(start, len, &slice).unwrap();
+            // Adding the same slice again is harmless.
+            assembler.add_slice(start, len, &slice).unwrap();
+            assert_eq!(i == ranges.len() - 1, assembler.is_complete());
+            let expected = &input[start as usize..][..len as usize];
+            assert_eq!(Some(expected), assembler.read_range(start, len));
+        }
+        assert_eq!(&[(0, case as u64)], assembler.filled_ranges());
+        assert_eq!(None, assembler.read_range(case as u64, 1));
+        assert_eq!(input, assembler.into_content().unwrap());
+
+        // Partway through, only filled ranges can be read. The slice carries whole chunks, but
+        // only the requested range is filled.
+        let mut assembler = SliceAssembler::new(&hash, case as u64);
+        let slice = extract_slice(&encoded, 7000, 10000);
+        assembler.add_slice(7000, 10000, &slice).unwrap();
+        assert_eq!(&[(7000, 17000)], assembler.filled_ranges());
+        assert_eq!(Some(&input[8000..9000]), assembler.read_range(8000, 1000));
+        assert_eq!(None, assembler.read_range(6999, 2));
+        assert_eq!(None, assembler.read_range(16999, 2));
+        let assembler = assembler.into_content().unwrap_err();
+
+        // A corrupt slice is rejected, and nothing is filled in.
+        let mut assembler = assembler;
+        let mut bad_slice = extract_slice(&encoded, 0, 8000);
+        *bad_slice.last_mut().unwrap() ^= 1;
+        let err = assembler.add_slice(0, 8000, &bad_slice).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(&[(7000, 17000)], assembler.filled_ranges());
+
+        // So is a slice for a different content length.
+        let mut assembler = SliceAssembler::new(&hash, case as u64 + 1);
+        let slice = extract_slice(&encoded, 0, 8000);
+        let err = assembler.add_slice(0, 8000, &slice).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(assembler.filled_ranges().is_empty());
+
+        // Conflicting bytes in an overlap are rejected. A real conflict would need a hash
+        // collision, so fake one by tampering with the filled content directly.
+        let mut assembler = SliceAssembler::new(&hash, case as u64);
+        assembler.add_slice(0, 8000, &slice).unwrap();
+        assembler.content[7500] ^= 1;
+        let slice = extract_slice(&encoded, 7000, 10000);
+        let err = assembler.add_slice(7000, 10000, &slice).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(&[(0, 8000)], assembler.filled_ranges());
+    }
+
+    #[test]
+    fn test_block_reader() {
+        let input = make_test_input(20 * CHUNK_SIZE + 100);
+        let (encoded, hash) = encode::encode(&input);
+        for &block_size in &[1, 512, CHUNK_SIZE, 8192, input.len() + 1] {
+            println!("block_size {}", block_size);
+            let mut reader = BlockReader::new(&*encoded, &hash, block_size);
+            let mut blocks = Vec::new();
+            let mut buf = vec![0; 3 * block_size];
+            loop {
+                let n = reader.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                blocks.push(buf[..n].to_vec());
+            }
+            let (last, rest) = blocks.split_last().unwrap();
+            for block in rest {
+                assert_eq!(block_size, block.len());
+            }
+            assert!(!last.is_empty() && last.len() <= block_size);
+            assert_eq!(input, blocks.concat());
+        }
+
+        // Small output buffers still see the right bytes.
+        let mut reader = BlockReader::new(&*encoded, &hash, 8192);
+        let mut output = Vec::new();
+        let mut buf = [0; 100];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn test_parents_first() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode_parents_first(&input);
+            let mut output = Vec::new();
+            let mut reader = ParentsFirstReader::new(&*encoded, &hash);
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+
+            // Corrupting the final content byte is caught as usual.
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                *bad_encoded.last_mut().unwrap() ^= 1;
+                let mut reader = ParentsFirstReader::new(&*bad_encoded, &hash);
+                let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_postorder() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode_postorder(&input);
+            assert_eq!(blake3::hash(&input), hash);
+            assert_eq!(encode::encoded_size(case as u64), encoded.len() as u128);
+            let mut output = Vec::new();
+            PostorderReader::new(&*encoded, &hash)
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(input, output);
+
+            // Corrupting any single byte, including the length trailer, is caught before any
+            // content is returned. Truncating the encoding is too.
+            for &i in &[
+                0,
+                encoded.len() / 2,
+                encoded.len().saturating_sub(HEADER_SIZE + 1),
+                encoded.len() - 1,
+            ] {
+                let mut bad_encoded = encoded.clone();
+                bad_encoded[i] ^= 1;
+                let mut reader = PostorderReader::new(&*bad_encoded, &hash);
+                let mut output = [0; CHUNK_SIZE];
+                let err = reader.read(&mut output).unwrap_err();
+                assert!(
+                    err.kind() == io::ErrorKind::InvalidData
+                        || err.kind() == io::ErrorKind::UnexpectedEof
+                );
+            }
+            let mut reader = PostorderReader::new(&encoded[..encoded.len() - 1], &hash);
+            assert!(reader.read(&mut [0; CHUNK_SIZE]).is_err());
+        }
+    }
+
+    // Adapters for a one-way channel, to show that the post-order layout never needs to seek.
+    struct PipeWriter(std::sync::mpsc::SyncSender<Vec<u8>>);
+
+    impl Write for PipeWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .send(buf.to_vec())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct PipeReader {
+        receiver: std::sync::mpsc::Receiver<Vec<u8>>,
+        buf: io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for PipeReader {
+        fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let n = self.buf.read(output)?;
+                if n > 0 || output.is_empty() {
+                    return Ok(n);
+                }
+                match self.receiver.recv() {
+                    Ok(bytes) => self.buf = io::Cursor::new(bytes),
+                    // The writer hung up.
+                    Err(_) => return Ok(0),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_postorder_pipe() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+            let thread_input = input.clone();
+            let encoder_thread = std::thread::spawn(move || {
+                let mut encoder = encode::PostorderEncoder::new(PipeWriter(sender));
+                encoder.write_all(&thread_input).unwrap();
+                encoder.finalize().unwrap()
+            });
+            let pipe = PipeReader {
+                receiver,
+                buf: io::Cursor::new(Vec::new()),
+            };
+            let hash = blake3::hash(&input);
+            let mut output = Vec::new();
+            PostorderReader::new(pipe, &hash)
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(input, output);
+            assert_eq!(hash, encoder_thread.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_parents_first_bad_tree() {
+        for &case in crate::test::TEST_CASES {
+            if case <= CHUNK_SIZE {
+                continue;
+            }
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode_parents_first(&input);
+            let tree_len = encode::outboard_size(case as u64) as usize;
+            // Corrupt the last parent node, which is the deepest one on the right edge. The
+            // content is left off entirely, to show that it's never read.
+            let mut bad_tree = encoded[..tree_len].to_vec();
+            *bad_tree.last_mut().unwrap() ^= 1;
+            let mut reader = ParentsFirstReader::new(&*bad_tree, &hash);
+            let mut output = [0; CHUNK_SIZE];
+            let err = reader.read(&mut output).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+            // A truncated tree is also caught before the content.
+            let mut reader = ParentsFirstReader::new(&encoded[..tree_len - 1], &hash);
+            let err = reader.read(&mut output).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        }
+    }
+
+    // Returns an error once `bytes` runs out, and counts the reads after that.
+    struct BrokenReader<'a> {
+        bytes: &'a [u8],
+        reads_after_failure: usize,
+    }
+
+    impl<'a> Read for BrokenReader<'a> {
+        fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+            if self.bytes.is_empty() {
+                self.reads_after_failure += 1;
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "disk on fire"));
+            }
+            self.bytes.read(output)
+        }
+    }
+
+    #[test]
+    fn test_parents_first_poisoned() {
+        let input = make_test_input(4 * CHUNK_SIZE);
+        let (encoded, hash) = encode::encode_parents_first(&input);
+        let tree_len = encode::outboard_size(input.len() as u64) as usize;
+        // Fail partway through the tree.
+        let mut inner = BrokenReader {
+            bytes: &encoded[..tree_len / 2],
+            reads_after_failure: 0,
+        };
+        let mut reader = ParentsFirstReader::new(&mut inner, &hash);
+        let mut output = [0; CHUNK_SIZE];
+        let err = reader.read(&mut output).unwrap_err();
+        assert_eq!(io::ErrorKind::BrokenPipe, err.kind());
+        assert_eq!("disk on fire", err.to_string());
+        // Later reads return the same error without touching the inner reader again.
+        for _ in 0..2 {
+            let err = reader.read(&mut output).unwrap_err();
+            assert_eq!(io::ErrorKind::BrokenPipe, err.kind());
+            assert_eq!("disk on fire", err.to_string());
+        }
+        drop(reader);
+        assert_eq!(1, inner.reads_after_failure);
+
+        // A tree that fails verification poisons the reader too.
+        let mut bad_encoded = encoded.clone();
+        bad_encoded[tree_len - 1] ^= 1;
+        let mut reader = ParentsFirstReader::new(&*bad_encoded, &hash);
+        let first = reader.read(&mut output).unwrap_err();
+        let second = reader.read(&mut output).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, second.kind());
+        assert_eq!(first.to_string(), second.to_string());
+    }
+
+    #[test]
+    fn test_hash_from_encoded() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            assert_eq!((hash, case as u64), hash_from_encoded(&*encoded).unwrap());
+            assert_eq!(
+                (hash, case as u64),
+                hash_from_outboard(&*input, &*outboard).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_same_content() {
+        use EncodingKind::{Combined, Outboard};
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, _) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            assert!(same_content(&encoded, Combined, &outboard, Outboard(&input)).unwrap());
+            assert!(same_content(&outboard, Outboard(&input), &encoded, Combined).unwrap());
+            assert!(same_content(&encoded, Combined, &encoded, Combined).unwrap());
+
+            // Different content, and different lengths.
+            let mut other_input = input.clone();
+            other_input.push(0);
+            let (other_encoded, _) = encode::encode(&other_input);
+            let (other_outboard, _) = encode::outboard(&other_input);
+            assert!(!same_content(&encoded, Combined, &other_encoded, Combined).unwrap());
+            assert!(!same_content(
+                &outboard,
+                Outboard(&input),
+                &other_outboard,
+                Outboard(&other_input)
+            )
+            .unwrap());
+            if case > 0 {
+                other_input.pop();
+                other_input[0] ^= 1;
+                let (other_encoded, _) = encode::encode(&other_input);
+                assert!(!same_content(&encoded, Combined, &other_encoded, Combined).unwrap());
+            }
+
+            assert_eq!(
+                Err(Error::Truncated),
+                same_content(&encoded[..HEADER_SIZE - 1], Combined, &encoded, Combined)
+            );
         }
     }
 
-    #[test]
-    fn test_decode_outboard() {
-        for &case in crate::test::TEST_CASES {
-            println!("case {}", case);
-            let input = make_test_input(case);
-            let (outboard, hash) = { encode::outboard(&input) };
+    #[test]
+    fn test_decode_with_content_len() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            let headerless = &encoded[HEADER_SIZE..];
+            let headerless_outboard = &outboard[HEADER_SIZE..];
+
+            let mut output = Vec::new();
+            let mut decoder = Decoder::new_with_content_len(headerless, &hash, case as u64);
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+
             let mut output = Vec::new();
-            let mut reader = Decoder::new_outboard(&input[..], &outboard[..], &hash);
-            reader.read_to_end(&mut output).unwrap();
+            let mut decoder = Decoder::new_outboard_with_content_len(
+                &input[..],
+                headerless_outboard,
+                &hash,
+                case as u64,
+            );
+            decoder.read_to_end(&mut output).unwrap();
             assert_eq!(input, output);
+
+            // Seeking accounts for the missing header, in both modes.
+            for &seek in crate::test::TEST_CASES {
+                let expected = &input[cmp::min(seek, case)..];
+                let mut output = Vec::new();
+                let mut decoder =
+                    Decoder::new_with_content_len(Cursor::new(headerless), &hash, case as u64);
+                decoder.seek(SeekFrom::Start(seek as u64)).unwrap();
+                decoder.read_to_end(&mut output).unwrap();
+                assert_eq!(expected, &*output);
+
+                let mut output = Vec::new();
+                let mut decoder = Decoder::new_outboard_with_content_len(
+                    Cursor::new(&input),
+                    Cursor::new(headerless_outboard),
+                    &hash,
+                    case as u64,
+                );
+                decoder.seek(SeekFrom::Start(seek as u64)).unwrap();
+                decoder.read_to_end(&mut output).unwrap();
+                assert_eq!(expected, &*output);
+            }
+
+            // A wrong length fails verification.
+            let mut decoder = Decoder::new_with_content_len(headerless, &hash, case as u64 + 1);
+            let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+            assert!(
+                err.kind() == io::ErrorKind::InvalidData
+                    || err.kind() == io::ErrorKind::UnexpectedEof
+            );
         }
     }
 
@@ -770,6 +5970,211 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_trailer_crc() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            assert_eq!(Ok(false), check_trailer_crc(&encoded));
+            let mut cursor = Cursor::new(encoded.clone());
+            encode::append_trailer_crc(&mut cursor).unwrap();
+            let with_trailer = cursor.into_inner();
+            assert_eq!(encoded.len() + TRAILER_SIZE, with_trailer.len());
+            assert_eq!(Ok(true), check_trailer_crc(&with_trailer));
+            assert_eq!(input, decode(&with_trailer, &hash).unwrap());
+
+            // A flipped byte anywhere in the encoding, header and parents included, or in the CRC
+            // itself, is caught before any hashing.
+            for i in 0..with_trailer.len() - TRAILER_MAGIC.len() {
+                let mut bad = with_trailer.clone();
+                bad[i] ^= 1;
+                assert_eq!(Err(CrcError::Mismatch), check_trailer_crc(&bad), "{}", i);
+            }
+            // A damaged magic just looks like no trailer, and decoding still works.
+            let mut bad = with_trailer.clone();
+            *bad.last_mut().unwrap() ^= 1;
+            assert_eq!(Ok(false), check_trailer_crc(&bad));
+            assert_eq!(input, decode(&bad, &hash).unwrap());
+            assert_eq!(
+                Err(CrcError::Truncated),
+                check_trailer_crc(&with_trailer[..HEADER_SIZE - 1])
+            );
+        }
+
+        // Content that ends with the magic isn't mistaken for a trailer.
+        let mut input = make_test_input(2 * CHUNK_SIZE);
+        input.extend_from_slice(&TRAILER_MAGIC);
+        let (encoded, _) = encode::encode(&input);
+        assert_eq!(Ok(false), check_trailer_crc(&encoded));
+    }
+
+    #[test]
+    fn test_crc_check() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let sidecar = encode::crc_sidecar(&input);
+            crc_check(&encoded, &sidecar).unwrap();
+
+            // A sidecar of the wrong length never matches.
+            let err = crc_check(&encoded, &sidecar[..sidecar.len() - 1]).unwrap_err();
+            assert_eq!(CrcError::Mismatch, err);
+            assert_eq!(io::ErrorKind::InvalidData, io::Error::from(err).kind());
+
+            // A short encoding is truncated, not mismatched.
+            let err = crc_check(&encoded[..encoded.len() - 1], &sidecar).unwrap_err();
+            assert_eq!(CrcError::Truncated, err);
+            assert_eq!(io::ErrorKind::UnexpectedEof, io::Error::from(err).kind());
+
+            // Flipping a content byte is caught by the CRC pass, and by decoding.
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                *bad_encoded.last_mut().unwrap() ^= 1;
+                assert_eq!(
+                    CrcError::Mismatch,
+                    crc_check(&bad_encoded, &sidecar).unwrap_err()
+                );
+                let err = decode(&bad_encoded, &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+
+            // Flipping a byte in a parent node isn't covered by the CRCs at all, so the fast pass
+            // succeeds. Decoding is still authoritative, and it fails.
+            if case > CHUNK_SIZE {
+                let mut bad_encoded = encoded.clone();
+                bad_encoded[HEADER_SIZE] ^= 1;
+                crc_check(&bad_encoded, &sidecar).unwrap();
+                let err = decode(&bad_encoded, &hash).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_versioned() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (versioned, versioned_hash) = encode::encode_versioned(&input);
+            assert_eq!(hash, versioned_hash);
+            assert_eq!(&VERSION_MAGIC, &versioned[..VERSION_MAGIC.len()]);
+            assert_eq!(encoded, &versioned[VERSION_PREFIX_SIZE..]);
+            assert_eq!(Some(Version::CURRENT), detect_version(&versioned));
+            assert_eq!(input, decode_versioned(&versioned, &hash).unwrap());
+
+            // Encodings in the default format have no prefix.
+            assert_eq!(None, detect_version(&encoded));
+            let err = decode_versioned(&encoded, &hash).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            let inner = err.get_ref().unwrap().downcast_ref::<VersionError>();
+            assert_eq!(Some(&VersionError::Unversioned), inner);
+
+            // A decoder that only knows a later version rejects a v1 encoding up front, with a
+            // clear error rather than a hash mismatch.
+            let err = decode_versioned_inner(&versioned, &hash, &[Version(2)]).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            let inner = err.get_ref().unwrap().downcast_ref::<VersionError>();
+            assert_eq!(Some(&VersionError::Unsupported(Version(1))), inner);
+            assert_eq!("unsupported format version v1", err.to_string());
+
+            // And the v1 decoder rejects an encoding that claims to be v2.
+            let mut v2 = versioned.clone();
+            v2[VERSION_MAGIC.len()] = 2;
+            assert_eq!(Some(Version(2)), detect_version(&v2));
+            let err = decode_versioned(&v2, &hash).unwrap_err();
+            let inner = err.get_ref().unwrap().downcast_ref::<VersionError>();
+            assert_eq!(Some(&VersionError::Unsupported(Version(2))), inner);
+        }
+
+        // The prefix alone is detected, and a cut-off prefix isn't.
+        let (versioned, _) = encode::encode_versioned(b"");
+        assert_eq!(
+            Some(Version::CURRENT),
+            detect_version(&versioned[..VERSION_PREFIX_SIZE])
+        );
+        assert_eq!(None, detect_version(&versioned[..VERSION_PREFIX_SIZE - 1]));
+    }
+
+    #[test]
+    fn test_eof_proof() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let len = case as u64;
+            let proof = encode::eof_proof(&encoded, len).unwrap();
+            verify_eof_proof(&hash, &proof, len).unwrap();
+            assert_eq!(
+                Err(Error::HashMismatch),
+                encode::eof_proof(&encoded, len + 1)
+            );
+
+            // A proof can't claim any other length, even with its header rewritten to match. The
+            // final chunk and the parents above it don't fit a different length.
+            for &other in &[len + 1, len.saturating_sub(1), len + CHUNK_SIZE as u64, 0] {
+                if other == len {
+                    continue;
+                }
+                assert_eq!(
+                    Err(Error::HashMismatch),
+                    verify_eof_proof(&hash, &proof, other)
+                );
+                let mut forged = proof.clone();
+                forged[..HEADER_SIZE].copy_from_slice(&crate::hash::encode_len(other));
+                assert!(verify_eof_proof(&hash, &forged, other).is_err());
+            }
+
+            // A proof for the wrong hash, or one that's cut short, fails too.
+            if case > 0 {
+                let mut bad = proof.clone();
+                *bad.last_mut().unwrap() ^= 1;
+                assert_eq!(Err(Error::HashMismatch), verify_eof_proof(&hash, &bad, len));
+            }
+            assert_eq!(
+                Err(Error::Truncated),
+                verify_eof_proof(&hash, &proof[..proof.len() - 1], len)
+            );
+            assert_eq!(
+                Err(Error::Truncated),
+                verify_eof_proof(&hash, &proof[..HEADER_SIZE - 1], len)
+            );
+        }
+    }
+
+    #[test]
+    fn test_single_byte_proof() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            for &offset in crate::test::TEST_CASES {
+                println!("case {} offset {}", case, offset);
+                let proof = single_byte_proof(&encoded, offset as u64).unwrap();
+                let mut output = Vec::new();
+                let mut reader = SliceDecoder::new(&*proof, &hash, offset as u64, 1);
+                reader.read_to_end(&mut output).unwrap();
+                let expected_start = cmp::min(input.len(), offset);
+                let expected_end = cmp::min(input.len(), offset + 1);
+                assert_eq!(&input[expected_start..expected_end], &*output);
+            }
+        }
+
+        // The proof is the header, one parent node for each level of the tree, and one chunk. A
+        // 1024-chunk tree is 10 levels deep.
+        let input = make_test_input(1024 * CHUNK_SIZE);
+        let (encoded, _) = encode::encode(&input);
+        for &offset in &[0, 1, 512 * CHUNK_SIZE as u64 + 7, input.len() as u64 - 1] {
+            let proof = single_byte_proof(&encoded, offset).unwrap();
+            assert_eq!(HEADER_SIZE + 10 * PARENT_SIZE + CHUNK_SIZE, proof.len());
+        }
+
+        // A short encoding is an error.
+        let err = single_byte_proof(&encoded[..encoded.len() - 1], 0).unwrap_err();
+        assert_eq!(Error::Truncated, err);
+    }
+
     #[test]
     fn test_seek() {
         for &input_len in crate::test::TEST_CASES {
@@ -835,6 +6240,419 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_random_access() {
+        let input_len = 0b100101 * CHUNK_SIZE + 7;
+        let mut prng = ChaChaRng::from_seed([0; 32]);
+        let input = make_test_input(input_len);
+        let (encoded, hash) = encode::encode(&input);
+        let (outboard, _) = encode::outboard(&input);
+        let mut combined = RandomAccess::new(Cursor::new(&encoded), &hash);
+        let mut outboard =
+            RandomAccess::new_outboard(Cursor::new(&input), Cursor::new(&outboard), &hash);
+        assert_eq!(input_len as u64, combined.content_len().unwrap());
+        for _ in 0..1000 {
+            let offset = prng.gen_range(0, input_len + 10);
+            let len = prng.gen_range(0, 3 * CHUNK_SIZE);
+            let start = cmp::min(offset, input_len);
+            let end = cmp::min(offset + len, input_len);
+            let mut buf = vec![0; len];
+            let n = combined.read_at(offset as u64, &mut buf).unwrap();
+            assert_eq!(
+                &input[start..end],
+                &buf[..n],
+                "offset {} len {}",
+                offset,
+                len
+            );
+            let mut buf = vec![0; len];
+            let n = outboard.read_at(offset as u64, &mut buf).unwrap();
+            assert_eq!(
+                &input[start..end],
+                &buf[..n],
+                "offset {} len {}",
+                offset,
+                len
+            );
+        }
+        assert!(!combined.parent_cache().is_empty());
+
+        // Corruption in a chunk is caught by the reads that cover it, and not by other reads.
+        let mut bad_encoded = encoded.clone();
+        *bad_encoded.last_mut().unwrap() ^= 1;
+        let mut random_access = RandomAccess::new(Cursor::new(&bad_encoded), &hash);
+        let mut buf = [0; 10];
+        assert_eq!(10, random_access.read_at(0, &mut buf).unwrap());
+        assert_eq!(&input[..10], &buf[..]);
+        let err = random_access
+            .read_at(input_len as u64 - 5, &mut buf)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_decoder_stats() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+            let chunks = encode::count_chunks(case as u64);
+            assert_eq!(
+                DecoderStats {
+                    encoded_bytes_read: encoded.len() as u64,
+                    content_bytes_returned: case as u64,
+                    parents_verified: chunks - 1,
+                    chunks_verified: chunks,
+                    seeks: 0,
+                },
+                decoder.stats()
+            );
+
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+            let offset = case as u64 / 2;
+            decoder.seek(SeekFrom::Start(offset)).unwrap();
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(&input[offset as usize..], &*output);
+            let stats = decoder.stats();
+            assert_eq!(1, stats.seeks);
+            assert_eq!(case as u64 - offset, stats.content_bytes_returned);
+            assert_eq!(
+                encode::chunks_in_range(case as u64, offset, case as u64),
+                stats.chunks_verified
+            );
+            assert_eq!(
+                encode::parents_in_range(case as u64, offset, case as u64),
+                stats.parents_verified
+            );
+        }
+    }
+
+    #[test]
+    fn test_encoded_position() {
+        let mut cases = crate::test::TEST_CASES.to_vec();
+        cases.push(1 << 20);
+        for case in cases {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            let half = case / 2;
+
+            let mut combined = Decoder::new(&*encoded, &hash);
+            let mut outboard_decoder = Decoder::new_outboard(&*input, &*outboard, &hash);
+            let mut headerless =
+                Decoder::new_with_content_len(&encoded[HEADER_SIZE..], &hash, case as u64);
+            assert_eq!(0, combined.encoded_position());
+            assert_eq!(0, outboard_decoder.encoded_position());
+            assert_eq!(0, headerless.encoded_position());
+
+            // After reading half the content, the position is the end of the chunk holding the
+            // last byte read, since whole chunks are read at once.
+            let mut buf = vec![0; half];
+            combined.read_exact(&mut buf).unwrap();
+            outboard_decoder.read_exact(&mut buf).unwrap();
+            headerless.read_exact(&mut buf).unwrap();
+            if half > 0 {
+                let (_, _, chunk_len, chunk_offset) =
+                    encode::chunk_of_offset(case as u64, half as u64 - 1).unwrap();
+                let expected = chunk_offset + chunk_len;
+                assert_eq!(expected, combined.encoded_position());
+                assert_eq!(expected, outboard_decoder.encoded_position());
+                assert_eq!(expected - HEADER_SIZE as u64, headerless.encoded_position());
+
+                // Roughly half, give or take a chunk and the parent nodes along the way.
+                let slack = CHUNK_SIZE as u64 + PARENT_SIZE as u64 * 64;
+                let half_encoded = encoded.len() as u64 / 2;
+                assert!(expected + slack >= half_encoded && expected <= half_encoded + slack);
+            }
+
+            let mut rest = Vec::new();
+            combined.read_to_end(&mut rest).unwrap();
+            outboard_decoder.read_to_end(&mut Vec::new()).unwrap();
+            headerless.read_to_end(&mut Vec::new()).unwrap();
+            let total = encoded.len() as u64;
+            assert_eq!(total, combined.encoded_position());
+            assert_eq!(total, outboard_decoder.encoded_position());
+            assert_eq!(total - HEADER_SIZE as u64, headerless.encoded_position());
+        }
+    }
+
+    #[test]
+    fn test_debug_hides_content_and_hash() {
+        // A distinctive first chunk, so that any printed content would be easy to spot.
+        let mut input = b"secret".repeat(CHUNK_SIZE / 6 + 1);
+        input.extend_from_slice(&make_test_input(3 * CHUNK_SIZE));
+        let (encoded, hash) = encode::encode(&input);
+        let (outboard, _) = encode::outboard(&input);
+        let check = |debug: String, position: u64| {
+            println!("{}", debug);
+            assert!(debug.contains(&format!("content_position: {}", position)));
+            assert!(!debug.contains(&format!("{:?}", &b"secret"[..])[1..20]));
+            assert!(!debug.contains("secret"));
+            assert!(!debug.contains(&*hash.to_hex()));
+            assert!(!debug.contains(&format!("{:?}", hash.as_bytes())[1..20]));
+        };
+
+        // Read part of the first chunk, so that the rest of it is buffered.
+        let mut buf = [0; 100];
+        let mut decoder = Decoder::new(&*encoded, &hash);
+        decoder.read_exact(&mut buf).unwrap();
+        check(format!("{:?}", decoder), 100);
+        let mut decoder = Decoder::new_outboard(&*input, &*outboard, &hash);
+        decoder.read_exact(&mut buf).unwrap();
+        check(format!("{:?}", decoder), 100);
+
+        let mut slice = Vec::new();
+        encode::SliceExtractor::new(Cursor::new(&encoded), 0, 1000)
+            .read_to_end(&mut slice)
+            .unwrap();
+        let mut decoder = SliceDecoder::new(&*slice, &hash, 0, 1000);
+        decoder.read_exact(&mut buf).unwrap();
+        check(format!("{:?}", decoder), 100);
+    }
+
+    #[test]
+    fn test_slice_for_subtree() {
+        let input = make_test_input(10 * CHUNK_SIZE + 1);
+        let (_, root) = encode::encode(&input);
+        for &sub_len in &[0, 1, CHUNK_SIZE, 2 * CHUNK_SIZE, 8 * CHUNK_SIZE] {
+            let subtree = &input[..sub_len];
+            let subtree_hash = crate::hash::hash_subtree(subtree);
+            let (encoded, subtree_root) = encode::encode(subtree);
+            if sub_len == 8 * CHUNK_SIZE {
+                // This is the left child of the whole input's root node.
+                let root_node = &encode::encode(&input).0[HEADER_SIZE..][..PARENT_SIZE];
+                assert_eq!(&subtree_hash.as_bytes()[..], &root_node[..HASH_SIZE]);
+            }
+            for &(start, len) in &[(0, 0), (0, 1), (1000, 2000), (sub_len as u64, 5)] {
+                let mut slice = Vec::new();
+                encode::SliceExtractor::new(Cursor::new(&encoded), start, len)
+                    .read_to_end(&mut slice)
+                    .unwrap();
+                let decode = |hash: &Hash, content_len: u64| {
+                    let mut output = Vec::new();
+                    SliceDecoder::new_for_subtree(&*slice, hash, content_len, start, len)
+                        .read_to_end(&mut output)
+                        .map(|_| output)
+                };
+                let output = decode(&subtree_hash, sub_len as u64).unwrap();
+                let expected_start = cmp::min(start as usize, sub_len);
+                let expected_end = cmp::min((start + len) as usize, sub_len);
+                assert_eq!(&subtree[expected_start..expected_end], &*output);
+
+                // Neither root hash verifies as a subtree hash, and the wrong length fails too.
+                for bad_hash in &[subtree_root, root] {
+                    let err = decode(bad_hash, sub_len as u64).unwrap_err();
+                    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+                }
+                let err = decode(&subtree_hash, sub_len as u64 + 1).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+                // And the subtree hash doesn't verify as a root.
+                let err = SliceDecoder::new(&*slice, &subtree_hash, start, len)
+                    .read_to_end(&mut Vec::new())
+                    .unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_header() {
+        for &case in crate::test::TEST_CASES {
+            let (encoded, _) = encode::encode(make_test_input(case));
+            let header = *array_ref!(encoded, 0, HEADER_SIZE);
+            let total = encoded.len() as u64;
+            assert_eq!(Ok(case as u64), parse_header(&header, None));
+            assert_eq!(Ok(case as u64), parse_header(&header, Some(total)));
+            assert_eq!(
+                Err(Error::Truncated),
+                parse_header(&header, Some(total - 1))
+            );
+            assert_eq!(
+                Err(Error::HashMismatch),
+                parse_header(&header, Some(total + 1))
+            );
+        }
+
+        // A header claiming a huge length is rejected up front, whether or not the total is
+        // known, and it's never mistaken for the small encoding it arrived with.
+        let max = crate::MAX_CONTENT_LEN;
+        assert_eq!(Ok(max), parse_header(&crate::hash::encode_len(max), None));
+        assert_eq!(
+            Ok(max),
+            parse_header(&crate::hash::encode_len(max), Some(u64::MAX))
+        );
+        for &bad in &[max + 1, u64::MAX] {
+            let header = crate::hash::encode_len(bad);
+            assert_eq!(Err(Error::HashMismatch), parse_header(&header, None));
+            assert_eq!(
+                Err(Error::HashMismatch),
+                parse_header(&header, Some(u64::MAX))
+            );
+        }
+        let header = crate::hash::encode_len(1 << 40);
+        assert_eq!(Err(Error::Truncated), parse_header(&header, Some(1 << 20)));
+    }
+
+    #[test]
+    fn test_into_chunks() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let chunks = into_chunks(&*encoded, &hash)
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(decode(&encoded, &hash).unwrap(), chunks.concat());
+            for (i, chunk) in chunks.iter().enumerate() {
+                assert_eq!(encode::chunk_size(i as u64, case as u64), chunk.len());
+            }
+
+            // Corrupt the last byte of each chunk in turn. Every chunk before it comes out intact,
+            // then the error, and then nothing.
+            if case == 0 {
+                continue;
+            }
+            for chunk_index in 0..encode::count_chunks(case as u64) {
+                let (_, _, chunk_len, encoded_offset) =
+                    encode::chunk_of_offset(case as u64, chunk_index * CHUNK_SIZE as u64).unwrap();
+                let mut corrupt = encoded.clone();
+                corrupt[(encoded_offset + chunk_len - 1) as usize] ^= 1;
+                let mut iter = into_chunks(&*corrupt, &hash);
+                for chunk in &chunks[..chunk_index as usize] {
+                    assert_eq!(*chunk, iter.next().unwrap().unwrap());
+                }
+                let err = iter.next().unwrap().unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+                assert!(iter.next().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_outboard_root() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (outboard, hash) = encode::outboard(&input);
+            if case <= CHUNK_SIZE {
+                // A complete header-only outboard isn't truncated, but it can't give a root.
+                assert_eq!(Ok(None), outboard_root(&outboard, case as u64));
+                assert_eq!(
+                    Err(Error::Truncated),
+                    outboard_root(&outboard[..HEADER_SIZE - 1], case as u64)
+                );
+                // Another single-chunk length doesn't match the header.
+                let other_len = if case == 0 { 1 } else { case as u64 - 1 };
+                assert_eq!(
+                    Err(Error::HashMismatch),
+                    outboard_root(&outboard, other_len)
+                );
+                continue;
+            }
+            assert_eq!(Ok(Some(hash)), outboard_root(&outboard, case as u64));
+
+            // The same length with different content gives a different root.
+            let mut other_input = input.clone();
+            other_input[0] ^= 1;
+            let (other_outboard, other_hash) = encode::outboard(&other_input);
+            assert_eq!(
+                Ok(Some(other_hash)),
+                outboard_root(&other_outboard, case as u64)
+            );
+            assert_ne!(hash, other_hash);
+
+            // Corrupting any hash in the tree is caught, except for the chunk hashes at the
+            // bottom, which only the content can check. The last bytes of the outboard are
+            // always a chunk hash.
+            for i in HEADER_SIZE..outboard.len() - HASH_SIZE {
+                let mut corrupt = outboard.clone();
+                corrupt[i] ^= 1;
+                match outboard_root(&corrupt, case as u64) {
+                    Ok(root) => assert_ne!(Some(hash), root),
+                    Err(e) => assert_eq!(Error::HashMismatch, e),
+                }
+            }
+            assert!(outboard_root(&outboard, case as u64 + 1).is_err());
+            assert_eq!(
+                Err(Error::Truncated),
+                outboard_root(&outboard[..outboard.len() - 1], case as u64)
+            );
+        }
+    }
+
+    #[test]
+    fn test_hash_mismatch_offset() {
+        fn mismatch_offset(err: io::Error) -> u64 {
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<HashMismatchAt>())
+                .expect("no offset")
+                .content_offset
+        }
+
+        let content_len = 10 * CHUNK_SIZE as u64 + 1;
+        let input = make_test_input(content_len as usize);
+        let (encoded, hash) = encode::encode(&input);
+        let (outboard, _) = encode::outboard(&input);
+        for chunk_index in 0..encode::count_chunks(content_len) {
+            let chunk_start = chunk_index * CHUNK_SIZE as u64;
+            let (_, _, _, encoded_offset) =
+                encode::chunk_of_offset(content_len, chunk_start).unwrap();
+
+            // Corrupt the chunk in a combined encoding, streamed through a pipe that isn't
+            // seekable and hands out a few bytes at a time.
+            let mut corrupt = encoded.clone();
+            corrupt[encoded_offset as usize] ^= 1;
+            let (sender, receiver) = std::sync::mpsc::channel();
+            for piece in corrupt.chunks(100) {
+                sender.send(piece.to_vec()).unwrap();
+            }
+            drop(sender);
+            let pipe = PipeReader {
+                receiver,
+                buf: io::Cursor::new(Vec::new()),
+            };
+            let err = Decoder::new(pipe, &hash)
+                .read_to_end(&mut Vec::new())
+                .unwrap_err();
+            assert_eq!(chunk_start, mismatch_offset(err));
+
+            // And the same chunk in the content of an outboard encoding.
+            let mut corrupt_content = input.clone();
+            corrupt_content[chunk_start as usize] ^= 1;
+            let err = Decoder::new_outboard(&*corrupt_content, &*outboard, &hash)
+                .read_to_end(&mut Vec::new())
+                .unwrap_err();
+            assert_eq!(chunk_start, mismatch_offset(err));
+        }
+
+        // A bad parent node reports the start of its subtree. The root node's right child is the
+        // parent of the last 2 chunks, starting at chunk 8.
+        let mut corrupt = encoded.clone();
+        corrupt[HEADER_SIZE
+            + PARENT_SIZE
+            + encode::encoded_subtree_size(8 * CHUNK_SIZE as u64) as usize] ^= 1;
+        let err = Decoder::new(&*corrupt, &hash)
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(8 * CHUNK_SIZE as u64, mismatch_offset(err));
+
+        // Seeking reports offsets too, and they're still hash mismatches as far as Error goes.
+        let mut corrupt = encoded.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let mut decoder = Decoder::new(Cursor::new(&corrupt), &hash);
+        let err = decoder.seek(SeekFrom::End(0)).unwrap_err();
+        assert_eq!(10 * CHUNK_SIZE as u64, mismatch_offset(err));
+        assert_eq!(
+            Error::HashMismatch,
+            Error::from(HashMismatchAt { content_offset: 0 })
+        );
+    }
+
     #[test]
     fn test_invalid_zero_length() {
         // There are different ways of structuring a decoder, and many of them are vulnerable to a
@@ -860,6 +6678,142 @@ mod test {
         assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
     }
 
+    #[test]
+    fn test_zero_length_header_with_trailing_bytes() {
+        let (zero_encoded, zero_hash) = encode::encode(b"");
+        let mut garbage = zero_encoded.clone();
+        garbage.extend_from_slice(&[0xff; 2 * CHUNK_SIZE]);
+
+        // Lenient decoding stops at the end of the encoding and ignores the rest.
+        assert!(decode(&garbage, &zero_hash).unwrap().is_empty());
+        let mut output = Vec::new();
+        Decoder::new(&*garbage, &zero_hash)
+            .read_to_end(&mut output)
+            .unwrap();
+        assert!(output.is_empty());
+        // It still verifies the empty chunk, garbage or not.
+        let err = decode(&garbage, &blake3::hash(b"x")).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // Strict decoding rejects the trailing bytes, but not the exact encoding.
+        assert!(decode_strict(&zero_encoded, &zero_hash).unwrap().is_empty());
+        let err = decode_strict(&garbage, &zero_hash).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // A nonzero length with missing content is truncated in both modes.
+        let (encoded, hash) = encode::encode(make_test_input(3 * CHUNK_SIZE));
+        let truncated = &encoded[..encoded.len() - 1];
+        for result in &[decode(truncated, &hash), decode_strict(truncated, &hash)] {
+            let err = result.as_ref().unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        }
+        // Even if the header is all that's left.
+        let header_only = &encoded[..HEADER_SIZE];
+        for result in &[
+            decode(header_only, &hash),
+            decode_strict(header_only, &hash),
+        ] {
+            let err = result.as_ref().unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        }
+        let err = decode_strict(&encoded[..HEADER_SIZE - 1], &hash).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_metadata_tlv() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let metadata = Metadata {
+                hash,
+                content_len: case as u64,
+            };
+            let tlv = metadata.to_tlv();
+            let parsed = Metadata::from_tlv(&tlv).unwrap();
+            assert_eq!(metadata, parsed);
+
+            // Decode driven entirely by the parsed metadata.
+            let mut output = Vec::new();
+            Decoder::from_metadata(&*encoded, &parsed)
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(input, output);
+
+            // A wrong content length fails, even though the hash is right.
+            let wrong = Metadata {
+                hash,
+                content_len: case as u64 + 1,
+            };
+            let err = Decoder::from_metadata(&*encoded, &wrong)
+                .read_to_end(&mut Vec::new())
+                .unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+
+        let tlv = Metadata {
+            hash: blake3::hash(b"foo"),
+            content_len: 42,
+        }
+        .to_tlv();
+
+        // Unknown optional fields are skipped, in any position after the version.
+        let mut extended = tlv.clone();
+        extended.splice(3..3, vec![0x80, 2, 0xaa, 0xbb]);
+        extended.extend_from_slice(&[0xff, 0]);
+        assert_eq!(Metadata::from_tlv(&tlv), Metadata::from_tlv(&extended));
+
+        // Unknown required fields are rejected.
+        let mut required = tlv.clone();
+        required.extend_from_slice(&[0x7f, 1, 0]);
+        assert_eq!(
+            Err(MetadataError::UnknownRequiredField(0x7f)),
+            Metadata::from_tlv(&required)
+        );
+
+        // So are other versions, and a version that isn't first.
+        let mut version = tlv.clone();
+        version[2] = 2;
+        assert_eq!(
+            Err(MetadataError::UnsupportedVersion(2)),
+            Metadata::from_tlv(&version)
+        );
+        let mut moved = tlv[3..].to_vec();
+        moved.extend_from_slice(&tlv[..3]);
+        assert_eq!(Err(MetadataError::Malformed), Metadata::from_tlv(&moved));
+        assert_eq!(Err(MetadataError::Malformed), Metadata::from_tlv(&[]));
+
+        // Truncation is malformed or missing a field, never a panic. Cutting off just the
+        // trailing chunk size record is fine, since that one's optional.
+        assert!(Metadata::from_tlv(&tlv[..tlv.len() - 10]).is_ok());
+        for i in (0..tlv.len()).filter(|&i| i != tlv.len() - 10) {
+            assert!(Metadata::from_tlv(&tlv[..i]).is_err());
+        }
+        let without_len = &tlv[..3 + 2 + HASH_SIZE];
+        assert_eq!(
+            Err(MetadataError::MissingField(TLV_CONTENT_LEN)),
+            Metadata::from_tlv(without_len)
+        );
+
+        // Duplicates, wrong widths, and other chunk sizes are malformed.
+        let mut duplicate = tlv.clone();
+        duplicate.extend_from_slice(&tlv[3 + 2 + HASH_SIZE..3 + 2 + HASH_SIZE + 10]);
+        assert_eq!(
+            Err(MetadataError::Malformed),
+            Metadata::from_tlv(&duplicate)
+        );
+        let mut chunk_size = tlv.clone();
+        let last = chunk_size.len() - 7;
+        chunk_size[last] ^= 1;
+        assert_eq!(
+            Err(MetadataError::Malformed),
+            Metadata::from_tlv(&chunk_size)
+        );
+        let mut short_hash = tlv.clone();
+        short_hash[4] = 31;
+        assert!(Metadata::from_tlv(&short_hash).is_err());
+    }
+
     #[test]
     fn test_seeking_around_invalid_data() {
         for &case in crate::test::TEST_CASES {
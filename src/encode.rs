@@ -12,6 +12,10 @@
 //! copying any input bytes. The outboard encoding is much smaller, but it can
 //! only be used together with the original input file.
 //!
+//! Encoding is deterministic. The shape of the tree depends only on the input length, so the
+//! encoded bytes depend only on the input bytes, and not on how the input is split into writes or
+//! how many threads hash it.
+//!
 //! # Example
 //!
 //! ```
@@ -37,14 +41,18 @@
 //! ```
 
 use crate::Finalization::{self, NotRoot, Root};
-use crate::{Hash, ParentNode, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, MAX_DEPTH, PARENT_SIZE};
-use arrayref::array_mut_ref;
+use crate::{
+    Hash, ParentNode, CHUNK_SIZE, CRC_SIZE, HASH_SIZE, HEADER_SIZE, MAX_DEPTH, PARENT_SIZE,
+    TRAILER_MAGIC, VERSION_MAGIC, VERSION_PREFIX_SIZE,
+};
+use arrayref::{array_mut_ref, array_ref};
 use arrayvec::ArrayVec;
 use std::cmp;
 use std::fmt;
 use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
+use std::sync::{Arc, Mutex};
 
 /// Encode an entire slice into a bytes vector in the default combined mode.
 /// This is a convenience wrapper around `Encoder::write_all`.
@@ -68,12 +76,565 @@ pub fn outboard(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
     (vec, hash)
 }
 
+/// Encode the content from a reader into a bytes vector in the combined mode, when the content
+/// length is known in advance, like from an HTTP `Content-Length`. Because the length fixes the
+/// shape of the tree, this writes every chunk straight into its place in a buffer of exactly
+/// `encoded_size(content_len)`, without the post-order flip that `Encoder` needs. That buffer is
+/// allocated up front, so don't pass an untrusted length without bounding it first.
+///
+/// # Errors
+///
+/// `UnexpectedEof` if the reader ends early, and `InvalidData` if it has more than `content_len`
+/// bytes. `InvalidInput` if the encoding wouldn't fit in memory.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 10_000];
+/// let (encoded, hash) = bao::encode::encode_reader_sized(&*input, 10_000)?;
+/// assert_eq!(bao::encode::encode(&input), (encoded, hash));
+/// # Ok(())
+/// # }
+/// ```
+pub fn encode_reader_sized(mut reader: impl Read, content_len: u64) -> io::Result<(Vec<u8>, Hash)> {
+    let size = encoded_size(content_len);
+    if size > isize::MAX as u128 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "encoding too large for memory",
+        ));
+    }
+    let mut encoded = vec![0; size as usize];
+    encoded[..HEADER_SIZE].copy_from_slice(&crate::hash::encode_len(content_len));
+    let hash = fill_subtree(
+        &mut reader,
+        &mut encoded[HEADER_SIZE..],
+        content_len,
+        0,
+        Root,
+    )?;
+    let mut extra = [0];
+    loop {
+        match reader.read(&mut extra) {
+            Ok(0) => return Ok((encoded, hash)),
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "reader longer than content_len",
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Read the content of a subtree into its pre-order encoding, filling in the parent nodes on the
+// way back up, and return its hash. Pre-order keeps the content in order, so the reads are too.
+fn fill_subtree(
+    reader: &mut impl Read,
+    output: &mut [u8],
+    len: u64,
+    chunk_index: u64,
+    finalization: Finalization,
+) -> io::Result<Hash> {
+    if len <= CHUNK_SIZE as u64 {
+        let chunk = &mut output[..len as usize];
+        reader.read_exact(chunk)?;
+        return Ok(crate::hash::hash_subtree_inner(
+            chunk,
+            chunk_index,
+            finalization,
+        ));
+    }
+    let left_len = left_subtree_len(len);
+    let (parent, children) = output.split_at_mut(PARENT_SIZE);
+    let (left, right) = children.split_at_mut(encoded_subtree_size(left_len) as usize);
+    let left_hash = fill_subtree(reader, left, left_len, chunk_index, NotRoot)?;
+    let right_index = chunk_index + left_len / CHUNK_SIZE as u64;
+    let right_hash = fill_subtree(reader, right, len - left_len, right_index, NotRoot)?;
+    parent[..HASH_SIZE].copy_from_slice(left_hash.as_bytes());
+    parent[HASH_SIZE..].copy_from_slice(right_hash.as_bytes());
+    Ok(blake3::guts::parent_cv(
+        &left_hash,
+        &right_hash,
+        finalization.is_root(),
+    ))
+}
+
+/// Encode an entire slice into a bytes vector in the combined mode, with the final chunk
+/// zero-padded to a full `CHUNK_SIZE`. The header still holds the true content length, and the
+/// hash is the same as for `encode`. This is for storage that can only write whole chunks. Every
+/// chunk in the result has `CHUNK_SIZE` bytes, and its size is `encoded_size` of the padded length.
+/// Decode it with `decode::decode_padded`.
+///
+/// The final chunk is always the last thing in a combined encoding, so the padding simply follows
+/// a regular encoding. It isn't part of the tree, and it isn't verified.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (encoded, hash) = bao::encode::encode_padded(b"some input");
+/// assert_eq!(bao::encode::encoded_size(blake3::CHUNK_LEN as u64) as usize, encoded.len());
+/// assert_eq!(b"some input", &*bao::decode::decode_padded(&encoded, &hash)?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn encode_padded(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
+    let bytes = input.as_ref();
+    let (mut vec, hash) = encode(bytes);
+    vec.resize(vec.len() + final_chunk_padding(bytes.len() as u64), 0);
+    (vec, hash)
+}
+
+/// Like `encode`, but prefix the encoding with a 4-byte magic and the format version, so that a
+/// decoder can tell which version of the format it's looking at with `decode::detect_version`. The
+/// default format has no prefix, and this framing is opt-in. Decode it with
+/// `decode::decode_versioned`.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use bao::decode::Version;
+///
+/// let (encoded, hash) = bao::encode::encode_versioned(b"some input");
+/// assert_eq!(Some(Version::CURRENT), bao::decode::detect_version(&encoded));
+/// assert_eq!(b"some input", &*bao::decode::decode_versioned(&encoded, &hash)?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn encode_versioned(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
+    // The encoder seeks to absolute offsets when it finalizes, so the prefix can't be written
+    // ahead of it in the same buffer.
+    let (encoded, hash) = encode(input);
+    let mut vec = Vec::with_capacity(VERSION_PREFIX_SIZE + encoded.len());
+    vec.extend_from_slice(&VERSION_MAGIC);
+    vec.push(crate::decode::Version::CURRENT.number());
+    vec.extend_from_slice(&encoded);
+    (vec, hash)
+}
+
+// The number of zero bytes that fill the final chunk out to CHUNK_SIZE.
+pub(crate) fn final_chunk_padding(content_len: u64) -> usize {
+    let final_chunk_len = content_len - (count_chunks(content_len) - 1) * CHUNK_SIZE as u64;
+    CHUNK_SIZE - final_chunk_len as usize
+}
+
+/// Encode an entire slice into a bytes vector in the parents-first layout. That's the header, then
+/// all the parent nodes in pre-order, then all the content bytes. In other words, it's the outboard
+/// encoding with the content appended.
+///
+/// Decoding this layout with `decode::ParentsFirstReader` verifies the entire tree before reading
+/// any content, so a receiver can reject a bad tree without downloading the content behind it.
+pub fn encode_parents_first(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
+    let bytes = input.as_ref();
+    let capacity = encoded_size(bytes.len() as u64) as usize;
+    let mut vec = Vec::with_capacity(capacity);
+    let mut encoder = Encoder::new_outboard(io::Cursor::new(&mut vec));
+    encoder.write_all(bytes).unwrap();
+    let hash = encoder.finalize().unwrap();
+    vec.extend_from_slice(bytes);
+    (vec, hash)
+}
+
+/// Encode an entire slice into a bytes vector in the post-order layout. This is a convenience
+/// wrapper around `PostorderEncoder`.
+pub fn encode_postorder(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
+    let bytes = input.as_ref();
+    let mut vec = Vec::with_capacity(encoded_size(bytes.len() as u64) as usize);
+    let mut encoder = PostorderEncoder::new(&mut vec);
+    encoder.write_all(bytes).unwrap();
+    let hash = encoder.finalize().unwrap();
+    (vec, hash)
+}
+
+/// Build the combined encoding of the concatenation of two inputs from their combined encodings,
+/// `enc_a` of `len_a` content bytes followed by `enc_b` of `len_b` content bytes.
+///
+/// Every complete subtree of `a` is also a subtree of the combined tree, in the same position and
+/// with the same hash, so those are copied over without hashing their content. The nodes along
+/// the right edge of `a`, and all of `b`, need to be hashed again. That includes `b`'s chunks,
+/// because a chunk's hash depends on its index, and the index of every chunk in `b` changes.
+///
+/// This doesn't verify either encoding. Any bad parent nodes in `enc_a` get carried over into the
+/// result, so decode the inputs first if they aren't trusted.
+///
+/// # Panics
+///
+/// Panics if either encoding isn't the right size for its content length, or if its header
+/// doesn't match that length.
+///
+/// # Example
+///
+/// ```
+/// let (encoded_a, _) = bao::encode::encode(b"foo");
+/// let (encoded_b, _) = bao::encode::encode(b"bar");
+/// let (encoded, hash) = bao::encode::concat(&encoded_a, 3, &encoded_b, 3);
+/// assert_eq!(bao::encode::encode(b"foobar"), (encoded, hash));
+/// ```
+pub fn concat(enc_a: &[u8], len_a: u64, enc_b: &[u8], len_b: u64) -> (Vec<u8>, Hash) {
+    for &(encoded, len) in &[(enc_a, len_a), (enc_b, len_b)] {
+        assert_eq!(
+            encoded_size(len),
+            encoded.len() as u128,
+            "wrong encoding size"
+        );
+        assert_eq!(
+            &crate::hash::encode_len(len)[..],
+            &encoded[..HEADER_SIZE],
+            "wrong header"
+        );
+    }
+    let total_len = len_a.checked_add(len_b).expect("length overflow");
+    let mut content = Vec::with_capacity(total_len as usize);
+    append_subtree_content(&enc_a[HEADER_SIZE..], len_a, &mut content);
+    append_subtree_content(&enc_b[HEADER_SIZE..], len_b, &mut content);
+    let mut output = Vec::with_capacity(encoded_size(total_len) as usize);
+    output.extend_from_slice(&crate::hash::encode_len(total_len));
+    let tree_a = &enc_a[HEADER_SIZE..];
+    let hash = concat_recurse(tree_a, len_a, &content, 0, Root, &mut output);
+    (output, hash)
+}
+
+// Append the subtree rooted at content offset `start` to the output, and return its hash. The
+// subtree covers the rest of `content`, or its left subtree for non-root calls.
+fn concat_recurse(
+    tree_a: &[u8],
+    len_a: u64,
+    content: &[u8],
+    start: u64,
+    finalization: Finalization,
+    output: &mut Vec<u8>,
+) -> Hash {
+    let len = content.len() as u64;
+    if !finalization.is_root() && start + len <= len_a {
+        if let Some(offset) = encoded_subtree_offset(len_a, start, len) {
+            let subtree = &tree_a[offset as usize..][..encoded_subtree_size(len) as usize];
+            output.extend_from_slice(subtree);
+            if len <= CHUNK_SIZE as u64 {
+                let chunk_index = start / CHUNK_SIZE as u64;
+                return crate::hash::hash_subtree_inner(content, chunk_index, finalization);
+            }
+            let left: Hash = (*array_ref!(subtree, 0, HASH_SIZE)).into();
+            let right: Hash = (*array_ref!(subtree, HASH_SIZE, HASH_SIZE)).into();
+            return blake3::guts::parent_cv(&left, &right, false);
+        }
+    }
+    if len <= CHUNK_SIZE as u64 {
+        output.extend_from_slice(content);
+        let chunk_index = start / CHUNK_SIZE as u64;
+        return crate::hash::hash_subtree_inner(content, chunk_index, finalization);
+    }
+    let left_len = left_subtree_len(len);
+    let parent_position = output.len();
+    output.extend_from_slice(&[0; PARENT_SIZE]);
+    let (left_content, right_content) = content.split_at(left_len as usize);
+    let left = concat_recurse(tree_a, len_a, left_content, start, NotRoot, output);
+    let right = concat_recurse(
+        tree_a,
+        len_a,
+        right_content,
+        start + left_len,
+        NotRoot,
+        output,
+    );
+    let parent = array_mut_ref!(output, parent_position, PARENT_SIZE);
+    parent[..HASH_SIZE].copy_from_slice(left.as_bytes());
+    parent[HASH_SIZE..].copy_from_slice(right.as_bytes());
+    blake3::guts::parent_cv(&left, &right, finalization.is_root())
+}
+
+// If the content range is a node in the tree of the given length, return the offset of its
+// subtree in the encoding, not counting the header.
+fn encoded_subtree_offset(content_len: u64, start: u64, len: u64) -> Option<u64> {
+    let mut node_start = 0;
+    let mut node_len = content_len;
+    let mut offset = 0;
+    loop {
+        if node_start == start && node_len == len {
+            return Some(offset);
+        }
+        if node_len <= CHUNK_SIZE as u64 || start < node_start {
+            return None;
+        }
+        let left_len = left_subtree_len(node_len);
+        offset += PARENT_SIZE as u64;
+        if start < node_start + left_len {
+            node_len = left_len;
+        } else {
+            offset += encoded_subtree_size(left_len) as u64;
+            node_start += left_len;
+            node_len -= left_len;
+        }
+    }
+}
+
+// Copy the content bytes out of an encoded subtree, skipping the parent nodes.
+pub(crate) fn append_subtree_content(subtree: &[u8], len: u64, output: &mut Vec<u8>) {
+    if len <= CHUNK_SIZE as u64 {
+        output.extend_from_slice(&subtree[..len as usize]);
+        return;
+    }
+    let left_len = left_subtree_len(len);
+    let left_size = encoded_subtree_size(left_len) as usize;
+    let (left, right) = subtree[PARENT_SIZE..].split_at(left_size);
+    append_subtree_content(left, left_len, output);
+    append_subtree_content(right, len - left_len, output);
+}
+
+/// Rebuild the parent nodes of a damaged combined encoding from its content. The header and the
+/// content chunks are taken as they are, and none of the parent nodes are trusted. The tree is
+/// re-hashed from the content, and the result is returned only if its root matches `hash`, so
+/// damage to the header or to the content makes this fail instead. Trailing bytes past the end of
+/// the encoding are ignored, like the decoder ignores them.
+///
+/// # Errors
+///
+/// `Truncated` if the encoding is shorter than its header says, and `HashMismatch` if the
+/// rebuilt root doesn't match.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 3000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let mut damaged = encoded.clone();
+/// damaged[8] ^= 1; // the first byte of the root node
+/// assert_eq!(encoded, bao::encode::rebuild_tree(&damaged, &hash)?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn rebuild_tree(damaged: &[u8], hash: &Hash) -> Result<Vec<u8>, crate::decode::Error> {
+    if damaged.len() < HEADER_SIZE {
+        return Err(crate::decode::Error::Truncated);
+    }
+    let content_len = crate::hash::decode_len(array_ref!(damaged, 0, HEADER_SIZE));
+    // Checking the size first also keeps a damaged header from causing a huge allocation.
+    if encoded_size(content_len) > damaged.len() as u128 {
+        return Err(crate::decode::Error::Truncated);
+    }
+    let mut content = Vec::with_capacity(content_len as usize);
+    append_subtree_content(&damaged[HEADER_SIZE..], content_len, &mut content);
+    let (rebuilt, rebuilt_hash) = encode(&content);
+    // Hash implements constant time equality.
+    if &rebuilt_hash != hash {
+        return Err(crate::decode::Error::HashMismatch);
+    }
+    Ok(rebuilt)
+}
+
+/// Extract the smallest slice of a combined encoding that proves the content is exactly
+/// `content_len` bytes long: the header, the final chunk, and the parent nodes along the right
+/// edge of the tree above it. The root hash commits to the length, and the final chunk is the
+/// one node that can only verify if the length is right, so nothing else is needed. Check it with
+/// `decode::verify_eof_proof`. This is the same as `decode::single_byte_proof` at `content_len`.
+///
+/// The length header of `encoded` has to match `content_len`, or this returns `HashMismatch`, and
+/// an encoding that's shorter than its header says is `Truncated`. Nothing is hashed here, so a
+/// proof extracted from a corrupt encoding will fail verification.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0xab; 1_000_000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let proof = bao::encode::eof_proof(&encoded, 1_000_000)?;
+/// assert!(proof.len() < 2048);
+/// bao::decode::verify_eof_proof(&hash, &proof, 1_000_000)?;
+/// assert!(bao::decode::verify_eof_proof(&hash, &proof, 999_999).is_err());
+/// # Ok(())
+/// # }
+/// ```
+pub fn eof_proof(encoded: &[u8], content_len: u64) -> Result<Vec<u8>, crate::decode::Error> {
+    let header = encoded
+        .get(..HEADER_SIZE)
+        .ok_or(crate::decode::Error::Truncated)?;
+    if crate::hash::decode_len(array_ref!(header, 0, HEADER_SIZE)) != content_len {
+        return Err(crate::decode::Error::HashMismatch);
+    }
+    crate::decode::single_byte_proof(encoded, content_len)
+}
+
+/// Given the content ranges that a client already has, as `(start, end)` pairs with `end`
+/// exclusive, compute the byte ranges of a combined encoding that it still needs in order to
+/// verify the rest of the content. That's every chunk that isn't entirely covered by `have`, and
+/// every parent node on the path from the root down to one of those chunks, along with the
+/// header. The result is sorted, and adjacent ranges are merged. This is what `bao plan` prints.
+///
+/// Parent nodes are included even where `have` covers their siblings, because `have` says
+/// nothing about which parent nodes the client kept.
+///
+/// Returns `None` if the combined encoding is too long for its offsets to fit in a `u64`.
+///
+/// # Example
+///
+/// ```
+/// // Nothing yet: the whole encoding.
+/// let encoded_len = bao::encode::encoded_size(5000) as u64;
+/// assert_eq!(Some(vec![(0, encoded_len)]), bao::encode::required_encoded_ranges(5000, &[]));
+/// // Everything: just the header.
+/// assert_eq!(Some(vec![(0, 8)]), bao::encode::required_encoded_ranges(5000, &[(0, 5000)]));
+/// ```
+pub fn required_encoded_ranges(content_len: u64, have: &[(u64, u64)]) -> Option<Vec<(u64, u64)>> {
+    fn push_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+        if start == end {
+            return;
+        }
+        match ranges.last_mut() {
+            Some((_, last_end)) if *last_end == start => *last_end = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+
+    fn recurse(
+        have: &[(u64, u64)],
+        start: u64,
+        len: u64,
+        encoded_offset: u64,
+        ranges: &mut Vec<(u64, u64)>,
+    ) {
+        // The ranges in have are sorted and merged, so the first one that ends after the start of
+        // this subtree is the only one that can cover it, and if it starts after the end of the
+        // subtree, nothing in have touches it.
+        let end = start + len;
+        let i = have.partition_point(|&(_, have_end)| have_end <= start);
+        match have.get(i) {
+            Some(&(have_start, have_end)) if have_start <= start && end <= have_end => return,
+            Some(&(have_start, _)) if have_start < end => {}
+            // The whole subtree is missing, so it's needed in full.
+            _ => {
+                push_range(
+                    ranges,
+                    encoded_offset,
+                    encoded_offset + encoded_subtree_size(len) as u64,
+                );
+                return;
+            }
+        }
+        if len <= CHUNK_SIZE as u64 {
+            push_range(ranges, encoded_offset, encoded_offset + len);
+            return;
+        }
+        push_range(ranges, encoded_offset, encoded_offset + PARENT_SIZE as u64);
+        let left_len = left_subtree_len(len);
+        let left_offset = encoded_offset + PARENT_SIZE as u64;
+        let right_offset = left_offset + encoded_subtree_size(left_len) as u64;
+        recurse(have, start, left_len, left_offset, ranges);
+        recurse(have, start + left_len, len - left_len, right_offset, ranges);
+    }
+
+    // Every offset below is at most the encoded size, so checking that once up front means the
+    // arithmetic in recurse can't overflow.
+    if encoded_size(content_len) > u64::MAX as u128 {
+        return None;
+    }
+
+    // Sort and merge the ranges in have, so that coverage checks are simple.
+    let mut sorted_have: Vec<(u64, u64)> = have.iter().filter(|(s, e)| s < e).cloned().collect();
+    sorted_have.sort_unstable();
+    let mut merged_have: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in sorted_have {
+        match merged_have.last_mut() {
+            Some((_, last_end)) if *last_end >= start => *last_end = cmp::max(*last_end, end),
+            _ => merged_have.push((start, end)),
+        }
+    }
+
+    let mut ranges = Vec::new();
+    push_range(&mut ranges, 0, HEADER_SIZE as u64);
+    recurse(
+        &merged_have,
+        0,
+        content_len,
+        HEADER_SIZE as u64,
+        &mut ranges,
+    );
+    Some(ranges)
+}
+
+/// Compute a CRC sidecar for an entire slice. This is a convenience wrapper around
+/// `CrcSidecar::write_all`.
+///
+/// The sidecar is **not** a security feature. See `CrcSidecar` for details.
+pub fn crc_sidecar(input: impl AsRef<[u8]>) -> Vec<u8> {
+    let bytes = input.as_ref();
+    let mut sidecar = CrcSidecar::new();
+    sidecar.write_all(bytes).unwrap();
+    sidecar.finalize()
+}
+
+/// Compute the size of a CRC sidecar, given the size of the input.
+pub fn crc_sidecar_size(content_len: u64) -> u128 {
+    count_chunks(content_len) as u128 * CRC_SIZE as u128
+}
+
+/// Append a CRC trailer to a combined encoding: the CRC32 of every byte in `encoded`, from the
+/// start through the current end, followed by a 4-byte magic. `decode::check_trailer_crc` checks
+/// it, and `bao encode --trailer-crc` writes it. Decoders ignore bytes after the end of an
+/// encoding, so an encoding with a trailer still decodes as usual. This reads `encoded` from the
+/// start, and leaves it positioned at the end of the trailer.
+///
+/// Like the CRC sidecar, the trailer is **not** a security feature. It's a cheap way to catch
+/// accidental corruption of the encoded file itself, for example in transit. Decoding against the
+/// root hash is always authoritative.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (encoded, hash) = bao::encode::encode(b"some input");
+/// let mut cursor = std::io::Cursor::new(encoded);
+/// bao::encode::append_trailer_crc(&mut cursor)?;
+/// let with_trailer = cursor.into_inner();
+/// assert!(bao::decode::check_trailer_crc(&with_trailer)?);
+/// assert_eq!(b"some input", &*bao::decode::decode(&with_trailer, &hash)?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn append_trailer_crc<T: Read + Write + Seek>(encoded: &mut T) -> io::Result<()> {
+    encoded.seek(SeekFrom::Start(0))?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = [0; 65536];
+    loop {
+        let n = match encoded.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buf[..n]);
+    }
+    encoded.write_all(&hasher.finalize().to_le_bytes())?;
+    encoded.write_all(&TRAILER_MAGIC)
+}
+
 /// Compute the size of a combined encoding, given the size of the input. Note that for input sizes
 /// close to `u64::MAX`, the result can overflow a `u64`.
 pub fn encoded_size(content_len: u64) -> u128 {
     content_len as u128 + outboard_size(content_len)
 }
 
+/// The largest content length whose combined encoding fits in a `u64`, the same as
+/// `MAX_CONTENT_LEN`. Callers can check untrusted lengths against this up front, before any of the
+/// functions here that return `None` for longer ones.
+///
+/// # Example
+///
+/// ```
+/// let max = bao::encode::max_content_len();
+/// assert_eq!(u64::MAX as u128, bao::encode::encoded_size(max));
+/// assert!(bao::encode::chunk_of_offset(max, 0).is_some());
+/// assert!(bao::encode::chunk_of_offset(max + 1, 0).is_none());
+/// ```
+pub fn max_content_len() -> u64 {
+    crate::MAX_CONTENT_LEN
+}
+
 /// Compute the size of an outboard encoding, given the size of the input.
 pub fn outboard_size(content_len: u64) -> u128 {
     // Should the return type here really by u128? Two reasons: 1) It's convenient to use the same
@@ -82,6 +643,153 @@ pub fn outboard_size(content_len: u64) -> u128 {
     outboard_subtree_size(content_len) + HEADER_SIZE as u128
 }
 
+/// Compute the number of bytes a combined encoding adds on top of the input, the header plus all
+/// the parent nodes. This is the same as the size of an outboard encoding, and unlike
+/// `encoded_size` it always fits in a `u64`.
+pub fn overhead_bytes(content_len: u64) -> u64 {
+    outboard_size(content_len) as u64
+}
+
+/// Compute the overhead of a combined encoding as a fraction of the input size. For large inputs
+/// this approaches `PARENT_SIZE / CHUNK_SIZE`, or 6.25%, but for small inputs the 8-byte header
+/// dominates. The ratio for an empty input is infinite.
+pub fn overhead_ratio(content_len: u64) -> f64 {
+    overhead_bytes(content_len) as f64 / content_len as f64
+}
+
+/// Find the chunk containing the content byte at `offset`, in the tree of an input of length
+/// `content_len`. This returns the chunk's index, the start and length of its content range, and
+/// the offset of its content bytes in a combined encoding, after the header and any parent nodes
+/// that come before it. In an outboard encoding, the chunk's content is at its content start in
+/// the input. An offset of zero in the empty input is in the single empty chunk.
+///
+/// Returns `None` if `offset` is past the end of the content, or if the combined encoding is too
+/// long for its offsets to fit in a `u64`.
+///
+/// # Example
+///
+/// ```
+/// let content_len = 3 * blake3::CHUNK_LEN as u64;
+/// let (index, start, len, encoded_offset) =
+///     bao::encode::chunk_of_offset(content_len, 2500).unwrap();
+/// assert_eq!((2, 2048, 1024), (index, start, len));
+///
+/// let input = vec![0xab; content_len as usize];
+/// let (encoded, _) = bao::encode::encode(&input);
+/// let encoded_chunk = &encoded[encoded_offset as usize..][..len as usize];
+/// assert_eq!(&input[start as usize..][..len as usize], encoded_chunk);
+/// ```
+pub fn chunk_of_offset(content_len: u64, offset: u64) -> Option<(u64, u64, u64, u64)> {
+    if encoded_size(content_len) > u64::MAX as u128 {
+        return None;
+    }
+    if offset >= content_len && !(offset == 0 && content_len == 0) {
+        return None;
+    }
+    let index = offset / CHUNK_SIZE as u64;
+    let start = index * CHUNK_SIZE as u64;
+    let len = chunk_size(index, content_len) as u64;
+    let subtree_offset =
+        encoded_subtree_offset(content_len, start, len).expect("every chunk is a subtree");
+    Some((index, start, len, HEADER_SIZE as u64 + subtree_offset))
+}
+
+// The chunk indices that a slice of [start, start+len) covers, end exclusive. A slice always holds
+// at least one chunk, so an empty range covers the chunk at start, or the final chunk if start is
+// at or past the end.
+fn slice_chunk_range(content_len: u64, start: u64, len: u64) -> (u64, u64) {
+    let total_chunks = count_chunks(content_len);
+    if len == 0 || start >= content_len {
+        let chunk = cmp::min(start / CHUNK_SIZE as u64, total_chunks - 1);
+        return (chunk, chunk + 1);
+    }
+    let end = cmp::min(start.saturating_add(len), content_len);
+    (start / CHUNK_SIZE as u64, (end - 1) / CHUNK_SIZE as u64 + 1)
+}
+
+/// Count the chunks that a slice of `len` bytes starting at `start` covers, in the tree of an
+/// input of length `content_len`. That's every chunk overlapping the range, after it's clipped to
+/// the content. Like a slice, an empty range still covers one chunk: the chunk at `start`, or the
+/// final chunk if `start` is at or past the end. This is a measure of how much hashing a slice
+/// request costs, for example to rate-limit them.
+///
+/// # Example
+///
+/// ```
+/// let content_len = 10 * blake3::CHUNK_LEN as u64;
+/// assert_eq!(1, bao::encode::chunks_in_range(content_len, 100, 200));
+/// assert_eq!(2, bao::encode::chunks_in_range(content_len, 1000, 200));
+/// assert_eq!(10, bao::encode::chunks_in_range(content_len, 0, content_len));
+/// ```
+pub fn chunks_in_range(content_len: u64, start: u64, len: u64) -> u64 {
+    let (first_chunk, end_chunk) = slice_chunk_range(content_len, start, len);
+    end_chunk - first_chunk
+}
+
+/// The indices of the chunks that overlap the content range `[start, start+len)`, in the tree of
+/// an input of length `content_len`. The range is clipped to the content first. Unlike
+/// `chunks_in_range`, which follows slices, an empty range here covers no chunks at all, and
+/// neither does a range that starts at or past the end. This is what a scheduler that fetches
+/// chunks by index iterates over.
+///
+/// # Example
+///
+/// ```
+/// let content_len = 10 * blake3::CHUNK_LEN as u64;
+/// assert_eq!(0..1, bao::encode::chunk_range(content_len, 100, 200));
+/// assert_eq!(0..2, bao::encode::chunk_range(content_len, 1000, 200));
+/// assert_eq!(9..10, bao::encode::chunk_range(content_len, 9500, 1_000_000));
+/// assert!(bao::encode::chunk_range(content_len, 5000, 0).is_empty());
+/// ```
+pub fn chunk_range(content_len: u64, start: u64, len: u64) -> std::ops::Range<u64> {
+    if len == 0 || start >= content_len {
+        let chunk = cmp::min(start, content_len).div_ceil(CHUNK_SIZE as u64);
+        return chunk..chunk;
+    }
+    let (first_chunk, end_chunk) = slice_chunk_range(content_len, start, len);
+    first_chunk..end_chunk
+}
+
+/// Count the distinct parent nodes on the paths from the root down to the chunks that
+/// `chunks_in_range` counts. These are the parent nodes a slice of the same range includes, and
+/// the ones a decoder has to verify to reach that content.
+///
+/// This walks at most two paths down the tree, so it's cheap even for huge ranges.
+///
+/// # Example
+///
+/// ```
+/// let content_len = 4 * blake3::CHUNK_LEN as u64;
+/// // The root, and the parent of chunks 0 and 1.
+/// assert_eq!(2, bao::encode::parents_in_range(content_len, 0, 1));
+/// // The whole tree has one fewer parent than chunks.
+/// assert_eq!(3, bao::encode::parents_in_range(content_len, 0, content_len));
+/// ```
+pub fn parents_in_range(content_len: u64, start: u64, len: u64) -> u64 {
+    fn recurse(subtree_start: u64, subtree_len: u64, first_chunk: u64, end_chunk: u64) -> u64 {
+        let subtree_chunks = count_chunks(subtree_len);
+        let subtree_first_chunk = subtree_start / CHUNK_SIZE as u64;
+        let subtree_end_chunk = subtree_first_chunk + subtree_chunks;
+        if subtree_end_chunk <= first_chunk || end_chunk <= subtree_first_chunk {
+            return 0;
+        }
+        if first_chunk <= subtree_first_chunk && subtree_end_chunk <= end_chunk {
+            return subtree_chunks - 1;
+        }
+        // A partially covered subtree is more than one chunk, so it has children.
+        let left_len = left_subtree_len(subtree_len);
+        1 + recurse(subtree_start, left_len, first_chunk, end_chunk)
+            + recurse(
+                subtree_start + left_len,
+                subtree_len - left_len,
+                first_chunk,
+                end_chunk,
+            )
+    }
+    let (first_chunk, end_chunk) = slice_chunk_range(content_len, start, len);
+    recurse(0, content_len, first_chunk, end_chunk)
+}
+
 pub(crate) fn encoded_subtree_size(content_len: u64) -> u128 {
     content_len as u128 + outboard_subtree_size(content_len)
 }
@@ -107,6 +815,16 @@ pub(crate) fn chunk_size(chunk_index: u64, content_len: u64) -> usize {
     cmp::min(CHUNK_SIZE, (content_len - chunk_start) as usize)
 }
 
+// The left subtree of any parent node is the largest power-of-two number of chunks that still
+// leaves at least one byte for the right subtree. The content_len must be more than one chunk.
+pub(crate) fn left_subtree_len(content_len: u64) -> u64 {
+    debug_assert!(content_len > CHUNK_SIZE as u64);
+    let full_chunks = (content_len - 1) / CHUNK_SIZE as u64;
+    // The highest set bit of full_chunks, i.e. the largest power of two <= full_chunks.
+    let left_chunks = 1 << (63 - full_chunks.leading_zeros());
+    left_chunks * CHUNK_SIZE as u64
+}
+
 // ----------------------------------------------------------------------------
 // When flipping the post-order tree to pre-order during encoding, and when
 // traversing the pre-order tree during decoding, we need to know how many
@@ -125,14 +843,14 @@ pub(crate) fn chunk_size(chunk_index: u64, content_len: u64) -> usize {
 // is the rightmost. This is the same as the number of trailing ones in the
 // chunk index (counting from 0). For example, chunk number 11 (0b1011) has two
 // trailing parent nodes.
-fn post_order_parent_nodes_nonfinal(chunk_index: u64) -> u8 {
+pub(crate) fn post_order_parent_nodes_nonfinal(chunk_index: u64) -> u8 {
     (!chunk_index).trailing_zeros() as u8
 }
 
 // The final chunk of a post order tree has to have a parent node for each of
 // the not yet merged subtrees behind it. This is the same as the total number
 // of ones in the chunk index (counting from 0).
-fn post_order_parent_nodes_final(chunk_index: u64) -> u8 {
+pub(crate) fn post_order_parent_nodes_final(chunk_index: u64) -> u8 {
     chunk_index.count_ones() as u8
 }
 
@@ -275,16 +993,89 @@ pub(crate) enum StateFinish {
     Root(Hash),
 }
 
+// The storage for the subtree hashes in a State. By default it's a fixed array on the stack, with
+// room for MAX_DEPTH hashes, which is enough for any input up to u64::MAX bytes. Callers with very
+// little stack space can trade that for a heap allocation with a smaller capacity.
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)] // Only with_max_depth should pay for a heap allocation.
+enum SubtreeStack {
+    Fixed(ArrayVec<[Hash; MAX_DEPTH]>),
+    Heap { hashes: Vec<Hash>, max_depth: usize },
+}
+
+impl SubtreeStack {
+    fn len(&self) -> usize {
+        match self {
+            SubtreeStack::Fixed(hashes) => hashes.len(),
+            SubtreeStack::Heap { hashes, .. } => hashes.len(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        match self {
+            SubtreeStack::Fixed(hashes) => hashes.is_full(),
+            SubtreeStack::Heap { hashes, max_depth } => hashes.len() >= *max_depth,
+        }
+    }
+
+    fn push(&mut self, hash: Hash) {
+        debug_assert!(!self.is_full());
+        match self {
+            SubtreeStack::Fixed(hashes) => hashes.push(hash),
+            SubtreeStack::Heap { hashes, .. } => hashes.push(hash),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Hash> {
+        match self {
+            SubtreeStack::Fixed(hashes) => hashes.pop(),
+            SubtreeStack::Heap { hashes, .. } => hashes.pop(),
+        }
+    }
+
+    fn first(&self) -> Hash {
+        match self {
+            SubtreeStack::Fixed(hashes) => hashes[0],
+            SubtreeStack::Heap { hashes, .. } => hashes[0],
+        }
+    }
+
+    fn as_slice(&self) -> &[Hash] {
+        match self {
+            SubtreeStack::Fixed(hashes) => hashes,
+            SubtreeStack::Heap { hashes, .. } => hashes,
+        }
+    }
+}
+
+/// The error returned by `State::push_subtree` when the input has more subtrees than a
+/// `State::with_max_depth` can hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DepthExceeded;
+
 #[derive(Clone)]
 pub(crate) struct State {
-    subtrees: ArrayVec<[Hash; MAX_DEPTH]>,
+    subtrees: SubtreeStack,
     total_len: u64,
 }
 
 impl State {
     pub fn new() -> Self {
         Self {
-            subtrees: ArrayVec::new(),
+            subtrees: SubtreeStack::Fixed(ArrayVec::new()),
+            total_len: 0,
+        }
+    }
+
+    /// Create a `State` that keeps at most `max_depth` subtree hashes, in a heap allocation rather
+    /// than the fixed array that `new` uses. That handles inputs of up to `2^max_depth - 1`
+    /// chunks. Pushing more than that makes `push_subtree` return an error.
+    pub fn with_max_depth(max_depth: usize) -> Self {
+        Self {
+            subtrees: SubtreeStack::Heap {
+                hashes: Vec::with_capacity(max_depth),
+                max_depth,
+            },
             total_len: 0,
         }
     }
@@ -293,12 +1084,61 @@ impl State {
         self.total_len
     }
 
-    fn merge_inner(&mut self, finalization: Finalization) -> ParentNode {
-        let right_child = self.subtrees.pop().unwrap();
-        let left_child = self.subtrees.pop().unwrap();
-        let parent_cv = blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
-        self.subtrees.push(parent_cv);
-        let mut parent_node = [0; PARENT_SIZE];
+    /// Combine the states for two adjacent parts of the input, as though one state had taken all
+    /// of `left` followed by all of `right`. This lets separate machines hash separate halves of
+    /// an input, with only the final merge and finalization done in one place. The chunk hashes
+    /// pushed into `right` must already use chunk indexes that continue from where `left` ends.
+    ///
+    /// `left` must be a whole subtree: a power of 2 number of full chunks. `right` can be any
+    /// length up to the length of `left`. Without those, the subtrees of `right` wouldn't line
+    /// up with the tree over the whole input. The merged state takes the stack type of `left`.
+    /// If `right` is empty, `left` is returned unchanged, since no more input is known to follow
+    /// it. But if `left` is a single chunk, that chunk was pushed as a non-root, and finalizing the
+    /// result returns the wrong root. Only `hash::PartialHasher` should call this. It keeps the
+    /// last chunk of each part out of the state until it knows whether that chunk is the root.
+    ///
+    /// # Panic
+    ///
+    /// This will panic if `left` isn't a whole subtree, or if `right` is longer than `left`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `DepthExceeded` if the merged subtrees don't fit in the stack of `left`. That can
+    /// only happen with `with_max_depth`.
+    pub fn merge(mut left: State, right: State) -> Result<State, DepthExceeded> {
+        let left_chunks = left.total_len / CHUNK_SIZE as u64;
+        assert!(
+            left.total_len.is_multiple_of(CHUNK_SIZE as u64) && left_chunks.is_power_of_two(),
+            "left must be a whole subtree"
+        );
+        assert!(
+            right.total_len <= left.total_len,
+            "right is longer than left"
+        );
+        // Merging down the left side below would finalize its parents as non-root, which is only
+        // right if more input follows.
+        if right.total_len == 0 {
+            return Ok(left);
+        }
+        // More input is coming, so the left side can be merged down to its single subtree hash.
+        // The pending merges on the right side are on top of the stack, and they stay pending.
+        while left.merge_parent().is_some() {}
+        for hash in right.subtrees.as_slice() {
+            if left.subtrees.is_full() {
+                return Err(DepthExceeded);
+            }
+            left.subtrees.push(*hash);
+        }
+        left.total_len += right.total_len;
+        Ok(left)
+    }
+
+    fn merge_inner(&mut self, finalization: Finalization) -> ParentNode {
+        let right_child = self.subtrees.pop().unwrap();
+        let left_child = self.subtrees.pop().unwrap();
+        let parent_cv = blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
+        self.subtrees.push(parent_cv);
+        let mut parent_node = [0; PARENT_SIZE];
         parent_node[..HASH_SIZE].copy_from_slice(left_child.as_bytes());
         parent_node[HASH_SIZE..].copy_from_slice(right_child.as_bytes());
         parent_node
@@ -332,8 +1172,16 @@ impl State {
     /// # Panic
     ///
     /// This will panic if the total input length overflows a `u64`.
-    pub fn push_subtree(&mut self, hash: &Hash, len: usize) {
+    ///
+    /// # Errors
+    ///
+    /// Returns `DepthExceeded`, leaving the state unchanged, if the subtree stack is full. That can
+    /// only happen with `with_max_depth`.
+    pub fn push_subtree(&mut self, hash: &Hash, len: usize) -> Result<(), DepthExceeded> {
         debug_assert!(!self.needs_merge());
+        if self.subtrees.is_full() {
+            return Err(DepthExceeded);
+        }
         self.subtrees.push(*hash);
         // Overflow in the length is practically impossible if we're actually hashing the input,
         // since it would take several hundred CPU years of work. But it could happen if we're
@@ -343,6 +1191,7 @@ impl State {
             .total_len
             .checked_add(len as u64)
             .expect("addition overflowed");
+        Ok(())
     }
 
     /// Returns a `ParentNode` corresponding to a just-completed subtree, if
@@ -373,7 +1222,7 @@ impl State {
         } else if self.subtrees.len() == 2 {
             StateFinish::Parent(self.merge_inner(Root))
         } else {
-            StateFinish::Root(self.subtrees[0])
+            StateFinish::Root(self.subtrees.first())
         }
     }
 }
@@ -411,6 +1260,28 @@ pub struct Encoder<T: Read + Write + Seek> {
     chunk_state: blake3::guts::ChunkState,
     tree_state: State,
     outboard: bool,
+    chunk_sink: Option<ChunkSink>,
+}
+
+// The callback from Encoder::with_chunk_index. It's shared between clones of the Encoder.
+type ChunkCallback = dyn FnMut(u64, &Hash) + Send;
+
+#[derive(Clone)]
+struct ChunkSink(Arc<Mutex<ChunkCallback>>);
+
+impl ChunkSink {
+    fn send(&self, offset: u64, hash: &Hash) {
+        // If another clone's callback panicked, keep going.
+        let mut sink = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        (*sink)(offset, hash);
+    }
+}
+
+impl fmt::Debug for ChunkSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes, they might be secret.
+        write!(f, "ChunkSink {{ ... }}")
+    }
 }
 
 impl<T: Read + Write + Seek> Encoder<T> {
@@ -423,6 +1294,7 @@ impl<T: Read + Write + Seek> Encoder<T> {
             chunk_state: blake3::guts::ChunkState::new(0),
             tree_state: State::new(),
             outboard: false,
+            chunk_sink: None,
         }
     }
 
@@ -436,6 +1308,89 @@ impl<T: Read + Write + Seek> Encoder<T> {
         encoder
     }
 
+    /// Call `sink` with the content offset and hash of each chunk, in order, as the chunks are
+    /// finalized. This builds an index of chunk hashes without a second pass over the input. Each
+    /// hash is finalized as a non-root node, as it appears in the parent node above it, except
+    /// when the whole input is a single chunk, where the chunk hash is the root hash. The final
+    /// chunk is reported by `finalize`.
+    ///
+    /// Clones of this `Encoder` share the same `sink`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::prelude::*;
+    /// use std::sync::mpsc;
+    ///
+    /// let (sender, receiver) = mpsc::channel();
+    /// let mut encoder = bao::encode::Encoder::new_outboard(std::io::Cursor::new(Vec::new()))
+    ///     .with_chunk_index(move |offset, hash| sender.send((offset, *hash)).unwrap());
+    /// encoder.write_all(&[0; 3000])?;
+    /// encoder.finalize()?;
+    /// let offsets: Vec<u64> = receiver.try_iter().map(|(offset, _)| offset).collect();
+    /// assert_eq!(vec![0, 1024, 2048], offsets);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_chunk_index(mut self, sink: impl FnMut(u64, &Hash) + Send + 'static) -> Self {
+        self.chunk_sink = Some(ChunkSink(Arc::new(Mutex::new(sink))));
+        self
+    }
+
+    /// Keep the subtree hashes of the input in a heap allocation with room for `max_depth` hashes,
+    /// rather than in a fixed array big enough for any input. That's for callers short on stack
+    /// space. It handles inputs of up to `2^max_depth - 1` chunks of 1024 bytes, and past that
+    /// `write` and `finalize` return an `ErrorKind::InvalidInput` error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any input has already been written.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        assert_eq!(0, self.tree_state.count(), "input already written");
+        assert_eq!(0, self.chunk_state.len(), "input already written");
+        self.tree_state = State::with_max_depth(max_depth);
+        self
+    }
+
+    /// The root hash of the input written so far, as if it were the complete input. Nothing is
+    /// written, and the `Encoder` can keep accepting input afterwards, so this is a verifiable
+    /// checkpoint for content that's still arriving. It costs one hash per level of the tree.
+    ///
+    /// This fails in the same case that `finalize` would, when an `Encoder` with a
+    /// `with_max_depth` limit couldn't fit the current partial chunk.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::prelude::*;
+    ///
+    /// let mut encoder = bao::encode::Encoder::new(std::io::Cursor::new(Vec::new()));
+    /// encoder.write_all(b"some ")?;
+    /// assert_eq!(blake3::hash(b"some "), encoder.current_root()?);
+    /// encoder.write_all(b"input")?;
+    /// assert_eq!(blake3::hash(b"some input"), encoder.finalize()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn current_root(&self) -> io::Result<Hash> {
+        // The same as the first half of finalize, on a copy of the tree state.
+        let mut tree_state = self.tree_state.clone();
+        if self.chunk_state.len() > 0 || tree_state.count() == 0 {
+            let is_root = tree_state.count() == 0;
+            let hash = self.chunk_state.finalize(is_root);
+            tree_state
+                .push_subtree(&hash, self.chunk_state.len())
+                .map_err(|_| max_depth_error())?;
+        }
+        loop {
+            if let StateFinish::Root(root) = tree_state.merge_finalize() {
+                return Ok(root);
+            }
+        }
+    }
+
     /// Finalize the encoding, after all the input has been written. You can't
     /// use this `Encoder` again after calling `finalize`.
     ///
@@ -460,7 +1415,13 @@ impl<T: Read + Write + Seek> Encoder<T> {
         if self.chunk_state.len() > 0 || self.tree_state.count() == 0 {
             let is_root = self.tree_state.count() == 0;
             let hash = self.chunk_state.finalize(is_root);
-            self.tree_state.push_subtree(&hash, self.chunk_state.len());
+            let offset = self.tree_state.count();
+            self.tree_state
+                .push_subtree(&hash, self.chunk_state.len())
+                .map_err(|_| max_depth_error())?;
+            if let Some(sink) = &self.chunk_sink {
+                sink.send(offset, &hash);
+            }
         }
 
         // Merge and write all the parents along the right edge.
@@ -476,7 +1437,7 @@ impl<T: Read + Write + Seek> Encoder<T> {
         }
 
         // Write the length header, at the end.
-        self.inner.write_all(&crate::encode_len(total_len))?;
+        self.inner.write_all(&crate::hash::encode_len(total_len))?;
 
         // Finally, flip the tree to be pre-order. This means rewriting the
         // entire output, so it's expensive.
@@ -491,7 +1452,7 @@ impl<T: Read + Write + Seek> Encoder<T> {
         let mut header = [0; HEADER_SIZE];
         self.inner.seek(SeekFrom::Start(read_cursor))?;
         self.inner.read_exact(&mut header)?;
-        let content_len = crate::decode_len(&header);
+        let content_len = crate::hash::decode_len(&header);
         let mut flipper = FlipperState::new(content_len);
         loop {
             match flipper.next() {
@@ -536,13 +1497,23 @@ impl<T: Read + Write + Seek> Encoder<T> {
     }
 }
 
+fn max_depth_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "input too long for max depth")
+}
+
 impl<T: Read + Write + Seek> Write for Encoder<T> {
     fn write(&mut self, input: &[u8]) -> io::Result<usize> {
         // If the current chunk is full, we need to finalize it, add it to
         // the tree state, and write out any completed parent nodes.
         if self.chunk_state.len() == CHUNK_SIZE {
             let chunk_hash = self.chunk_state.finalize(false);
-            self.tree_state.push_subtree(&chunk_hash, CHUNK_SIZE);
+            let offset = self.tree_state.count();
+            self.tree_state
+                .push_subtree(&chunk_hash, CHUNK_SIZE)
+                .map_err(|_| max_depth_error())?;
+            if let Some(sink) = &self.chunk_sink {
+                sink.send(offset, &chunk_hash);
+            }
             let chunk_counter = self.tree_state.count() / CHUNK_SIZE as u64;
             self.chunk_state = blake3::guts::ChunkState::new(chunk_counter);
             while let Some(parent) = self.tree_state.merge_parent() {
@@ -565,6 +1536,318 @@ impl<T: Read + Write + Seek> Write for Encoder<T> {
     }
 }
 
+/// An encoder for producers that always have whole chunks in hand, like a driver that transfers
+/// one chunk at a time. Each call to `update_chunk` must supply exactly one full chunk, 1024 bytes
+/// or [`blake3::CHUNK_LEN`](https://docs.rs/blake3/0.3/blake3/constant.CHUNK_LEN.html), except
+/// the last, which can be shorter. A misaligned chunk is an error rather than something that gets
+/// buffered and split up, so the producer's chunk boundaries always match the tree's.
+///
+/// The output is the same as `Encoder`, which this wraps.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use bao::encode::AlignedWriter;
+/// use blake3::CHUNK_LEN;
+///
+/// let input = vec![0; 2 * CHUNK_LEN + 100];
+/// let mut encoded = std::io::Cursor::new(Vec::new());
+/// let mut writer = AlignedWriter::new(&mut encoded);
+/// for chunk in input.chunks(CHUNK_LEN) {
+///     writer.update_chunk(chunk)?;
+/// }
+/// let hash = writer.finalize()?;
+/// assert_eq!(bao::encode::encode(&input), (encoded.into_inner(), hash));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct AlignedWriter<T: Read + Write + Seek> {
+    encoder: Encoder<T>,
+    // Set after a short chunk, which has to be the last one.
+    finished: bool,
+}
+
+impl<T: Read + Write + Seek> AlignedWriter<T> {
+    /// Create a new `AlignedWriter` that will produce a combined encoding, like `Encoder::new`.
+    pub fn new(inner: T) -> Self {
+        Self {
+            encoder: Encoder::new(inner),
+            finished: false,
+        }
+    }
+
+    /// Create a new `AlignedWriter` that will produce an outboard encoding, like
+    /// `Encoder::new_outboard`.
+    pub fn new_outboard(inner: T) -> Self {
+        Self {
+            encoder: Encoder::new_outboard(inner),
+            finished: false,
+        }
+    }
+
+    /// Add the next chunk of input. This returns an `ErrorKind::InvalidInput` error, without
+    /// consuming anything, if the chunk is longer than a full chunk, or if it follows a chunk that
+    /// was shorter than a full chunk.
+    pub fn update_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if self.finished || chunk.len() > CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "misaligned chunk",
+            ));
+        }
+        self.finished = chunk.len() < CHUNK_SIZE;
+        self.encoder.write_all(chunk)
+    }
+
+    /// Finalize the encoding, after all the chunks have been added. See `Encoder::finalize`.
+    pub fn finalize(&mut self) -> io::Result<Hash> {
+        self.encoder.finalize()
+    }
+}
+
+/// An incremental encoder for the post-order layout, which only ever writes forward, so its output
+/// can be a pipe or a socket. Each parent node comes right after the subtree it covers, and the
+/// 8-byte length goes at the very end, as a trailer instead of a header. The encoding is the same
+/// size as a combined encoding, and it has the same root hash. This is the layout that `Encoder`
+/// writes before it seeks back to flip everything into pre-order.
+///
+/// The tradeoff is on the decoding side. Nothing in a post-order encoding can be verified until
+/// the root node at the end, so `decode::PostorderReader` has to buffer the whole thing before it
+/// returns any content. Prefer the combined layout whenever the producer can seek.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let mut encoded = Vec::new();
+/// let mut encoder = bao::encode::PostorderEncoder::new(&mut encoded);
+/// encoder.write_all(b"some input")?;
+/// let hash = encoder.finalize()?;
+/// assert_eq!(blake3::hash(b"some input"), hash);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct PostorderEncoder<T: Write> {
+    inner: T,
+    chunk_state: blake3::guts::ChunkState,
+    tree_state: State,
+}
+
+impl<T: Write> PostorderEncoder<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            chunk_state: blake3::guts::ChunkState::new(0),
+            tree_state: State::new(),
+        }
+    }
+
+    /// Finalize the encoding, after all the input has been written, by writing the parent nodes
+    /// along the right edge of the tree and then the length trailer. You can't use this
+    /// `PostorderEncoder` again after calling `finalize`.
+    pub fn finalize(&mut self) -> io::Result<Hash> {
+        let total_len = self
+            .tree_state
+            .count()
+            .checked_add(self.chunk_state.len() as u64)
+            .expect("addition overflowed");
+        if self.chunk_state.len() > 0 || self.tree_state.count() == 0 {
+            let is_root = self.tree_state.count() == 0;
+            let hash = self.chunk_state.finalize(is_root);
+            self.tree_state
+                .push_subtree(&hash, self.chunk_state.len())
+                .expect("the fixed stack is never full");
+        }
+        let root_hash;
+        loop {
+            match self.tree_state.merge_finalize() {
+                StateFinish::Parent(parent) => self.inner.write_all(&parent)?,
+                StateFinish::Root(root) => {
+                    root_hash = root;
+                    break;
+                }
+            }
+        }
+        self.inner.write_all(&crate::hash::encode_len(total_len))?;
+        self.inner.flush()?;
+        Ok(root_hash)
+    }
+}
+
+impl<T: Write> Write for PostorderEncoder<T> {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        // This is the same as Encoder::write, except that the output is never flipped.
+        if self.chunk_state.len() == CHUNK_SIZE {
+            let chunk_hash = self.chunk_state.finalize(false);
+            self.tree_state
+                .push_subtree(&chunk_hash, CHUNK_SIZE)
+                .expect("the fixed stack is never full");
+            let chunk_counter = self.tree_state.count() / CHUNK_SIZE as u64;
+            self.chunk_state = blake3::guts::ChunkState::new(chunk_counter);
+            while let Some(parent) = self.tree_state.merge_parent() {
+                self.inner.write_all(&parent)?;
+            }
+        }
+        let want = CHUNK_SIZE - self.chunk_state.len();
+        let take = cmp::min(want, input.len());
+        self.inner.write_all(&input[..take])?;
+        self.chunk_state.update(&input[..take]);
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// An encoder that passes the content through unchanged to one writer while it builds the
+/// outboard encoding for another, like `tee` feeding `bao encode --outboard`. Neither output needs
+/// to seek, so both can be pipes or sockets.
+///
+/// The content is never buffered. The outboard tree is, because its root node comes first and
+/// can't be known until the end, so nothing reaches the outboard writer until `finalize`. The tree
+/// is small, about 6% of the content length.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let mut content = Vec::new();
+/// let mut outboard = Vec::new();
+/// let mut encoder = bao::encode::TeeEncoder::new(&mut content, &mut outboard);
+/// encoder.write_all(b"some input")?;
+/// let hash = encoder.finalize()?;
+/// assert_eq!(b"some input", &*content);
+/// assert_eq!(bao::encode::outboard(b"some input"), (outboard, hash));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct TeeEncoder<C: Write, O: Write> {
+    content_out: C,
+    outboard_out: O,
+    encoder: Encoder<io::Cursor<Vec<u8>>>,
+}
+
+impl<C: Write, O: Write> TeeEncoder<C, O> {
+    pub fn new(content_out: C, outboard_out: O) -> Self {
+        Self {
+            content_out,
+            outboard_out,
+            encoder: Encoder::new_outboard(io::Cursor::new(Vec::new())),
+        }
+    }
+
+    /// Finalize the outboard encoding, after all the input has been written, and write it to the
+    /// outboard writer. Both writers are flushed. You can't use this `TeeEncoder` again after
+    /// calling `finalize`.
+    pub fn finalize(&mut self) -> io::Result<Hash> {
+        let hash = self.encoder.finalize()?;
+        let outboard = std::mem::take(self.encoder.inner.get_mut());
+        self.outboard_out.write_all(&outboard)?;
+        self.outboard_out.flush()?;
+        self.content_out.flush()?;
+        Ok(hash)
+    }
+}
+
+impl<C: Write, O: Write> Write for TeeEncoder<C, O> {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        // Only hash what the content writer accepted, so that a short write leaves the two
+        // outputs in agreement.
+        let n = self.content_out.write(input)?;
+        self.encoder.write_all(&input[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.content_out.flush()
+    }
+}
+
+/// An incremental builder for a CRC sidecar, a table of CRC32 checksums with one entry for each
+/// chunk of input. Each entry is 4 bytes, little-endian. This is what you get from `bao encode
+/// --crc`.
+///
+/// **The CRC sidecar is not a security feature.** It's a cheap way for a transport to catch
+/// accidental corruption before paying for full verification, but anyone who can tamper with the
+/// encoding can also recompute the CRCs. Verifying against the root hash (that is, decoding) is
+/// always authoritative, and a passing `decode::crc_check` is never a substitute for it.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::prelude::*;
+///
+/// let mut sidecar = bao::encode::CrcSidecar::new();
+/// sidecar.write_all(b"some input")?;
+/// let table = sidecar.finalize();
+/// assert_eq!(table, bao::encode::crc_sidecar(b"some input"));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct CrcSidecar {
+    hasher: crc32fast::Hasher,
+    chunk_len: usize,
+    table: Vec<u8>,
+}
+
+impl CrcSidecar {
+    pub fn new() -> Self {
+        Self {
+            hasher: crc32fast::Hasher::new(),
+            chunk_len: 0,
+            table: Vec::new(),
+        }
+    }
+
+    /// Finalize the sidecar and return the table. As with `Encoder`, the empty input still gets
+    /// one (empty) chunk, so the table is never empty.
+    pub fn finalize(mut self) -> Vec<u8> {
+        if self.chunk_len > 0 || self.table.is_empty() {
+            self.table
+                .extend_from_slice(&self.hasher.finalize().to_le_bytes());
+        }
+        self.table
+    }
+}
+
+impl Default for CrcSidecar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for CrcSidecar {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        // If the current chunk is full, emit its CRC and start a new one.
+        if self.chunk_len == CHUNK_SIZE {
+            let hasher = std::mem::replace(&mut self.hasher, crc32fast::Hasher::new());
+            self.table
+                .extend_from_slice(&hasher.finalize().to_le_bytes());
+            self.chunk_len = 0;
+        }
+        let want = CHUNK_SIZE - self.chunk_len;
+        let take = cmp::min(want, input.len());
+        self.hasher.update(&input[..take]);
+        self.chunk_len += take;
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 // This incremental parser underlies the VerifyState (which does the actual
 // hash checking part of `bao decode`) and the SliceExtractor (which implements
 // `bao slice` and doesn't actually check any hashes). It encapsulates the tree
@@ -581,6 +1864,9 @@ pub(crate) struct ParseState {
     // requirement" in the spec. This parser doesn't actually check hashes, but
     // it drives callers that do check.
     final_chunk_validated: bool,
+    // If the tree is a subtree of some larger tree, its top node is an interior node, and it's
+    // finalized as NotRoot like any other.
+    subtree: bool,
 }
 
 impl ParseState {
@@ -592,6 +1878,14 @@ impl ParseState {
             stack_depth: 1,
             upcoming_parents: 0, // set later in feed_header
             final_chunk_validated: false,
+            subtree: false,
+        }
+    }
+
+    pub fn new_subtree() -> Self {
+        Self {
+            subtree: true,
+            ..Self::new()
         }
     }
 
@@ -599,6 +1893,12 @@ impl ParseState {
         self.content_position
     }
 
+    // The offset of the next read in a combined encoding, even if the caller is reading an
+    // outboard encoding.
+    pub fn encoding_position(&self) -> u128 {
+        self.encoding_position
+    }
+
     fn at_root(&self) -> bool {
         self.content_position < CHUNK_SIZE as u64 && self.stack_depth == 1
     }
@@ -636,7 +1936,7 @@ impl ParseState {
     }
 
     pub fn finalization(&self) -> Finalization {
-        if self.at_root() {
+        if self.at_root() && !self.subtree {
             Root
         } else {
             NotRoot
@@ -833,7 +2133,7 @@ impl ParseState {
     // Returns the parsed length.
     pub fn feed_header(&mut self, header: &[u8; HEADER_SIZE]) {
         debug_assert!(self.content_len.is_none(), "second call to feed_header");
-        let content_len = crate::decode_len(header);
+        let content_len = crate::hash::decode_len(header);
         self.content_len = Some(content_len);
         self.reset_to_root();
     }
@@ -1014,6 +2314,43 @@ impl<T: Read + Seek> SliceExtractor<T, T> {
     pub fn new(input: T, slice_start: u64, slice_len: u64) -> Self {
         Self::new_inner(input, None, slice_start, slice_len)
     }
+
+    /// Like `new`, but the range is `chunk_count` chunks starting at chunk index `first_chunk`,
+    /// so callers don't need to know the chunk size. A range that runs past the end is clipped
+    /// the same way a byte range would be. Decode the result with `SliceDecoder::by_chunks`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::io::prelude::*;
+    ///
+    /// let input = vec![0; 10_000];
+    /// let (encoded, hash) = bao::encode::encode(&input);
+    /// let encoded_cursor = std::io::Cursor::new(&encoded);
+    /// let mut extractor = bao::encode::SliceExtractor::by_chunks(encoded_cursor, 2, 3);
+    /// let mut slice = Vec::new();
+    /// extractor.read_to_end(&mut slice)?;
+    ///
+    /// let mut decoded = Vec::new();
+    /// bao::decode::SliceDecoder::by_chunks(&*slice, &hash, 2, 3).read_to_end(&mut decoded)?;
+    /// assert_eq!(&input[2048..5120], &*decoded);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn by_chunks(input: T, first_chunk: u64, chunk_count: u64) -> Self {
+        let (slice_start, slice_len) = chunks_to_bytes(first_chunk, chunk_count);
+        Self::new(input, slice_start, slice_len)
+    }
+}
+
+// Convert a range of chunk indices to the byte range of a slice. Ranges past the end of any
+// possible content saturate, and slicing clips them like any other range.
+pub(crate) fn chunks_to_bytes(first_chunk: u64, chunk_count: u64) -> (u64, u64) {
+    (
+        first_chunk.saturating_mul(CHUNK_SIZE as u64),
+        chunk_count.saturating_mul(CHUNK_SIZE as u64),
+    )
 }
 
 impl<T: Read + Seek, O: Read + Seek> SliceExtractor<T, O> {
@@ -1194,6 +2531,700 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_crc_sidecar() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let sidecar = crc_sidecar(&input);
+            assert_eq!(sidecar.len() as u128, crc_sidecar_size(case as u64));
+            // Check each entry against a one-shot CRC of the corresponding chunk.
+            for (i, entry) in sidecar.chunks(CRC_SIZE).enumerate() {
+                let start = i * CHUNK_SIZE;
+                let end = cmp::min(start + CHUNK_SIZE, case);
+                let expected = crc32fast::hash(&input[start..end]);
+                assert_eq!(&expected.to_le_bytes()[..], entry);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_parents_first() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (outboard, outboard_hash) = outboard(&input);
+            let (parents_first, hash) = encode_parents_first(&input);
+            assert_eq!(outboard_hash, hash);
+            assert_eq!(parents_first.len() as u128, encoded_size(case as u64));
+            assert_eq!(parents_first.len(), parents_first.capacity());
+            assert_eq!(&outboard[..], &parents_first[..outboard.len()]);
+            assert_eq!(&input[..], &parents_first[outboard.len()..]);
+        }
+    }
+
+    // The offset of every parent node in a combined encoding, including the header.
+    fn parent_offsets(content_len: u64) -> Vec<usize> {
+        fn recurse(len: u64, offset: usize, offsets: &mut Vec<usize>) {
+            if len <= CHUNK_SIZE as u64 {
+                return;
+            }
+            offsets.push(offset);
+            let left_len = left_subtree_len(len);
+            let right_offset = offset + PARENT_SIZE + encoded_subtree_size(left_len) as usize;
+            recurse(left_len, offset + PARENT_SIZE, offsets);
+            recurse(len - left_len, right_offset, offsets);
+        }
+        let mut offsets = Vec::new();
+        recurse(content_len, HEADER_SIZE, &mut offsets);
+        offsets
+    }
+
+    #[test]
+    fn test_tee_encoder() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let mut content = Vec::new();
+            let mut outboard = Vec::new();
+            let mut encoder = TeeEncoder::new(&mut content, &mut outboard);
+            // Uneven writes, to cross chunk boundaries at odd places.
+            for piece in input.chunks(1000) {
+                encoder.write_all(piece).unwrap();
+            }
+            let hash = encoder.finalize().unwrap();
+            assert_eq!(input, content);
+            assert_eq!(blake3::hash(&input), hash);
+            assert_eq!(outboard_size(case as u64), outboard.len() as u128);
+
+            let mut output = Vec::new();
+            crate::decode::Decoder::new_outboard(&*content, &*outboard, &hash)
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(input, output);
+        }
+    }
+
+    #[test]
+    fn test_encode_reader_sized() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let expected = encode(&input);
+            let found = encode_reader_sized(io::Cursor::new(&input), case as u64).unwrap();
+            assert_eq!(expected, found);
+
+            // A reader that's shorter or longer than claimed is an error.
+            let err = encode_reader_sized(io::Cursor::new(&input), case as u64 + 1).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+            if case > 0 {
+                let err =
+                    encode_reader_sized(io::Cursor::new(&input), case as u64 - 1).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn test_current_root() {
+        let input = make_test_input(10 * CHUNK_SIZE + 1);
+        for &outboard in &[false, true] {
+            let mut output = Vec::new();
+            let mut encoder = if outboard {
+                Encoder::new_outboard(io::Cursor::new(&mut output))
+            } else {
+                Encoder::new(io::Cursor::new(&mut output))
+            };
+            assert_eq!(blake3::hash(b""), encoder.current_root().unwrap());
+            // Feed uneven pieces, checking the root at every boundary, including the points where
+            // the current chunk is exactly full.
+            let mut written = 0;
+            for piece in input.chunks(CHUNK_SIZE / 2 + 1) {
+                encoder.write_all(piece).unwrap();
+                written += piece.len();
+                assert_eq!(
+                    blake3::hash(&input[..written]),
+                    encoder.current_root().unwrap(),
+                    "written {}",
+                    written
+                );
+            }
+            for n in 1..=10 {
+                let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+                encoder.write_all(&input[..n * CHUNK_SIZE]).unwrap();
+                let root = encoder.current_root().unwrap();
+                assert_eq!(blake3::hash(&input[..n * CHUNK_SIZE]), root);
+            }
+
+            // Checkpoints don't disturb the encoding.
+            assert_eq!(blake3::hash(&input), encoder.finalize().unwrap());
+            let expected = if outboard {
+                super::outboard(&input).0
+            } else {
+                encode(&input).0
+            };
+            assert_eq!(expected, output);
+        }
+
+        // A depth-limited encoder fails the same way finalize would.
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new())).with_max_depth(2);
+        encoder.write_all(&input[..3 * CHUNK_SIZE + 1]).unwrap();
+        let err = encoder.current_root().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_rebuild_tree() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode(&input);
+            assert_eq!(encoded, rebuild_tree(&encoded, &hash).unwrap());
+
+            // Corrupt every parent node, but none of the content.
+            let mut damaged = encoded.clone();
+            for offset in parent_offsets(case as u64) {
+                for byte in &mut damaged[offset..][..PARENT_SIZE] {
+                    *byte ^= 0x5a;
+                }
+            }
+            assert_eq!(encoded, rebuild_tree(&damaged, &hash).unwrap());
+            let mut trailing = damaged.clone();
+            trailing.push(0);
+            assert_eq!(encoded, rebuild_tree(&trailing, &hash).unwrap());
+
+            // Corrupting the content as well can't be repaired.
+            if case > 0 {
+                let content_offset = encoded.len() - 1;
+                damaged[content_offset] ^= 1;
+                assert_eq!(
+                    Err(crate::decode::Error::HashMismatch),
+                    rebuild_tree(&damaged, &hash)
+                );
+            }
+            assert_eq!(
+                Err(crate::decode::Error::Truncated),
+                rebuild_tree(&encoded[..encoded.len() - 1], &hash)
+            );
+        }
+    }
+
+    #[test]
+    fn test_required_encoded_ranges() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let case = case as u64;
+            let encoded_len = encoded_size(case) as u64;
+            assert_eq!(
+                vec![(0, encoded_len)],
+                required_encoded_ranges(case, &[]).unwrap()
+            );
+            assert_eq!(
+                vec![(0, HEADER_SIZE as u64)],
+                required_encoded_ranges(case, &[(0, case)]).unwrap()
+            );
+        }
+
+        // With the first half of the chunks in hand, we need the header, the root node, and the
+        // whole right half of the tree.
+        let content_len = 16 * CHUNK_SIZE as u64;
+        let half = content_len / 2;
+        let right_start = (HEADER_SIZE + PARENT_SIZE) as u64 + encoded_subtree_size(half) as u64;
+        let expected = vec![
+            (0, right_start - encoded_subtree_size(half) as u64),
+            (right_start, encoded_size(content_len) as u64),
+        ];
+        assert_eq!(
+            expected,
+            required_encoded_ranges(content_len, &[(0, half)]).unwrap()
+        );
+        // Split or overlapping ranges of have make no difference.
+        assert_eq!(
+            expected,
+            required_encoded_ranges(content_len, &[(1000, half), (0, 1000), (5, 10)]).unwrap()
+        );
+
+        // Pruning untouched subtrees gives the same answer as walking down to every chunk.
+        fn walk(have: &[(u64, u64)], start: u64, len: u64, offset: u64, out: &mut Vec<(u64, u64)>) {
+            if len > 0 && have.iter().any(|&(s, e)| s <= start && start + len <= e) {
+                return;
+            }
+            if len <= CHUNK_SIZE as u64 {
+                out.push((offset, offset + len));
+                return;
+            }
+            out.push((offset, offset + PARENT_SIZE as u64));
+            let left_len = left_subtree_len(len);
+            let right_offset = offset + PARENT_SIZE as u64 + encoded_subtree_size(left_len) as u64;
+            walk(have, start, left_len, offset + PARENT_SIZE as u64, out);
+            walk(have, start + left_len, len - left_len, right_offset, out);
+        }
+        for &have in &[
+            &[(0, 1)][..],
+            &[(CHUNK_SIZE as u64, 3 * CHUNK_SIZE as u64)][..],
+            &[
+                (100, 5000),
+                (9000, 9001),
+                (content_len - 1, content_len + 10),
+            ][..],
+        ] {
+            let mut walked = vec![(0, HEADER_SIZE as u64)];
+            walk(have, 0, content_len, HEADER_SIZE as u64, &mut walked);
+            let mut expected: Vec<(u64, u64)> = Vec::new();
+            for (s, e) in walked {
+                match expected.last_mut() {
+                    Some((_, last_end)) if *last_end == s => *last_end = e,
+                    _ => expected.push((s, e)),
+                }
+            }
+            assert_eq!(
+                expected,
+                required_encoded_ranges(content_len, have).unwrap(),
+                "{:?}",
+                have
+            );
+        }
+
+        // Missing part of a chunk means we need the whole chunk, and its path from the root.
+        let ranges = required_encoded_ranges(content_len, &[(0, content_len - 1)]).unwrap();
+        let last_chunk_start = encoded_size(content_len) as u64 - CHUNK_SIZE as u64;
+        let (last_start, last_end) = *ranges.last().unwrap();
+        assert_eq!(encoded_size(content_len) as u64, last_end);
+        // The parent node above the last chunk comes before its sibling chunk, so the last
+        // chunk is a range of its own.
+        assert_eq!(last_chunk_start, last_start);
+        let total: u64 = ranges.iter().map(|(s, e)| e - s).sum();
+        assert_eq!(HEADER_SIZE + 4 * PARENT_SIZE + CHUNK_SIZE, total as usize);
+    }
+
+    #[test]
+    fn test_geometry_at_chunk_boundaries() {
+        // Collect the leaf ranges of the tree by recursing with left_subtree_len.
+        fn leaves(start: u64, len: u64, out: &mut Vec<(u64, u64)>) {
+            if len <= CHUNK_SIZE as u64 {
+                out.push((start, len));
+                return;
+            }
+            let left_len = left_subtree_len(len);
+            // The left side is a power of two chunks, and the right side isn't empty.
+            assert!((left_len / CHUNK_SIZE as u64).is_power_of_two());
+            assert_eq!(0, left_len % CHUNK_SIZE as u64);
+            assert!(left_len < len);
+            leaves(start, left_len, out);
+            leaves(start + left_len, len - left_len, out);
+        }
+
+        for boundary in 0..=16u64 {
+            for len in
+                (boundary * CHUNK_SIZE as u64).saturating_sub(2)..=boundary * CHUNK_SIZE as u64 + 2
+            {
+                let mut out = Vec::new();
+                leaves(0, len, &mut out);
+                let mut expected_start = 0;
+                for &(start, leaf_len) in &out {
+                    assert_eq!(expected_start, start, "len {}", len);
+                    assert!(leaf_len > 0 || len == 0, "len {}", len);
+                    expected_start += leaf_len;
+                }
+                assert_eq!(len, expected_start, "len {}", len);
+
+                let expected_chunks = cmp::max(1, len.div_ceil(CHUNK_SIZE as u64));
+                assert_eq!(expected_chunks, count_chunks(len), "len {}", len);
+                assert_eq!(expected_chunks, out.len() as u64, "len {}", len);
+
+                let input = vec![0; len as usize];
+                assert_eq!(
+                    encode(&input).0.len() as u128,
+                    encoded_size(len),
+                    "len {}",
+                    len
+                );
+                assert_eq!(
+                    outboard(&input).0.len() as u128,
+                    outboard_size(len),
+                    "len {}",
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_slice_by_chunks() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode(&input);
+            for &(first_chunk, chunk_count) in &[(0, 0), (0, 1), (2, 3), (3, 100), (100, 1)] {
+                let mut slice = Vec::new();
+                SliceExtractor::by_chunks(io::Cursor::new(&encoded), first_chunk, chunk_count)
+                    .read_to_end(&mut slice)
+                    .unwrap();
+                let start = first_chunk * CHUNK_SIZE as u64;
+                let len = chunk_count * CHUNK_SIZE as u64;
+                let mut expected_slice = Vec::new();
+                SliceExtractor::new(io::Cursor::new(&encoded), start, len)
+                    .read_to_end(&mut expected_slice)
+                    .unwrap();
+                assert_eq!(expected_slice, slice);
+
+                let mut output = Vec::new();
+                crate::decode::SliceDecoder::by_chunks(&*slice, &hash, first_chunk, chunk_count)
+                    .read_to_end(&mut output)
+                    .unwrap();
+                let clamped_start = cmp::min(start as usize, case);
+                let clamped_end = cmp::min((start + len) as usize, case);
+                assert_eq!(&input[clamped_start..clamped_end], &*output);
+            }
+        }
+        assert_eq!((u64::MAX, u64::MAX), chunks_to_bytes(u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn test_chunk_range() {
+        let content_len = 10 * CHUNK_SIZE as u64 + 1;
+        let chunk = CHUNK_SIZE as u64;
+        // Inside one chunk, including one that touches the chunk's last byte.
+        assert_eq!(0..1, chunk_range(content_len, 0, 1));
+        assert_eq!(3..4, chunk_range(content_len, 3 * chunk + 10, 100));
+        assert_eq!(3..4, chunk_range(content_len, 3 * chunk, chunk));
+        // Spanning three chunks.
+        assert_eq!(3..6, chunk_range(content_len, 3 * chunk + 10, 2 * chunk));
+        assert_eq!(3..6, chunk_range(content_len, 4 * chunk - 1, chunk + 2));
+        // Zero length covers nothing, wherever it is.
+        for &start in &[0, 1, 3 * chunk, content_len, content_len + 1, u64::MAX] {
+            assert!(chunk_range(content_len, start, 0).is_empty());
+        }
+        // Past the end, the range is clipped.
+        assert_eq!(10..11, chunk_range(content_len, 10 * chunk, u64::MAX));
+        assert_eq!(0..11, chunk_range(content_len, 0, u64::MAX));
+        assert!(chunk_range(content_len, content_len, 100).is_empty());
+        assert!(chunk_range(0, 0, 100).is_empty());
+
+        // Every chunk in the range overlaps it, and every overlapping chunk is in the range.
+        for &case in crate::test::TEST_CASES {
+            let case = case as u64;
+            for &(start, len) in &[(0, 1), (1, 2000), (1023, 2), (2048, 5000), (case / 2, 3000)] {
+                let range = chunk_range(case, start, len);
+                let end = cmp::min(start.saturating_add(len), case);
+                for i in 0..count_chunks(case) {
+                    let chunk_start = i * chunk;
+                    let chunk_end = cmp::min(chunk_start + chunk, case);
+                    let overlaps = chunk_start < end && start < chunk_end;
+                    assert_eq!(
+                        overlaps,
+                        range.contains(&i),
+                        "{} {} {} {}",
+                        case,
+                        start,
+                        len,
+                        i
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunks_and_parents_in_range() {
+        let chunk = CHUNK_SIZE as u64;
+        // Within one chunk, across a chunk boundary, and the whole input.
+        assert_eq!(1, chunks_in_range(10 * chunk, chunk + 1, 10));
+        assert_eq!(2, chunks_in_range(10 * chunk, chunk - 1, 2));
+        assert_eq!(10, chunks_in_range(10 * chunk, 0, 10 * chunk));
+        assert_eq!(10, chunks_in_range(10 * chunk + 1, 1, 10 * chunk - 1));
+        assert_eq!(9, parents_in_range(10 * chunk, 0, 10 * chunk));
+        assert_eq!(0, parents_in_range(chunk, 0, chunk));
+        // Huge ranges are cheap.
+        assert_eq!(u64::MAX / chunk + 1, chunks_in_range(u64::MAX, 0, u64::MAX));
+        assert_eq!(u64::MAX / chunk, parents_in_range(u64::MAX, 0, u64::MAX));
+        assert_eq!(54, parents_in_range(u64::MAX, u64::MAX - 1, 1));
+
+        // Check both against the chunks and parent nodes in real slices.
+        for &case in crate::test::TEST_CASES {
+            let input = vec![0xab; case];
+            let (encoded, _) = encode(&input);
+            let case = case as u64;
+            let interesting = [0, 1, chunk - 1, chunk, chunk + 1, 3 * chunk, case, case + 1];
+            for &start in &interesting {
+                for &len in &interesting {
+                    let mut extractor = SliceExtractor::new(io::Cursor::new(&encoded), start, len);
+                    let mut slice = Vec::new();
+                    extractor.read_to_end(&mut slice).unwrap();
+                    let (first_chunk, end_chunk) = slice_chunk_range(case, start, len);
+                    assert_eq!(end_chunk - first_chunk, chunks_in_range(case, start, len));
+                    let content_start = first_chunk * chunk;
+                    let content_end = cmp::min(end_chunk * chunk, case);
+                    let expected_len = HEADER_SIZE as u64
+                        + PARENT_SIZE as u64 * parents_in_range(case, start, len)
+                        + content_end
+                        - content_start;
+                    assert_eq!(
+                        expected_len,
+                        slice.len() as u64,
+                        "case {} start {} len {}",
+                        case,
+                        start,
+                        len
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_content_len() {
+        assert_eq!(crate::MAX_CONTENT_LEN, max_content_len());
+        assert_eq!(0xf0f0f0f0f0f0f0f7, max_content_len());
+        assert!(encoded_size(max_content_len()) <= u64::MAX as u128);
+        assert!(encoded_size(max_content_len() + 1) > u64::MAX as u128);
+        assert!(chunk_of_offset(max_content_len(), max_content_len() - 1).is_some());
+        assert!(chunk_of_offset(max_content_len() + 1, 0).is_none());
+    }
+
+    #[test]
+    fn test_geometry_near_max_len() {
+        // Find the longest content whose combined encoding still fits in a u64.
+        let max_encoded_len = (u64::MAX - 2 * (CHUNK_SIZE + PARENT_SIZE) as u64..=u64::MAX)
+            .rev()
+            .find(|&encoded_len| crate::decode::is_valid_encoded_len(encoded_len).is_some())
+            .unwrap();
+        let max_len = crate::decode::is_valid_encoded_len(max_encoded_len).unwrap();
+        assert!(encoded_size(max_len + 1) > u64::MAX as u128);
+
+        // Offsets all the way out to the end of the encoding don't overflow.
+        let ranges = required_encoded_ranges(max_len, &[(0, max_len - 1)]).unwrap();
+        assert_eq!(max_encoded_len, ranges.last().unwrap().1);
+        assert_eq!(
+            Some(vec![(0, HEADER_SIZE as u64)]),
+            required_encoded_ranges(max_len, &[(0, max_len)])
+        );
+        let (_, start, len, encoded_offset) = chunk_of_offset(max_len, max_len - 1).unwrap();
+        assert_eq!(max_len, start + len);
+
+        // Subtrees that have doesn't touch are taken whole, without walking down to their chunks,
+        // so sparse ranges at this size are quick.
+        assert_eq!(
+            Some(vec![(0, max_encoded_len)]),
+            required_encoded_ranges(max_len, &[])
+        );
+        let middle = max_len / 2 / CHUNK_SIZE as u64 * CHUNK_SIZE as u64;
+        let have = [(0, 1 << 30), (middle, middle + CHUNK_SIZE as u64)];
+        let ranges = required_encoded_ranges(max_len, &have).unwrap();
+        assert_eq!(max_encoded_len, ranges.last().unwrap().1);
+        assert!(ranges.len() < 200);
+        let needed: u64 = ranges.iter().map(|(s, e)| e - s).sum();
+        // Each range of have saves at least its content, and some parents stay needed above it.
+        assert!(needed <= max_encoded_len - (1 << 30) - CHUNK_SIZE as u64);
+        assert!(needed >= max_encoded_len - (1 << 31));
+        assert_eq!(max_encoded_len, encoded_offset + len);
+
+        // Anything longer can't be addressed, and that's an error rather than a wraparound.
+        for &content_len in &[max_len + 1, u64::MAX - 1, u64::MAX] {
+            assert_eq!(None, required_encoded_ranges(content_len, &[]));
+            assert_eq!(None, chunk_of_offset(content_len, 0));
+        }
+    }
+
+    #[test]
+    fn test_aligned_writer() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (expected_encoded, expected_hash) = encode(&input);
+            let (expected_outboard, _) = outboard(&input);
+
+            let mut encoded = io::Cursor::new(Vec::new());
+            let mut writer = AlignedWriter::new(&mut encoded);
+            let mut outboard = io::Cursor::new(Vec::new());
+            let mut outboard_writer = AlignedWriter::new_outboard(&mut outboard);
+            for chunk in input.chunks(CHUNK_SIZE) {
+                writer.update_chunk(chunk).unwrap();
+                outboard_writer.update_chunk(chunk).unwrap();
+            }
+            assert_eq!(expected_hash, writer.finalize().unwrap());
+            assert_eq!(expected_hash, outboard_writer.finalize().unwrap());
+            assert_eq!(expected_encoded, encoded.into_inner());
+            assert_eq!(expected_outboard, outboard.into_inner());
+        }
+    }
+
+    #[test]
+    fn test_aligned_writer_misaligned() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let mut encoded = io::Cursor::new(Vec::new());
+        let mut writer = AlignedWriter::new(&mut encoded);
+        writer.update_chunk(&input[..CHUNK_SIZE]).unwrap();
+        // A short chunk that isn't the last one.
+        writer.update_chunk(&input[CHUNK_SIZE..][..100]).unwrap();
+        let err = writer.update_chunk(&input[CHUNK_SIZE + 100..]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        // The rejected chunk wasn't consumed, so finishing now encodes what came before.
+        let hash = writer.finalize().unwrap();
+        assert_eq!(
+            encode(&input[..CHUNK_SIZE + 100]),
+            (encoded.into_inner(), hash)
+        );
+
+        // A chunk that's too long.
+        let mut writer = AlignedWriter::new(io::Cursor::new(Vec::new()));
+        let err = writer.update_chunk(&input[..CHUNK_SIZE + 1]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_chunk_index() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let index = Arc::new(Mutex::new(Vec::new()));
+            let sink_index = index.clone();
+            let mut encoder = Encoder::new(io::Cursor::new(Vec::new())).with_chunk_index(
+                move |offset, hash: &Hash| sink_index.lock().unwrap().push((offset, *hash)),
+            );
+            // Write in odd-sized pieces, so chunks get split across writes.
+            for piece in input.chunks(100) {
+                encoder.write_all(piece).unwrap();
+            }
+            let root_hash = encoder.finalize().unwrap();
+
+            let index = index.lock().unwrap();
+            let num_chunks = count_chunks(case as u64);
+            assert_eq!(num_chunks as usize, index.len());
+            if num_chunks == 1 {
+                assert_eq!(&[(0, root_hash)], &index[..]);
+                continue;
+            }
+            for (i, &(offset, hash)) in index.iter().enumerate() {
+                assert_eq!((i * CHUNK_SIZE) as u64, offset);
+                let chunk = &input[i * CHUNK_SIZE..cmp::min(case, (i + 1) * CHUNK_SIZE)];
+                let expected = blake3::guts::ChunkState::new(i as u64)
+                    .update(chunk)
+                    .finalize(false);
+                assert_eq!(expected, hash);
+            }
+        }
+    }
+
+    #[test]
+    fn test_concat() {
+        for &case_a in crate::test::TEST_CASES {
+            for &case_b in crate::test::TEST_CASES {
+                println!("case {} + {}", case_a, case_b);
+                let input = make_test_input(case_a + case_b);
+                let (input_a, input_b) = input.split_at(case_a);
+                let (encoded_a, _) = encode(input_a);
+                let (encoded_b, _) = encode(input_b);
+                let concatenated = concat(&encoded_a, case_a as u64, &encoded_b, case_b as u64);
+                assert_eq!(encode(&input), concatenated);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_concat_wrong_len() {
+        let (encoded_a, _) = encode(b"foo");
+        let (encoded_b, _) = encode(b"bar");
+        concat(&encoded_a, 4, &encoded_b, 3);
+    }
+
+    #[test]
+    fn test_chunk_of_offset() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, _) = encode(&input);
+
+            // Walk the encoding to find where each chunk's content actually is.
+            let mut chunk_offsets = Vec::new();
+            let mut parser = ParseState::new();
+            let mut position = 0;
+            loop {
+                match parser.read_next() {
+                    NextRead::Header => {
+                        parser.feed_header(array_ref!(encoded, 0, HEADER_SIZE));
+                        position += HEADER_SIZE;
+                    }
+                    NextRead::Parent => {
+                        parser.advance_parent();
+                        position += PARENT_SIZE;
+                    }
+                    NextRead::Chunk { size, .. } => {
+                        chunk_offsets.push(position as u64);
+                        parser.advance_chunk();
+                        position += size;
+                    }
+                    NextRead::Done => break,
+                }
+            }
+            assert_eq!(encoded.len(), position);
+
+            let offsets = if case == 0 {
+                vec![0]
+            } else {
+                (0..case).collect()
+            };
+            for offset in offsets {
+                let (index, start, len, encoded_offset) =
+                    chunk_of_offset(case as u64, offset as u64).unwrap();
+                assert_eq!((offset / CHUNK_SIZE) as u64, index);
+                if case > 0 {
+                    assert!(start <= offset as u64 && (offset as u64) < start + len);
+                }
+                assert_eq!(chunk_size(index, case as u64) as u64, len);
+                assert_eq!(chunk_offsets[index as usize], encoded_offset);
+                assert_eq!(
+                    &input[start as usize..][..len as usize],
+                    &encoded[encoded_offset as usize..][..len as usize]
+                );
+            }
+            if case > 0 {
+                assert_eq!(None, chunk_of_offset(case as u64, case as u64));
+            }
+        }
+        assert_eq!(Some((0, 0, 0, 8)), chunk_of_offset(0, 0));
+        assert_eq!(None, chunk_of_offset(0, 1));
+        assert_eq!(None, chunk_of_offset(u64::MAX, 0));
+        let last_byte = chunk_of_offset(u64::MAX / 2, u64::MAX / 2 - 1).unwrap();
+        assert_eq!(u64::MAX / 2 / CHUNK_SIZE as u64, last_byte.0);
+    }
+
+    #[test]
+    fn test_overhead() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let case = case as u64;
+            let overhead = overhead_bytes(case);
+            assert_eq!(encoded_size(case), (case + overhead) as u128);
+            let num_parents = count_chunks(case) - 1;
+            assert_eq!(
+                HEADER_SIZE as u64 + num_parents * PARENT_SIZE as u64,
+                overhead
+            );
+            if case <= CHUNK_SIZE as u64 {
+                assert_eq!(HEADER_SIZE as u64, overhead);
+            }
+            if case > 0 {
+                assert_eq!(overhead as f64 / case as f64, overhead_ratio(case));
+            }
+        }
+        assert_eq!(f64::INFINITY, overhead_ratio(0));
+        assert_eq!(8.0, overhead_ratio(1));
+        let big_ratio = overhead_ratio(1 << 40);
+        let limit = PARENT_SIZE as f64 / CHUNK_SIZE as f64;
+        assert!(big_ratio < limit && limit - big_ratio < 1e-6);
+        assert_eq!(outboard_size(u64::MAX), overhead_bytes(u64::MAX) as u128);
+    }
+
+    #[test]
+    fn test_left_subtree_len() {
+        let chunk = CHUNK_SIZE as u64;
+        assert_eq!(chunk, left_subtree_len(chunk + 1));
+        assert_eq!(chunk, left_subtree_len(2 * chunk));
+        assert_eq!(2 * chunk, left_subtree_len(2 * chunk + 1));
+        assert_eq!(2 * chunk, left_subtree_len(3 * chunk));
+        assert_eq!(2 * chunk, left_subtree_len(4 * chunk));
+        assert_eq!(4 * chunk, left_subtree_len(4 * chunk + 1));
+        assert_eq!(1 << 63, left_subtree_len(u64::MAX));
+    }
+
     #[test]
     fn test_outboard_encode() {
         for &case in crate::test::TEST_CASES {
@@ -1279,7 +3310,7 @@ mod test {
                 .update(&input[..CHUNK_SIZE])
                 .finalize(false);
             chunk_index += 1;
-            state.push_subtree(&hash, CHUNK_SIZE);
+            state.push_subtree(&hash, CHUNK_SIZE).unwrap();
             input = &input[CHUNK_SIZE..];
             // Merge any parents, but throw away the result. We don't need
             // them, but we need to avoid tripping an assert.
@@ -1288,7 +3319,7 @@ mod test {
         let hash = blake3::guts::ChunkState::new(chunk_index)
             .update(input)
             .finalize(last_chunk_is_root);
-        state.push_subtree(&hash, input.len());
+        state.push_subtree(&hash, input.len()).unwrap();
         loop {
             match state.merge_finalize() {
                 StateFinish::Parent(_) => {}
@@ -1297,6 +3328,203 @@ mod test {
         }
     }
 
+    fn drive_state_with_max_depth(input: &[u8], max_depth: usize) -> Result<Hash, DepthExceeded> {
+        let mut state = State::with_max_depth(max_depth);
+        let num_chunks = count_chunks(input.len() as u64);
+        for (chunk_index, chunk) in input.chunks(CHUNK_SIZE).enumerate() {
+            let is_root = num_chunks == 1;
+            let hash = blake3::guts::ChunkState::new(chunk_index as u64)
+                .update(chunk)
+                .finalize(is_root);
+            state.push_subtree(&hash, chunk.len())?;
+            if chunk_index as u64 + 1 < num_chunks {
+                while state.merge_parent().is_some() {}
+            }
+        }
+        if input.is_empty() {
+            let hash = blake3::guts::ChunkState::new(0).finalize(true);
+            state.push_subtree(&hash, 0)?;
+        }
+        loop {
+            match state.merge_finalize() {
+                StateFinish::Parent(_) => {}
+                StateFinish::Root(hash) => return Ok(hash),
+            }
+        }
+    }
+
+    #[test]
+    fn test_state_with_max_depth() {
+        let max_depth = 3;
+        let capacity = (1 << max_depth) - 1;
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let result = drive_state_with_max_depth(&input, max_depth);
+            if count_chunks(case as u64) <= capacity {
+                assert_eq!(blake3::hash(&input), result.unwrap());
+            } else {
+                assert_eq!(Err(DepthExceeded), result);
+            }
+        }
+        let input = make_test_input(capacity as usize * CHUNK_SIZE);
+        assert_eq!(
+            blake3::hash(&input),
+            drive_state_with_max_depth(&input, max_depth).unwrap()
+        );
+        let input = make_test_input(capacity as usize * CHUNK_SIZE + 1);
+        assert_eq!(
+            Err(DepthExceeded),
+            drive_state_with_max_depth(&input, max_depth)
+        );
+        // The default depth handles anything.
+        let input = make_test_input(100 * CHUNK_SIZE);
+        assert_eq!(
+            blake3::hash(&input),
+            drive_state_with_max_depth(&input, MAX_DEPTH).unwrap()
+        );
+
+        // Same thing through the Encoder.
+        let input = make_test_input(capacity as usize * CHUNK_SIZE);
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new())).with_max_depth(max_depth);
+        encoder.write_all(&input).unwrap();
+        assert_eq!(blake3::hash(&input), encoder.finalize().unwrap());
+        let input = make_test_input(capacity as usize * CHUNK_SIZE + 1);
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new())).with_max_depth(max_depth);
+        encoder.write_all(&input).unwrap();
+        let err = encoder.finalize().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let input = make_test_input(capacity as usize * CHUNK_SIZE + CHUNK_SIZE + 1);
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new())).with_max_depth(max_depth);
+        let err = encoder.write_all(&input).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_state_merge() {
+        // Push the chunks of `input` into a state, with chunk indexes starting at `first_chunk`,
+        // merging only between pushes, so that the final merges are still pending at the end.
+        fn partial_state(input: &[u8], first_chunk: u64) -> State {
+            let mut state = State::new();
+            for (i, chunk) in input.chunks(CHUNK_SIZE).enumerate() {
+                while state.merge_parent().is_some() {}
+                let hash = blake3::guts::ChunkState::new(first_chunk + i as u64)
+                    .update(chunk)
+                    .finalize(false);
+                state.push_subtree(&hash, chunk.len()).unwrap();
+            }
+            state
+        }
+
+        fn finalize(mut state: State) -> Hash {
+            loop {
+                if let StateFinish::Root(root) = state.merge_finalize() {
+                    return root;
+                }
+            }
+        }
+
+        let input = make_test_input(20 * CHUNK_SIZE);
+        for &left_chunks in &[1, 2, 4, 8] {
+            let split = left_chunks * CHUNK_SIZE;
+            for right_len in 0..=split {
+                // An empty right side leaves the left side to be finalized on its own. The one
+                // exception is a single chunk, which a State can't root-finalize after it was
+                // pushed as a non-root. That's why only PartialHasher calls merge, and
+                // test_partial_hasher covers its single-chunk parts.
+                if right_len == 0 && left_chunks == 1 {
+                    continue;
+                }
+                // Every right length near a chunk boundary, and a few in between.
+                let near_boundary =
+                    right_len % CHUNK_SIZE <= 1 || right_len % CHUNK_SIZE >= CHUNK_SIZE - 1;
+                if !near_boundary && right_len % 300 != 0 {
+                    continue;
+                }
+                let whole = &input[..split + right_len];
+                let left = partial_state(&whole[..split], 0);
+                let right = partial_state(&whole[split..], left_chunks as u64);
+                let merged = State::merge(left, right).unwrap();
+                assert_eq!(whole.len() as u64, merged.count());
+                assert_eq!(blake3::hash(whole), finalize(merged));
+            }
+        }
+
+        // The merged state keeps going like any other.
+        let left = partial_state(&input[..4 * CHUNK_SIZE], 0);
+        let right = partial_state(&input[4 * CHUNK_SIZE..][..2 * CHUNK_SIZE], 4);
+        let mut merged = State::merge(left, right).unwrap();
+        for (i, chunk) in input[6 * CHUNK_SIZE..].chunks(CHUNK_SIZE).enumerate() {
+            while merged.merge_parent().is_some() {}
+            let hash = blake3::guts::ChunkState::new(6 + i as u64)
+                .update(chunk)
+                .finalize(false);
+            merged.push_subtree(&hash, chunk.len()).unwrap();
+        }
+        assert_eq!(blake3::hash(&input), finalize(merged));
+
+        // A small heap stack can overflow.
+        let mut left = State::with_max_depth(1);
+        let hash = blake3::hash(b"foo");
+        left.push_subtree(&hash, CHUNK_SIZE).unwrap();
+        assert_eq!(
+            Err(DepthExceeded),
+            State::merge(left, partial_state(&input[..100], 1)).map(|_| ())
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_state_merge_unaligned() {
+        let hash = blake3::hash(b"foo");
+        let mut left = State::new();
+        for _ in 0..3 {
+            while left.merge_parent().is_some() {}
+            left.push_subtree(&hash, CHUNK_SIZE).unwrap();
+        }
+        let _ = State::merge(left, State::new());
+    }
+
+    #[test]
+    fn test_state_merge_schedule() {
+        // Track subtree sizes explicitly, merging while the two smallest are equal, and check that
+        // the carry trick in needs_merge produces the same schedule. Subtrees larger than one
+        // chunk are allowed too, as long as they're a constant power of two.
+        let hash = blake3::hash(b"foo");
+        for &subtree_chunks in &[1u64, 2, 8] {
+            let subtree_len = subtree_chunks as usize * CHUNK_SIZE;
+            let max_subtrees = crate::test::TEST_CASES.iter().max().unwrap() / CHUNK_SIZE + 100;
+            let mut state = State::new();
+            let mut sizes: Vec<u64> = Vec::new();
+            for _ in 0..max_subtrees {
+                state.push_subtree(&hash, subtree_len).unwrap();
+                sizes.push(subtree_chunks);
+                let mut merges = 0;
+                while state.merge_parent().is_some() {
+                    merges += 1;
+                }
+                let mut expected_merges = 0;
+                while sizes.len() >= 2 && sizes[sizes.len() - 1] == sizes[sizes.len() - 2] {
+                    let size = sizes.pop().unwrap();
+                    *sizes.last_mut().unwrap() += size;
+                    expected_merges += 1;
+                }
+                assert_eq!(expected_merges, merges);
+                assert_eq!(sizes.len(), state.subtrees.len());
+            }
+        }
+
+        // And the shape that schedule builds matches the tree geometry.
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let mut encoded = Vec::new();
+            let mut encoder = PostorderEncoder::new(&mut encoded);
+            encoder.write_all(&input).unwrap();
+            assert_eq!(blake3::hash(&input), encoder.finalize().unwrap());
+            assert_eq!(encoded_size(case as u64), encoded.len() as u128);
+        }
+    }
+
     // These tests just check the different implementations against each other,
     // but explicit test vectors are included in test_vectors.json and checked
     // in the integration tests.
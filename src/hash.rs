@@ -0,0 +1,2090 @@
+//! Compute hashes of parts of the Bao tree.
+//!
+//! For the hash of an entire input, use
+//! [`blake3::hash`](https://docs.rs/blake3/0.3/blake3/fn.hash.html) or
+//! [`blake3::Hasher`](https://docs.rs/blake3/0.3/blake3/struct.Hasher.html) directly. Bao and BLAKE3
+//! share the same root hash. The functions in this module expose the interior of the tree, which
+//! is useful for things like comparing the subtrees of different inputs.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! // Two inputs that share their first 4 chunks (4096 bytes).
+//! let mut input1 = vec![0; 5000];
+//! let mut input2 = vec![0; 6000];
+//! input1[4500] = 1;
+//! input2[4500] = 2;
+//!
+//! // Their first subtrees match, even though their root hashes don't.
+//! let subtree1 = bao::hash::subtree_hash(&input1, 0, 4096)?;
+//! let subtree2 = bao::hash::subtree_hash(&input2, 0, 4096)?;
+//! assert_eq!(subtree1, subtree2);
+//! assert_ne!(blake3::hash(&input1), blake3::hash(&input2));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::encode;
+use crate::{Finalization, Hash, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, PARENT_SIZE};
+use arrayref::array_ref;
+use std::cmp;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Errors that can happen when hashing part of the tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The requested range isn't a node in the tree of the given input. A subtree always starts
+    /// at a multiple of its own size, rounded up to a power of two chunks, and only a subtree on
+    /// the right edge of the tree can end with a partial chunk.
+    InvalidSubtree,
+    /// The chunk can't appear in any tree at the requested position. A chunk holds at most
+    /// `CHUNK_SIZE` bytes, only a non-root chunk can have an index other than zero, and only the
+    /// root chunk of the empty input can be empty.
+    InvalidChunk,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidSubtree => write!(f, "invalid subtree"),
+            Error::InvalidChunk => write!(f, "invalid chunk"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::InvalidSubtree => io::Error::new(io::ErrorKind::InvalidInput, "invalid subtree"),
+            Error::InvalidChunk => io::Error::new(io::ErrorKind::InvalidInput, "invalid chunk"),
+        }
+    }
+}
+
+/// Compute the hash of the subtree covering the content range `[start, start + len)` of `input`.
+///
+/// The range must be a node in the tree of `input`, that is, a chunk or a parent node. Otherwise
+/// this returns `Error::InvalidSubtree`. The result is always finalized as a non-root node, so for
+/// the range covering all of `input` it's *not* the same as the root hash. That's what makes
+/// subtree hashes comparable between inputs of different lengths: the hashes of two subtrees
+/// match if and only if their content and position match. These are the same hashes that appear
+/// as the children in encoded parent nodes.
+pub fn subtree_hash(input: &[u8], start: u64, len: u64) -> Result<Hash, Error> {
+    if !is_subtree(input.len() as u64, start, len) {
+        return Err(Error::InvalidSubtree);
+    }
+    let bytes = &input[start as usize..][..len as usize];
+    Ok(hash_subtree_inner(
+        bytes,
+        start / CHUNK_SIZE as u64,
+        Finalization::NotRoot,
+    ))
+}
+
+/// Compute the hash of all of `input` as a non-root node, the way it would appear as the left child
+/// in the root parent node of a larger input that starts with `input`. This is `subtree_hash` of
+/// the full range. When `input` is a power of two chunks, it's the left subtree of any larger
+/// input that starts with it and is at most twice as long, and this is the left child hash in
+/// that input's root node. The length of the larger input doesn't affect it.
+///
+/// Note that this only works at the front of the larger input. Each chunk hash depends on the
+/// chunk's index, so the same content at any other offset has a different subtree hash. Use
+/// `subtree_hash` for those.
+///
+/// # Example
+///
+/// ```
+/// let left = vec![0xab; 4096];
+/// let mut larger = left.clone();
+/// larger.extend_from_slice(&[0xcd; 1000]);
+/// let (encoded, _) = bao::encode::encode(&larger);
+/// let root_left_child = &encoded[8..][..32];
+/// assert_eq!(root_left_child, bao::hash::hash_subtree(&left).as_bytes());
+/// ```
+pub fn hash_subtree(input: &[u8]) -> Hash {
+    hash_subtree_inner(input, 0, Finalization::NotRoot)
+}
+
+/// Compute the hash of a single chunk, given its index in the tree. This is for systems that hash
+/// chunks in different places and combine them later. If the chunk is the entire input, set
+/// `is_root`, and the result is the root hash. Otherwise the result is the same hash that appears
+/// as a child in the encoded parent node above the chunk, or equivalently the `subtree_hash` of
+/// the chunk's range.
+///
+/// Returns `Error::InvalidChunk` if the chunk is longer than `CHUNK_SIZE`, if a root chunk has a
+/// nonzero index, or if a non-root chunk is empty.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let root = bao::hash::hash_chunk(b"foo", 0, true)?;
+/// assert_eq!(blake3::hash(b"foo"), root);
+/// # Ok(())
+/// # }
+/// ```
+pub fn hash_chunk(chunk: &[u8], chunk_index: u64, is_root: bool) -> Result<Hash, Error> {
+    let invalid = if is_root {
+        chunk_index != 0
+    } else {
+        chunk.is_empty()
+    };
+    if chunk.len() > CHUNK_SIZE || invalid {
+        return Err(Error::InvalidChunk);
+    }
+    let finalization = if is_root {
+        Finalization::Root
+    } else {
+        Finalization::NotRoot
+    };
+    Ok(hash_subtree_inner(chunk, chunk_index, finalization))
+}
+
+/// Compute the hash of every chunk of `input`, in content order. This is the leaf layer of the
+/// tree, without any of the parent nodes, for things like a columnar index of chunk hashes.
+///
+/// Every hash is finalized as a non-root, including the only chunk of an input that's a single
+/// chunk or less, so each one is the same as `hash_chunk` with `is_root` false. That keeps the
+/// layer uniform, but it means that for a single chunk input the root hash isn't in the result,
+/// and it can't be derived from it. Use `blake3::hash` for that. The empty input still has one
+/// chunk, so the result always has one hash per chunk, and it's never empty.
+///
+/// With the `rayon` Cargo feature, chunks are hashed in parallel.
+///
+/// # Example
+///
+/// ```
+/// let input = vec![0xab; 2500];
+/// let hashes = bao::hash::chunk_hashes(&input);
+/// assert_eq!(3, hashes.len());
+/// assert_eq!(bao::hash::subtree_hash(&input, 1024, 1024).unwrap(), hashes[1]);
+/// ```
+pub fn chunk_hashes(input: &[u8]) -> Vec<Hash> {
+    if input.is_empty() {
+        return vec![hash_subtree_inner(input, 0, Finalization::NotRoot)];
+    }
+    let hash_one =
+        |(i, chunk): (usize, &[u8])| hash_subtree_inner(chunk, i as u64, Finalization::NotRoot);
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        input
+            .par_chunks(CHUNK_SIZE)
+            .enumerate()
+            .map(hash_one)
+            .collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        input.chunks(CHUNK_SIZE).enumerate().map(hash_one).collect()
+    }
+}
+
+/// Compute the root hash of each of `inputs`, in order. The result is the same as calling
+/// `blake3::hash` on each one.
+///
+/// With the `rayon` Cargo feature, this parallelizes across inputs, with one task per input rather
+/// than splitting up each input. That keeps the thread pool busy when there are many small inputs,
+/// like thousands of small files, where each one alone is too short to be worth splitting.
+///
+/// # Example
+///
+/// ```
+/// let inputs: &[&[u8]] = &[b"foo", b"bar"];
+/// let hashes = bao::hash::hash_many(inputs);
+/// assert_eq!(vec![blake3::hash(b"foo"), blake3::hash(b"bar")], hashes);
+/// ```
+pub fn hash_many(inputs: &[&[u8]]) -> Vec<Hash> {
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        inputs.par_iter().map(|input| blake3::hash(input)).collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        inputs.iter().map(|input| blake3::hash(input)).collect()
+    }
+}
+
+/// Encode a content length as the 8-byte header at the front of every encoding and outboard
+/// encoding, little-endian. The header isn't part of any hash. The root hash commits to the
+/// length through the shape of the tree, and decoders authenticate the header by checking that
+/// the tree it implies hashes to the expected root. See `hash::root_finalize`.
+///
+/// # Example
+///
+/// ```
+/// let (encoded, _) = bao::encode::encode(b"foo");
+/// assert_eq!(bao::hash::encode_len(3), encoded[..8]);
+/// assert_eq!(3, bao::hash::decode_len(&bao::hash::encode_len(3)));
+/// ```
+pub fn encode_len(len: u64) -> [u8; HEADER_SIZE] {
+    debug_assert_eq!(std::mem::size_of_val(&len), HEADER_SIZE);
+    len.to_le_bytes()
+}
+
+/// Decode a content length from an 8-byte header, the inverse of `encode_len`. Any 8 bytes are a
+/// valid header, but one that disagrees with the encoding after it fails verification.
+pub fn decode_len(bytes: &[u8; HEADER_SIZE]) -> u64 {
+    u64::from_le_bytes(*bytes)
+}
+
+/// Compute the root hash from the root node of a tree and the total content length. This is the
+/// last step of hashing, and it's how the root hash ends up committing to the length.
+///
+/// The length determines what the root node is. If `content_len` is at most `CHUNK_SIZE`, the
+/// whole input is a single chunk, and `node` must be that chunk, `content_len` bytes long.
+/// Otherwise `node` must be the 64-byte root parent node, the concatenation of the left and
+/// right child hashes, the same bytes that follow the header in a combined or outboard encoding.
+/// Either way the node is compressed with the BLAKE3 ROOT flag, which an interior node never
+/// gets, so a root hash can't collide with a subtree hash.
+///
+/// Unlike older versions of Bao, the length isn't appended to the root node. A single chunk root
+/// commits to the length directly, since BLAKE3 hashes the chunk's byte count. A parent root
+/// commits to it through its children: each chunk hash commits to the chunk's index and size,
+/// and the left subtree always holds the largest power of two chunks that leaves something on
+/// the right, so two different lengths can't produce the same tree of nodes. That's why a
+/// decoder can trust the header once the root node verifies.
+///
+/// Returns `Error::InvalidChunk` if a single-chunk `node` isn't `content_len` bytes, and
+/// `Error::InvalidSubtree` if a multi-chunk `node` isn't a parent node.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0; 5000];
+/// let (encoded, hash) = bao::encode::encode(&input);
+/// let root_node = &encoded[8..][..64];
+/// assert_eq!(hash, bao::hash::root_finalize(root_node, input.len() as u64)?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn root_finalize(node: &[u8], content_len: u64) -> Result<Hash, Error> {
+    if content_len <= CHUNK_SIZE as u64 {
+        if node.len() as u64 != content_len {
+            return Err(Error::InvalidChunk);
+        }
+        return hash_chunk(node, 0, true);
+    }
+    if node.len() != PARENT_SIZE {
+        return Err(Error::InvalidSubtree);
+    }
+    let left = array_ref!(node, 0, HASH_SIZE);
+    let right = array_ref!(node, HASH_SIZE, HASH_SIZE);
+    Ok(blake3::guts::parent_cv(
+        &(*left).into(),
+        &(*right).into(),
+        true,
+    ))
+}
+
+/// Parameters for a root hash that commits to more than the content. With `associated_data`, the
+/// hash also commits to a small blob like a version tag or a context string, which isn't part of
+/// the content and never gets streamed.
+///
+/// The tree is the same as always, and so are its interior nodes and its standard root hash.
+/// Associated data can't go into the root node itself, because that's a BLAKE3 compression with
+/// the ROOT flag, and changing it would break compatibility with BLAKE3. Instead, the bound hash
+/// is the keyed BLAKE3 hash of the associated data followed by the 8-byte little-endian content
+/// length, keyed with the standard root hash. Decoding takes the standard root hash from the
+/// encoding's root node, checks it against the bound hash, and then verifies everything under it
+/// as usual. See `decode::decode_with_params`.
+///
+/// Without associated data, the hash is the standard root hash.
+///
+/// # Example
+///
+/// ```
+/// let mut params = bao::hash::Params::new();
+/// params.associated_data(b"protocol v1");
+/// let hash = params.hash(b"some input");
+/// assert_ne!(blake3::hash(b"some input"), hash);
+/// assert_eq!(blake3::hash(b"some input"), bao::hash::Params::new().hash(b"some input"));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Params {
+    associated_data: Option<Vec<u8>>,
+}
+
+impl Params {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Commit to `aad` in the root hash. Calling this again replaces the previous associated
+    /// data. Empty associated data still changes the hash.
+    pub fn associated_data(&mut self, aad: &[u8]) -> &mut Self {
+        self.associated_data = Some(aad.to_vec());
+        self
+    }
+
+    /// The root hash of `input` under these parameters.
+    pub fn hash(&self, input: &[u8]) -> Hash {
+        self.bind_root(&blake3::hash(input), input.len() as u64)
+    }
+
+    /// Turn a standard root hash, for example from `encode::encode`, into the root hash under
+    /// these parameters.
+    pub fn bind_root(&self, root: &Hash, content_len: u64) -> Hash {
+        match &self.associated_data {
+            Some(aad) => blake3::Hasher::new_keyed(root.as_bytes())
+                .update(aad)
+                .update(&encode_len(content_len))
+                .finalize(),
+            None => *root,
+        }
+    }
+}
+
+/// Hash `input` with caller-supplied leaf boundaries in place of the fixed chunk size. Leaf `i`
+/// is the range `[boundaries[i], boundaries[i + 1])`, so `boundaries` starts at 0, ends at
+/// `input.len()`, and is strictly increasing. The one exception is the empty input, which has a
+/// single empty leaf, `[0, 0]`. The usual tree is built above the leaves, with the left subtree
+/// of every parent holding the largest power of two leaves that leaves something on the right,
+/// and the root finalized as usual.
+///
+/// Each leaf is hashed as a BLAKE3 subtree of its own bytes, with chunk counters starting at the
+/// leaf's index. A leaf of at most `CHUNK_SIZE` bytes is a single chunk, so boundaries at every
+/// multiple of `CHUNK_SIZE` give the standard root hash, and so does a single leaf covering all of
+/// `input`. Any other boundaries give a different hash, which the standard decoders can't verify.
+/// This is a non-standard mode, for systems that store content in known, variable-size pieces and
+/// record the boundaries alongside the hash.
+///
+/// # Panic
+///
+/// This will panic if `boundaries` doesn't describe leaves covering all of `input` as above.
+///
+/// # Example
+///
+/// ```
+/// let input = vec![0xab; 3000];
+/// let uniform = bao::hash::hash_with_boundaries(&input, &[0, 1024, 2048, 3000]);
+/// assert_eq!(blake3::hash(&input), uniform);
+/// let custom = bao::hash::hash_with_boundaries(&input, &[0, 100, 3000]);
+/// assert_ne!(uniform, custom);
+/// ```
+pub fn hash_with_boundaries(input: &[u8], boundaries: &[u64]) -> Hash {
+    assert!(
+        boundaries.len() >= 2,
+        "boundaries must have at least two entries"
+    );
+    assert_eq!(0, boundaries[0], "boundaries must start at 0");
+    assert_eq!(
+        input.len() as u64,
+        boundaries[boundaries.len() - 1],
+        "boundaries must end at the input length"
+    );
+    let empty_input = input.is_empty() && boundaries.len() == 2;
+    assert!(
+        empty_input || boundaries.windows(2).all(|pair| pair[0] < pair[1]),
+        "boundaries must be strictly increasing"
+    );
+    if boundaries.len() == 2 {
+        return hash_subtree_inner(input, 0, Finalization::Root);
+    }
+
+    fn recurse(leaves: &[Hash], finalization: Finalization) -> Hash {
+        if leaves.len() == 1 {
+            return leaves[0];
+        }
+        // The largest power of two that leaves at least one leaf on the right.
+        let left_leaves = 1 << (63 - (leaves.len() as u64 - 1).leading_zeros());
+        let left = recurse(&leaves[..left_leaves], Finalization::NotRoot);
+        let right = recurse(&leaves[left_leaves..], Finalization::NotRoot);
+        blake3::guts::parent_cv(&left, &right, finalization.is_root())
+    }
+    let leaves: Vec<Hash> = boundaries
+        .windows(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            let leaf = &input[pair[0] as usize..pair[1] as usize];
+            hash_subtree_inner(leaf, i as u64, Finalization::NotRoot)
+        })
+        .collect();
+    recurse(&leaves, Finalization::Root)
+}
+
+/// Count the content bytes covered by subtrees that `input1` and `input2` have in common. This is
+/// what `bao dedup` reports.
+///
+/// Two subtrees are in common if they cover the same range in both trees and their subtree hashes
+/// are equal, so this only finds shared content at the same offsets, aligned to the tree. Each
+/// byte is counted at most once, in the largest common subtree that covers it. The result is a
+/// lower bound on what a sender with `input1` doesn't need to transfer, so `len(input2) - result`
+/// is an upper bound on what a sender with `input1` needs to transfer to reproduce `input2`.
+pub fn shared_subtree_len(input1: &[u8], input2: &[u8]) -> u64 {
+    // Walk the tree of input1, checking each node against input2. If a node is also in the tree
+    // of input2, then its children are too, so we can compute both hashes bottom-up.
+    fn recurse(input1: &[u8], input2: &[u8], start: u64, len: u64) -> (Hash, Option<Hash>, u64) {
+        let chunk_index = start / CHUNK_SIZE as u64;
+        let in_both = is_subtree(input2.len() as u64, start, len);
+        if len <= CHUNK_SIZE as u64 {
+            let bytes1 = &input1[start as usize..][..len as usize];
+            let hash1 = hash_subtree_inner(bytes1, chunk_index, Finalization::NotRoot);
+            if !in_both {
+                return (hash1, None, 0);
+            }
+            let bytes2 = &input2[start as usize..][..len as usize];
+            let hash2 = hash_subtree_inner(bytes2, chunk_index, Finalization::NotRoot);
+            let shared = if hash1 == hash2 { len } else { 0 };
+            return (hash1, Some(hash2), shared);
+        }
+        let left_len = encode::left_subtree_len(len);
+        let (left1, left2, left_shared) = recurse(input1, input2, start, left_len);
+        let (right1, right2, right_shared) =
+            recurse(input1, input2, start + left_len, len - left_len);
+        let hash1 = blake3::guts::parent_cv(&left1, &right1, false);
+        if !in_both {
+            return (hash1, None, left_shared + right_shared);
+        }
+        let hash2 = blake3::guts::parent_cv(&left2.unwrap(), &right2.unwrap(), false);
+        let shared = if hash1 == hash2 {
+            len
+        } else {
+            left_shared + right_shared
+        };
+        (hash1, Some(hash2), shared)
+    }
+    recurse(input1, input2, 0, input1.len() as u64).2
+}
+
+/// The error returned by `Writer::update_capped` when the input would go past the cap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuotaExceeded;
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "quota exceeded")
+    }
+}
+
+impl error::Error for QuotaExceeded {}
+
+/// An incremental hasher that keeps track of how many bytes it's consumed.
+///
+/// The hash is the same as `blake3::Hasher` would give. The byte count makes it easier to enforce
+/// a limit on untrusted input as it's hashed, for example with `update_capped`.
+///
+/// # Example
+///
+/// ```
+/// use std::io::prelude::*;
+///
+/// let mut writer = bao::hash::Writer::new();
+/// writer.write_all(b"some ")?;
+/// writer.update_capped(b"input", 10)?;
+/// assert_eq!(10, writer.count());
+/// assert_eq!(blake3::hash(b"some input"), writer.finalize());
+///
+/// // Going past the cap consumes input up to the cap and then fails.
+/// assert!(writer.update_capped(b"more", 12).is_err());
+/// assert_eq!(12, writer.count());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Writer {
+    hasher: WriterHasher,
+    count: u64,
+}
+
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)] // The regular case shouldn't pay for a heap allocation.
+enum WriterHasher {
+    Root(blake3::Hasher),
+    Subtree(SubtreeHasher),
+}
+
+impl Default for WriterHasher {
+    fn default() -> Self {
+        WriterHasher::Root(blake3::Hasher::new())
+    }
+}
+
+// Hashes input that starts at chunk `first_chunk` of some larger input, as a non-root subtree.
+// Since the result is never finalized as a root, completed chunks and subtrees can be merged
+// eagerly, without waiting to see whether more input follows.
+#[derive(Clone, Debug)]
+struct SubtreeHasher {
+    first_chunk: u64,
+    chunks: u64,
+    chunk_state: blake3::guts::ChunkState,
+    stack: Vec<Hash>,
+}
+
+impl SubtreeHasher {
+    fn new(first_chunk: u64) -> Self {
+        Self {
+            first_chunk,
+            chunks: 0,
+            chunk_state: blake3::guts::ChunkState::new(first_chunk),
+            stack: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            let take = cmp::min(CHUNK_SIZE - self.chunk_state.len(), input.len());
+            self.chunk_state.update(&input[..take]);
+            input = &input[take..];
+            if self.chunk_state.len() == CHUNK_SIZE {
+                self.stack.push(self.chunk_state.finalize(false));
+                self.chunks += 1;
+                let mut merges = self.chunks.trailing_zeros();
+                while merges > 0 {
+                    let right = self.stack.pop().unwrap();
+                    let left = self.stack.pop().unwrap();
+                    self.stack
+                        .push(blake3::guts::parent_cv(&left, &right, false));
+                    merges -= 1;
+                }
+                self.chunk_state = blake3::guts::ChunkState::new(self.first_chunk + self.chunks);
+            }
+        }
+    }
+
+    fn finalize(&self) -> Hash {
+        let mut stack = self.stack.clone();
+        if self.chunk_state.len() > 0 || stack.is_empty() {
+            stack.push(self.chunk_state.finalize(false));
+        }
+        let mut hash = stack.pop().unwrap();
+        while let Some(left) = stack.pop() {
+            hash = blake3::guts::parent_cv(&left, &hash, false);
+        }
+        hash
+    }
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a new `Writer` that continues from the current position as the start of a subtree.
+    /// Writing the following bytes to it gives the subtree hashes of that region of the input,
+    /// without rehashing anything before it. This is how to get the hash of everything from byte
+    /// `K` to the end cheaply, after hashing the whole input once.
+    ///
+    /// The fork can only start on a chunk boundary, so this returns `None` unless `count` is a
+    /// multiple of `CHUNK_SIZE`. The fork's `finalize` returns a non-root subtree hash, the same
+    /// as `subtree_hash` for that region, and that's only a real node of the original tree if the
+    /// region is a subtree of it: its start has to be aligned to its size, rounded up to a power
+    /// of 2 number of chunks. Forking a fork works the same way, relative to the original input.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::io::prelude::*;
+    ///
+    /// let input = vec![0xab; 4096];
+    /// let mut writer = bao::hash::Writer::new();
+    /// writer.write_all(&input[..2048])?;
+    /// let mut fork = writer.fork_at_subtree_boundary().unwrap();
+    /// fork.write_all(&input[2048..])?;
+    /// assert_eq!(bao::hash::subtree_hash(&input, 2048, 2048)?, fork.finalize());
+    ///
+    /// writer.write_all(b"x")?;
+    /// assert!(writer.fork_at_subtree_boundary().is_none());
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn fork_at_subtree_boundary(&self) -> Option<Writer> {
+        if !self.count.is_multiple_of(CHUNK_SIZE as u64) {
+            return None;
+        }
+        let start_chunk = match &self.hasher {
+            WriterHasher::Root(_) => 0,
+            WriterHasher::Subtree(subtree) => subtree.first_chunk,
+        } + self.count / CHUNK_SIZE as u64;
+        Some(Writer {
+            hasher: WriterHasher::Subtree(SubtreeHasher::new(start_chunk)),
+            count: 0,
+        })
+    }
+
+    /// The number of bytes hashed so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Hash `input`, unless that would take the total past `max_total` bytes. In that case, only
+    /// the bytes up to `max_total` are hashed, and this returns `QuotaExceeded`. Either way the
+    /// writer stays usable, and `count` reflects exactly what was hashed.
+    pub fn update_capped(&mut self, input: &[u8], max_total: u64) -> Result<(), QuotaExceeded> {
+        let remaining = max_total.saturating_sub(self.count);
+        if input.len() as u64 <= remaining {
+            self.update(input);
+            Ok(())
+        } else {
+            self.update(&input[..remaining as usize]);
+            Err(QuotaExceeded)
+        }
+    }
+
+    /// The root hash of everything written so far. The writer can keep accepting input after
+    /// this. For a writer from `fork_at_subtree_boundary`, this is the subtree hash of everything
+    /// written since the fork instead.
+    pub fn finalize(&self) -> Hash {
+        match &self.hasher {
+            WriterHasher::Root(hasher) => hasher.finalize(),
+            WriterHasher::Subtree(subtree) => subtree.finalize(),
+        }
+    }
+
+    fn update(&mut self, input: &[u8]) {
+        match &mut self.hasher {
+            WriterHasher::Root(hasher) => {
+                hasher.update(input);
+            }
+            WriterHasher::Subtree(subtree) => subtree.update(input),
+        }
+        self.count += input.len() as u64;
+    }
+}
+
+impl io::Write for Writer {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        self.update(input);
+        Ok(input.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hashes one part of a larger input, so that separate machines can each hash their own part and
+/// combine the results with `merge`, without rehashing anything. Each part starts on a chunk
+/// boundary, given to `new`. Once the parts have been merged into one that starts at 0,
+/// `finalize` gives the root hash of the whole input.
+///
+/// # Example
+///
+/// ```
+/// use bao::hash::PartialHasher;
+///
+/// let input = vec![0xab; 10_000];
+/// // Hash the first 8 chunks on one machine, and the rest on another.
+/// let mut left = PartialHasher::new(0);
+/// left.update(&input[..8192]);
+/// let mut right = PartialHasher::new(8192);
+/// right.update(&input[8192..]);
+/// let whole = PartialHasher::merge(left, right);
+/// assert_eq!(blake3::hash(&input), whole.finalize());
+/// ```
+#[derive(Clone, Debug)]
+pub struct PartialHasher {
+    start: u64,
+    chunk_state: blake3::guts::ChunkState,
+    tree_state: encode::State,
+}
+
+impl PartialHasher {
+    /// Create a hasher for the part of the input starting at byte `start`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` isn't a multiple of the chunk size, 1024.
+    pub fn new(start: u64) -> Self {
+        assert!(
+            start.is_multiple_of(CHUNK_SIZE as u64),
+            "start must be on a chunk boundary"
+        );
+        Self {
+            start,
+            chunk_state: blake3::guts::ChunkState::new(start / CHUNK_SIZE as u64),
+            tree_state: encode::State::new(),
+        }
+    }
+
+    /// The offset in the whole input where this part starts.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The number of bytes hashed so far.
+    pub fn count(&self) -> u64 {
+        self.tree_state.count() + self.chunk_state.len() as u64
+    }
+
+    pub fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // Only push a full chunk once more input arrives, because the final chunk of the
+            // whole input might need root finalization.
+            if self.chunk_state.len() == CHUNK_SIZE {
+                self.push_chunk();
+            }
+            let take = cmp::min(CHUNK_SIZE - self.chunk_state.len(), input.len());
+            self.chunk_state.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+
+    fn push_chunk(&mut self) {
+        let hash = self.chunk_state.finalize(false);
+        self.tree_state
+            .push_subtree(&hash, self.chunk_state.len())
+            .expect("the fixed stack holds any input");
+        while self.tree_state.merge_parent().is_some() {}
+        let next_chunk = (self.start + self.tree_state.count()) / CHUNK_SIZE as u64;
+        self.chunk_state = blake3::guts::ChunkState::new(next_chunk);
+    }
+
+    /// Combine the hashers for two adjacent parts of the input, as though one hasher had taken
+    /// all of `left` followed by all of `right`. If either side is empty, the other one is
+    /// returned.
+    ///
+    /// Otherwise, `left` has to be a whole subtree of the tree over the whole input: a power of 2
+    /// number of full chunks, starting at a multiple of its own length. `right` can be any length
+    /// up to the length of `left`. Merging the parts smallest-first, like adjacent bits carrying
+    /// in a binary number, always meets those conditions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `right` doesn't start where `left` ends, or if the conditions above don't hold.
+    pub fn merge(mut left: PartialHasher, right: PartialHasher) -> PartialHasher {
+        assert_eq!(
+            left.start + left.count(),
+            right.start,
+            "right must start where left ends"
+        );
+        if right.count() == 0 {
+            return left;
+        }
+        if left.count() == 0 {
+            return right;
+        }
+        let left_len = left.count();
+        assert!(
+            left.chunk_state.len() == CHUNK_SIZE
+                && (left_len / CHUNK_SIZE as u64).is_power_of_two()
+                && left.start.is_multiple_of(left_len),
+            "left must be a whole subtree"
+        );
+        assert!(right.count() <= left_len, "right is longer than left");
+        // More input follows the left side now, so its last chunk can be pushed.
+        left.push_chunk();
+        let tree_state = encode::State::merge(left.tree_state, right.tree_state)
+            .expect("the fixed stack holds any input");
+        PartialHasher {
+            start: left.start,
+            chunk_state: right.chunk_state,
+            tree_state,
+        }
+    }
+
+    /// The root hash of the whole input, for a hasher whose part starts at 0 and runs to the end.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this part doesn't start at 0. The hash of a part in the middle of the input
+    /// depends on what comes after it, so it isn't defined until the parts are merged.
+    pub fn finalize(&self) -> Hash {
+        assert_eq!(0, self.start, "only the whole input has a root hash");
+        let mut tree_state = self.tree_state.clone();
+        if self.chunk_state.len() > 0 || tree_state.count() == 0 {
+            let is_root = tree_state.count() == 0;
+            let hash = self.chunk_state.finalize(is_root);
+            tree_state
+                .push_subtree(&hash, self.chunk_state.len())
+                .expect("the fixed stack holds any input");
+        }
+        loop {
+            if let encode::StateFinish::Root(root) = tree_state.merge_finalize() {
+                return root;
+            }
+        }
+    }
+}
+
+impl io::Write for PartialHasher {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        self.update(input);
+        Ok(input.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A `Writer` that also passes everything written through to another writer, so that a server can
+/// hash content and store it in one pass, and encode it later without receiving it again. Only
+/// the bytes that the inner writer accepts are hashed, so a short write leaves the two in
+/// agreement.
+///
+/// # Example
+///
+/// ```
+/// use std::io::prelude::*;
+///
+/// let mut stored = Vec::new();
+/// let mut writer = bao::hash::TappedWriter::new(&mut stored);
+/// writer.write_all(b"some input")?;
+/// assert_eq!(blake3::hash(b"some input"), writer.finalize()?);
+/// assert_eq!(b"some input", &*stored);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct TappedWriter<W: io::Write> {
+    writer: Writer,
+    inner: W,
+}
+
+impl<W: io::Write> TappedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            writer: Writer::new(),
+            inner,
+        }
+    }
+
+    /// The number of bytes hashed and passed through so far.
+    pub fn count(&self) -> u64 {
+        self.writer.count()
+    }
+
+    /// Flush the inner writer, and return the root hash of everything written so far. Like
+    /// `Writer::finalize`, this doesn't end the input.
+    pub fn finalize(&mut self) -> io::Result<Hash> {
+        self.inner.flush()?;
+        Ok(self.writer.finalize())
+    }
+
+    /// Return the inner writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: io::Write> io::Write for TappedWriter<W> {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(input)?;
+        self.writer.update(&input[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hash the file at `path`, and return its root hash and its length. The file is read and hashed
+/// incrementally, so this works the same for things that aren't regular files, like pipes and
+/// devices, and the length is however many bytes the file produced. This crate doesn't use
+/// `unsafe`, so it doesn't memory-map the file the way the `bao` command line tool does.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let dir = tempfile::tempdir()?;
+/// let path = dir.path().join("file");
+/// std::fs::write(&path, b"some input")?;
+/// let (hash, len) = bao::hash::hash_file(&path)?;
+/// assert_eq!(blake3::hash(b"some input"), hash);
+/// assert_eq!(10, len);
+/// # Ok(())
+/// # }
+/// ```
+pub fn hash_file(path: impl AsRef<Path>) -> io::Result<(Hash, u64)> {
+    let mut file = File::open(path)?;
+    let mut writer = Writer::new();
+    io::copy(&mut file, &mut writer)?;
+    Ok((writer.finalize(), writer.count()))
+}
+
+/// Hash everything left in `reader`, taking the bytes straight from its own buffer. Unlike
+/// `io::copy` into a `Writer`, nothing is copied into a second buffer first, so this is the
+/// faster way to hash an already-buffered source like a `BufReader<File>`. An empty buffer from
+/// `fill_buf` means EOF. `ErrorKind::Interrupted` errors are retried, and any other error is
+/// returned, with the bytes before it already consumed.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut reader = std::io::BufReader::new(&b"some input"[..]);
+/// assert_eq!(blake3::hash(b"some input"), bao::hash::hash_bufread(&mut reader)?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn hash_bufread<R: io::BufRead>(reader: &mut R) -> io::Result<Hash> {
+    let mut writer = Writer::new();
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if buf.is_empty() {
+            return Ok(writer.finalize());
+        }
+        let n = buf.len();
+        writer.update(buf);
+        reader.consume(n);
+    }
+}
+
+/// Find the content ranges of `new` that aren't covered by a matching chunk in `old`, as sorted,
+/// non-overlapping `(start, end)` pairs, with `end` exclusive. Adjacent ranges are merged. These
+/// are the bytes a sender would need to transfer to turn `old` into `new`.
+///
+/// This compares the two inputs one chunk at a time. A chunk of `new` is unchanged if `old` has a
+/// chunk of the same length at the same offset, with the same chunk hash. The chunk hashes are the
+/// leaves of the tree, so this finds the same ranges as comparing the trees level by level, but
+/// there's nothing to gain from pruning matching subtrees here. Both inputs are in memory, and
+/// their subtree hashes would have to be computed from all of their chunks anyway. The cost is
+/// about the same as hashing both inputs.
+///
+/// Because it compares chunks at the same offsets, this is **alignment-sensitive**. An edit in
+/// place only changes the chunks it touches, but an insertion or a deletion shifts all the content
+/// after it to different chunk boundaries, and all of those chunks show up as changed.
+///
+/// # Example
+///
+/// ```
+/// let old = vec![0; 10_000];
+/// let mut new = old.clone();
+/// new[5000] = 1;
+/// // Only the chunk containing byte 5000 changed.
+/// assert_eq!(vec![(4096, 5120)], bao::hash::tree_diff(&old, &new));
+/// ```
+pub fn tree_diff(old: &[u8], new: &[u8]) -> Vec<(u64, u64)> {
+    let mut changed: Vec<(u64, u64)> = Vec::new();
+    for (chunk_index, new_chunk) in new.chunks(CHUNK_SIZE).enumerate() {
+        let start = chunk_index as u64 * CHUNK_SIZE as u64;
+        let len = new_chunk.len() as u64;
+        // A chunk of the same length at the same offset has the same chunk index in both trees.
+        let unchanged = is_subtree(old.len() as u64, start, len) && {
+            let old_chunk = &old[start as usize..][..len as usize];
+            let old_hash = hash_subtree_inner(old_chunk, chunk_index as u64, Finalization::NotRoot);
+            let new_hash = hash_subtree_inner(new_chunk, chunk_index as u64, Finalization::NotRoot);
+            // Hash implements constant time equality.
+            old_hash == new_hash
+        };
+        if !unchanged {
+            match changed.last_mut() {
+                Some((_, end)) if *end == start => *end = start + len,
+                _ => changed.push((start, start + len)),
+            }
+        }
+    }
+    changed
+}
+
+/// An in-memory copy of some content along with every node hash in its tree, which supports
+/// cheap root hash updates after small edits.
+///
+/// Rehashing the whole content after every edit is O(n). But an edit only changes the chunks it
+/// overlaps and the parent nodes above them, so `update_range` recomputes just those, for a cost of
+/// O(changed chunks + depth). The tree shape depends on the content length, so edits can't change
+/// the length.
+///
+/// # Example
+///
+/// ```
+/// let mut content = vec![0; 100_000];
+/// let mut tree = bao::hash::MutableTree::new(content.clone());
+/// assert_eq!(blake3::hash(&content), tree.root());
+///
+/// tree.update_range(50_000, b"hello");
+/// content[50_000..][..5].copy_from_slice(b"hello");
+/// assert_eq!(blake3::hash(&content), tree.root());
+/// ```
+#[derive(Clone)]
+pub struct MutableTree {
+    content: Vec<u8>,
+    // levels[0] holds the (non-root) chunk hashes. Each level above that pairs up the nodes below
+    // it, and an odd node at the end gets carried up unchanged. That's the same as the BLAKE3
+    // tree, where the left subtree is always the largest power of two. The top level has two
+    // nodes, the children of the root, or only one if the whole tree is a single chunk.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MutableTree {
+    /// Hash all of `content` and keep the hashes of every node. This takes as long as hashing
+    /// the content normally.
+    pub fn new(content: Vec<u8>) -> Self {
+        let num_chunks = encode::count_chunks(content.len() as u64) as usize;
+        let mut tree = Self {
+            content,
+            levels: vec![vec![Hash::from([0; 32]); num_chunks]],
+        };
+        while tree.levels.last().unwrap().len() > 2 {
+            let next_len = tree.levels.last().unwrap().len().div_ceil(2);
+            tree.levels.push(vec![Hash::from([0; 32]); next_len]);
+        }
+        tree.rehash_chunks(0, num_chunks - 1);
+        tree
+    }
+
+    /// The current content.
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    /// Overwrite the content starting at `offset` with `bytes`, and update the affected hashes.
+    ///
+    /// # Panic
+    ///
+    /// This will panic if the range extends past the end of the content.
+    pub fn update_range(&mut self, offset: u64, bytes: &[u8]) {
+        let end = offset
+            .checked_add(bytes.len() as u64)
+            .expect("addition overflowed");
+        assert!(
+            end <= self.content.len() as u64,
+            "update past the end of the content"
+        );
+        if bytes.is_empty() {
+            return;
+        }
+        self.content[offset as usize..end as usize].copy_from_slice(bytes);
+        let first_chunk = offset as usize / CHUNK_SIZE;
+        let last_chunk = (end as usize - 1) / CHUNK_SIZE;
+        self.rehash_chunks(first_chunk, last_chunk);
+    }
+
+    /// The root hash of the current content. This is the same as `blake3::hash(tree.content())`.
+    pub fn root(&self) -> Hash {
+        let top = self.levels.last().unwrap();
+        if top.len() == 1 {
+            // A single chunk is finalized as the root directly.
+            hash_subtree_inner(&self.content, 0, Finalization::Root)
+        } else {
+            blake3::guts::parent_cv(&top[0], &top[1], true)
+        }
+    }
+
+    // Recompute the chunks in the inclusive range, and then every node above them.
+    fn rehash_chunks(&mut self, first: usize, last: usize) {
+        for index in first..=last {
+            let start = index * CHUNK_SIZE;
+            let end = cmp::min(start + CHUNK_SIZE, self.content.len());
+            self.levels[0][index] = hash_subtree_inner(
+                &self.content[start..end],
+                index as u64,
+                Finalization::NotRoot,
+            );
+        }
+        let (mut first, mut last) = (first, last);
+        for level in 1..self.levels.len() {
+            first /= 2;
+            last /= 2;
+            let (below, above) = self.levels.split_at_mut(level);
+            let below = &below[level - 1];
+            for index in first..=last {
+                above[0][index] = if 2 * index + 1 < below.len() {
+                    blake3::guts::parent_cv(&below[2 * index], &below[2 * index + 1], false)
+                } else {
+                    below[2 * index]
+                };
+            }
+        }
+    }
+}
+
+impl fmt::Debug for MutableTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Avoid printing hashes or content, they might be secret.
+        write!(
+            f,
+            "MutableTree {{ content_len: {}, levels: {} }}",
+            self.content.len(),
+            self.levels.len()
+        )
+    }
+}
+
+/// An incremental hasher for overlapping windows of a stream. Windows are `window_len` bytes long
+/// and start every `stride` bytes, and each window gets its own root hash, the same as
+/// `blake3::hash` of its bytes. Windows that run past the end of the stream are never completed.
+/// If `stride` is longer than `window_len`, the bytes between windows are skipped.
+///
+/// Each window in progress keeps its own hasher, so memory use is proportional to the number of
+/// overlapping windows, and nothing is buffered. Windows can't share subtree hashes with each
+/// other, though, not even where their content overlaps: each chunk hash depends on the chunk's
+/// index within its window, and a window that starts `stride` bytes later sees the same bytes at
+/// different indexes. The overlapping bytes get hashed once per window.
+///
+/// # Example
+///
+/// ```
+/// let input = vec![0xab; 10_000];
+/// let mut hasher = bao::hash::WindowHasher::new(4096, 2048);
+/// let windows = hasher.push(&input);
+/// let starts: Vec<u64> = windows.iter().map(|&(start, _)| start).collect();
+/// assert_eq!(vec![0, 2048, 4096], starts);
+/// assert_eq!(blake3::hash(&input[2048..6144]), windows[1].1);
+/// ```
+#[derive(Clone, Debug)]
+pub struct WindowHasher {
+    window_len: u64,
+    stride: u64,
+    position: u64,
+    next_start: Option<u64>,
+    // Windows in progress, oldest first, along with their start positions. The oldest one always
+    // finishes first.
+    windows: VecDeque<(u64, blake3::Hasher)>,
+}
+
+impl WindowHasher {
+    /// # Panic
+    ///
+    /// This will panic if `window_len` or `stride` is zero.
+    pub fn new(window_len: u64, stride: u64) -> Self {
+        assert!(window_len > 0, "window_len must be nonzero");
+        assert!(stride > 0, "stride must be nonzero");
+        Self {
+            window_len,
+            stride,
+            position: 0,
+            next_start: Some(0),
+            windows: VecDeque::new(),
+        }
+    }
+
+    /// The number of bytes pushed so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Hash more of the stream, and return the start position and root hash of every window that
+    /// it completes, in order.
+    pub fn push(&mut self, mut bytes: &[u8]) -> Vec<(u64, Hash)> {
+        let mut completed = Vec::new();
+        while !bytes.is_empty() {
+            if self.next_start == Some(self.position) {
+                self.windows
+                    .push_back((self.position, blake3::Hasher::new()));
+                self.next_start = self.position.checked_add(self.stride);
+            }
+            // Hash up to the next place where a window starts or ends.
+            let mut boundary = self.next_start.unwrap_or(u64::MAX);
+            if let Some(&(start, _)) = self.windows.front() {
+                boundary = cmp::min(boundary, start + self.window_len);
+            }
+            let take = cmp::min(bytes.len() as u64, boundary - self.position) as usize;
+            for (_, hasher) in self.windows.iter_mut() {
+                hasher.update(&bytes[..take]);
+            }
+            self.position += take as u64;
+            bytes = &bytes[take..];
+            while let Some(&(start, _)) = self.windows.front() {
+                if start + self.window_len != self.position {
+                    break;
+                }
+                let (start, hasher) = self.windows.pop_front().unwrap();
+                completed.push((start, hasher.finalize()));
+            }
+        }
+        completed
+    }
+}
+
+// Descend from the root toward the subtree containing `start`, and see whether we hit the
+// requested range exactly.
+pub(crate) fn is_subtree(content_len: u64, start: u64, len: u64) -> bool {
+    let mut node_start = 0;
+    let mut node_len = content_len;
+    loop {
+        if node_start == start && node_len == len {
+            return true;
+        }
+        if node_len <= CHUNK_SIZE as u64 || start < node_start {
+            return false;
+        }
+        let left_len = encode::left_subtree_len(node_len);
+        if start < node_start + left_len {
+            node_len = left_len;
+        } else {
+            node_start += left_len;
+            node_len -= left_len;
+        }
+    }
+}
+
+// Hash a subtree whose first chunk has the given index. This doesn't validate anything; the caller
+// needs to make sure the bytes are a real subtree.
+pub(crate) fn hash_subtree_inner(
+    bytes: &[u8],
+    chunk_index: u64,
+    finalization: Finalization,
+) -> Hash {
+    if bytes.len() <= CHUNK_SIZE {
+        return blake3::guts::ChunkState::new(chunk_index)
+            .update(bytes)
+            .finalize(finalization.is_root());
+    }
+    let left_len = encode::left_subtree_len(bytes.len() as u64) as usize;
+    let left_chunks = (left_len / CHUNK_SIZE) as u64;
+    let left = hash_subtree_inner(&bytes[..left_len], chunk_index, Finalization::NotRoot);
+    let right = hash_subtree_inner(
+        &bytes[left_len..],
+        chunk_index + left_chunks,
+        Finalization::NotRoot,
+    );
+    blake3::guts::parent_cv(&left, &right, finalization.is_root())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::make_test_input;
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+
+    #[test]
+    fn test_root_subtree() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let root = hash_subtree_inner(&input, 0, Finalization::Root);
+            assert_eq!(blake3::hash(&input), root);
+            // The full-range subtree is valid, but it's finalized as a non-root.
+            let subtree = subtree_hash(&input, 0, case as u64).unwrap();
+            if case > 0 {
+                assert_ne!(root, subtree);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hash_subtree() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let subtree = hash_subtree(&input);
+            assert_eq!(subtree_hash(&input, 0, case as u64).unwrap(), subtree);
+            if case > 0 {
+                assert_ne!(blake3::hash(&input), subtree);
+            }
+
+            // Pad the input to a power of two chunks and add one more byte, so that the padded
+            // input is the left subtree of the larger input. Compare against the left child of
+            // the root parent node.
+            let full_chunks = cmp::max(1, encode::count_chunks(case as u64).next_power_of_two());
+            let left_len = full_chunks as usize * CHUNK_SIZE;
+            let mut larger = make_test_input(left_len);
+            larger.push(0xff);
+            let (encoded, _) = encode::encode(&larger);
+            let root_node = &encoded[HEADER_SIZE..][..PARENT_SIZE];
+            let left_child: Hash = (*array_ref!(root_node, 0, HASH_SIZE)).into();
+            assert_eq!(hash_subtree(&larger[..left_len]), left_child);
+            if case == left_len {
+                assert_eq!(subtree, left_child);
+            }
+        }
+    }
+
+    // Known-answer vectors for root_finalize, as (input_len, root hash) with the input from
+    // make_test_input. They were generated with the Python reference implementation in
+    // tests/bao.py, so other implementations can check against them without trusting this crate.
+    const ROOT_VECTORS: &[(usize, &str)] = &[
+        (
+            0,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+        ),
+        (
+            1,
+            "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+        ),
+        (
+            10,
+            "87fcf07cac5be3c91735b34e535c67286e4e7a63bf152d95f2cf4cd1a244758b",
+        ),
+        (
+            1023,
+            "5b391e822a608808b1c1f88df4f4af7895da33de87ed1f6350dadf0e49df6da0",
+        ),
+        (
+            1024,
+            "fbd9d482bafff8144c12def7a0bf9e31cd58029ce139c333d9abd5ea5defc1ce",
+        ),
+        (
+            1025,
+            "1772c7c40186d212cec883c44a448210d5fe05a7c5baf646850066c70a40903c",
+        ),
+        (
+            2047,
+            "cda83c074fb7fe0e49c15cae2e903c7fd888dad6c13b87adf3a0de0b44bbc4c1",
+        ),
+        (
+            2048,
+            "290acbb4c8486a7f45b4d76c36da9aa0e7ac57163776df764727f01845485077",
+        ),
+        (
+            2049,
+            "6eba1131ac94d4c1ee9cedb2a6de868c2d0d7e8348b44fed29035a5664e8b3f4",
+        ),
+        (
+            3071,
+            "50b19488e6f344c5e943a7fecf4e72cfc776fd0d212ded3869c8b42172dd219d",
+        ),
+        (
+            3072,
+            "ced6f0e311c786e6ef0def20186073b4a6b6de807672315595996b594941daf3",
+        ),
+        (
+            3073,
+            "a91eb767c779087b421622b068f5c301e664dbf43fdfd87f354446d9d998b968",
+        ),
+        (
+            4095,
+            "51815bde286cb80ba81c9196acbb4868d3185aca9a557295ccc54596c1d5bd2f",
+        ),
+        (
+            4096,
+            "2c0e68e6e97ab48400a4ffacc4b9924def65c237facc76cde2f733c3bbb32683",
+        ),
+        (
+            4097,
+            "f8276d5c622ae77ef810cbe1368bc4f9d79f03ce939312ca497793eba2786c7a",
+        ),
+        (
+            8191,
+            "1b00e48922212264e9958c08262c3a4c27d1afb014095eee0fda237c979c5249",
+        ),
+        (
+            8192,
+            "e0b94bbd5642ab21a8f538d4e59633d1039904677e3966fea620215b551b8d41",
+        ),
+        (
+            8193,
+            "d2694a337a813b007f779fcbb164bd6277996383b7e9bfc86efa9d337b1314fc",
+        ),
+        (
+            16383,
+            "0ff84e51e8ec3f9185db20345ef5962e44e87dea60d0de3a23b00b740c0f4081",
+        ),
+        (
+            16384,
+            "aa962fa42324ad803a04bc9f409ce84701b9c66ba16aa6fd129882d3d9f2cc03",
+        ),
+        (
+            16385,
+            "d0ff8319fbeade02b764543683dde6fd26cfde590c1a1e63a64f6c0704a7fe46",
+        ),
+    ];
+
+    #[test]
+    fn test_root_finalize() {
+        let cases: Vec<usize> = ROOT_VECTORS.iter().map(|&(len, _)| len).collect();
+        assert_eq!(crate::test::TEST_CASES, &cases[..]);
+        for &(case, expected_hex) in ROOT_VECTORS {
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            assert_eq!(expected_hex, &*hash.to_hex());
+            let root_node = if case <= CHUNK_SIZE {
+                &input[..]
+            } else {
+                &encoded[HEADER_SIZE..][..PARENT_SIZE]
+            };
+            assert_eq!(hash, root_finalize(root_node, case as u64).unwrap());
+            assert_eq!(case as u64, decode_len(array_ref!(encoded, 0, HEADER_SIZE)));
+            assert_eq!(encode_len(case as u64), encoded[..HEADER_SIZE]);
+
+            // A chunk root is only valid at its own length. A parent root finalizes the same way
+            // at any multi-chunk length, because the length is committed in its children.
+            for &other in crate::test::TEST_CASES {
+                match root_finalize(root_node, other as u64) {
+                    Ok(other_hash) => {
+                        assert_eq!(hash, other_hash);
+                        assert!(other == case || (case > CHUNK_SIZE && other > CHUNK_SIZE));
+                    }
+                    Err(Error::InvalidChunk) => assert!(other <= CHUNK_SIZE && other != case),
+                    Err(Error::InvalidSubtree) => assert!(other > CHUNK_SIZE && case <= CHUNK_SIZE),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_params_associated_data() {
+        for &case in crate::test::TEST_CASES {
+            let input = crate::decode::make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            assert_eq!(hash, Params::new().hash(&input));
+
+            let mut params1 = Params::new();
+            params1.associated_data(b"one");
+            let mut params2 = Params::new();
+            params2.associated_data(b"two");
+            let mut params_empty = Params::new();
+            params_empty.associated_data(b"");
+            let hash1 = params1.hash(&input);
+            let hash2 = params2.hash(&input);
+            assert_ne!(hash, hash1);
+            assert_ne!(hash1, hash2);
+            assert_ne!(hash, params_empty.hash(&input));
+            assert_eq!(hash1, params1.bind_root(&hash, case as u64));
+
+            // The content decodes the same way under either set of associated data, but only
+            // with the matching hash.
+            assert_eq!(
+                input,
+                crate::decode::decode_with_params(&encoded, &hash1, &params1).unwrap()
+            );
+            assert_eq!(
+                input,
+                crate::decode::decode_with_params(&encoded, &hash2, &params2).unwrap()
+            );
+            let err = crate::decode::decode_with_params(&encoded, &hash2, &params1).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            let err = crate::decode::decode_with_params(&encoded, &hash, &params1).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_hash_many() {
+        let inputs: Vec<Vec<u8>> = crate::test::TEST_CASES
+            .iter()
+            .map(|&case| make_test_input(case))
+            .collect();
+        let slices: Vec<&[u8]> = inputs.iter().map(|input| &input[..]).collect();
+        let expected: Vec<Hash> = slices.iter().map(|input| blake3::hash(input)).collect();
+        assert_eq!(expected, hash_many(&slices));
+        assert!(hash_many(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_chunk_hashes() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let hashes = chunk_hashes(&input);
+            assert_eq!(encode::count_chunks(case as u64), hashes.len() as u64);
+            if case <= CHUNK_SIZE {
+                // The only chunk is a non-root, so it isn't the root hash.
+                assert_eq!(
+                    hash_subtree_inner(&input, 0, Finalization::NotRoot),
+                    hashes[0]
+                );
+                assert_ne!(blake3::hash(&input), hashes[0]);
+                continue;
+            }
+
+            // Building the tree from the chunk hashes gives the root hash.
+            let mut state = encode::State::new();
+            for (i, hash) in hashes.iter().enumerate() {
+                while state.merge_parent().is_some() {}
+                let size = encode::chunk_size(i as u64, case as u64);
+                assert_eq!(
+                    hash_chunk(&input[i * CHUNK_SIZE..][..size], i as u64, false),
+                    Ok(*hash)
+                );
+                state.push_subtree(hash, size).unwrap();
+            }
+            let root = loop {
+                if let encode::StateFinish::Root(root) = state.merge_finalize() {
+                    break root;
+                }
+            };
+            assert_eq!(blake3::hash(&input), root);
+        }
+    }
+
+    #[test]
+    fn test_hash_chunk() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            if case <= CHUNK_SIZE {
+                assert_eq!(blake3::hash(&input), hash_chunk(&input, 0, true).unwrap());
+                continue;
+            }
+            // Compare the first and last chunks against the children of the parent nodes on the
+            // left and right edges of the tree.
+            let (encoded, _) = encode::encode(&input);
+            let first_parent = &encoded[HEADER_SIZE..][..PARENT_SIZE];
+            let left_child: Hash = (*array_ref!(first_parent, 0, HASH_SIZE)).into();
+            let first_chunk = &input[..CHUNK_SIZE];
+            let left_len = encode::left_subtree_len(case as u64) as usize;
+            if left_len == CHUNK_SIZE {
+                assert_eq!(left_child, hash_chunk(first_chunk, 0, false).unwrap());
+            }
+            let last_index = encode::count_chunks(case as u64) - 1;
+            let last_chunk = &input[last_index as usize * CHUNK_SIZE..];
+            let proof = crate::decode::single_byte_proof(&encoded, case as u64 - 1).unwrap();
+            let last_parent = &proof[proof.len() - last_chunk.len() - PARENT_SIZE..];
+            let right_child: Hash = (*array_ref!(last_parent, HASH_SIZE, HASH_SIZE)).into();
+            assert_eq!(
+                right_child,
+                hash_chunk(last_chunk, last_index, false).unwrap()
+            );
+            assert_eq!(
+                subtree_hash(&input, 0, CHUNK_SIZE as u64).unwrap(),
+                hash_chunk(first_chunk, 0, false).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_chunks() {
+        let big = [0; CHUNK_SIZE + 1];
+        assert_eq!(Err(Error::InvalidChunk), hash_chunk(&big, 0, true));
+        assert_eq!(Err(Error::InvalidChunk), hash_chunk(&big, 1, false));
+        assert_eq!(Err(Error::InvalidChunk), hash_chunk(b"foo", 1, true));
+        assert_eq!(Err(Error::InvalidChunk), hash_chunk(b"", 1, false));
+        assert_eq!(blake3::hash(b""), hash_chunk(b"", 0, true).unwrap());
+    }
+
+    #[test]
+    fn test_subtree_hash_matches_proof() {
+        for &case in crate::test::TEST_CASES {
+            if case <= CHUNK_SIZE {
+                continue;
+            }
+            let input = make_test_input(case);
+            let (encoded, _) = encode::encode(&input);
+            for &offset in crate::test::TEST_CASES {
+                if offset >= case {
+                    continue;
+                }
+                println!("case {} offset {}", case, offset);
+                // Walk the parent nodes in the proof for this offset, and check both children of
+                // each one against the subtree hashes computed from the input.
+                let proof = crate::decode::single_byte_proof(&encoded, offset as u64).unwrap();
+                let mut parents = &proof[HEADER_SIZE..];
+                let mut node_start = 0;
+                let mut node_len = case as u64;
+                while node_len > CHUNK_SIZE as u64 {
+                    let left_len = encode::left_subtree_len(node_len);
+                    let right_len = node_len - left_len;
+                    let left_child: Hash = (*array_ref!(parents, 0, HASH_SIZE)).into();
+                    let right_child: Hash = (*array_ref!(parents, HASH_SIZE, HASH_SIZE)).into();
+                    assert_eq!(
+                        left_child,
+                        subtree_hash(&input, node_start, left_len).unwrap()
+                    );
+                    assert_eq!(
+                        right_child,
+                        subtree_hash(&input, node_start + left_len, right_len).unwrap()
+                    );
+                    parents = &parents[PARENT_SIZE..];
+                    if (offset as u64) < node_start + left_len {
+                        node_len = left_len;
+                    } else {
+                        node_start += left_len;
+                        node_len = right_len;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_shared_subtree_len() {
+        let chunk = CHUNK_SIZE as u64;
+        let input = make_test_input(16 * CHUNK_SIZE + 1);
+
+        // Identical inputs share everything.
+        assert_eq!(
+            input.len() as u64,
+            shared_subtree_len(&input, &input.clone())
+        );
+
+        // A prefix shares its complete subtrees, and so does the reverse comparison.
+        let prefix = &input[..5 * CHUNK_SIZE + 10];
+        assert_eq!(5 * chunk, shared_subtree_len(&input, prefix));
+        assert_eq!(5 * chunk, shared_subtree_len(prefix, &input));
+
+        // Changing one byte in chunk 9 loses only that chunk.
+        let mut changed = input.clone();
+        changed[9 * CHUNK_SIZE + 3] ^= 1;
+        assert_eq!(
+            input.len() as u64 - chunk,
+            shared_subtree_len(&input, &changed)
+        );
+
+        // Shifting the content by one byte breaks all the alignment.
+        let shifted = &input[1..];
+        assert_eq!(0, shared_subtree_len(&input, shifted));
+
+        // Empty inputs.
+        assert_eq!(0, shared_subtree_len(b"", &input));
+        assert_eq!(0, shared_subtree_len(&input, b""));
+        assert_eq!(0, shared_subtree_len(b"", b""));
+    }
+
+    #[test]
+    fn test_writer_capped() {
+        let input = make_test_input(10_000);
+        let cap = 4097;
+        let mut writer = Writer::new();
+        for piece in input.chunks(1000) {
+            if writer.update_capped(piece, cap).is_err() {
+                break;
+            }
+        }
+        assert_eq!(cap, writer.count());
+        assert_eq!(blake3::hash(&input[..cap as usize]), writer.finalize());
+
+        // Once at the cap, any more input fails without being hashed, but empty input is fine.
+        assert_eq!(Err(QuotaExceeded), writer.update_capped(b"x", cap));
+        assert_eq!(Ok(()), writer.update_capped(b"", cap));
+        assert_eq!(cap, writer.count());
+
+        // Raising the cap lets the writer continue where it left off.
+        writer
+            .update_capped(&input[cap as usize..], u64::MAX)
+            .unwrap();
+        assert_eq!(input.len() as u64, writer.count());
+        assert_eq!(blake3::hash(&input), writer.finalize());
+    }
+
+    #[test]
+    fn test_writer_fork() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let mut writer = Writer::new();
+            for start in (0..=case).step_by(CHUNK_SIZE) {
+                let position = writer.count() as usize;
+                io::Write::write_all(&mut writer, &input[position..start]).unwrap();
+                let fork = writer.fork_at_subtree_boundary().unwrap();
+                // Feed the fork every subtree that starts here, in pieces that don't line up with
+                // chunks, and check it against the subtree hashes of the original input.
+                for end in start + 1..=case {
+                    let expected = match subtree_hash(&input, start as u64, (end - start) as u64) {
+                        Ok(hash) => hash,
+                        Err(_) => continue,
+                    };
+                    let mut fork = fork.clone();
+                    for piece in input[start..end].chunks(700) {
+                        io::Write::write_all(&mut fork, piece).unwrap();
+                    }
+                    assert_eq!(expected, fork.finalize());
+                    assert_eq!((end - start) as u64, fork.count());
+                }
+
+                // Forking a fork is relative to the original input.
+                if start + 2 * CHUNK_SIZE <= case {
+                    let mut fork = fork.clone();
+                    io::Write::write_all(&mut fork, &input[start..][..CHUNK_SIZE]).unwrap();
+                    let mut fork_of_fork = fork.fork_at_subtree_boundary().unwrap();
+                    io::Write::write_all(
+                        &mut fork_of_fork,
+                        &input[start + CHUNK_SIZE..][..CHUNK_SIZE],
+                    )
+                    .unwrap();
+                    let expected =
+                        subtree_hash(&input, (start + CHUNK_SIZE) as u64, CHUNK_SIZE as u64);
+                    assert_eq!(expected.unwrap(), fork_of_fork.finalize());
+                }
+            }
+
+            // Off a chunk boundary, there's no fork.
+            let position = writer.count() as usize;
+            io::Write::write_all(&mut writer, &input[position..]).unwrap();
+            assert_eq!(blake3::hash(&input), writer.finalize());
+            assert_eq!(
+                case % CHUNK_SIZE == 0,
+                writer.fork_at_subtree_boundary().is_some()
+            );
+        }
+    }
+
+    #[test]
+    fn test_partial_hasher() {
+        // Split the input into parts at every boundary in `splits`, hash the parts separately,
+        // and merge adjacent pairs until one part is left.
+        fn hash_parts(input: &[u8], splits: &[usize]) -> Hash {
+            let mut parts = Vec::new();
+            let mut start = 0;
+            for &end in splits.iter().chain(Some(&input.len())) {
+                let mut part = PartialHasher::new(start as u64);
+                for piece in input[start..end].chunks(700) {
+                    part.update(piece);
+                }
+                parts.push(part);
+                start = end;
+            }
+            while parts.len() > 1 {
+                let mut merged = Vec::new();
+                let mut iter = parts.into_iter();
+                while let Some(left) = iter.next() {
+                    merged.push(match iter.next() {
+                        Some(right) => PartialHasher::merge(left, right),
+                        None => left,
+                    });
+                }
+                parts = merged;
+            }
+            let whole = parts.pop().unwrap();
+            assert_eq!(input.len() as u64, whole.count());
+            whole.finalize()
+        }
+
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let expected = blake3::hash(&input);
+            assert_eq!(expected, hash_parts(&input, &[]));
+            // A whole subtree on the left, and everything after it on the right, as long as the
+            // right side is no longer.
+            for &left_chunks in &[1, 2, 4, 8] {
+                let split = left_chunks * CHUNK_SIZE;
+                if split <= case && case - split <= split {
+                    assert_eq!(expected, hash_parts(&input, &[split]), "split {}", split);
+                }
+            }
+            // Empty parts merge away.
+            assert_eq!(expected, hash_parts(&input, &[0]));
+            if case % CHUNK_SIZE == 0 {
+                assert_eq!(expected, hash_parts(&input, &[case]));
+            }
+            // Parts of one chunk each.
+            let splits: Vec<usize> = (CHUNK_SIZE..case).step_by(CHUNK_SIZE).collect();
+            assert_eq!(expected, hash_parts(&input, &splits));
+        }
+
+        // A part in the middle of the input has no root hash of its own.
+        let result = std::panic::catch_unwind(|| PartialHasher::new(CHUNK_SIZE as u64).finalize());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_partial_hasher_unaligned() {
+        // Three chunks on the left isn't a whole subtree.
+        let input = make_test_input(4 * CHUNK_SIZE);
+        let mut left = PartialHasher::new(0);
+        left.update(&input[..3 * CHUNK_SIZE]);
+        let mut right = PartialHasher::new(3 * CHUNK_SIZE as u64);
+        right.update(&input[3 * CHUNK_SIZE..]);
+        PartialHasher::merge(left, right);
+    }
+
+    #[test]
+    fn test_hash_bufread() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            // A buffer size that doesn't line up with chunks.
+            let mut reader = io::BufReader::with_capacity(700, io::Cursor::new(&input));
+            assert_eq!(blake3::hash(&input), hash_bufread(&mut reader).unwrap());
+            // Everything was consumed, and hashing again at EOF gives the empty hash.
+            assert_eq!(case as u64, reader.get_ref().position());
+            assert_eq!(blake3::hash(b""), hash_bufread(&mut reader).unwrap());
+        }
+
+        // A reader that's interrupted before every buffer, and fails partway through.
+        struct Flaky {
+            input: Vec<u8>,
+            position: usize,
+            interrupted: bool,
+            fail_at: usize,
+        }
+
+        impl io::Read for Flaky {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let available = io::BufRead::fill_buf(self)?;
+                let n = cmp::min(buf.len(), available.len());
+                buf[..n].copy_from_slice(&available[..n]);
+                io::BufRead::consume(self, n);
+                Ok(n)
+            }
+        }
+
+        impl io::BufRead for Flaky {
+            fn fill_buf(&mut self) -> io::Result<&[u8]> {
+                self.interrupted = !self.interrupted;
+                if self.interrupted {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                if self.position == self.fail_at {
+                    return Err(io::ErrorKind::Other.into());
+                }
+                let end = cmp::min(self.position + 100, self.input.len());
+                Ok(&self.input[self.position..end])
+            }
+
+            fn consume(&mut self, n: usize) {
+                self.position += n;
+            }
+        }
+
+        let input = make_test_input(2 * CHUNK_SIZE);
+        let mut reader = Flaky {
+            input: input.clone(),
+            position: 0,
+            interrupted: false,
+            fail_at: usize::MAX,
+        };
+        assert_eq!(blake3::hash(&input), hash_bufread(&mut reader).unwrap());
+        let mut reader = Flaky {
+            input,
+            position: 0,
+            interrupted: false,
+            fail_at: 500,
+        };
+        let err = hash_bufread(&mut reader).unwrap_err();
+        assert_eq!(io::ErrorKind::Other, err.kind());
+        assert_eq!(500, reader.position);
+    }
+
+    #[test]
+    fn test_tapped_writer() {
+        // Accepts at most 700 bytes per write, to exercise short writes.
+        struct ShortWriter(Vec<u8>);
+
+        impl io::Write for ShortWriter {
+            fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+                let n = cmp::min(input.len(), 700);
+                self.0.extend_from_slice(&input[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let mut writer = TappedWriter::new(ShortWriter(Vec::new()));
+            for piece in input.chunks(1000) {
+                io::Write::write_all(&mut writer, piece).unwrap();
+            }
+            assert_eq!(blake3::hash(&input), writer.finalize().unwrap());
+            assert_eq!(case as u64, writer.count());
+            let stored = writer.into_inner().0;
+            assert_eq!(input, stored);
+            // The stored bytes are enough to encode later.
+            assert_eq!(blake3::hash(&input), crate::encode::encode(&stored).1);
+        }
+    }
+
+    #[test]
+    fn test_tree_diff() {
+        let old = make_test_input(20 * CHUNK_SIZE + 100);
+        assert!(tree_diff(&old, &old).is_empty());
+        assert!(tree_diff(&old, &[]).is_empty());
+        assert_eq!(vec![(0, old.len() as u64)], tree_diff(&[], &old));
+
+        // In-place edits only change the chunks they touch.
+        let mut new = old.clone();
+        new[3 * CHUNK_SIZE + 5] ^= 1;
+        new[4 * CHUNK_SIZE] ^= 1;
+        new[10 * CHUNK_SIZE + 5] ^= 1;
+        new[20 * CHUNK_SIZE + 50] ^= 1;
+        let chunk = |i: u64| i * CHUNK_SIZE as u64;
+        assert_eq!(
+            vec![
+                (chunk(3), chunk(5)),
+                (chunk(10), chunk(11)),
+                (chunk(20), old.len() as u64),
+            ],
+            tree_diff(&old, &new)
+        );
+
+        // Appending changes the old partial chunk and everything after it.
+        let mut appended = old.clone();
+        appended.extend_from_slice(&[0; 2 * CHUNK_SIZE]);
+        assert_eq!(
+            vec![(chunk(20), appended.len() as u64)],
+            tree_diff(&old, &appended)
+        );
+
+        // Prepending shifts the alignment, so every chunk changes.
+        let mut prepended = vec![0xff];
+        prepended.extend_from_slice(&old);
+        assert_eq!(
+            vec![(0, prepended.len() as u64)],
+            tree_diff(&old, &prepended)
+        );
+
+        // The changed ranges plus the shared subtrees cover all of new.
+        let changed: u64 = tree_diff(&old, &new).iter().map(|(s, e)| e - s).sum();
+        assert_eq!(new.len() as u64, changed + shared_subtree_len(&old, &new));
+    }
+
+    #[test]
+    fn test_hash_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cases = crate::test::TEST_CASES.to_vec();
+        cases.push(1 << 20);
+        for case in cases {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let path = dir.path().join(format!("file{}", case));
+            std::fs::write(&path, &input).unwrap();
+            assert_eq!(
+                (blake3::hash(&input), case as u64),
+                hash_file(&path).unwrap()
+            );
+        }
+
+        // Devices aren't regular files, but they hash the same way.
+        #[cfg(unix)]
+        assert_eq!((blake3::hash(b""), 0), hash_file("/dev/null").unwrap());
+
+        let missing = dir.path().join("missing");
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            hash_file(&missing).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_mutable_tree() {
+        let mut prng = ChaChaRng::from_seed([0; 32]);
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let mut content = make_test_input(case);
+            let mut tree = MutableTree::new(content.clone());
+            assert_eq!(blake3::hash(&content), tree.root());
+            if case == 0 {
+                tree.update_range(0, b"");
+                assert_eq!(blake3::hash(b""), tree.root());
+                continue;
+            }
+            for _ in 0..20 {
+                let offset = prng.gen_range(0, case);
+                let len = prng.gen_range(0, cmp::min(case - offset, 3 * CHUNK_SIZE) + 1);
+                let mut bytes = vec![0; len];
+                prng.fill_bytes(&mut bytes);
+                tree.update_range(offset as u64, &bytes);
+                content[offset..][..len].copy_from_slice(&bytes);
+                assert_eq!(&content[..], tree.content());
+                assert_eq!(blake3::hash(&content), tree.root());
+            }
+        }
+    }
+
+    #[test]
+    fn test_window_hasher() {
+        let input = make_test_input(20_000);
+        let mut prng = ChaChaRng::from_seed([0; 32]);
+        let params = [
+            (1, 1),
+            (CHUNK_SIZE, CHUNK_SIZE),
+            (4096, 1000),
+            (5000, 4096),
+            (3000, 7000),
+            (20_000, 4096),
+            (20_001, 4096),
+        ];
+        for &(window_len, stride) in params.iter() {
+            println!("window_len {} stride {}", window_len, stride);
+            let mut hasher = WindowHasher::new(window_len as u64, stride as u64);
+            // Push the input in random-sized pieces, so that pieces straddle window boundaries.
+            let mut windows = Vec::new();
+            let mut pushed = 0;
+            while pushed < input.len() {
+                let len = cmp::min(prng.gen_range(0, 3000), input.len() - pushed);
+                windows.extend(hasher.push(&input[pushed..][..len]));
+                pushed += len;
+            }
+            assert_eq!(input.len() as u64, hasher.position());
+
+            let expected: Vec<(u64, Hash)> = (0..input.len())
+                .step_by(stride)
+                .take_while(|&start| start + window_len <= input.len())
+                .map(|start| (start as u64, blake3::hash(&input[start..][..window_len])))
+                .collect();
+            assert_eq!(expected, windows);
+        }
+    }
+
+    #[test]
+    fn test_hash_with_boundaries() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let expected = blake3::hash(&input);
+            let mut uniform: Vec<u64> = (0..case as u64).step_by(CHUNK_SIZE).collect();
+            uniform.push(case as u64);
+            if case == 0 {
+                uniform = vec![0, 0];
+            }
+            assert_eq!(expected, hash_with_boundaries(&input, &uniform));
+            assert_eq!(expected, hash_with_boundaries(&input, &[0, case as u64]));
+            if case < 2 {
+                continue;
+            }
+
+            // Other boundaries give other hashes, but the same ones every time.
+            let len = case as u64;
+            let mut candidates = vec![vec![0, len / 2, len], vec![0, 1, len]];
+            if uniform.len() > 2 {
+                let mut shifted = uniform.clone();
+                shifted[1] -= 1;
+                candidates.push(shifted);
+            }
+            let mut double: Vec<u64> = (0..len).step_by(2 * CHUNK_SIZE).collect();
+            double.push(len);
+            candidates.push(double);
+            candidates.retain(|boundaries| boundaries.len() > 2 && boundaries != &uniform);
+            candidates.sort();
+            candidates.dedup();
+            let mut other_hashes = Vec::new();
+            for boundaries in &candidates {
+                let hash = hash_with_boundaries(&input, boundaries);
+                assert_eq!(hash, hash_with_boundaries(&input, boundaries));
+                assert_ne!(expected, hash, "{:?}", boundaries);
+                assert!(!other_hashes.contains(&hash), "{:?}", boundaries);
+                other_hashes.push(hash);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_hash_with_boundaries_not_increasing() {
+        hash_with_boundaries(&[0; 10], &[0, 5, 5, 10]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_mutable_tree_past_end() {
+        let mut tree = MutableTree::new(vec![0; 10]);
+        tree.update_range(5, &[0; 6]);
+    }
+
+    #[test]
+    fn test_invalid_subtrees() {
+        let chunk = CHUNK_SIZE as u64;
+        let input = make_test_input(5 * CHUNK_SIZE + 1);
+        let bad_ranges = [
+            (0, 1),                 // part of a chunk
+            (1, chunk),             // misaligned
+            (0, 3 * chunk),         // not a power of two chunks
+            (chunk, 2 * chunk),     // misaligned for its size
+            (4 * chunk, 2 * chunk), // the right edge has a partial chunk
+            (0, 8 * chunk),         // past the end
+            (6 * chunk, 1),         // past the end
+        ];
+        for &(start, len) in bad_ranges.iter() {
+            println!("start {} len {}", start, len);
+            assert_eq!(
+                Error::InvalidSubtree,
+                subtree_hash(&input, start, len).unwrap_err()
+            );
+        }
+        let good_ranges = [
+            (0, chunk),
+            (chunk, chunk),
+            (0, 2 * chunk),
+            (2 * chunk, 2 * chunk),
+            (0, 4 * chunk),
+            (4 * chunk, chunk + 1),
+            (5 * chunk, 1),
+        ];
+        for &(start, len) in good_ranges.iter() {
+            println!("start {} len {}", start, len);
+            subtree_hash(&input, start, len).unwrap();
+        }
+    }
+}
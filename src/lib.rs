@@ -7,6 +7,12 @@
 //! [project README](https://github.com/oconnor663/bao) and the [full
 //! specification](https://github.com/oconnor663/bao/blob/master/docs/spec.md).
 //!
+//! By default, the library does all of its hashing on the calling thread, and it never
+//! memory-maps files, so it builds for targets that support neither, like
+//! `wasm32-unknown-unknown`. The optional `rayon` Cargo feature adds multi-threading in a few
+//! places: `decode::decode_parallel`, `hash::chunk_hashes`, and `hash::hash_many`. Memory mapping
+//! is a feature of the `bao` command line utility.
+//!
 //! # Example
 //!
 //! ```
@@ -40,36 +46,54 @@
 
 #![forbid(unsafe_code)]
 
+#[cfg(feature = "cdc")]
+pub mod cdc;
 pub mod decode;
 pub mod encode;
+pub mod hash;
 
 pub use blake3::Hash;
 
-use std::mem;
-
 /// The size of a `Hash`, 32 bytes.
 pub const HASH_SIZE: usize = 32;
 pub(crate) const PARENT_SIZE: usize = 2 * HASH_SIZE;
 pub(crate) const HEADER_SIZE: usize = 8;
 pub(crate) const CHUNK_SIZE: usize = blake3::CHUNK_LEN;
 pub(crate) const MAX_DEPTH: usize = blake3::MAX_DEPTH;
+pub(crate) const CRC_SIZE: usize = 4;
+// A CRC trailer is the CRC32 of the encoding, little-endian, followed by this magic.
+pub(crate) const TRAILER_MAGIC: [u8; 4] = *b"bcrc";
+pub(crate) const TRAILER_SIZE: usize = CRC_SIZE + TRAILER_MAGIC.len();
+// A versioned encoding starts with this magic and a format version byte, followed by the usual
+// header-first encoding.
+pub(crate) const VERSION_MAGIC: [u8; 4] = *b"baov";
+pub(crate) const VERSION_PREFIX_SIZE: usize = VERSION_MAGIC.len() + 1;
+
+/// The largest content length whose combined encoding fits in a `u64`, 0xf0f0f0f0f0f0f0f7 bytes.
+/// The header can hold longer lengths, but offsets into their encodings overflow, so functions
+/// like `encode::chunk_of_offset` reject them. See also `encode::max_content_len`.
+pub const MAX_CONTENT_LEN: u64 = {
+    // Every chunk but the last brings one parent node along with it, so n full chunks followed by
+    // a final chunk of r bytes encode to HEADER_SIZE + n * (CHUNK_SIZE + PARENT_SIZE) + r bytes.
+    // Fit as many full chunks as possible while leaving at least one byte for the final chunk.
+    let full_chunk_cost = (CHUNK_SIZE + PARENT_SIZE) as u64;
+    let full_chunks = (u64::MAX - HEADER_SIZE as u64 - 1) / full_chunk_cost;
+    let remaining = u64::MAX - HEADER_SIZE as u64 - full_chunks * full_chunk_cost;
+    let final_chunk = if remaining < CHUNK_SIZE as u64 {
+        remaining
+    } else {
+        CHUNK_SIZE as u64
+    };
+    full_chunks * CHUNK_SIZE as u64 + final_chunk
+};
 
 /// An array of `HASH_SIZE` bytes. This will be a wrapper type in a future version.
 pub(crate) type ParentNode = [u8; 2 * HASH_SIZE];
 
-pub(crate) fn encode_len(len: u64) -> [u8; HEADER_SIZE] {
-    debug_assert_eq!(mem::size_of_val(&len), HEADER_SIZE);
-    len.to_le_bytes()
-}
-
-pub(crate) fn decode_len(bytes: &[u8; HEADER_SIZE]) -> u64 {
-    u64::from_le_bytes(*bytes)
-}
-
-// The root node is hashed differently from interior nodes. It gets suffixed
-// with the length of the entire input, and we set the Blake2 final node flag.
-// That means that no root hash can ever collide with an interior hash, or with
-// the root of a different size tree.
+// The root node is hashed differently from interior nodes. BLAKE3 sets the
+// ROOT flag when it compresses it. That means that no root hash can ever
+// collide with an interior hash. The length isn't hashed directly, but it
+// determines the shape of the tree, and every chunk hash commits to its index.
 #[derive(Clone, Copy, Debug)]
 pub(crate) enum Finalization {
     NotRoot,
@@ -88,6 +112,24 @@ impl Finalization {
 #[doc(hidden)]
 pub mod benchmarks {
     pub const CHUNK_SIZE: usize = super::CHUNK_SIZE;
+
+    // Push the same chunk hash through the tree builder `num_chunks` times, merging parents as
+    // they complete. This isolates the merge bookkeeping from chunk hashing.
+    pub fn state_merge(num_chunks: u64) -> super::Hash {
+        let chunk_hash = blake3::guts::ChunkState::new(0).finalize(false);
+        let mut state = super::encode::State::new();
+        for i in 0..num_chunks {
+            state.push_subtree(&chunk_hash, CHUNK_SIZE).unwrap();
+            if i + 1 < num_chunks {
+                while state.merge_parent().is_some() {}
+            }
+        }
+        loop {
+            if let super::encode::StateFinish::Root(root) = state.merge_finalize() {
+                return root;
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -97,6 +97,23 @@ fn test_hash_vectors() {
     }
 }
 
+#[test]
+fn test_root_finalize_vectors() {
+    for case in &TEST_VECTORS.hash {
+        println!("case {:?}", case);
+        let input = make_input(case.input_len);
+        let (encoded, _) = bao::encode::encode(&input);
+        let root_node = if input.len() <= blake3::CHUNK_LEN {
+            &input[..]
+        } else {
+            &encoded[8..][..64]
+        };
+        let root = bao::hash::root_finalize(root_node, case.input_len as u64).unwrap();
+        assert_eq!(case.bao_hash, root.to_hex().to_string());
+        assert_eq!(bao::hash::encode_len(case.input_len as u64), encoded[..8]);
+    }
+}
+
 fn corrupt_hash(hash: &Hash) -> Hash {
     let mut bad_bytes = *hash.as_bytes();
     bad_bytes[0] ^= 1;